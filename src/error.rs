@@ -19,4 +19,21 @@ pub enum Error {
     CannotRemoveMountedMemoryFile,
     IncompatibleChunkSize,
     InvalidMagicMarker,
+    TooManyFiles,
+    XattrTooLarge,
+    BadFileDescriptor,
+    FileTooLarge,
+    CyclicLink,
+    UnexpectedEof,
+    InvalidNodeId,
+    CorruptedAllocator,
+    TooManyOpenFiles,
+    InvalidArgument,
+    PermissionDenied,
+    ReadOnlyFileSystem,
+    WouldBlock,
+    IllegalByteSequence,
+    OutOfMemory,
+    FileChunkLimitExceeded,
+    DirEntryLimitExceeded,
 }
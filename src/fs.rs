@@ -1,3 +1,8 @@
+use std::{
+    collections::BTreeSet,
+    ops::{Deref, DerefMut},
+};
+
 use ic_stable_structures::Memory;
 
 use crate::{
@@ -7,10 +12,16 @@ use crate::{
         dir::Dir,
         fd::{FdEntry, FdTable},
         file::File,
-        structure_helpers::{create_hard_link, find_node, rm_dir_entry},
+        hash::FileHasher,
+        structure_helpers::{
+            create_hard_link, create_path, find_ancestors, find_multi_linked_dirs, find_node,
+            glob_match, rm_dir_entry, try_grow_memory, walk_tree,
+        },
     },
     storage::{
-        types::{DirEntry, DirEntryIndex, FileType, Metadata, Node},
+        types::{
+            DirEntry, DirEntryIndex, FileChunkIndex, FileType, Metadata, Node, FILE_CHUNK_SIZE_V1,
+        },
         Storage,
     },
 };
@@ -18,18 +29,92 @@ use crate::{
 pub use crate::runtime::fd::Fd;
 
 pub use crate::runtime::types::{
-    ChunkSize, ChunkType, DstBuf, DstIoVec, FdFlags, FdStat, OpenFlags, SrcBuf, SrcIoVec, Whence,
+    Advice, ChunkSize, ChunkType, DiffEntry, DirOrder, DstBuf, DstIoVec, FdFlags, FdStat,
+    FullReads, HashAlgo, Normalization, OpenFlags, RemoveProgress, SrcBuf, SrcIoVec, Whence,
 };
-pub use crate::storage::types::FileSize;
+pub use crate::storage::types::{FileSize, OperationCounters, Times, MAX_FILE_SIZE};
+
+// A page of directory entries with the token of the next page, see `FileSystem::list_page`.
+pub type DirPage = (Vec<(DirEntryIndex, String)>, Option<DirEntryIndex>);
 
 // The main class implementing the API to work with the file system.
 pub struct FileSystem {
     root_fd: Fd,
     fd_table: FdTable,
     names_cache: FilenameCache,
+    // reject all modifications of the file system.
+    read_only: bool,
+    // bytes the cursor writes may store per call, see `set_write_budget_per_call`.
+    write_budget_per_call: Option<FileSize>,
+    write_budget_left: FileSize,
+    // entries created by the running `transaction`, removed in reverse order if it fails.
+    undo_log: Option<Vec<CreatedEntry>>,
     pub storage: Box<dyn Storage>,
 }
 
+// A folder or file created during a transaction, the entry `path` of the `parent` folder.
+struct CreatedEntry {
+    parent: Node,
+    path: String,
+    is_dir: bool,
+}
+
+// Defers the metadata updates of a folder while many entries are created in it,
+// the folder metadata is stored once the scope is dropped. See `FileSystem::bulk_create_scope`.
+pub struct BulkCreateScope<'a> {
+    fs: &'a mut FileSystem,
+}
+
+impl Deref for BulkCreateScope<'_> {
+    type Target = FileSystem;
+
+    fn deref(&self) -> &FileSystem {
+        self.fs
+    }
+}
+
+impl DerefMut for BulkCreateScope<'_> {
+    fn deref_mut(&mut self) -> &mut FileSystem {
+        self.fs
+    }
+}
+
+impl Drop for BulkCreateScope<'_> {
+    fn drop(&mut self) {
+        self.fs.storage.flush_deferred_metadata();
+    }
+}
+
+// Walks the entries of a folder one at a time, reading each entry only when it is requested.
+// See `FileSystem::dir_iter`.
+pub struct DirIter<'a> {
+    fs: &'a FileSystem,
+    node: Node,
+    next_index: Option<DirEntryIndex>,
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<(Node, String), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.next_index?;
+
+        match self.fs.storage.get_direntry(self.node, index) {
+            Ok(entry) => {
+                self.next_index = entry.next_entry;
+
+                let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+                Some(Ok((entry.node, name.into_owned())))
+            }
+            Err(err) => {
+                // the entry list is broken, stop after reporting the error
+                self.next_index = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
 impl FileSystem {
     // Create a new file system hosted on a given storage implementation.
     pub fn new(storage: Box<dyn Storage>) -> Result<Self, Error> {
@@ -40,6 +125,10 @@ impl FileSystem {
                 root_fd: 0,
                 fd_table,
                 names_cache: FilenameCache::new(),
+                read_only: false,
+                write_budget_per_call: None,
+                write_budget_left: 0,
+                undo_log: None,
                 storage,
             });
         }
@@ -53,13 +142,32 @@ impl FileSystem {
             root_fd,
             fd_table,
             names_cache,
+            read_only: false,
+            write_budget_per_call: None,
+            write_budget_left: 0,
+            undo_log: None,
             storage,
         })
     }
 
     pub fn flush(&mut self, fd: Fd) -> Result<(), Error> {
         let node = self.get_node(fd)?;
-        self.storage.flush(node);
+        self.storage.flush(node)
+    }
+
+    // Write out the changes of all files that are only kept in memory, like the tails buffered by the append buffering.
+    // Call it in `pre_upgrade`, the buffered data that is not flushed is lost on upgrade.
+    pub fn flush_all(&mut self) -> Result<(), Error> {
+        self.storage.flush_all()
+    }
+
+    // Store the current size and times of every mounted memory file with its mounted metadata,
+    // this only forwards to `Storage::persist_mounted_metadata`. After an upgrade `mount_memory_file` for the same
+    // file name loads the stored metadata again, so the file keeps its logical size rather than the size of the memory.
+    // The stable storage keeps the last update in its cache journal, which also survives an upgrade,
+    // so the call only moves that update into the mounted metadata map.
+    pub fn persist_mount_metadata(&mut self) -> Result<(), Error> {
+        self.storage.persist_mounted_metadata();
 
         Ok(())
     }
@@ -69,6 +177,38 @@ impl FileSystem {
         self.storage.get_version()
     }
 
+    // Check that the file system header in stable memory was not overwritten from outside since the file system
+    // last wrote it, e.g. by another structure sharing the memory manager with a wrong memory ID.
+    // This is a cheap tripwire to call at the start of a canister call, it does not verify the file contents.
+    // The fingerprint is kept on the heap, so changes made while the canister upgrades are not detected.
+    pub fn check_integrity_fingerprint(&self) -> Result<bool, Error> {
+        Ok(self.storage.check_integrity_fingerprint())
+    }
+
+    // Drop the cached file names, chunk pointers and metadata to free heap memory, e.g. before a phase that needs
+    // a lot of it. The caches are filled again by the following calls, the file system contents are not affected.
+    pub fn clear_caches(&mut self) {
+        self.names_cache.clear();
+        self.storage.clear_caches();
+    }
+
+    // Get the node ID that will be assigned to the next created file or folder.
+    pub fn next_node_id(&self) -> Node {
+        self.storage.next_node()
+    }
+
+    // Get the number of stored files and folders, the root folder included. The nodes are counted without
+    // walking the tree, so the files unlinked but not yet released (see `remove_file_chunked`) are counted too.
+    pub fn total_nodes(&self) -> u64 {
+        self.storage.node_count()
+    }
+
+    // Get the low-level operation counts of the storage, useful to catch performance regressions in tests.
+    #[cfg(feature = "profiling")]
+    pub fn operation_counters(&self) -> OperationCounters {
+        self.storage.operation_counters()
+    }
+
     // Get the file descriptor of the root folder.
     pub fn root_fd(&self) -> Fd {
         self.root_fd
@@ -85,7 +225,151 @@ impl FileSystem {
         self.fd_table.renumber(from, to)
     }
 
+    // Limit the number of simultaneously open file descriptors, the root folder descriptor is included.
+    // Opening a descriptor beyond the limit fails with `Error::TooManyOpenFiles`, None means no limit.
+    pub fn set_max_open_fds(&mut self, limit: Option<usize>) {
+        self.fd_table.set_max_open(limit);
+    }
+
+    // Switch the read-only mode, while it is on all modifications fail with `Error::PermissionDenied`.
+    // Reading and listing files works as usual, e.g. to serve query calls from the same file system.
+    // On a storage that is not writable itself the modifications fail with `Error::ReadOnlyFileSystem` instead.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Set the clock used for the folder modification times, while it is set adding or removing an entry
+    // updates the modification time of the folder. None disables it, which is the default.
+    // Unlike the file name policies, the clock cannot be stored in the header: set it again after every upgrade.
+    pub fn set_dir_clock(&mut self, clock: Option<Box<dyn Fn() -> u64>>) {
+        self.storage.set_dir_clock(clock);
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::PermissionDenied);
+        }
+
+        if !self.storage.is_writable() {
+            return Err(Error::ReadOnlyFileSystem);
+        }
+
+        Ok(())
+    }
+
+    // Limit the bytes that `write`, `write_vec` and `write_vectored` store until the next `reset_write_budget`,
+    // so that a huge write does not run into the instruction limit of a single canister message.
+    // A write crossing the remaining budget is cut short and returns the bytes written so far, once the budget is used up
+    // the writes fail with `Error::WouldBlock` and the canister can continue in the next message. None means no limit.
+    pub fn set_write_budget_per_call(&mut self, bytes: Option<FileSize>) {
+        self.write_budget_per_call = bytes;
+        self.write_budget_left = bytes.unwrap_or(0);
+    }
+
+    // Restore the full write budget, call it at the start of each canister message.
+    pub fn reset_write_budget(&mut self) {
+        self.write_budget_left = self.write_budget_per_call.unwrap_or(0);
+    }
+
+    // Get how many of `len` bytes can be written within the remaining write budget.
+    fn write_budget_limit(&self, len: FileSize) -> Result<FileSize, Error> {
+        match self.write_budget_per_call {
+            None => Ok(len),
+            Some(_) if len > 0 && self.write_budget_left == 0 => Err(Error::WouldBlock),
+            Some(_) => Ok(len.min(self.write_budget_left)),
+        }
+    }
+
+    fn spend_write_budget(&mut self, written: FileSize) {
+        if self.write_budget_per_call.is_some() {
+            self.write_budget_left = self.write_budget_left.saturating_sub(written);
+        }
+    }
+
+    // The prefixes of `path` that do not exist yet, they are created along with the path.
+    // Only collected while a transaction is running.
+    fn missing_path_prefixes(&mut self, parent: Node, path: &str) -> Vec<String> {
+        if self.undo_log.is_none() {
+            return Vec::new();
+        }
+
+        let mut prefixes = Vec::new();
+        let mut end = 0;
+
+        for part in path.split('/') {
+            end += part.len();
+
+            if !part.is_empty() && part != "." {
+                let prefix = &path[..end];
+
+                if !prefixes.is_empty()
+                    || find_node(parent, prefix, &mut self.names_cache, self.storage.as_ref())
+                        .is_err()
+                {
+                    prefixes.push(prefix.to_string());
+                }
+            }
+
+            end += 1;
+        }
+
+        prefixes
+    }
+
+    // Record the entries created for `path` in the undo log of the running transaction.
+    fn record_created(&mut self, parent: Node, prefixes: Vec<String>, leaf_is_dir: bool) {
+        if let Some(undo_log) = self.undo_log.as_mut() {
+            let count = prefixes.len();
+
+            for (i, path) in prefixes.into_iter().enumerate() {
+                let is_dir = i + 1 < count || leaf_is_dir;
+                undo_log.push(CreatedEntry {
+                    parent,
+                    path,
+                    is_dir,
+                });
+            }
+        }
+    }
+
+    // Remove an entry created during a failed transaction, closing its descriptors first.
+    fn undo_created_entry(&mut self, entry: &CreatedEntry) -> Result<(), Error> {
+        let node = find_node(
+            entry.parent,
+            &entry.path,
+            &mut self.names_cache,
+            self.storage.as_ref(),
+        )?;
+
+        for fd in self.fd_table.fds() {
+            if self.get_node(fd) == Ok(node) {
+                self.fd_table.close(fd);
+            }
+        }
+
+        let (node, metadata) = rm_dir_entry(
+            entry.parent,
+            &entry.path,
+            Some(entry.is_dir),
+            self.fd_table.node_refcount(),
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )?;
+
+        if metadata.link_count == 0 {
+            self.storage.rm_file(node)?;
+        }
+
+        Ok(())
+    }
+
     fn get_node(&self, fd: Fd) -> Result<Node, Error> {
+        self.fd_table.check(fd)?;
+
         match self.fd_table.get(fd) {
             Some(FdEntry::File(file)) => Ok(file.node),
             Some(FdEntry::Dir(dir)) => Ok(dir.node),
@@ -94,6 +378,8 @@ impl FileSystem {
     }
 
     fn get_file(&self, fd: Fd) -> Result<File, Error> {
+        self.fd_table.check(fd)?;
+
         match self.fd_table.get(fd) {
             Some(FdEntry::File(file)) => Ok(file.clone()),
             Some(FdEntry::Dir(_)) => Err(Error::InvalidFileType),
@@ -106,6 +392,8 @@ impl FileSystem {
     }
 
     fn get_dir(&self, fd: Fd) -> Result<Dir, Error> {
+        self.fd_table.check(fd)?;
+
         match self.fd_table.get(fd) {
             Some(FdEntry::Dir(dir)) => Ok(dir.clone()),
             Some(FdEntry::File(_)) => Err(Error::InvalidFileType),
@@ -120,6 +408,8 @@ impl FileSystem {
         filename: &str,
         memory: Box<dyn Memory>,
     ) -> Result<(), Error> {
+        self.check_writable()?;
+
         // create a file for the mount
         let fd = self.open_or_create(
             self.root_fd,
@@ -137,8 +427,59 @@ impl FileSystem {
         Ok(())
     }
 
+    // Mount memory on the top of an existing host file, moving the file content into the memory.
+    // The chunks of the host file are released, so the content only lives in the memory afterwards.
+    // This is the inverse of `persist_and_unmount`, use it to store the content in the host file again.
+    pub fn mount_with_migration(
+        &mut self,
+        filename: &str,
+        memory: Box<dyn Memory>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let fd = self.open_or_create(
+            self.root_fd,
+            filename,
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        )?;
+
+        let node = self.get_node(fd)?;
+        self.close(fd)?;
+
+        self.storage.mount_node(node, memory)?;
+        self.storage.init_mounted_memory(node)?;
+
+        // release the host file chunks while the mount is temporarily disabled
+        let memory = self.storage.unmount_node(node)?;
+
+        let mut metadata = self.storage.get_metadata(node)?;
+        metadata.size = 0;
+        self.storage.put_metadata(node, metadata);
+        self.storage.rm_file_chunks(node, usize::MAX)?;
+
+        self.storage.mount_node(node, memory)
+    }
+
+    // Keep the file in regular chunks while it is small, and move it into the given memory on the write that
+    // extends it past `threshold`. From then on the file works as a mounted memory file.
+    pub fn set_spill_mount(
+        &mut self,
+        fd: Fd,
+        threshold: FileSize,
+        memory: Box<dyn Memory>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        self.storage.set_spill_mount(file.node, threshold, memory)
+    }
+
     // initialize mounted memory with the data stored in the host file
     pub fn init_memory_file(&mut self, filename: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
         // create a file for the mount
         let fd = self.open_or_create(
             self.root_fd,
@@ -156,6 +497,8 @@ impl FileSystem {
 
     // store content of the currently active memory file to the file system
     pub fn store_memory_file(&mut self, filename: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
         // create a file for the mount
         let fd = self.open_or_create(
             self.root_fd,
@@ -173,6 +516,8 @@ impl FileSystem {
 
     // Unmount memory, the system will continue to work with the file in normal mode.
     pub fn unmount_memory_file(&mut self, filename: &str) -> Result<Box<dyn Memory>, Error> {
+        self.check_writable()?;
+
         // create a file for the mount
         let fd = self.open_or_create(
             self.root_fd,
@@ -190,1519 +535,7141 @@ impl FileSystem {
         Ok(memory)
     }
 
-    // Get directory entry for a given directory file descriptor and the entry index.
-    pub fn get_direntry(&self, fd: Fd, index: DirEntryIndex) -> Result<DirEntry, Error> {
-        self.get_dir(fd)?.get_entry(index, self.storage.as_ref())
-    }
+    // Grow the mounted memory to hold at least `bytes`, so that the following writes do not need to grow it.
+    // The file size is not changed. Fails with `Error::OutOfMemory` if the memory cannot grow that far.
+    pub fn ensure_mounted_capacity(
+        &mut self,
+        filename: &str,
+        bytes: FileSize,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
 
-    fn put_dir(&mut self, fd: Fd, dir: Dir) {
-        self.fd_table.update(fd, FdEntry::Dir(dir))
-    }
+        let fd = self.open_or_create(
+            self.root_fd,
+            filename,
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        )?;
 
-    // Read file's `fd` contents into `dst`.
-    pub fn read(&mut self, fd: Fd, dst: &mut [u8]) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let read_size = file.read_with_cursor(dst, self.storage.as_mut())?;
-        self.put_file(fd, file);
-        Ok(read_size)
-    }
+        let node = self.get_node(fd)?;
+        self.close(fd)?;
 
-    // Write `src` contents into a file.
-    pub fn write(&mut self, fd: Fd, src: &[u8]) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let written_size = file.write_with_cursor(src, self.storage.as_mut())?;
-        self.put_file(fd, file);
-        Ok(written_size)
-    }
+        let memory = self
+            .storage
+            .get_mounted_memory(node)
+            .ok_or(Error::MemoryFileIsNotMounted)?;
 
-    // Read file into a vector of buffers.
-    pub fn read_vec(&mut self, fd: Fd, dst: DstIoVec) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let mut read_size = 0;
-        for buf in dst {
-            let buf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
-            let size = file.read_with_cursor(buf, self.storage.as_mut())?;
-            read_size += size;
-        }
-        self.put_file(fd, file);
-        Ok(read_size)
+        try_grow_memory(memory, bytes)
     }
 
-    // Read file into a vector of buffers at a given offset, the file cursor is NOT updated.
-    pub fn read_vec_with_offset(
-        &mut self,
-        fd: Fd,
-        dst: DstIoVec,
-        offset: FileSize,
-    ) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
-        let mut read_size = 0;
+    // Insert the entries added to a directory in the name order, so that listing the directory is sorted by name.
+    // The setting is stored with the directory, the entries added while it is disabled are appended at the end.
+    pub fn set_sorted_entries(&mut self, fd: Fd, sorted: bool) -> Result<(), Error> {
+        self.check_writable()?;
 
-        for buf in dst {
-            let rbuf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
+        let dir = self.get_dir(fd)?;
+        let mut metadata = self.storage.get_metadata(dir.node)?;
 
-            let size = file.read_with_offset(read_size + offset, rbuf, self.storage.as_mut())?;
+        metadata.sorted_dir_entries = sorted;
+        self.storage.put_metadata(dir.node, metadata);
 
-            read_size += size;
-        }
-        self.put_file(fd, file);
-        Ok(read_size)
+        Ok(())
     }
 
-    // Write a vector of buffers into a file at a given offset, the file cursor is updated.
-    pub fn write_vec(&mut self, fd: Fd, src: SrcIoVec) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let mut written_size = 0;
-        for buf in src {
-            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
-            let size = file.write_with_cursor(buf, self.storage.as_mut())?;
-            written_size += size;
-        }
-        self.put_file(fd, file);
-        Ok(written_size)
-    }
+    // Get the number of entries in a given directory.
+    pub fn dir_entry_count(&self, fd: Fd) -> Result<FileSize, Error> {
+        let dir = self.get_dir(fd)?;
+        let metadata = self.storage.get_metadata(dir.node)?;
 
-    // Write a vector of buffers into a file at a given offset, the file cursor is NOT updated.
-    pub fn write_vec_with_offset(
-        &mut self,
-        fd: Fd,
-        src: SrcIoVec,
-        offset: FileSize,
-    ) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
-        let mut written_size = 0;
-        for buf in src {
-            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
+        Ok(metadata.size)
+    }
 
-            let size = file.write_with_offset(written_size + offset, buf, self.storage.as_mut())?;
+    // Count the entries of a directory by walking its entry list.
+    fn count_dir_entries(&self, node: Node) -> Result<FileSize, Error> {
+        let mut count = 0;
+        let mut next_index = self.storage.get_metadata(node)?.first_dir_entry;
 
-            written_size += size;
+        while let Some(index) = next_index {
+            next_index = self.storage.get_direntry(node, index)?.next_entry;
+            count += 1;
         }
-        self.put_file(fd, file);
-        Ok(written_size)
-    }
 
-    // Position file cursor to a given position.
-    pub fn seek(&mut self, fd: Fd, delta: i64, whence: Whence) -> Result<FileSize, Error> {
-        let mut file = self.get_file(fd)?;
-        let pos = file.seek(delta, whence, self.storage.as_mut())?;
-        self.put_file(fd, file);
-        Ok(pos)
+        Ok(count)
     }
 
-    // Get the current file cursor position.
-    pub fn tell(&mut self, fd: Fd) -> Result<FileSize, Error> {
-        let file = self.get_file(fd)?;
-        let pos = file.tell();
-        Ok(pos)
+    // Check that the stored entry count of a directory matches the actual number of its entries.
+    pub fn verify_dir_size(&mut self, dir_fd: Fd) -> Result<bool, Error> {
+        let dir = self.get_dir(dir_fd)?;
+        let metadata = self.storage.get_metadata(dir.node)?;
+
+        Ok(self.count_dir_entries(dir.node)? == metadata.size)
     }
 
-    // Close the opened file and release the corresponding file descriptor.
-    pub fn close(&mut self, fd: Fd) -> Result<(), Error> {
-        self.flush(fd)?;
-        self.fd_table.close(fd).ok_or(Error::NotFound)?;
+    // Set the stored entry count of a directory to the actual number of its entries, returns the corrected count.
+    pub fn repair_dir_size(&mut self, dir_fd: Fd) -> Result<FileSize, Error> {
+        self.check_writable()?;
 
-        Ok(())
-    }
+        let dir = self.get_dir(dir_fd)?;
+        let mut metadata = self.storage.get_metadata(dir.node)?;
 
-    // Get the metadata for a given file descriptor
-    pub fn metadata_from_node(&self, node: Node) -> Result<Metadata, Error> {
-        self.storage.get_metadata(node)
+        metadata.size = self.count_dir_entries(dir.node)?;
+        self.storage.put_metadata(dir.node, metadata.clone());
+
+        Ok(metadata.size)
     }
 
-    // Get the metadata for a given file descriptor
-    pub fn metadata(&self, fd: Fd) -> Result<Metadata, Error> {
-        let node = self.get_node(fd)?;
-        self.storage.get_metadata(node)
-    }
+    // Remove all files and folders leaving only an empty root folder, the node IDs and the chunk memory are reused from the start.
+    // All file descriptors apart from the root folder are closed. Mounted memories are not touched,
+    // the call fails with `Error::CannotRemoveMountedMemoryFile` until they are unmounted.
+    pub fn format(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
 
-    // update metadata of a given file descriptor
-    pub fn set_metadata(&mut self, fd: Fd, metadata: Metadata) -> Result<(), Error> {
-        let node = self.get_node(fd)?;
-        self.storage.put_metadata(node, metadata);
+        self.storage.format()?;
+
+        for fd in self.fd_table.fds() {
+            if fd != self.root_fd {
+                self.fd_table.close(fd);
+            }
+        }
+
+        self.names_cache.clear();
 
         Ok(())
     }
 
-    // Update access time.
-    pub fn set_accessed_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
+    // Store the content of the mounted memory to the host file and unmount it,
+    // the system will continue to work with the file in normal mode.
+    pub fn persist_and_unmount(&mut self, filename: &str) -> Result<Box<dyn Memory>, Error> {
+        self.check_writable()?;
+
+        let fd = self.open_or_create(
+            self.root_fd,
+            filename,
+            FdStat::default(),
+            OpenFlags::empty(),
+            0,
+        )?;
+
         let node = self.get_node(fd)?;
-        let mut metadata = self.storage.get_metadata(node)?;
+        self.close(fd)?;
 
-        metadata.times.accessed = time;
+        self.storage.store_mounted_memory(node)?;
 
-        self.storage.put_metadata(node, metadata);
+        self.storage.unmount_node(node)
+    }
 
-        Ok(())
+    // Get directory entry for a given directory file descriptor and the entry index.
+    pub fn get_direntry(&self, fd: Fd, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.get_dir(fd)?.get_entry(index, self.storage.as_ref())
     }
 
-    // Update modification time.
-    pub fn set_modified_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
-        let node = self.get_node(fd)?;
-        let mut metadata = self.storage.get_metadata(node)?;
+    // Get all entries of a given directory in the requested order.
+    // Use `DirOrder::Name` when the order must be reproducible.
+    // None of the listings include the `.` and `..` entries, on every storage. The WASI layer on top of the file system
+    // adds them when it serializes the `fd_readdir` result, since they do not exist as stored entries.
+    pub fn read_dir(&self, fd: Fd, order: DirOrder) -> Result<Vec<DirEntry>, Error> {
+        self.get_dir(fd)?.entries(order, self.storage.as_ref())
+    }
 
-        metadata.times.modified = time;
+    // Get the nodes and names of all entries of a given directory, the most recently added entry comes first.
+    pub fn read_dir_reverse(&mut self, fd: Fd) -> Result<Vec<(Node, String)>, Error> {
+        let entries = self.get_dir(fd)?.entries_reverse(self.storage.as_ref())?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let name =
+                    String::from_utf8(entry.name.bytes[..entry.name.length as usize].to_vec())
+                        .unwrap();
+                (entry.node, name)
+            })
+            .collect())
+    }
 
-        self.storage.put_metadata(node, metadata);
+    // Get the names of the entries of a given directory that have the requested type, in the entry list order.
+    pub fn list_by_type(&self, fd: Fd, file_type: FileType) -> Result<Vec<String>, Error> {
+        let entries = self.read_dir(fd, DirOrder::Insertion)?;
+
+        let mut names = Vec::new();
+
+        for entry in entries {
+            if self.storage.get_metadata(entry.node)?.file_type == file_type {
+                let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+                names.push(name.into_owned());
+            }
+        }
+
+        Ok(names)
+    }
+
+    // Get the names of the regular files in a given directory.
+    pub fn list_files(&self, fd: Fd) -> Result<Vec<String>, Error> {
+        self.list_by_type(fd, FileType::RegularFile)
+    }
+
+    // Get the names of the subdirectories of a given directory.
+    pub fn list_dirs(&self, fd: Fd) -> Result<Vec<String>, Error> {
+        self.list_by_type(fd, FileType::Directory)
+    }
+
+    // Repair the chunk type of a file so that it matches the chunks actually stored, returns the corrected type.
+    pub fn fix_chunk_type(&mut self, fd: Fd) -> Result<ChunkType, Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        self.storage.fix_chunk_type(file.node)
+    }
+
+    // Get the chunk type the file contents are read and written with, also when the file metadata does not specify it.
+    pub fn effective_chunk_type(&mut self, fd: Fd) -> Result<ChunkType, Error> {
+        let file = self.get_file(fd)?;
+        self.storage.effective_chunk_type(file.node)
+    }
+
+    // Start creating many entries in a folder, the folder metadata is only stored when the returned scope is dropped.
+    // All file system calls are done through the scope, only one folder can be deferred at a time.
+    pub fn bulk_create_scope(&mut self, dir_fd: Fd) -> Result<BulkCreateScope<'_>, Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(dir_fd)?;
+        self.storage.defer_metadata(dir.node)?;
+
+        Ok(BulkCreateScope { fs: self })
+    }
+
+    // Reserve the node IDs for `count` files or folders about to be created, the node counter in stable memory
+    // is then updated once instead of on every creation. The reservation only lives on the heap,
+    // the IDs not used before an upgrade are skipped.
+    pub fn reserve_nodes(&mut self, count: u64) -> Result<(), Error> {
+        self.check_writable()?;
+
+        self.storage.reserve_nodes(count);
 
         Ok(())
     }
 
-    // Get file or directory stats.
-    pub fn get_stat(&self, fd: Fd) -> Result<(FileType, FdStat), Error> {
-        match self.fd_table.get(fd) {
-            None => Err(Error::NotFound),
-            Some(FdEntry::File(file)) => Ok((FileType::RegularFile, file.stat)),
-            Some(FdEntry::Dir(dir)) => Ok((FileType::Directory, dir.stat)),
+    // Run `f` as one unit: if it returns an error, the files and folders it created are removed again
+    // and the error is returned. The undo log covers the creation of entries (including the missing folders
+    // of a created path and hard links), writes into existing files, renames and removals are not reverted.
+    // The file descriptors opened on the removed entries are closed. A nested call joins the outer transaction.
+    pub fn transaction<R>(
+        &mut self,
+        f: impl FnOnce(&mut FileSystem) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        if self.undo_log.is_some() {
+            return f(self);
         }
-    }
 
-    // Update stats of a given file.
-    pub fn set_stat(&mut self, fd: Fd, stat: FdStat) -> Result<(), Error> {
-        match self.fd_table.get(fd) {
-            Some(FdEntry::File(file)) => {
-                let mut file = file.clone();
-                file.stat = stat;
-                self.put_file(fd, file);
-                Ok(())
-            }
-            Some(FdEntry::Dir(dir)) => {
-                let mut dir = dir.clone();
-                dir.stat = stat;
-                self.put_dir(fd, dir);
-                Ok(())
+        self.undo_log = Some(Vec::new());
+        let result = f(self);
+        let undo_log = self.undo_log.take().unwrap_or_default();
+
+        if result.is_err() {
+            for entry in undo_log.iter().rev() {
+                // the rollback is best effort, the error of `f` is reported
+                let _ = self.undo_created_entry(entry);
             }
-            None => Err(Error::NotFound),
         }
+
+        result
     }
 
-    // Get metadata of a file with name `path` in a given folder.
-    pub fn open_metadata(&mut self, parent: Fd, path: &str) -> Result<Metadata, Error> {
-        let dir = self.get_dir(parent)?;
-        let node = find_node(dir.node, path, &mut self.names_cache, self.storage.as_ref())?;
-        self.storage.get_metadata(node)
+    // Get the total size of all regular files under the given folder, including its subfolders.
+    // A file linked several times is only counted once.
+    pub fn recursive_size(&mut self, fd: Fd) -> Result<FileSize, Error> {
+        let dir = self.get_dir(fd)?;
+
+        let mut files = BTreeSet::new();
+        let mut size: FileSize = 0;
+
+        walk_tree(dir.node, self.storage.as_ref(), |entry, metadata| {
+            if metadata.file_type == FileType::RegularFile && files.insert(entry.node) {
+                size += metadata.size;
+            }
+        })?;
+
+        Ok(size)
     }
 
-    // Opens of creates a new file.
-    pub fn open_or_create(
-        &mut self,
-        parent: Fd,
-        path: &str,
-        stat: FdStat,
-        flags: OpenFlags,
-        ctime: u64,
-    ) -> Result<Fd, Error> {
-        let dir = self.get_dir(parent)?;
+    // Set every regular file in the folder to size 0 and release its chunks, the folder structure is kept intact.
+    // With `recursive` the files of all subfolders are truncated as well. Returns the number of truncated files.
+    pub fn truncate_all(&mut self, dir_fd: Fd, recursive: bool) -> Result<usize, Error> {
+        self.check_writable()?;
 
-        match find_node(dir.node, path, &mut self.names_cache, self.storage.as_ref()) {
-            Ok(node) => self.open(node, stat, flags),
-            Err(Error::NotFound) => {
-                if !flags.contains(OpenFlags::CREATE) {
-                    return Err(Error::NotFound);
+        let dir = self.get_dir(dir_fd)?;
+
+        let mut files = BTreeSet::new();
+
+        if recursive {
+            walk_tree(dir.node, self.storage.as_ref(), |entry, metadata| {
+                if metadata.file_type == FileType::RegularFile {
+                    files.insert(entry.node);
+                }
+            })?;
+        } else {
+            for entry in dir.entries(DirOrder::Node, self.storage.as_ref())? {
+                if self.storage.get_metadata(entry.node)?.file_type == FileType::RegularFile {
+                    files.insert(entry.node);
                 }
+            }
+        }
 
-                if flags.contains(OpenFlags::DIRECTORY) {
-                    return Err(Error::InvalidFileType);
+        for node in files.iter() {
+            let size = self.storage.get_metadata(*node)?.size;
+
+            // trimming the whole content releases all chunks of the file
+            self.storage.trim_front(*node, size)?;
+        }
+
+        Ok(files.len())
+    }
+
+    // Find all files and folders below the given folder whose relative path matches the glob `pattern`.
+    // `*` and `?` match within a single name, `**` matches any number of folders. The paths are returned sorted.
+    pub fn glob(&mut self, base_fd: Fd, pattern: &str) -> Result<Vec<String>, Error> {
+        let dir = self.get_dir(base_fd)?;
+
+        let mut result = Vec::new();
+
+        let mut visited = BTreeSet::from([dir.node]);
+        let mut stack = vec![(dir.node, String::new())];
+
+        while let Some((cur_dir, prefix)) = stack.pop() {
+            let mut next_index = self.storage.get_metadata(cur_dir)?.first_dir_entry;
+
+            while let Some(index) = next_index {
+                let entry = self.storage.get_direntry(cur_dir, index)?;
+                next_index = entry.next_entry;
+
+                let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+                let path = format!("{prefix}{name}");
+
+                let metadata = self.storage.get_metadata(entry.node)?;
+                if metadata.file_type == FileType::Directory && visited.insert(entry.node) {
+                    stack.push((entry.node, format!("{path}/")));
                 }
 
-                self.create_file(parent, path, stat, ctime)
+                if glob_match(pattern, &path) {
+                    result.push(path);
+                }
             }
-            Err(err) => Err(err),
         }
+
+        result.sort();
+
+        Ok(result)
     }
 
-    // Opens a file and returns its new file descriptor.
-    fn open(&mut self, node: Node, stat: FdStat, flags: OpenFlags) -> Result<Fd, Error> {
-        if flags.contains(OpenFlags::EXCLUSIVE) {
-            return Err(Error::FileAlreadyExists);
-        }
-        let metadata = self.storage.get_metadata(node)?;
-        match metadata.file_type {
-            FileType::Directory => {
-                let dir = Dir::new(node, stat, self.storage.as_mut())?;
-                let fd = self.fd_table.open(FdEntry::Dir(dir));
-                Ok(fd)
+    // Get all nodes linked from more than one place together with their link count.
+    pub fn hard_linked_nodes(&mut self) -> Result<Vec<(Node, u64)>, Error> {
+        let mut result = Vec::new();
+
+        for node in self.storage.iter_nodes() {
+            let metadata = self.storage.get_metadata(node)?;
+
+            if metadata.link_count > 1 {
+                result.push((node, metadata.link_count));
             }
-            FileType::RegularFile => {
-                if flags.contains(OpenFlags::DIRECTORY) {
-                    return Err(Error::InvalidFileType);
+        }
+
+        Ok(result)
+    }
+
+    // Check whether the path stays inside the given folder after resolving `.` and `..` parts.
+    // The check is purely lexical, the path does not have to exist and links are not followed.
+    pub fn is_within(&mut self, base_fd: Fd, path: &str) -> Result<bool, Error> {
+        self.get_dir(base_fd)?;
+
+        let mut depth: usize = 0;
+
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    if depth == 0 {
+                        return Ok(false);
+                    }
+                    depth -= 1;
                 }
-                let file = File::new(node, stat, self.storage.as_mut())?;
-                if flags.contains(OpenFlags::TRUNCATE) {
-                    file.truncate(self.storage.as_mut())?;
+                _ => depth += 1,
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Check the folder tree integrity, returns the folders reachable through more than one parent.
+    // In a healthy tree every folder has exactly one parent, so any returned folder is part of a cycle or an illegal link.
+    pub fn detect_cycles(&self) -> Result<Vec<Node>, Error> {
+        find_multi_linked_dirs(self.storage.root_node(), self.storage.as_ref())
+    }
+
+    // Get the names of all entries of a given directory together with their metadata.
+    // The metadata is fetched in the node order for better locality, the entries are returned in that order.
+    pub fn read_dir_with_metadata(&mut self, fd: Fd) -> Result<Vec<(String, Metadata)>, Error> {
+        let entries = self.read_dir(fd, DirOrder::Node)?;
+
+        let mut result = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            let name =
+                String::from_utf8(entry.name.bytes[..entry.name.length as usize].to_vec()).unwrap();
+            let metadata = self.storage.get_metadata(entry.node)?;
+
+            result.push((name, metadata));
+        }
+
+        Ok(result)
+    }
+
+    // Get the entries of a given directory sorted by name, each with a flag whether it is held open by a descriptor
+    // and whether it is a mounted memory file. Useful to find out why a removal fails with a busy error.
+    pub fn read_dir_with_open_state(
+        &mut self,
+        dir_fd: Fd,
+    ) -> Result<Vec<(String, Node, bool, bool)>, Error> {
+        let entries = self.read_dir(dir_fd, DirOrder::Name)?;
+
+        let refcount = self.fd_table.node_refcount();
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let name =
+                    String::from_utf8(entry.name.bytes[..entry.name.length as usize].to_vec())
+                        .unwrap();
+                let open = refcount.get(&entry.node).is_some_and(|count| *count > 0);
+                let mounted = self.storage.is_mounted(entry.node);
+
+                (name, entry.node, open, mounted)
+            })
+            .collect())
+    }
+
+    // Iterate over the entries of a directory as `(node, name)` pairs in their stored order.
+    // Unlike collecting the whole listing, an entry is only read once the iterator reaches it,
+    // so stopping at the first match does not pay for the rest of a large directory.
+    pub fn dir_iter(&self, dir_fd: Fd) -> Result<DirIter<'_>, Error> {
+        let dir = self.get_dir(dir_fd)?;
+        let next_index = self.storage.get_metadata(dir.node)?.first_dir_entry;
+
+        Ok(DirIter {
+            fs: self,
+            node: dir.node,
+            next_index,
+        })
+    }
+
+    // List up to `limit` entries of a directory following the entry with index `after`, or from the start if `None`.
+    // Returns the page together with the token to pass as `after` for the next page, `None` when the listing is complete.
+    // The entry indices are persisted and grow with each new entry, so a token stays valid across canister upgrades
+    // and even if the entry it points to was removed in the meantime. The latter does not hold for sorted directories,
+    // where the entry indices do not follow the list order. A zero `limit` fails with `Error::InvalidArgument`.
+    pub fn list_page(
+        &mut self,
+        dir_fd: Fd,
+        after: Option<DirEntryIndex>,
+        limit: usize,
+    ) -> Result<DirPage, Error> {
+        if limit == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let dir = self.get_dir(dir_fd)?;
+
+        let mut next_index = match after {
+            None => self.storage.get_metadata(dir.node)?.first_dir_entry,
+            Some(after) => match self.storage.get_direntry(dir.node, after) {
+                Ok(entry) => entry.next_entry,
+                // the entry was removed, skip to the first entry with a greater index
+                Err(Error::NotFound) => {
+                    let mut index = self.storage.get_metadata(dir.node)?.first_dir_entry;
+
+                    while let Some(i) = index {
+                        if i > after {
+                            break;
+                        }
+                        index = self.storage.get_direntry(dir.node, i)?.next_entry;
+                    }
+
+                    index
                 }
-                let fd = self.fd_table.open(FdEntry::File(file));
-                Ok(fd)
+                Err(err) => return Err(err),
+            },
+        };
+
+        let mut page = Vec::new();
+
+        while let Some(index) = next_index {
+            if page.len() == limit {
+                break;
             }
-            FileType::SymbolicLink => unimplemented!("Symbolic links are not supported yet"),
+
+            let entry = self.storage.get_direntry(dir.node, index)?;
+            next_index = entry.next_entry;
+
+            let name =
+                String::from_utf8(entry.name.bytes[..entry.name.length as usize].to_vec()).unwrap();
+            page.push((index, name));
+        }
+
+        let token = match next_index {
+            Some(_) => page.last().map(|(index, _)| *index),
+            None => None,
+        };
+
+        Ok((page, token))
+    }
+
+    fn put_dir(&mut self, fd: Fd, dir: Dir) {
+        self.fd_table.update(fd, FdEntry::Dir(dir))
+    }
+
+    // Read file's `fd` contents into `dst`.
+    pub fn read(&mut self, fd: Fd, dst: &mut [u8]) -> Result<FileSize, Error> {
+        let mut file = self.get_file(fd)?;
+        let read_size = file.read_with_cursor(dst, self.storage.as_mut())?;
+        file.check_full_read(dst.len() as FileSize, read_size)?;
+        self.put_file(fd, file);
+        Ok(read_size)
+    }
+
+    // Fill the whole `dst` with the file's `fd` contents, `Error::UnexpectedEof` is returned if the file ends earlier.
+    // The file cursor is only updated on success.
+    pub fn read_exact(&mut self, fd: Fd, dst: &mut [u8]) -> Result<(), Error> {
+        let mut file = self.get_file(fd)?;
+        let read_size = file.read_with_cursor(dst, self.storage.as_mut())?;
+
+        if read_size < dst.len() as FileSize {
+            return Err(Error::UnexpectedEof);
         }
+
+        self.put_file(fd, file);
+        Ok(())
     }
 
-    // Create a new file named `path` in the given `parent` folder.
-    pub fn create_file(
+    // Call `f` with the file contents in the range of `len` bytes starting at `offset`, the file cursor is NOT updated.
+    // The slice is shorter if the file ends earlier. The memory trait does not expose its contents directly,
+    // so the region is read into a temporary buffer for both the mounted and the chunked files.
+    pub fn with_file_bytes<R>(
         &mut self,
-        parent: Fd,
-        path: &str,
-        stat: FdStat,
-        ctime: u64,
-    ) -> Result<Fd, Error> {
-        let dir = self.get_dir(parent)?;
+        fd: Fd,
+        offset: FileSize,
+        len: FileSize,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, Error> {
+        let file = self.get_file(fd)?;
 
-        let child = dir.create_file(
-            path,
-            stat,
-            &mut self.names_cache,
-            self.storage.as_mut(),
-            ctime,
-        )?;
+        let size = self.storage.get_metadata(file.node)?.size;
+        let len = len.min(size.saturating_sub(offset));
+
+        let mut buf = vec![0u8; len as usize];
+        let read_size = file.read_with_offset(offset, &mut buf, self.storage.as_mut())?;
+
+        Ok(f(&buf[..read_size as usize]))
+    }
+
+    // Read the file contents from the start as a string, at most `max` bytes are read if given.
+    // The file cursor is NOT updated. Content that is not valid UTF-8 fails with `Error::IllegalByteSequence`,
+    // this also happens if `max` cuts a multi-byte character.
+    pub fn read_to_string(&mut self, fd: Fd, max: Option<FileSize>) -> Result<String, Error> {
+        let file = self.get_file(fd)?;
+
+        let size = self.storage.get_metadata(file.node)?.size;
+        let len = max.map_or(size, |max| size.min(max));
+
+        let mut buf = vec![0u8; len as usize];
+        let read_size = file.read_with_offset(0, &mut buf, self.storage.as_mut())?;
+        buf.truncate(read_size as usize);
+
+        String::from_utf8(buf).map_err(|_| Error::IllegalByteSequence)
+    }
+
+    // Write `src` contents into a file.
+    pub fn write(&mut self, fd: Fd, src: &[u8]) -> Result<FileSize, Error> {
+        self.check_writable()?;
+
+        let allowed = self.write_budget_limit(src.len() as FileSize)?;
+        let src = &src[..allowed as usize];
+
+        let mut file = self.get_file(fd)?;
+        let written_size = file.write_with_cursor(src, self.storage.as_mut())?;
+        self.put_file(fd, file);
+        self.spend_write_budget(written_size);
+        Ok(written_size)
+    }
+
+    // Write `src` contents into a file like `write`, and flush the file afterwards, so that the written data
+    // and the file size are stored in stable memory when the call returns.
+    pub fn write_sync(&mut self, fd: Fd, src: &[u8]) -> Result<FileSize, Error> {
+        let written_size = self.write(fd, src)?;
+        self.flush(fd)?;
+
+        Ok(written_size)
+    }
+
+    // Read file into a vector of buffers.
+    pub fn read_vec(&mut self, fd: Fd, dst: DstIoVec) -> Result<FileSize, Error> {
+        let mut file = self.get_file(fd)?;
+        let mut read_size = 0;
+        for buf in dst {
+            let buf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
+            let size = file.read_with_cursor(buf, self.storage.as_mut())?;
+            read_size += size;
+        }
+        let total: FileSize = dst.iter().map(|buf| buf.len as FileSize).sum();
+        file.check_full_read(total, read_size)?;
+        self.put_file(fd, file);
+        Ok(read_size)
+    }
+
+    // Read file into a list of slices, the file cursor is updated. The safe alternative to `read_vec`.
+    pub fn read_vectored(&mut self, fd: Fd, bufs: &mut [&mut [u8]]) -> Result<FileSize, Error> {
+        let mut file = self.get_file(fd)?;
+        let mut read_size = 0;
+        for buf in bufs.iter_mut() {
+            let size = file.read_with_cursor(buf, self.storage.as_mut())?;
+            read_size += size;
+        }
+        let total: FileSize = bufs.iter().map(|buf| buf.len() as FileSize).sum();
+        file.check_full_read(total, read_size)?;
+        self.put_file(fd, file);
+        Ok(read_size)
+    }
+
+    // Read file into a vector of buffers at a given offset, the file cursor is NOT updated.
+    pub fn read_vec_with_offset(
+        &mut self,
+        fd: Fd,
+        dst: DstIoVec,
+        offset: FileSize,
+    ) -> Result<FileSize, Error> {
+        let file = self.get_file(fd)?;
+        let mut read_size = 0;
+
+        for buf in dst {
+            let rbuf = unsafe { std::slice::from_raw_parts_mut(buf.buf, buf.len) };
+
+            let size = file.read_with_offset(read_size + offset, rbuf, self.storage.as_mut())?;
+
+            read_size += size;
+        }
+        let total: FileSize = dst.iter().map(|buf| buf.len as FileSize).sum();
+        file.check_full_read(total, read_size)?;
+        self.put_file(fd, file);
+        Ok(read_size)
+    }
+
+    // Read several `(offset, len)` ranges of a file, the file cursor is NOT updated.
+    // The ranges are read in the ascending offset order to reuse the cached chunk pointers,
+    // the results follow the order of `ranges` and are shorter if the file ends earlier.
+    pub fn read_ranges(
+        &mut self,
+        fd: Fd,
+        ranges: &[(FileSize, FileSize)],
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let file = self.get_file(fd)?;
+        let size = self.storage.get_metadata(file.node)?.size;
+
+        let mut order: Vec<usize> = (0..ranges.len()).collect();
+        order.sort_by_key(|i| ranges[*i].0);
+
+        let mut result = vec![Vec::new(); ranges.len()];
+
+        for i in order {
+            let (offset, len) = ranges[i];
+            let len = len.min(size.saturating_sub(offset));
+
+            let mut buf = vec![0u8; len as usize];
+            let read_size = file.read_with_offset(offset, &mut buf, self.storage.as_mut())?;
+            buf.truncate(read_size as usize);
+
+            result[i] = buf;
+        }
+
+        Ok(result)
+    }
+
+    // Read file at a position relative to `whence`, the file cursor is NOT updated.
+    pub fn pread(
+        &mut self,
+        fd: Fd,
+        dst: &mut [u8],
+        whence: Whence,
+        delta: i64,
+    ) -> Result<FileSize, Error> {
+        let file = self.get_file(fd)?;
+
+        // compute the read position on a copy of the file entry
+        let mut positioned = file.clone();
+        let offset = positioned.seek(delta, whence, self.storage.as_mut())?;
+
+        let read_size = file.read_with_offset(offset, dst, self.storage.as_mut())?;
+        file.check_full_read(dst.len() as FileSize, read_size)?;
+
+        Ok(read_size)
+    }
+
+    // Read a single file chunk into `dst`, the file cursor is NOT updated.
+    // The chunk `index` is in units of the storage chunk size, at most one chunk is read even if `dst` is larger.
+    // For V2 chunks the chunk pointer is looked up directly, V1 chunks and mounted files fall back to the regular read.
+    pub fn read_chunk_into(
+        &mut self,
+        fd: Fd,
+        index: FileChunkIndex,
+        dst: &mut [u8],
+    ) -> Result<FileSize, Error> {
+        let file = self.get_file(fd)?;
+        self.storage.read_chunk(file.node, index, dst)
+    }
+
+    // Estimate how many new chunks writing `len` bytes at `offset` would allocate, without writing anything.
+    // Each new chunk costs an allocation and a chunk pointer insert, this can be used to split large writes.
+    pub fn estimate_write_chunks(
+        &mut self,
+        fd: Fd,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Result<usize, Error> {
+        let file = self.get_file(fd)?;
+        self.storage.estimate_write_chunks(file.node, offset, len)
+    }
+
+    // Declare the expected access pattern of a file range (WASI `fd_advise`).
+    // Only the chunk pointer cache is affected, there is no cache of the file data to evict:
+    // `Advice::WillNeed` preloads the chunk pointers of the range, `Advice::DontNeed` evicts them from the cache,
+    // e.g. after a one-time scan of a large file. The other advice values are accepted and ignored.
+    pub fn advise(
+        &mut self,
+        fd: Fd,
+        offset: FileSize,
+        len: FileSize,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        let file = self.get_file(fd)?;
+        self.storage.advise(file.node, offset, len, advice)
+    }
+
+    // Use V2 chunks of the given size for a new file, independent of the storage chunk size. Call it before the first
+    // write. The size must be a multiple of the storage chunk size, so choose a small storage chunk size (e.g. 4K)
+    // for small records and set larger chunks for big files. A transient storage ignores the setting.
+    pub fn set_file_chunk_size(&mut self, fd: Fd, chunk_size: ChunkSize) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        self.storage.set_file_chunk_size(file.node, chunk_size)
+    }
+
+    // Get the ratio of the file chunks that do not follow the previous chunk in memory, from 0.0 for a contiguous file
+    // up to nearly 1.0 for a file written in reverse order. Use it to decide whether a hot file is worth `defragment_file`.
+    pub fn file_fragmentation(&mut self, fd: Fd) -> Result<f64, Error> {
+        let file = self.get_file(fd)?;
+        self.storage.fragmentation(file.node)
+    }
+
+    // Get the lowest and the highest index of the chunks stored for a file, None if it has no chunks,
+    // e.g. to process a large file chunk by chunk over several messages. The indices are in units of the file chunk size,
+    // a sparse file has fewer chunks than the range spans. Mounted files do not store chunks.
+    pub fn chunk_range(
+        &mut self,
+        fd: Fd,
+    ) -> Result<Option<(FileChunkIndex, FileChunkIndex)>, Error> {
+        let file = self.get_file(fd)?;
+        self.storage.chunk_range(file.node)
+    }
+
+    // Read the last `n` chunks worth of bytes of a file, e.g. the tail of a log, or the whole file if it is smaller.
+    // The blocks are read from the end of the file, the blocks past the last stored chunk are zeroes and are not read.
+    // The file cursor is NOT updated.
+    pub fn read_last_chunks(&mut self, fd: Fd, n: usize) -> Result<Vec<u8>, Error> {
+        let file = self.get_file(fd)?;
+        let metadata = self.storage.get_metadata(file.node)?;
+
+        let chunk_size = match self.storage.effective_chunk_type(file.node)? {
+            ChunkType::V1 => FILE_CHUNK_SIZE_V1,
+            ChunkType::V2 => metadata
+                .chunk_size
+                .map_or(self.storage.chunk_size(), |chunk_size| chunk_size as usize),
+        } as FileSize;
+
+        let size = metadata.size;
+        let start = size.saturating_sub(chunk_size.saturating_mul(n as FileSize));
+
+        // mounted files have no chunks, all of their content is read
+        let stored_end = match self.storage.chunk_range(file.node)? {
+            Some((_, last)) => ((last as FileSize + 1) * chunk_size).min(size),
+            None if self.storage.is_mounted(file.node) => size,
+            None => 0,
+        };
+
+        let mut result = vec![0u8; (size - start) as usize];
+
+        // chunk aligned blocks from the last one down
+        let mut block_end = size;
+        while block_end > start {
+            let block_start = ((block_end - 1) / chunk_size * chunk_size).max(start);
+
+            if block_start < stored_end {
+                let read_end = block_end.min(stored_end);
+                let buf = &mut result[(block_start - start) as usize..(read_end - start) as usize];
+                file.read_with_offset(block_start, buf, self.storage.as_mut())?;
+            }
+
+            block_end = block_start;
+        }
+
+        Ok(result)
+    }
+
+    // Rewrite the chunks of a file into one contiguous run of memory to improve the locality of sequential reads.
+    pub fn defragment_file(&mut self, fd: Fd) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        self.storage.defragment(file.node)
+    }
+
+    // Write a vector of buffers into a file at a given offset, the file cursor is updated.
+    pub fn write_vec(&mut self, fd: Fd, src: SrcIoVec) -> Result<FileSize, Error> {
+        self.check_writable()?;
+
+        let total: FileSize = src.iter().map(|buf| buf.len as FileSize).sum();
+        let mut allowed = self.write_budget_limit(total)?;
+
+        let mut file = self.get_file(fd)?;
+        let mut written_size = 0;
+        for buf in src {
+            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
+            let buf = &buf[..buf.len().min(allowed as usize)];
+            let size = file.write_with_cursor(buf, self.storage.as_mut())?;
+            written_size += size;
+            allowed -= size;
+        }
+        self.put_file(fd, file);
+        self.spend_write_budget(written_size);
+        Ok(written_size)
+    }
+
+    // Write a list of slices into a file, the file cursor is updated. The safe alternative to `write_vec`.
+    pub fn write_vectored(&mut self, fd: Fd, bufs: &[&[u8]]) -> Result<FileSize, Error> {
+        self.check_writable()?;
+
+        let total: FileSize = bufs.iter().map(|buf| buf.len() as FileSize).sum();
+        let mut allowed = self.write_budget_limit(total)?;
+
+        let mut file = self.get_file(fd)?;
+        let mut written_size = 0;
+        for buf in bufs {
+            let buf = &buf[..buf.len().min(allowed as usize)];
+            let size = file.write_with_cursor(buf, self.storage.as_mut())?;
+            written_size += size;
+            allowed -= size;
+        }
+        self.put_file(fd, file);
+        self.spend_write_budget(written_size);
+        Ok(written_size)
+    }
+
+    // Write a vector of buffers into a file, the file cursor is updated.
+    // Nothing is written if the file would grow beyond `MAX_FILE_SIZE`, in that case `Error::FileTooLarge` is returned,
+    // or if the buffers do not fit into the remaining write budget, in that case `Error::WouldBlock` is returned.
+    pub fn write_vec_atomic(&mut self, fd: Fd, src: SrcIoVec) -> Result<FileSize, Error> {
+        let file = self.get_file(fd)?;
+
+        let total: FileSize = src.iter().map(|buf| buf.len as FileSize).sum();
+
+        if file
+            .cursor
+            .checked_add(total)
+            .is_none_or(|end| end > MAX_FILE_SIZE)
+        {
+            return Err(Error::FileTooLarge);
+        }
+
+        if self.write_budget_limit(total)? < total {
+            return Err(Error::WouldBlock);
+        }
+
+        self.write_vec(fd, src)
+    }
+
+    // Write a vector of buffers into a file at a given offset, the file cursor is NOT updated.
+    pub fn write_vec_with_offset(
+        &mut self,
+        fd: Fd,
+        src: SrcIoVec,
+        offset: FileSize,
+    ) -> Result<FileSize, Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        let mut written_size = 0;
+        for buf in src {
+            let buf = unsafe { std::slice::from_raw_parts(buf.buf, buf.len) };
+
+            let size = file.write_with_offset(written_size + offset, buf, self.storage.as_mut())?;
+
+            written_size += size;
+        }
+        self.put_file(fd, file);
+        Ok(written_size)
+    }
+
+    // Read exactly `N` bytes at a given offset, the file cursor is NOT updated.
+    fn read_array_with_offset<const N: usize>(
+        &mut self,
+        fd: Fd,
+        offset: FileSize,
+    ) -> Result<[u8; N], Error> {
+        let file = self.get_file(fd)?;
+
+        let mut buf = [0u8; N];
+        let read_size = file.read_with_offset(offset, &mut buf, self.storage.as_mut())?;
+
+        if read_size < N as FileSize {
+            return Err(Error::UnexpectedEof);
+        }
+
+        Ok(buf)
+    }
+
+    // Write all of `src` at a given offset, the file cursor is NOT updated.
+    fn write_slice_with_offset(
+        &mut self,
+        fd: Fd,
+        offset: FileSize,
+        src: &[u8],
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        file.write_with_offset(offset, src, self.storage.as_mut())?;
+        self.put_file(fd, file);
+        Ok(())
+    }
+
+    // Read a little-endian integer at a given offset, the file cursor is NOT updated.
+    // `Error::UnexpectedEof` is returned if the file ends before the whole integer is read.
+    pub fn read_u16_le(&mut self, fd: Fd, offset: FileSize) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.read_array_with_offset(fd, offset)?))
+    }
+
+    pub fn read_u32_le(&mut self, fd: Fd, offset: FileSize) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_array_with_offset(fd, offset)?))
+    }
+
+    pub fn read_u64_le(&mut self, fd: Fd, offset: FileSize) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.read_array_with_offset(fd, offset)?))
+    }
+
+    // Write a little-endian integer at a given offset, the file cursor is NOT updated.
+    pub fn write_u16_le(&mut self, fd: Fd, offset: FileSize, val: u16) -> Result<(), Error> {
+        self.write_slice_with_offset(fd, offset, &val.to_le_bytes())
+    }
+
+    pub fn write_u32_le(&mut self, fd: Fd, offset: FileSize, val: u32) -> Result<(), Error> {
+        self.write_slice_with_offset(fd, offset, &val.to_le_bytes())
+    }
+
+    pub fn write_u64_le(&mut self, fd: Fd, offset: FileSize, val: u64) -> Result<(), Error> {
+        self.write_slice_with_offset(fd, offset, &val.to_le_bytes())
+    }
+
+    // Add `delta` to the little-endian counter at a given offset and return its previous value, the file cursor is NOT updated.
+    // The counter wraps around on overflow like `AtomicU64::fetch_add`, `Error::UnexpectedEof` is returned
+    // if the file ends before the whole counter, so a new counter has to be initialized with `write_u64_le`.
+    pub fn fetch_add_u64(&mut self, fd: Fd, offset: FileSize, delta: u64) -> Result<u64, Error> {
+        let previous = self.read_u64_le(fd, offset)?;
+        self.write_u64_le(fd, offset, previous.wrapping_add(delta))?;
+
+        Ok(previous)
+    }
+
+    // Position file cursor to a given position.
+    pub fn seek(&mut self, fd: Fd, delta: i64, whence: Whence) -> Result<FileSize, Error> {
+        let mut file = self.get_file(fd)?;
+        let pos = file.seek(delta, whence, self.storage.as_mut())?;
+        self.put_file(fd, file);
+        Ok(pos)
+    }
+
+    // Get the current file cursor position.
+    pub fn tell(&mut self, fd: Fd) -> Result<FileSize, Error> {
+        let file = self.get_file(fd)?;
+        let pos = file.tell();
+        Ok(pos)
+    }
+
+    // Close the opened file and release the corresponding file descriptor.
+    pub fn close(&mut self, fd: Fd) -> Result<(), Error> {
+        self.flush(fd)?;
+        self.fd_table.close(fd).ok_or(Error::NotFound)?;
+
+        Ok(())
+    }
+
+    // Release the file descriptor without flushing the node. Only use it right before the file is removed,
+    // otherwise the pending changes of the node may not be stored.
+    pub fn close_nosync(&mut self, fd: Fd) -> Result<(), Error> {
+        self.fd_table.check(fd)?;
+        self.fd_table.close(fd).ok_or(Error::NotFound)?;
+
+        Ok(())
+    }
+
+    // Remove the first `bytes` of a file, the remaining content is shifted to the beginning of the file.
+    pub fn trim_front(&mut self, fd: Fd, bytes: FileSize) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let file = self.get_file(fd)?;
+        self.storage.trim_front(file.node, bytes)
+    }
+
+    // Compute a hash of the file contents, the holes are hashed as zeroes.
+    pub fn hash_file(&mut self, fd: Fd, algo: HashAlgo) -> Result<[u8; 32], Error> {
+        let file = self.get_file(fd)?;
+        self.hash_node(file.node, algo)
+    }
+
+    // Compute the digest of the node contents.
+    fn hash_node(&mut self, node: Node, algo: HashAlgo) -> Result<[u8; 32], Error> {
+        let size = self.storage.get_metadata(node)?.size;
+
+        let mut hasher = FileHasher::new(algo);
+        let mut buf = vec![0u8; self.storage.chunk_size()];
+        let mut offset = 0;
+
+        while offset < size {
+            let len = (size - offset).min(buf.len() as FileSize) as usize;
+            let read_size = self.storage.read(node, offset, &mut buf[..len])?;
+
+            if read_size == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..read_size as usize]);
+            offset += read_size;
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    // Compute an XXH3 128-bit hash of every `block_size` bytes of the file, the last block may be shorter.
+    // Two copies of a file can be synchronized by comparing the hashes and transferring only the differing blocks.
+    // The holes are hashed as zeroes, like in `hash_file`.
+    pub fn block_hashes(&mut self, fd: Fd, block_size: FileSize) -> Result<Vec<[u8; 16]>, Error> {
+        if block_size == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let file = self.get_file(fd)?;
+        let size = self.storage.get_metadata(file.node)?.size;
+
+        let mut hashes = Vec::with_capacity(size.div_ceil(block_size) as usize);
+        let mut buf = vec![0u8; block_size.min(size) as usize];
+        let mut offset = 0;
+
+        while offset < size {
+            let len = (size - offset).min(block_size) as usize;
+            let read_size =
+                file.read_with_offset(offset, &mut buf[..len], self.storage.as_mut())?;
+
+            if read_size == 0 {
+                break;
+            }
+
+            hashes.push(xxhash_rust::xxh3::xxh3_128(&buf[..read_size as usize]).to_le_bytes());
+            offset += read_size;
+        }
+
+        Ok(hashes)
+    }
+
+    // Get the relative path, SHA-256 content digest and size of every regular file under the given folder.
+    // The list is sorted by path, so two folders with identical contents produce identical manifests.
+    pub fn directory_manifest(
+        &mut self,
+        dir_fd: Fd,
+    ) -> Result<Vec<(String, [u8; 32], FileSize)>, Error> {
+        let files = self.list_files_recursive(dir_fd)?;
+
+        let mut result = Vec::with_capacity(files.len());
+
+        for (path, node, size) in files {
+            let hash = self.hash_node(node, HashAlgo::Sha256)?;
+            result.push((path, hash, size));
+        }
+
+        Ok(result)
+    }
+
+    // Get the path and full contents of every regular file in the file system, sorted by path. Meant for small
+    // file systems, e.g. to snapshot them in tests: fails with `Error::FileTooLarge` before reading any content
+    // if the files hold more than `max_total_size` bytes together.
+    pub fn dump(&mut self, max_total_size: FileSize) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let files = self.list_files_recursive(self.root_fd)?;
+
+        let total_size = files.iter().try_fold(0 as FileSize, |total, (_, _, size)| {
+            total.checked_add(*size)
+        });
+
+        if total_size.is_none_or(|total_size| total_size > max_total_size) {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut result = Vec::with_capacity(files.len());
+
+        for (path, node, size) in files {
+            let mut content = vec![0u8; size as usize];
+            let read_size = self.storage.read(node, 0, &mut content)?;
+            content.truncate(read_size as usize);
+
+            result.push((path, content));
+        }
+
+        Ok(result)
+    }
+
+    // Compare the regular files under two folders, sorted by path. The files of the same size are compared by their
+    // SHA-256 digests, so the content is only read when the sizes match. Symbolic links are not compared.
+    pub fn diff(&mut self, a_fd: Fd, b_fd: Fd) -> Result<Vec<DiffEntry>, Error> {
+        let a_files = self.list_files_recursive(a_fd)?;
+        let b_files = self.list_files_recursive(b_fd)?;
+
+        let mut result = Vec::new();
+
+        let mut a_iter = a_files.into_iter().peekable();
+        let mut b_iter = b_files.into_iter().peekable();
+
+        loop {
+            let order = match (a_iter.peek(), b_iter.peek()) {
+                (None, None) => break,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(a), Some(b)) => a.0.cmp(&b.0),
+            };
+
+            match order {
+                std::cmp::Ordering::Less => {
+                    result.push(DiffEntry::Removed(a_iter.next().unwrap().0));
+                }
+                std::cmp::Ordering::Greater => {
+                    result.push(DiffEntry::Added(b_iter.next().unwrap().0));
+                }
+                std::cmp::Ordering::Equal => {
+                    let (path, a_node, a_size) = a_iter.next().unwrap();
+                    let (_, b_node, b_size) = b_iter.next().unwrap();
+
+                    if a_size != b_size
+                        || self.hash_node(a_node, HashAlgo::Sha256)?
+                            != self.hash_node(b_node, HashAlgo::Sha256)?
+                    {
+                        result.push(DiffEntry::Changed(path));
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Get the relative path, node and size of every regular file under the given folder, sorted by path.
+    fn list_files_recursive(&self, dir_fd: Fd) -> Result<Vec<(String, Node, FileSize)>, Error> {
+        let dir = self.get_dir(dir_fd)?;
+
+        let mut files = Vec::new();
+
+        let mut visited = BTreeSet::from([dir.node]);
+        let mut stack = vec![(dir.node, String::new())];
+
+        while let Some((cur_dir, prefix)) = stack.pop() {
+            let mut next_index = self.storage.get_metadata(cur_dir)?.first_dir_entry;
+
+            while let Some(index) = next_index {
+                let entry = self.storage.get_direntry(cur_dir, index)?;
+                next_index = entry.next_entry;
+
+                let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+                let path = format!("{prefix}{name}");
+
+                let metadata = self.storage.get_metadata(entry.node)?;
+                match metadata.file_type {
+                    FileType::Directory => {
+                        if visited.insert(entry.node) {
+                            stack.push((entry.node, format!("{path}/")));
+                        }
+                    }
+                    FileType::RegularFile => files.push((path, entry.node, metadata.size)),
+                    FileType::SymbolicLink => {}
+                }
+            }
+        }
+
+        files.sort();
+
+        Ok(files)
+    }
+
+    // Get the node and name of every folder on the way to `path`, starting below `parent` and ending with the folder
+    // that holds the leaf entry, e.g. for breadcrumbs. The leaf must exist, a path of a single name has no ancestors.
+    pub fn ancestors(&mut self, parent: Fd, path: &str) -> Result<Vec<(Node, String)>, Error> {
+        let dir = self.get_dir(parent)?;
+        find_ancestors(dir.node, path, self.storage.as_ref())
+    }
+
+    // Get the absolute path of an open file or folder, e.g. for error messages. The nodes do not link to their
+    // parent folders, so the tree is searched from the root, which is slow for big file systems. A node with
+    // several hard links gets one of its paths, an unlinked node is `Error::NotFound`.
+    pub fn path_of_fd(&self, fd: Fd) -> Result<String, Error> {
+        let node = self.get_node(fd)?;
+        let root_node = self.storage.root_node();
+
+        if node == root_node {
+            return Ok(self.root_path().to_string());
+        }
+
+        let mut visited = BTreeSet::from([root_node]);
+        let mut stack = vec![(root_node, String::new())];
+
+        while let Some((cur_dir, prefix)) = stack.pop() {
+            let mut next_index = self.storage.get_metadata(cur_dir)?.first_dir_entry;
+
+            while let Some(index) = next_index {
+                let entry = self.storage.get_direntry(cur_dir, index)?;
+                next_index = entry.next_entry;
+
+                let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+                let path = format!("{prefix}/{name}");
+
+                if entry.node == node {
+                    return Ok(path);
+                }
+
+                if self.storage.get_metadata(entry.node)?.file_type == FileType::Directory
+                    && visited.insert(entry.node)
+                {
+                    stack.push((entry.node, path));
+                }
+            }
+        }
+
+        Err(Error::NotFound)
+    }
+
+    // Get the metadata for a given file descriptor
+    pub fn metadata_from_node(&self, node: Node) -> Result<Metadata, Error> {
+        self.storage.get_metadata(node)
+    }
+
+    // Get the metadata of several nodes, the records are fetched in the ascending node order for better locality.
+    // The results follow the order of `nodes`.
+    pub fn metadata_batch(&self, nodes: &[Node]) -> Vec<Result<Metadata, Error>> {
+        let mut order: Vec<usize> = (0..nodes.len()).collect();
+        order.sort_by_key(|i| nodes[*i]);
+
+        let mut result: Vec<Option<Result<Metadata, Error>>> =
+            (0..nodes.len()).map(|_| None).collect();
+
+        for i in order {
+            result[i] = Some(self.storage.get_metadata(nodes[i]));
+        }
+
+        result.into_iter().flatten().collect()
+    }
+
+    // Get the metadata for a given file descriptor
+    pub fn metadata(&self, fd: Fd) -> Result<Metadata, Error> {
+        let node = self.get_node(fd)?;
+        self.storage.get_metadata(node)
+    }
+
+    // update metadata of a given file descriptor
+    pub fn set_metadata(&mut self, fd: Fd, metadata: Metadata) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+
+        // the buffered file tail is written first, a new size must not leave it outside the file
+        self.storage.flush(node)?;
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Update the metadata for a given file descriptor like `set_metadata`, but reject the changes that would corrupt
+    // the file system: a different node, file type or chunk size, or directory entries set on a regular file.
+    // Such updates fail with `Error::InvalidArgument`, a file size beyond `MAX_FILE_SIZE` fails with `Error::FileTooLarge`.
+    pub fn set_metadata_checked(&mut self, fd: Fd, metadata: Metadata) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        let current = self.storage.get_metadata(node)?;
+
+        if metadata.node != current.node
+            || metadata.file_type != current.file_type
+            || metadata.chunk_size != current.chunk_size
+        {
+            return Err(Error::InvalidArgument);
+        }
+
+        if metadata.file_type == FileType::RegularFile {
+            if metadata.first_dir_entry.is_some() || metadata.last_dir_entry.is_some() {
+                return Err(Error::InvalidArgument);
+            }
+
+            if metadata.size > MAX_FILE_SIZE {
+                return Err(Error::FileTooLarge);
+            }
+        }
+
+        self.storage.flush(node)?;
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Set an extended attribute of a file or a directory.
+    pub fn set_xattr(&mut self, fd: Fd, name: &str, value: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        self.storage.set_xattr(node, name, value)
+    }
+
+    // Get an extended attribute of a file or a directory.
+    pub fn get_xattr(&self, fd: Fd, name: &str) -> Result<Vec<u8>, Error> {
+        let node = self.get_node(fd)?;
+        self.storage.get_xattr(node, name)
+    }
+
+    // List the names of the extended attributes of a file or a directory.
+    pub fn list_xattrs(&self, fd: Fd) -> Result<Vec<String>, Error> {
+        let node = self.get_node(fd)?;
+        self.storage.list_xattrs(node)
+    }
+
+    // Remove an extended attribute of a file or a directory.
+    pub fn remove_xattr(&mut self, fd: Fd, name: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        self.storage.remove_xattr(node, name)
+    }
+
+    // Get the creation time of a file or a directory, it is kept when the file is renamed or linked elsewhere.
+    pub fn created_time(&self, fd: Fd) -> Result<u64, Error> {
+        let node = self.get_node(fd)?;
+        let metadata = self.storage.get_metadata(node)?;
+
+        Ok(metadata.times.created)
+    }
+
+    // Make the reads of `fd` fail with `Error::UnexpectedEof` instead of returning fewer bytes than requested,
+    // e.g. for a database that expects every page read to be complete. A failed read does not move the cursor.
+    // This covers `read`, `read_vec`, `read_vectored`, `read_vec_with_offset` and `pread`.
+    pub fn set_require_full_reads(&mut self, fd: Fd, mode: FullReads) -> Result<(), Error> {
+        let mut file = self.get_file(fd)?;
+        file.full_reads = mode;
+        self.put_file(fd, file);
+
+        Ok(())
+    }
+
+    // Update access time.
+    pub fn set_accessed_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        let mut metadata = self.storage.get_metadata(node)?;
+
+        metadata.times.accessed = time;
+
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Update modification time.
+    pub fn set_modified_time(&mut self, fd: Fd, time: u64) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        let mut metadata = self.storage.get_metadata(node)?;
+
+        metadata.times.modified = time;
+
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Update access, modification and creation times with a single metadata write.
+    pub fn set_all_times(&mut self, fd: Fd, times: Times) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let node = self.get_node(fd)?;
+        let mut metadata = self.storage.get_metadata(node)?;
+
+        metadata.times = times;
+
+        self.storage.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // Get file or directory stats.
+    pub fn get_stat(&self, fd: Fd) -> Result<(FileType, FdStat), Error> {
+        self.fd_table.check(fd)?;
+
+        match self.fd_table.get(fd) {
+            None => Err(Error::NotFound),
+            Some(FdEntry::File(file)) => Ok((FileType::RegularFile, file.stat)),
+            Some(FdEntry::Dir(dir)) => Ok((FileType::Directory, dir.stat)),
+        }
+    }
+
+    // Update stats of a given file.
+    pub fn set_stat(&mut self, fd: Fd, stat: FdStat) -> Result<(), Error> {
+        self.fd_table.check(fd)?;
+
+        match self.fd_table.get(fd) {
+            Some(FdEntry::File(file)) => {
+                let mut file = file.clone();
+                file.stat = stat;
+                self.put_file(fd, file);
+                Ok(())
+            }
+            Some(FdEntry::Dir(dir)) => {
+                let mut dir = dir.clone();
+                dir.stat = stat;
+                self.put_dir(fd, dir);
+                Ok(())
+            }
+            None => Err(Error::NotFound),
+        }
+    }
+
+    // Get metadata of a file with name `path` in a given folder.
+    pub fn open_metadata(&mut self, parent: Fd, path: &str) -> Result<Metadata, Error> {
+        let dir = self.get_dir(parent)?;
+        let node = find_node(dir.node, path, &mut self.names_cache, self.storage.as_ref())?;
+        self.storage.get_metadata(node)
+    }
+
+    // Check whether two paths refer to the same node, e.g. two hard links of one file.
+    pub fn same_file(
+        &mut self,
+        a_parent: Fd,
+        a_path: &str,
+        b_parent: Fd,
+        b_path: &str,
+    ) -> Result<bool, Error> {
+        let a_dir = self.get_dir(a_parent)?;
+        let b_dir = self.get_dir(b_parent)?;
+
+        let a_node = find_node(
+            a_dir.node,
+            a_path,
+            &mut self.names_cache,
+            self.storage.as_ref(),
+        )?;
+        let b_node = find_node(
+            b_dir.node,
+            b_path,
+            &mut self.names_cache,
+            self.storage.as_ref(),
+        )?;
+
+        Ok(a_node == b_node)
+    }
+
+    // Opens of creates a new file.
+    // With `OpenFlags::CREATE` an existing entry is opened if its type matches the flags: `OpenFlags::DIRECTORY`
+    // requires a folder, without it both files and folders are opened. A file opened with `OpenFlags::DIRECTORY`
+    // fails with `Error::InvalidFileType`, as does a folder opened with `OpenFlags::TRUNCATE`.
+    // A new entry is created with all the missing folders of its path, e.g. `missing/dir/file.txt` creates
+    // `missing` and `missing/dir` first. Without `OpenFlags::CREATE` a missing entry or folder is `Error::NotFound`,
+    // with it a file in the middle of the path fails with `Error::InvalidFileType`.
+    pub fn open_or_create(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        check_open_flags(&flags)?;
+
+        if flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNCATE) {
+            self.check_writable()?;
+        }
+
+        let dir = self.get_dir(parent)?;
+        let close_on_boundary = flags.contains(OpenFlags::CLOSE_ON_BOUNDARY);
+
+        let fd = match find_node(dir.node, path, &mut self.names_cache, self.storage.as_ref()) {
+            Ok(node) => self.open(node, stat, flags),
+            Err(Error::NotFound) => {
+                if !flags.contains(OpenFlags::CREATE) {
+                    return Err(Error::NotFound);
+                }
+
+                if flags.contains(OpenFlags::DIRECTORY) {
+                    self.create_dir(parent, path, stat, ctime)
+                } else {
+                    self.create_file(parent, path, stat, ctime)
+                }
+            }
+            Err(err) => Err(err),
+        }?;
+
+        if close_on_boundary {
+            self.fd_table.mark_close_on_boundary(fd);
+        }
+
+        Ok(fd)
+    }
+
+    // Open or create a file like `open_or_create`, and return its metadata along with the descriptor.
+    pub fn open_with_metadata(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<(Fd, Metadata), Error> {
+        let fd = self.open_or_create(parent, path, stat, flags, ctime)?;
+        let metadata = self.metadata(fd)?;
+
+        Ok((fd, metadata))
+    }
+
+    // Open or create a file like `open_or_create` in the append-only mode: writes through the cursor always go
+    // to the end of the file, while seeking before the end or writing at an offset before the end fails with
+    // `Error::PermissionDenied`. This is stronger than `FdFlags::APPEND`, which only places the cursor at the end on opening.
+    pub fn open_append_only(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        mut stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        stat.flags |= FdFlags::APPEND;
+
+        let fd = self.open_or_create(parent, path, stat, flags, ctime)?;
+
+        let mut file = match self.get_file(fd) {
+            Ok(file) => file,
+            Err(err) => {
+                self.close(fd)?;
+                return Err(err);
+            }
+        };
+        file.append_only = true;
+        self.put_file(fd, file);
+
+        Ok(fd)
+    }
+
+    // Opens or creates a file like `open_or_create`, and reserves the chunks for `expected_size` bytes of content.
+    // The chunks are allocated as one contiguous run, so a following sequential write does not need to allocate them one by one.
+    pub fn open_with_hint(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        flags: OpenFlags,
+        ctime: u64,
+        expected_size: FileSize,
+    ) -> Result<Fd, Error> {
+        self.check_writable()?;
+
+        if expected_size > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        let fd = self.open_or_create(parent, path, stat, flags, ctime)?;
+
+        if let Ok(file) = self.get_file(fd) {
+            // the file stays as it is, but the caller does not get the descriptor
+            if let Err(err) = self.storage.preallocate(file.node, expected_size) {
+                self.close(fd)?;
+                return Err(err);
+            }
+        }
+
+        Ok(fd)
+    }
+
+    // Close all file descriptors opened with the `OpenFlags::CLOSE_ON_BOUNDARY` flag.
+    pub fn close_boundary_fds(&mut self) -> Result<(), Error> {
+        for fd in self.fd_table.boundary_fds() {
+            self.close(fd)?;
+        }
+
+        Ok(())
+    }
+
+    // Opens a file and returns its new file descriptor.
+    fn open(&mut self, node: Node, stat: FdStat, flags: OpenFlags) -> Result<Fd, Error> {
+        if flags.contains(OpenFlags::EXCLUSIVE) {
+            return Err(Error::FileAlreadyExists);
+        }
+        self.fd_table.check_open_limit()?;
+
+        let metadata = self.storage.get_metadata(node)?;
+        match metadata.file_type {
+            FileType::Directory => {
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    return Err(Error::InvalidFileType);
+                }
+                let dir = Dir::new(node, stat, self.storage.as_mut())?;
+                let fd = self.fd_table.open(FdEntry::Dir(dir));
+                Ok(fd)
+            }
+            FileType::RegularFile => {
+                if flags.contains(OpenFlags::DIRECTORY) {
+                    return Err(Error::InvalidFileType);
+                }
+                let file = File::new(node, stat, self.storage.as_mut())?;
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    file.truncate(self.storage.as_mut())?;
+                }
+                let fd = self.fd_table.open(FdEntry::File(file));
+                Ok(fd)
+            }
+            FileType::SymbolicLink => unimplemented!("Symbolic links are not supported yet"),
+        }
+    }
+
+    // Re-apply open flags and stats to an already opened file descriptor, the descriptor number is kept.
+    // The cursor is reset on truncation and moved to the end of file when the append mode is newly set.
+    pub fn freopen(&mut self, fd: Fd, flags: OpenFlags, stat: FdStat) -> Result<(), Error> {
+        check_open_flags(&flags)?;
+
+        if flags.contains(OpenFlags::EXCLUSIVE) {
+            return Err(Error::FileAlreadyExists);
+        }
+
+        if flags.contains(OpenFlags::TRUNCATE) {
+            self.check_writable()?;
+        }
+
+        self.fd_table.check(fd)?;
+
+        match self.fd_table.get(fd) {
+            Some(FdEntry::File(file)) => {
+                if flags.contains(OpenFlags::DIRECTORY) {
+                    return Err(Error::InvalidFileType);
+                }
+
+                let mut file = file.clone();
+
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    file.truncate(self.storage.as_mut())?;
+                    file.cursor = 0;
+                }
+
+                if stat.flags.contains(FdFlags::APPEND)
+                    && !file.stat.flags.contains(FdFlags::APPEND)
+                {
+                    file.cursor = self.storage.get_metadata(file.node)?.size;
+                }
+
+                file.stat = stat;
+                self.put_file(fd, file);
+            }
+            Some(FdEntry::Dir(dir)) => {
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    return Err(Error::InvalidFileType);
+                }
+
+                let mut dir = dir.clone();
+                dir.stat = stat;
+                self.put_dir(fd, dir);
+            }
+            None => return Err(Error::NotFound),
+        }
+
+        if flags.contains(OpenFlags::CLOSE_ON_BOUNDARY) {
+            self.fd_table.mark_close_on_boundary(fd);
+        }
+
+        Ok(())
+    }
+
+    // Create a new file named `path` in the given `parent` folder.
+    pub fn create_file(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+        self.fd_table.check_open_limit()?;
+        let created = self.missing_path_prefixes(dir.node, path);
+
+        let child = dir.create_file(
+            path,
+            stat,
+            &mut self.names_cache,
+            self.storage.as_mut(),
+            ctime,
+        )?;
+
+        self.record_created(dir.node, created, false);
+
+        let child_fd = self.fd_table.open(FdEntry::File(child));
+        self.put_dir(parent, dir);
+        Ok(child_fd)
+    }
+
+    // Create a new file of the given `size` with every byte set to `fill`.
+    // A zero fill only sets the file size and allocates no chunks, other values are written chunk by chunk.
+    pub fn create_filled(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        size: FileSize,
+        fill: u8,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        if size > MAX_FILE_SIZE {
+            return Err(Error::FileTooLarge);
+        }
+
+        let fd = self.create_file(parent, path, FdStat::default(), ctime)?;
+        let node = self.get_node(fd)?;
+
+        if fill == 0 {
+            let mut metadata = self.storage.get_metadata(node)?;
+            metadata.size = size;
+            self.storage.put_metadata(node, metadata);
+
+            return Ok(fd);
+        }
+
+        // one buffer is reused for all the chunks
+        let buf = vec![fill; self.storage.chunk_size()];
+
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(buf.len() as FileSize);
+
+            // the partly filled file stays, but the caller does not get the descriptor
+            match self.storage.write(node, offset, &buf[..len as usize]) {
+                Ok(written) => offset += written,
+                Err(err) => {
+                    self.close(fd)?;
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(fd)
+    }
+
+    // Create a new file with a unique name starting with `prefix`, returns the file descriptor and the chosen name.
+    // The name suffix is the next node ID, it is increased until there is no file with such a name.
+    pub fn create_temp(
+        &mut self,
+        parent: Fd,
+        prefix: &str,
+        ctime: u64,
+    ) -> Result<(Fd, String), Error> {
+        let mut counter = self.storage.next_node();
+
+        loop {
+            let name = format!("{prefix}{counter}");
+
+            match self.create_file(parent, &name, FdStat::default(), ctime) {
+                Ok(fd) => return Ok((fd, name)),
+                Err(Error::FileAlreadyExists) => counter += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Delete a file by name `path` in the given file folder.
+    pub fn remove_file(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+        dir.remove_file(
+            path,
+            self.fd_table.node_refcount(),
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )
+    }
+
+    // Remove a file releasing at most `max_chunks_per_call` of its chunks, so that a huge file can be deleted
+    // over several messages without hitting the instruction limit. The first call unlinks the file, it is not
+    // visible from then on. Repeat the call with the same arguments while it returns `RemoveProgress::Pending`.
+    // The unlinked file is recorded in the storage header, so the removal can be continued after an upgrade.
+    // A zero `max_chunks_per_call` would never make progress and fails with `Error::InvalidArgument`.
+    pub fn remove_file_chunked(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        max_chunks_per_call: usize,
+    ) -> Result<RemoveProgress, Error> {
+        self.check_writable()?;
+
+        if max_chunks_per_call == 0 {
+            return Err(Error::InvalidArgument);
+        }
+
+        let dir = self.get_dir(parent)?;
+
+        let pending = self
+            .storage
+            .pending_removals()
+            .into_iter()
+            .find(|(dir_node, pending_path, _)| *dir_node == dir.node && pending_path == path);
+
+        let node = match pending {
+            Some((_, _, node)) => node,
+            None => {
+                let (node, metadata) = rm_dir_entry(
+                    dir.node,
+                    path,
+                    Some(false),
+                    self.fd_table.node_refcount(),
+                    &mut self.names_cache,
+                    self.storage.as_mut(),
+                )?;
+
+                // the data is still reachable through other links
+                if metadata.link_count > 0 {
+                    return Ok(RemoveProgress::Done);
+                }
+
+                let mut removals = self.storage.pending_removals();
+                removals.push((dir.node, path.to_string(), node));
+                self.storage.set_pending_removals(removals);
+
+                node
+            }
+        };
+
+        if !self.storage.rm_file_chunks(node, max_chunks_per_call)? {
+            return Ok(RemoveProgress::Pending);
+        }
+
+        self.storage.rm_file(node)?;
+
+        let mut removals = self.storage.pending_removals();
+        removals.retain(|(_, _, pending_node)| *pending_node != node);
+        self.storage.set_pending_removals(removals);
+
+        Ok(RemoveProgress::Done)
+    }
+
+    // Create a new directory named `path` in the given `parent` folder.
+    pub fn create_dir(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<Fd, Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+        self.fd_table.check_open_limit()?;
+        let created = self.missing_path_prefixes(dir.node, path);
+
+        let child = dir.create_dir(
+            path,
+            stat,
+            &mut self.names_cache,
+            self.storage.as_mut(),
+            ctime,
+        )?;
+        self.record_created(dir.node, created, true);
+
+        let child_fd = self.fd_table.open(FdEntry::Dir(child));
+        self.put_dir(parent, dir);
+        Ok(child_fd)
+    }
+
+    // Create several directory paths in the given `parent` folder without opening them, e.g. to seed a folder structure.
+    // The missing folders on each path are created and the existing ones are reused. The call stops at the first path
+    // that cannot be created, the folders created until then are kept.
+    pub fn create_dirs(&mut self, parent: Fd, paths: &[&str], ctime: u64) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+
+        for path in paths {
+            let created = self.missing_path_prefixes(dir.node, path);
+
+            let (node, _leaf_name) = create_path(
+                dir.node,
+                path,
+                Some(FileType::Directory),
+                ctime,
+                self.storage.as_mut(),
+            )?;
+
+            // the path may end with an existing file
+            if self.storage.get_metadata(node)?.file_type != FileType::Directory {
+                return Err(Error::InvalidFileType);
+            }
+
+            self.record_created(dir.node, created, true);
+            self.names_cache.add((dir.node, path.to_string()), node);
+        }
+
+        Ok(())
+    }
+
+    // Create a new directory named `path` without opening it, fails if the entry already exists.
+    // Like mkdir(2), the missing parent folders are not created, in that case `Error::NotFound` is returned.
+    pub fn mkdir_exclusive(
+        &mut self,
+        parent: Fd,
+        path: &str,
+        stat: FdStat,
+        ctime: u64,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+
+        if let Some((parent_path, _)) = path.trim_end_matches('/').rsplit_once('/') {
+            if parent_path
+                .split('/')
+                .any(|part| !part.is_empty() && part != ".")
+            {
+                let node = find_node(
+                    dir.node,
+                    parent_path,
+                    &mut self.names_cache,
+                    self.storage.as_ref(),
+                )?;
+
+                if self.storage.get_metadata(node)?.file_type != FileType::Directory {
+                    return Err(Error::InvalidFileType);
+                }
+            }
+        }
+
+        let created = self.missing_path_prefixes(dir.node, path);
+
+        dir.create_dir(
+            path,
+            stat,
+            &mut self.names_cache,
+            self.storage.as_mut(),
+            ctime,
+        )?;
+        self.record_created(dir.node, created, true);
+        self.put_dir(parent, dir);
+        Ok(())
+    }
+
+    // Delete a directory by name `path` in the given file folder.
+    pub fn remove_dir(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+
+        let dir = self.get_dir(parent)?;
+        dir.remove_dir(
+            path,
+            self.fd_table.node_refcount(),
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )
+    }
+
+    // Create a hard link to an existing file.
+    pub fn create_hard_link(
+        &mut self,
+        old_fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> Result<Fd, Error> {
+        self.check_writable()?;
+
+        let src_dir = self.get_dir(old_fd)?;
+        let dst_dir = self.get_dir(new_fd)?;
+        let created = self.missing_path_prefixes(dst_dir.node, new_path);
+
+        create_hard_link(
+            dst_dir.node,
+            new_path,
+            src_dir.node,
+            old_path,
+            false,
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )?;
+        self.record_created(dst_dir.node, created, false);
+
+        let node = find_node(
+            dst_dir.node,
+            new_path,
+            &mut self.names_cache,
+            self.storage.as_ref(),
+        )?;
+
+        self.open(node, FdStat::default(), OpenFlags::empty())
+    }
+
+    // Rename a file.
+    pub fn rename(
+        &mut self,
+        old_fd: Fd,
+        old_path: &str,
+        new_fd: Fd,
+        new_path: &str,
+    ) -> Result<Fd, Error> {
+        self.check_writable()?;
+
+        let src_dir = self.get_dir(old_fd)?;
+        let dst_dir = self.get_dir(new_fd)?;
+
+        // create a new link
+        create_hard_link(
+            dst_dir.node,
+            new_path,
+            src_dir.node,
+            old_path,
+            true,
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )?;
+
+        // now unlink the older version
+        let (node, _metadata) = rm_dir_entry(
+            src_dir.node,
+            old_path,
+            None,
+            self.fd_table.node_refcount(),
+            &mut self.names_cache,
+            self.storage.as_mut(),
+        )?;
+
+        self.open(node, FdStat::default(), OpenFlags::empty())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_test_storage(&mut self) -> &mut dyn Storage {
+        self.storage.as_mut()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get_test_file(&self, fd: Fd) -> File {
+        self.get_file(fd).unwrap()
+    }
+}
+
+// Reject the open flag combinations that conflict with each other regardless of the target.
+fn check_open_flags(flags: &OpenFlags) -> Result<(), Error> {
+    // a folder cannot be truncated
+    if flags.contains(OpenFlags::DIRECTORY | OpenFlags::TRUNCATE) {
+        return Err(Error::InvalidOpenFlags);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use ic_cdk::api::stable::WASM_PAGE_SIZE_IN_BYTES;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::{Memory, VectorMemory};
+
+    use crate::test_utils::write_text_at_offset;
+    use crate::{
+        error::Error,
+        fs::{
+            Advice, ChunkSize, ChunkType, DiffEntry, DirOrder, DstBuf, FdFlags, FullReads,
+            HashAlgo, Normalization, RemoveProgress, SrcBuf, Whence,
+        },
+        runtime::{
+            structure_helpers::find_node,
+            types::{FdStat, OpenFlags},
+        },
+        storage::{
+            stable::StableStorage,
+            types::{
+                FileSize, FileType, Times, FILE_CHUNK_SIZE_V1, MAX_FILE_CHUNK_COUNT,
+                MAX_FILE_CHUNK_SIZE_V2, MAX_FILE_SIZE, MAX_XATTR_SIZE_PER_NODE,
+            },
+        },
+        test_utils::{
+            new_vector_memory, read_text_file, test_fs, test_fs_custom_chunk_size, test_fs_setups,
+            test_fs_transient, test_fs_v1, write_text_fd, write_text_file,
+        },
+    };
+
+    use super::{Fd, FileSystem};
+
+    pub fn list_files(fs: &mut FileSystem, path: &str) -> Vec<String> {
+        use std::str::FromStr;
+
+        let mut res = vec![];
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, path, FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+
+        let mut entry_index = meta.first_dir_entry;
+
+        while let Some(index) = entry_index {
+            let entry = fs.get_direntry(fd, index).unwrap();
+
+            let filename_str: &str =
+                std::str::from_utf8(&entry.name.bytes[0..(entry.name.length as usize)]).unwrap();
+
+            let st = String::from_str(filename_str).unwrap();
+
+            res.push(st);
+
+            entry_index = entry.next_entry;
+        }
+
+        res
+    }
+
+    #[test]
+    fn get_root_info() {
+        let fs = test_fs();
+
+        let fd = fs.root_fd();
+        let path = fs.root_path();
+
+        assert!(fd == 3);
+        assert!(path == "/");
+    }
+
+    #[test]
+    fn create_file() {
+        let mut fs = test_fs();
+
+        let file = fs
+            .create_file(fs.root_fd(), "test.txt", Default::default(), 0)
+            .unwrap();
+
+        assert!(file > fs.root_fd());
+    }
+
+    #[test]
+    fn create_dir() {
+        let mut fs = test_fs();
+
+        let dir = fs
+            .create_dir(fs.root_fd(), "test", FdStat::default(), 0)
+            .unwrap();
+
+        let fd = fs
+            .create_file(dir, "file.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.write(fd, "Hello, world!".as_bytes()).unwrap();
+
+        let dir = fs
+            .open_or_create(
+                fs.root_fd(),
+                "test",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+
+        let fd = fs
+            .open_or_create(dir, "file.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+
+        let mut buf = [0; 13];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(&buf, "Hello, world!".as_bytes());
+    }
+
+    #[test]
+    fn create_file_creates_a_few_files() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let meta = fs.metadata(fs.root_fd()).unwrap();
+
+        let entry_index = meta.first_dir_entry.unwrap();
+
+        let entry1 = fs.get_direntry(fs.root_fd(), entry_index).unwrap();
+        let entry2 = fs.get_direntry(fs.root_fd(), entry_index + 1).unwrap();
+        let entry3 = fs.get_direntry(fs.root_fd(), entry_index + 2).unwrap();
+
+        assert_eq!(entry1.prev_entry, None);
+        assert_eq!(entry1.next_entry, Some(entry_index + 1));
+
+        assert_eq!(entry2.prev_entry, Some(entry_index));
+        assert_eq!(entry2.next_entry, Some(entry_index + 2));
+
+        assert_eq!(entry3.prev_entry, Some(entry_index + 1));
+        assert_eq!(entry3.next_entry, None);
+    }
+
+    #[test]
+    fn close_file_fd_reused() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd2 = fs
+            .create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+            .unwrap();
+
+        fs.close(fd2).unwrap();
+
+        let fd4 = fs
+            .create_file(dir, "test4.txt", FdStat::default(), 0)
+            .unwrap();
+
+        assert_eq!(fd2, fd4);
+    }
+
+    #[test]
+    fn fd_renumber() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd1 = fs
+            .create_file(dir, "test1.txt", FdStat::default(), 0)
+            .unwrap();
+        let fd2 = fs
+            .create_file(dir, "test2.txt", FdStat::default(), 0)
+            .unwrap();
+
+        let entry1 = fs.get_node(fd1);
+
+        fs.renumber(fd1, fd2).unwrap();
+
+        assert!(fs.get_node(fd1).is_err());
+        assert_eq!(fs.get_node(fd2), entry1);
+    }
+
+    #[test]
+    fn close_boundary_fds_closes_only_tagged() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        let tagged_fd = fs
+            .open_or_create(
+                root_fd,
+                "tagged.txt",
+                FdStat::default(),
+                OpenFlags::CREATE | OpenFlags::CLOSE_ON_BOUNDARY,
+                0,
+            )
+            .unwrap();
+
+        let normal_fd = fs
+            .open_or_create(
+                root_fd,
+                "normal.txt",
+                FdStat::default(),
+                OpenFlags::CREATE,
+                0,
+            )
+            .unwrap();
+
+        fs.close_boundary_fds().unwrap();
+
+        assert_eq!(fs.get_stat(tagged_fd).unwrap_err(), Error::NotFound);
+        assert!(fs.get_stat(normal_fd).is_ok());
+        assert!(fs.get_stat(root_fd).is_ok());
+
+        // nothing left to close on the next boundary
+        fs.close_boundary_fds().unwrap();
+        assert!(fs.get_stat(normal_fd).is_ok());
+    }
+
+    #[test]
+    fn seek_and_write() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.seek(fd, 24, super::Whence::SET).unwrap();
+
+        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+
+        assert_eq!(meta.size, 29);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn read_and_write_vec() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let write_content1 = "This is a sample file content.";
+        let write_content2 = "1234567890";
+
+        let write_content = [
+            SrcBuf {
+                buf: write_content1.as_ptr(),
+                len: write_content1.len(),
+            },
+            SrcBuf {
+                buf: write_content2.as_ptr(),
+                len: write_content2.len(),
+            },
+        ];
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.write_vec(fd, write_content.as_ref()).unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+        assert_eq!(meta.size, 40);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+
+        let mut read_content1 = String::from("......................");
+        let mut read_content2 = String::from("......................");
+
+        let read_content = [
+            DstBuf {
+                buf: read_content1.as_mut_ptr(),
+                len: read_content1.len(),
+            },
+            DstBuf {
+                buf: read_content2.as_mut_ptr(),
+                len: read_content2.len(),
+            },
+        ];
+
+        fs.read_vec(fd, &read_content).unwrap();
+
+        assert_eq!("This is a sample file ", read_content1);
+        assert_eq!("content.1234567890....", read_content2);
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn read_and_write_vectored() {
+        for mut fs in test_fs_setups("test.txt") {
+            let dir = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            let written = fs
+                .write_vectored(fd, &[b"This is a sample file content.", b"1234567890"])
+                .unwrap();
+            assert_eq!(written, 40);
+
+            let meta = fs.metadata(fd).unwrap();
+            assert_eq!(meta.size, 40);
+
+            fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+
+            let mut read_content1 = [b'.'; 22];
+            let mut read_content2 = [b'.'; 22];
+
+            let read = fs
+                .read_vectored(fd, &mut [&mut read_content1, &mut read_content2])
+                .unwrap();
+            assert_eq!(read, 40);
+
+            assert_eq!(b"This is a sample file ", &read_content1);
+            assert_eq!(b"content.1234567890....", &read_content2);
+
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_and_write_vec_with_offset() {
+        let mut fs = test_fs();
+
+        let dir = fs.root_fd();
+
+        let write_content1 = "This is a sample file content.";
+        let write_content2 = "1234567890";
+
+        let write_content = [
+            SrcBuf {
+                buf: write_content1.as_ptr(),
+                len: write_content1.len(),
+            },
+            SrcBuf {
+                buf: write_content2.as_ptr(),
+                len: write_content2.len(),
+            },
+        ];
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.write_vec_with_offset(fd, write_content.as_ref(), 2)
+            .unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+        assert_eq!(meta.size, 42);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+
+        let mut read_content1 = String::from("......................");
+        let mut read_content2 = String::from("......................");
+
+        let read_content = [
+            DstBuf {
+                buf: read_content1.as_mut_ptr(),
+                len: read_content1.len(),
+            },
+            DstBuf {
+                buf: read_content2.as_mut_ptr(),
+                len: read_content2.len(),
+            },
+        ];
+
+        fs.read_vec_with_offset(fd, &read_content, 1).unwrap();
+
+        assert_eq!("\0This is a sample file", read_content1);
+        assert_eq!(" content.1234567890...", read_content2);
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn seek_and_write_transient() {
+        let mut fs = test_fs_transient();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        fs.seek(fd, 24, super::Whence::SET).unwrap();
+
+        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+        let meta = fs.metadata(fd).unwrap();
+
+        assert_eq!(meta.size, 29);
+
+        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+
+        let fd = fs
+            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        let mut buf = [42u8; 29];
+        let rr = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(rr, 29);
+        assert_eq!(
+            buf,
+            [
+                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+                4, 5
+            ]
+        );
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn create_and_remove_file() {
+        for mut fs in test_fs_setups("virtual_memory.txt") {
+            let dir = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+            fs.close(fd).unwrap();
+
+            fs.remove_file(dir, "test.txt").unwrap();
+
+            let err = fs
+                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap_err();
+            assert_eq!(err, Error::NotFound);
+        }
+    }
+
+    #[test]
+    fn cannot_remove_opened_file() {
+        for mut fs in test_fs_setups("virtual_memory.txt") {
+            let dir = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+
+            let err = fs.remove_file(dir, "test.txt").unwrap_err();
+            assert_eq!(err, Error::CannotRemoveOpenedNode);
+
+            fs.close(fd).unwrap();
+            fs.remove_file(dir, "test.txt").unwrap();
+        }
+    }
+
+    #[test]
+    fn cannot_remove_directory_as_file() {
+        for mut fs in test_fs_setups("virtual_memory.txt") {
+            let dir = fs.root_fd();
+
+            let fd = fs.create_dir(dir, "test", FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+
+            let err = fs.remove_file(dir, "test").unwrap_err();
+            assert_eq!(err, Error::ExpectedToRemoveFile);
+
+            fs.remove_dir(dir, "test").unwrap();
+        }
+    }
+
+    #[test]
+    fn cannot_remove_file_as_directory() {
+        for mut fs in test_fs_setups("virtual_memory.txt") {
+            let dir = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+
+            let err = fs.remove_dir(dir, "test.txt").unwrap_err();
+            assert_eq!(err, Error::ExpectedToRemoveDirectory);
+
+            fs.remove_file(dir, "test.txt").unwrap();
+        }
+    }
+
+    #[test]
+    fn renumber_when_the_alternative_file_exists() {
+        for mut fs in test_fs_setups("virtual_memory.txt") {
+            let dir = fs.root_fd();
+
+            let fd1 = fs
+                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+
+            let pos1 = fs.tell(fd1).unwrap();
+
+            let fd2 = fs
+                .open_or_create(dir, "file2.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            let pos2 = fs.tell(fd2).unwrap();
+
+            assert!(pos1 == 5);
+            assert!(pos2 == 0);
+
+            fs.renumber(fd1, fd2).unwrap();
+
+            let pos2_renumbered = fs.tell(fd2).unwrap();
+
+            assert!(pos1 == pos2_renumbered);
+
+            let res = fs.tell(fd1);
+
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn renumber_when_the_alternative_file_doesnt_exist() {
+        for mut fs in test_fs_setups("file1.txt") {
+            let dir = fs.root_fd();
+
+            let fd1 = fs
+                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+
+            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+
+            let pos1 = fs.tell(fd1).unwrap();
+
+            assert!(pos1 == 5);
+
+            let fd2 = 100;
+
+            fs.renumber(fd1, fd2).unwrap();
+
+            let pos2_renumbered = fs.tell(fd2).unwrap();
+
+            assert!(pos1 == pos2_renumbered);
+
+            let res = fs.tell(fd1);
+
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn set_modified_set_accessed_time() {
+        for mut fs in test_fs_setups("") {
+            let dir = fs.root_fd();
+
+            let fd1 = fs
+                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 111)
+                .unwrap();
+
+            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+
+            fs.set_accessed_time(fd1, 333).unwrap();
+            fs.set_modified_time(fd1, 222).unwrap();
+
+            let metadata = fs.metadata(fd1).unwrap();
+
+            let times = metadata.times;
+
+            assert_eq!(times.accessed, 333);
+            assert_eq!(times.modified, 222);
+            assert_eq!(times.created, 111);
+
+            assert_eq!(metadata.size, 5);
+            assert_eq!(metadata.file_type, FileType::RegularFile);
+        }
+    }
+
+    #[test]
+    fn set_stat_get_stat() {
+        let mut fs = test_fs();
+        let dir = fs.root_fd();
+
+        let fd1 = fs
+            .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 111)
+            .unwrap();
+
+        fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+
+        let (file_type, mut stat) = fs.get_stat(fd1).unwrap();
+
+        assert_eq!(file_type, FileType::RegularFile);
+
+        assert_eq!(stat.flags, FdFlags::empty());
+
+        stat.flags = FdFlags::APPEND;
+
+        fs.set_stat(fd1, stat).unwrap();
+
+        let (_, stat2) = fs.get_stat(fd1).unwrap();
+
+        assert_eq!(stat2.flags, FdFlags::APPEND);
+    }
+
+    fn create_test_file_with_content(
+        fs: &mut FileSystem,
+        parent: Fd,
+        file_name: &str,
+        content: Vec<String>,
+    ) -> Fd {
+        let file_fd = fs
+            .create_file(parent, file_name, FdStat::default(), 0)
+            .unwrap();
+
+        let mut src = String::from("");
+
+        for str in content.iter() {
+            src.push_str(str.as_str());
+        }
+
+        let bytes_written = fs.write(file_fd, src.as_bytes()).unwrap();
+
+        assert!(bytes_written > 0);
+
+        file_fd as Fd
+    }
+
+    fn create_test_file(fs: &mut FileSystem, parent_fd: Fd, file_name: &str) -> Fd {
+        create_test_file_with_content(
+            fs,
+            parent_fd,
+            file_name,
+            vec![
+                String::from("This is a sample text."),
+                String::from("1234567890"),
+            ],
+        )
+    }
+
+    #[test]
+    fn link_seek_tell() {
+        let mut fs = test_fs();
+        let dir = fs.root_fd();
+
+        let root_fd = 3i32;
+
+        let file_name1 = String::from("file.txt");
+        let file_name2 = String::from("file_link.txt");
+
+        let file_fd = create_test_file(&mut fs, root_fd as Fd, &file_name1);
+
+        let root_node = fs.storage.as_ref().root_node();
+        let node1 = find_node(
+            root_node,
+            &file_name1,
+            &mut fs.names_cache,
+            fs.storage.as_ref(),
+        )
+        .unwrap();
+
+        // test seek and tell
+        let position = fs.tell(file_fd).unwrap();
+
+        assert_eq!(position, 32);
+
+        fs.seek(file_fd, 10, crate::fs::Whence::SET).unwrap();
+
+        let position_after_seek = fs.tell(file_fd).unwrap();
+
+        assert_eq!(position_after_seek, 10);
+
+        let mut buf_to_read1 = String::from("...............");
+
+        let bytes_read = fs
+            .read(file_fd, unsafe { buf_to_read1.as_bytes_mut() })
+            .unwrap();
+
+        assert_eq!(bytes_read, 15);
+        assert_eq!(buf_to_read1, "sample text.123");
+
+        // create link
+        fs.create_hard_link(dir, &file_name1, dir, &file_name2)
+            .unwrap();
+
+        let node2 = find_node(
+            root_node,
+            &file_name2,
+            &mut fs.names_cache,
+            fs.storage.as_ref(),
+        )
+        .unwrap();
+
+        assert_eq!(node1, node2);
+
+        let link_file_fd = fs
+            .open_or_create(
+                dir,
+                "file_link.txt",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+
+        fs.seek(link_file_fd, 10, crate::fs::Whence::SET).unwrap();
+
+        let position_link = fs.tell(link_file_fd).unwrap();
+
+        assert_eq!(position_link, 10);
+
+        let mut buf_to_read1 = String::from("................");
+
+        let bytes_read = fs
+            .read(link_file_fd, unsafe { buf_to_read1.as_bytes_mut() })
+            .unwrap();
+
+        assert_eq!(bytes_read, 16);
+
+        assert_eq!(buf_to_read1, "sample text.1234");
+    }
+
+    #[test]
+    fn renaming_folder_with_contents() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        let file_name = String::from("dir1/dir2/file.txt");
+        create_test_file(&mut fs, root_fd as Fd, &file_name);
+
+        fs.rename(root_fd, "dir1/dir2", root_fd, "dir2").unwrap();
+
+        let file_fd = fs
+            .open_or_create(
+                root_fd,
+                "dir2/file.txt",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+
+        fs.seek(file_fd, 10, crate::fs::Whence::SET).unwrap();
+
+        let mut buf_to_read1 = String::from("................");
+
+        let bytes_read = fs
+            .read(file_fd, unsafe { buf_to_read1.as_bytes_mut() })
+            .unwrap();
+
+        assert_eq!(bytes_read, 16);
+
+        assert_eq!(buf_to_read1, "sample text.1234");
+    }
+
+    #[test]
+    fn write_and_read_25_files() {
+        for mut fs in test_fs_setups("auto/my_file_6.txt") {
+            let root_fd = fs.root_fd();
+            const SIZE_OF_FILE: usize = 1_000_000;
+
+            // write files
+            let dir_name = "auto";
+            let file_count: u8 = 25;
+
+            for i in 0..file_count {
+                let filename = format!("{}/my_file_{}.txt", dir_name, i);
+                let content = format!("{i}");
+                let times = SIZE_OF_FILE / content.len();
+
+                write_text_file(&mut fs, root_fd, filename.as_str(), content.as_str(), times)
+                    .unwrap();
+            }
+
+            // read files
+            for i in 0..file_count {
+                let filename = format!("{}/my_file_{}.txt", dir_name, i);
+                let expected_content = format!("{i}{i}{i}");
+
+                let text_read = read_text_file(
+                    &mut fs,
+                    root_fd,
+                    filename.as_str(),
+                    0,
+                    expected_content.len(),
+                );
+
+                assert_eq!(expected_content, text_read);
+            }
+        }
+
+        // This test should not crash with an error
+    }
+
+    #[test]
+    fn empty_path_support() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        write_text_file(&mut fs, root_fd, "f1/f2/text.txt", "content123", 100).unwrap();
+
+        let content = read_text_file(&mut fs, root_fd, "f1/f2/text.txt", 7, 10);
+        assert_eq!(content, "123content");
+
+        let content = read_text_file(&mut fs, root_fd, "f1//f2/text.txt", 6, 10);
+        assert_eq!(content, "t123conten");
+
+        let content = read_text_file(&mut fs, root_fd, "/f1//f2/text.txt", 5, 10);
+        assert_eq!(content, "nt123conte");
+
+        write_text_file(&mut fs, root_fd, "text.txt", "abc", 100).unwrap();
+
+        let content = read_text_file(&mut fs, root_fd, "text.txt", 0, 6);
+        assert_eq!(content, "abcabc");
+
+        let content = read_text_file(&mut fs, root_fd, "/text.txt", 0, 6);
+        assert_eq!(content, "abcabc");
+
+        let content = read_text_file(&mut fs, root_fd, "///////text.txt", 0, 6);
+        assert_eq!(content, "abcabc");
+
+        // This test should not crash with an error
+    }
+
+    #[test]
+    fn writing_into_mounted_memory() {
+        let memory: VectorMemory = new_vector_memory();
+
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
+            .unwrap();
+
+        let content = "ABCDEFG123";
+
+        write_text_file(&mut fs, root_fd, "test.txt", content, 1).unwrap();
+
+        let mut buf = [0u8; 100];
+
+        memory.read(0, &mut buf);
+
+        println!("{:?}", buf);
+    }
+
+    #[test]
+    fn reading_mounted_memory_after_upgrade() {
+        let memory_manager = MemoryManager::init(new_vector_memory());
+        let memory = memory_manager.get(MemoryId::new(1));
+
+        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
+            .unwrap();
+
+        let root_fd = fs.root_fd();
+        let content = "ABCDEFG123";
+        write_text_file(&mut fs, root_fd, "test.txt", content, 2).unwrap();
+
+        // imitate canister upgrade (we keep the memory manager but recreate the file system with the same virtual memories)
+        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
+            .unwrap();
+        let root_fd = fs.root_fd();
+
+        let content = read_text_file(&mut fs, root_fd, "test.txt", 0, 100);
+
+        assert_eq!(content, "ABCDEFG123ABCDEFG123");
+    }
+
+    #[test]
+    fn mounted_file_size_persists_over_upgrade() {
+        let memory_manager = MemoryManager::init(new_vector_memory());
+        let memory_a = memory_manager.get(MemoryId::new(1));
+        let memory_b = memory_manager.get(MemoryId::new(2));
+
+        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        fs.mount_memory_file("a.txt", Box::new(memory_a.clone()))
+            .unwrap();
+        fs.mount_memory_file("b.txt", Box::new(memory_b.clone()))
+            .unwrap();
+
+        let root_fd = fs.root_fd();
+        for (name, size) in [("a.txt", 12345), ("b.txt", 100)] {
+            let fd = fs
+                .open_or_create(root_fd, name, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            fs.write(fd, &vec![1u8; size]).unwrap();
+            fs.close(fd).unwrap();
+        }
+
+        fs.persist_mount_metadata().unwrap();
+
+        // imitate canister upgrade
+        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        fs.mount_memory_file("a.txt", Box::new(memory_a.clone()))
+            .unwrap();
+        fs.mount_memory_file("b.txt", Box::new(memory_b.clone()))
+            .unwrap();
+
+        let root_fd = fs.root_fd();
+        for (name, size) in [("a.txt", 12345), ("b.txt", 100)] {
+            let fd = fs
+                .open_or_create(root_fd, name, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().size, size);
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn mount_with_migration_keeps_content() {
+        for mut fs in test_fs_setups("") {
+            let memory: VectorMemory = new_vector_memory();
+            let root_fd = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            write_text_fd(&mut fs, fd, "0123456789", 10_000).unwrap();
+            let node = fs.metadata(fd).unwrap().node;
+            fs.close(fd).unwrap();
+
+            fs.mount_with_migration("test.txt", Box::new(memory.clone()))
+                .unwrap();
+            assert!(fs.storage.is_mounted(node));
+            assert!(memory.size() > 0);
+
+            assert_eq!(
+                read_text_file(&mut fs, root_fd, "test.txt", 0, 100_000),
+                "0123456789".repeat(10_000)
+            );
+
+            // the host file does not keep a copy
+            let memory = fs.storage.unmount_node(node).unwrap();
+            assert_eq!(fs.storage.get_metadata(node).unwrap().size, 0);
+
+            // storing the content brings it back to the host file
+            fs.storage.mount_node(node, memory).unwrap();
+            fs.persist_and_unmount("test.txt").unwrap();
+            assert_eq!(
+                read_text_file(&mut fs, root_fd, "test.txt", 99_990, 100),
+                "0123456789"
+            );
+        }
+    }
+
+    // Memory that cannot grow past `max_pages`, like a canister reaching its subnet limit.
+    #[derive(Clone, Default)]
+    struct CappedMemory {
+        inner: VectorMemory,
+        max_pages: u64,
+    }
+
+    impl Memory for CappedMemory {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn grow(&self, pages: u64) -> i64 {
+            if self.inner.size() + pages > self.max_pages {
+                return -1;
+            }
+
+            self.inner.grow(pages)
+        }
+
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            self.inner.read(offset, dst)
+        }
+
+        fn write(&self, offset: u64, src: &[u8]) {
+            self.inner.write(offset, src)
+        }
+    }
+
+    #[test]
+    fn mounted_file_past_memory_cap_is_out_of_memory() {
+        for mut fs in test_fs_setups("") {
+            let memory = CappedMemory {
+                inner: new_vector_memory(),
+                max_pages: 2,
+            };
+            fs.mount_memory_file("mounted.txt", Box::new(memory))
+                .unwrap();
+
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "mounted.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+
+            // within the cap
+            fs.write(fd, b"hello").unwrap();
+
+            let cap = 2 * WASM_PAGE_SIZE_IN_BYTES;
+            let mut metadata = fs.metadata(fd).unwrap();
+            metadata.size = cap + 100;
+            fs.set_metadata(fd, metadata).unwrap();
+
+            let mut buf = [0u8; 10];
+            assert_eq!(fs.pread(fd, &mut buf, Whence::SET, 0), Ok(10));
+            assert_eq!(&buf[..5], b"hello");
+
+            assert_eq!(
+                fs.pread(fd, &mut buf, Whence::SET, (cap + 10) as i64),
+                Err(Error::OutOfMemory)
+            );
+
+            fs.seek(fd, (cap + 10) as i64, Whence::SET).unwrap();
+            assert_eq!(fs.write(fd, b"beyond"), Err(Error::OutOfMemory));
+
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn spill_mount_moves_file_past_threshold() {
+        for mut fs in test_fs_setups("") {
+            let memory: VectorMemory = new_vector_memory();
+            let root_fd = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            let node = fs.metadata(fd).unwrap().node;
+
+            fs.set_spill_mount(fd, 10_000, Box::new(memory.clone()))
+                .unwrap();
+
+            write_text_fd(&mut fs, fd, "0123456789", 900).unwrap();
+            assert!(!fs.storage.is_mounted(node));
+            assert_eq!(memory.size(), 0);
+
+            // crossing the threshold moves the content into the memory
+            write_text_fd(&mut fs, fd, "0123456789", 200).unwrap();
+            assert!(fs.storage.is_mounted(node));
+
+            let mut buf = [0u8; 10];
+            memory.read(10_990, &mut buf);
+            assert_eq!(&buf, b"0123456789");
+
+            // further writes go directly into the memory
+            fs.write(fd, b"ABCDEFGHIJ").unwrap();
+            memory.read(11_000, &mut buf);
+            assert_eq!(&buf, b"ABCDEFGHIJ");
+
+            let mut buf = [0u8; 20];
+            fs.pread(fd, &mut buf, Whence::SET, 0).unwrap();
+            assert_eq!(&buf, b"01234567890123456789");
+            assert_eq!(fs.metadata(fd).unwrap().size, 11_010);
+
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn deleting_mounted_file_fails() {
+        let memory: VectorMemory = new_vector_memory();
+
+        let mut fs = test_fs();
+
+        let root_fd = fs.root_fd();
+
+        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
+            .unwrap();
+
+        let res = fs.remove_file(root_fd, "test.txt");
+
+        assert!(
+            res == Err(Error::CannotRemoveMountedMemoryFile),
+            "Deleting a mounted file should not be allowed!"
+        );
+
+        // check the dir entry still exists after deletion
+        let files = list_files(&mut fs, "");
+
+        assert_eq!(files[0], "test.txt".to_string());
+    }
+
+    #[test]
+    fn mounted_memory_store_and_init_roundtrip() {
+        for mut fs in test_fs_setups("") {
+            let memory1: VectorMemory = new_vector_memory();
+            let memory2: VectorMemory = new_vector_memory();
+
+            let file_name = "test.txt";
+
+            fs.mount_memory_file(file_name, Box::new(memory1.clone()))
+                .unwrap();
+
+            let content = "ABCDEFG123";
+            let len = content.len();
+            let count = 1000;
+
+            memory1.grow(5);
+
+            // fill up memory with some data
+            for i in 0..count {
+                memory1.write(i as u64 * len as u64, content.as_bytes());
+            }
+
+            let fd = fs
+                .open_or_create(
+                    fs.root_fd,
+                    file_name,
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            let mut metadata = fs.metadata(fd).unwrap();
+            metadata.size = len as FileSize * count as FileSize;
+            fs.set_metadata(fd, metadata).unwrap();
+            fs.close(fd).unwrap();
+
+            // store memory into a file
+            fs.store_memory_file(file_name).unwrap();
+
+            fs.unmount_memory_file(file_name).unwrap();
+
+            fs.mount_memory_file(file_name, Box::new(memory2.clone()))
+                .unwrap();
+
+            // init new memory into a file
+            fs.init_memory_file(file_name).unwrap();
+
+            let mut buf1 = [0u8; 10];
+            let mut buf2 = [0u8; 10];
+
+            for i in 0..count {
+                memory1.read(i as u64 * len as u64, &mut buf1);
+                memory2.read(i as u64 * len as u64, &mut buf2);
+
+                assert_eq!(buf1, buf2);
+            }
+        }
+    }
+
+    #[test]
+    fn writing_from_different_file_descriptors() {
+        for mut fs in test_fs_setups("f1/f2/text.txt") {
+            let root_fd = fs.root_fd();
+
+            let fd1 = fs
+                .open_or_create(
+                    root_fd,
+                    "f1/f2/text.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    40,
+                )
+                .unwrap();
+            let fd2 = fs
+                .open_or_create(
+                    root_fd,
+                    "f1//f2/text.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    44,
+                )
+                .unwrap();
+
+            write_text_fd(&mut fs, fd1, "abc", 1).unwrap();
+            write_text_fd(&mut fs, fd2, "123", 1).unwrap();
+            write_text_fd(&mut fs, fd1, "xyz", 1).unwrap();
+
+            let content = read_text_file(&mut fs, root_fd, "/f1/f2/text.txt", 0, 9);
+
+            assert_eq!("123xyz", content);
+        }
+    }
+
+    #[test]
+    fn write_into_empty_filename_fails() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let res = write_text_file(&mut fs, root_fd, "", "content123", 100);
+            assert!(res.is_err());
+        }
+    }
+
+    fn create_file_with_size(filename: &str, size: FileSize, fs: &mut FileSystem) -> Fd {
+        let fd = fs
+            .open_or_create(
+                fs.root_fd,
+                filename,
+                FdStat::default(),
+                OpenFlags::CREATE,
+                12,
+            )
+            .unwrap();
+        let mut meta = fs.metadata(fd).unwrap();
+        meta.size = size;
+
+        fs.set_metadata(fd, meta).unwrap();
+
+        fd
+    }
+
+    #[test]
+    fn set_metadata_checked_rejects_illegal_changes() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            let fd = fs
+                .create_file(root_fd, "file.txt", FdStat::default(), 0)
+                .unwrap();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+
+            let meta = fs.metadata(fd).unwrap();
+
+            // size and times can be changed
+            let mut changed = meta.clone();
+            changed.size = 15;
+            changed.times.modified = 123;
+            changed.times.accessed = 456;
+            fs.set_metadata_checked(fd, changed.clone()).unwrap();
+            assert_eq!(fs.metadata(fd).unwrap(), changed);
+
+            let mut changed = meta.clone();
+            changed.file_type = FileType::Directory;
+            assert_eq!(
+                fs.set_metadata_checked(fd, changed),
+                Err(Error::InvalidArgument)
+            );
+
+            let mut changed = meta.clone();
+            changed.node += 1;
+            assert_eq!(
+                fs.set_metadata_checked(fd, changed),
+                Err(Error::InvalidArgument)
+            );
+
+            let mut changed = meta.clone();
+            changed.first_dir_entry = Some(1);
+            assert_eq!(
+                fs.set_metadata_checked(fd, changed),
+                Err(Error::InvalidArgument)
+            );
+
+            let mut changed = meta.clone();
+            changed.chunk_size = Some(ChunkSize::CHUNK64K);
+            assert_eq!(
+                fs.set_metadata_checked(fd, changed),
+                Err(Error::InvalidArgument)
+            );
+
+            let mut changed = meta.clone();
+            changed.size = MAX_FILE_SIZE + 1;
+            assert_eq!(
+                fs.set_metadata_checked(fd, changed),
+                Err(Error::FileTooLarge)
+            );
+
+            let mut changed = fs.metadata(dir_fd).unwrap();
+            changed.file_type = FileType::RegularFile;
+            assert_eq!(
+                fs.set_metadata_checked(dir_fd, changed),
+                Err(Error::InvalidArgument)
+            );
+
+            // the rejected updates are not stored
+            assert_eq!(fs.metadata(fd).unwrap().size, 15);
+            assert_eq!(fs.metadata(fd).unwrap().file_type, FileType::RegularFile);
+        }
+    }
+
+    // test sparse files
+    #[test]
+    fn set_size_for_an_empty_file() {
+        let filename = "test.txt";
+
+        for mut fs in test_fs_setups(filename) {
+            let root_fd = fs.root_fd();
+            let fd = create_file_with_size(filename, 15, &mut fs);
+
+            let content = read_text_file(&mut fs, root_fd, filename, 0, 100);
+
+            assert_eq!(content, "\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+
+            write_text_at_offset(&mut fs, fd, "abc", 3, 3).unwrap();
+
+            let content = read_text_file(&mut fs, root_fd, filename, 1, 100);
+
+            assert_eq!(content, "\0\0abcabcabc\0\0\0");
+        }
+    }
+
+    #[test]
+    fn create_file_missing_chunk_in_the_middle() {
+        let filename = "test.txt";
+
+        for mut fs in test_fs_setups(filename) {
+            let chunk_size = fs.storage.chunk_size();
+
+            let root_fd = fs.root_fd();
+            let fd = create_file_with_size(filename, chunk_size as FileSize * 2 + 500, &mut fs);
+
+            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+
+            let vec = vec![0; chunk_size * 2 + 500];
+            let expected = String::from_utf8(vec).unwrap();
+
+            // expect all zeroes at first
+            assert_eq!(content, expected);
+
+            // write some text to the first chunk
+            write_text_at_offset(&mut fs, fd, "abc", 33, 3).unwrap();
+
+            // write some text to the 100th position of the third chunk
+            write_text_at_offset(&mut fs, fd, "abc", 33, chunk_size as FileSize * 2 + 100).unwrap();
+
+            // read what we have now
+            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+
+            let mut expected = vec![0u8; chunk_size * 2 + 500];
+
+            let pattern = b"abc".repeat(33);
+            expected[3..3 + 99].copy_from_slice(&pattern[..]);
+            expected[chunk_size * 2 + 100..chunk_size * 2 + 100 + 99].copy_from_slice(&pattern[..]);
+
+            let expected = String::from_utf8(expected).unwrap();
+
+            assert_eq!(content, expected);
+        }
+    }
+
+    #[test]
+    fn iterate_file_only_middle_chunk_is_present() {
+        let filename = "test.txt";
+
+        for mut fs in test_fs_setups(filename) {
+            let chunk_size = fs.storage.chunk_size();
+
+            let root_fd = fs.root_fd();
+            let fd = create_file_with_size(filename, chunk_size as FileSize * 2 + 500, &mut fs);
+
+            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+
+            let vec = vec![0; chunk_size * 2 + 500];
+
+            let expected = String::from_utf8(vec).unwrap();
+
+            assert_eq!(content, expected);
+
+            write_text_at_offset(&mut fs, fd, "abc", 33, chunk_size as FileSize + 100).unwrap();
+
+            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+
+            let mut expected = vec![0u8; chunk_size * 2 + 500];
+
+            let pattern = b"abc".repeat(33);
+            expected[chunk_size + 100..chunk_size + 100 + 99].copy_from_slice(&pattern[..]);
+
+            let expected = String::from_utf8(expected).unwrap();
+
+            assert_eq!(content, expected);
+        }
+    }
+
+    #[test]
+    fn filename_cached_on_open_or_create() {
+        let filename = "test.txt";
+
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 12)
+                .unwrap();
+
+            fs.close(fd).unwrap();
+
+            assert_eq!(fs.names_cache.get_nodes().len(), 1);
+        }
+    }
+
+    #[test]
+    fn deleted_file_cannot_be_found() {
+        let filename = "test.txt";
+
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 12)
+                .unwrap();
+            fs.close(fd).unwrap();
+
+            fs.remove_file(root_fd, filename).unwrap();
+
+            assert_eq!(fs.names_cache.get_nodes().len(), 0);
+
+            // check we don't increase cache when the file is opened but not created
+            let fd2 =
+                fs.open_or_create(root_fd, filename, FdStat::default(), OpenFlags::empty(), 12);
+
+            assert_eq!(fd2, Err(Error::NotFound));
+            assert_eq!(fs.names_cache.get_nodes().len(), 0);
+        }
+    }
+
+    #[test]
+    fn hash_file_does_not_depend_on_chunk_size() {
+        for algo in [HashAlgo::Sha256, HashAlgo::Xxh3] {
+            let mut hashes = vec![];
+
+            for chunk_size in [ChunkSize::CHUNK4K, ChunkSize::CHUNK64K] {
+                let mut fs = test_fs_custom_chunk_size(chunk_size);
+                let root_fd = fs.root_fd();
+
+                let fd = fs
+                    .create_file(root_fd, "test.txt", FdStat::default(), 0)
+                    .unwrap();
+
+                // leave a hole in the middle of the file
+                write_text_at_offset(&mut fs, fd, "start", 1, 10).unwrap();
+                write_text_at_offset(&mut fs, fd, "end", 1, 100_000).unwrap();
+
+                hashes.push(fs.hash_file(fd, algo).unwrap());
+            }
+
+            assert_eq!(hashes[0], hashes[1]);
+        }
+
+        // different content yields a different hash
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+        let fd1 = create_test_file_with_content(&mut fs, root_fd, "a.txt", vec!["abc".to_string()]);
+        let fd2 = create_test_file_with_content(&mut fs, root_fd, "b.txt", vec!["abd".to_string()]);
+
+        assert_ne!(
+            fs.hash_file(fd1, HashAlgo::Sha256).unwrap(),
+            fs.hash_file(fd2, HashAlgo::Sha256).unwrap()
+        );
+    }
+
+    #[test]
+    fn block_hashes_change_only_for_modified_block() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "test.txt", FdStat::default(), 0)
+                .unwrap();
+
+            let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+            fs.write(fd, &data).unwrap();
+
+            let before = fs.block_hashes(fd, 16384).unwrap();
+            assert_eq!(before.len(), 7);
+
+            write_text_at_offset(&mut fs, fd, "changed", 1, 40_000).unwrap();
+
+            let after = fs.block_hashes(fd, 16384).unwrap();
+            assert_eq!(after.len(), 7);
+
+            let changed: Vec<usize> = (0..before.len())
+                .filter(|i| before[*i] != after[*i])
+                .collect();
+            assert_eq!(changed, vec![2]);
+
+            assert_eq!(fs.block_hashes(fd, 0), Err(Error::InvalidArgument));
+        }
+    }
+
+    #[test]
+    fn trim_front_shifts_remaining_content() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
+
+            for filename in ["test.txt", "virtual.txt"] {
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
+
+                let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+                fs.write(fd, &content).unwrap();
+
+                // non-aligned trim
+                fs.trim_front(fd, 1234).unwrap();
+                assert_eq!(
+                    fs.metadata(fd).unwrap().size,
+                    (content.len() - 1234) as FileSize
+                );
+
+                let mut buf = vec![0u8; content.len() - 1234];
+                fs.seek(fd, 0, Whence::SET).unwrap();
+                fs.read(fd, &mut buf).unwrap();
+                assert_eq!(buf, content[1234..]);
+
+                // growing the file again exposes zeroes instead of the old content
+                let size = fs.metadata(fd).unwrap().size;
+                write_text_at_offset(&mut fs, fd, "x", 1, size + 5000).unwrap();
+
+                let mut buf = vec![1u8; 5000];
+                fs.seek(fd, size as i64, Whence::SET).unwrap();
+                fs.read(fd, &mut buf).unwrap();
+                assert_eq!(buf, vec![0u8; 5000]);
+
+                // trimming more than the file size leaves an empty file
+                fs.trim_front(fd, 1_000_000).unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().size, 0);
+
+                fs.close(fd).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn read_chunk_into_reads_single_chunk() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
+            let chunk_size = fs.storage.chunk_size();
+
+            for filename in ["test.txt", "virtual.txt"] {
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
+
+                let content: Vec<u8> = (0..chunk_size * 2 + 100).map(|i| (i % 251) as u8).collect();
+                fs.write(fd, &content).unwrap();
+
+                let mut buf = vec![0u8; chunk_size * 3];
+
+                let read = fs.read_chunk_into(fd, 1, &mut buf).unwrap();
+                assert_eq!(read as usize, chunk_size);
+                assert_eq!(buf[..chunk_size], content[chunk_size..chunk_size * 2]);
+
+                // the last chunk is only partially filled
+                let read = fs.read_chunk_into(fd, 2, &mut buf).unwrap();
+                assert_eq!(read, 100);
+                assert_eq!(buf[..100], content[chunk_size * 2..]);
+
+                // nothing to read past the end of file
+                assert_eq!(fs.read_chunk_into(fd, 3, &mut buf).unwrap(), 0);
+
+                // the cursor is not moved
+                assert_eq!(fs.tell(fd).unwrap(), content.len() as FileSize);
+
+                fs.close(fd).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn read_chunk_into_reads_one_chunk_per_call() {
+        let mut fs = test_fs();
+        let chunk_size = fs.storage.chunk_size();
+        let chunk_count = 100;
+
+        let fd = create_file_with_size("test.txt", 0, &mut fs);
+        fs.write(fd, &vec![42u8; chunk_size * chunk_count]).unwrap();
+
+        let mut buf = vec![0u8; chunk_size];
+
+        let before = fs.operation_counters();
+        for index in 0..chunk_count {
+            let read = fs.read_chunk_into(fd, index as u32, &mut buf).unwrap();
+            assert_eq!(read, chunk_size as FileSize);
+        }
+        let after = fs.operation_counters();
+
+        // a single chunk read per call, without going through the generic read path
+        assert_eq!(after.chunk_reads - before.chunk_reads, chunk_count as u64);
+        assert!(buf.iter().all(|b| *b == 42));
+    }
+
+    #[test]
+    fn mkdir_exclusive_fails_on_existing_entry() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        fs.mkdir_exclusive(root_fd, "dir", FdStat::default(), 0)
+            .unwrap();
+
+        let meta = fs.open_metadata(root_fd, "dir").unwrap();
+        assert_eq!(meta.file_type, FileType::Directory);
+
+        assert_eq!(
+            fs.mkdir_exclusive(root_fd, "dir", FdStat::default(), 0),
+            Err(Error::FileAlreadyExists)
+        );
+
+        let fd = fs
+            .create_file(root_fd, "file.txt", FdStat::default(), 0)
+            .unwrap();
+        fs.close(fd).unwrap();
+
+        assert_eq!(
+            fs.mkdir_exclusive(root_fd, "file.txt", FdStat::default(), 0),
+            Err(Error::FileAlreadyExists)
+        );
+
+        // nested directories in an existing parent are fine
+        fs.mkdir_exclusive(root_fd, "dir/sub", FdStat::default(), 0)
+            .unwrap();
+
+        assert_eq!(list_files(&mut fs, "dir"), vec!["sub".to_string()]);
+
+        // missing parents are not created
+        assert_eq!(
+            fs.mkdir_exclusive(root_fd, "missing/sub", FdStat::default(), 0),
+            Err(Error::NotFound)
+        );
+        assert_eq!(fs.open_metadata(root_fd, "missing"), Err(Error::NotFound));
+        assert_eq!(
+            fs.mkdir_exclusive(root_fd, "file.txt/sub", FdStat::default(), 0),
+            Err(Error::InvalidFileType)
+        );
+    }
+
+    #[test]
+    fn max_open_fds_limit() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            // the root folder descriptor is already open
+            fs.set_max_open_fds(Some(4));
+
+            let a = fs
+                .create_file(root_fd, "a.txt", FdStat::default(), 0)
+                .unwrap();
+            let b = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            let c = fs
+                .open_or_create(root_fd, "a.txt", FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+
+            assert_eq!(
+                fs.open_or_create(root_fd, "a.txt", FdStat::default(), OpenFlags::empty(), 0),
+                Err(Error::TooManyOpenFiles)
+            );
+            assert_eq!(
+                fs.create_file(root_fd, "b.txt", FdStat::default(), 0),
+                Err(Error::TooManyOpenFiles)
+            );
+            assert_eq!(
+                fs.create_dir(root_fd, "dir2", FdStat::default(), 0),
+                Err(Error::TooManyOpenFiles)
+            );
+
+            // nothing was created by the failed calls
+            assert_eq!(fs.dir_entry_count(root_fd).unwrap(), 2);
+
+            fs.close(b).unwrap();
+            let d = fs
+                .create_file(root_fd, "b.txt", FdStat::default(), 0)
+                .unwrap();
+
+            fs.set_max_open_fds(None);
+            fs.create_file(root_fd, "c.txt", FdStat::default(), 0)
+                .unwrap();
+
+            for fd in [a, c, d] {
+                fs.close(fd).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn open_with_hint_sequential_write() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
+
+            for filename in ["test.txt", "virtual.txt"] {
+                let size = 1_000_000;
+                let fd = fs
+                    .open_with_hint(
+                        root_fd,
+                        filename,
+                        FdStat::default(),
+                        OpenFlags::CREATE,
+                        0,
+                        size,
+                    )
+                    .unwrap();
+
+                // the hint does not change the file size
+                assert_eq!(fs.metadata(fd).unwrap().size, 0);
+
+                let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+                for part in content.chunks(30_000) {
+                    fs.write(fd, part).unwrap();
+                }
+
+                let mut buf = vec![0u8; size as usize];
+                fs.seek(fd, 0, Whence::SET).unwrap();
+                fs.read(fd, &mut buf).unwrap();
+                assert_eq!(buf, content);
+
+                fs.close(fd).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn open_with_hint_reduces_allocations() {
+        let size = 10 * 1024 * 1024;
+        let buf = vec![7u8; 64 * 1024];
+
+        let mut allocations = Vec::new();
+
+        for hint in [None, Some(size)] {
+            let mut fs = test_fs();
+            let root_fd = fs.root_fd();
+
+            let fd = match hint {
+                Some(hint) => fs.open_with_hint(
+                    root_fd,
+                    "file.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0,
+                    hint,
+                ),
+                None => {
+                    fs.open_or_create(root_fd, "file.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                }
+            }
+            .unwrap();
+
+            let mut written = 0;
+            while written < size {
+                written += fs.write(fd, &buf).unwrap();
+            }
+
+            allocations.push(fs.operation_counters().allocations);
+        }
+
+        // one allocation per chunk without the hint, a single run with it
+        assert_eq!(allocations[0], size / ChunkSize::CHUNK16K as u64);
+        assert_eq!(allocations[1], 1);
+    }
+
+    #[test]
+    fn open_with_hint_beyond_limits_fails() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        // the root folder and one more descriptor
+        fs.set_max_open_fds(Some(2));
+
+        assert_eq!(
+            fs.open_with_hint(
+                root_fd,
+                "huge.txt",
+                FdStat::default(),
+                OpenFlags::CREATE,
+                0,
+                MAX_FILE_SIZE + 1
+            ),
+            Err(Error::FileTooLarge)
+        );
+        assert_eq!(fs.dir_entry_count(root_fd).unwrap(), 0);
+
+        // more than the memory manager can grow the memory to
+        assert_eq!(
+            fs.open_with_hint(
+                root_fd,
+                "big.txt",
+                FdStat::default(),
+                OpenFlags::CREATE,
+                0,
+                1 << 40
+            ),
+            Err(Error::OutOfMemory)
+        );
+
+        // the descriptor was closed, the file is still usable
+        let fd = fs
+            .open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().size, 0);
+
+        fs.write(fd, b"data").unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(read_text_file(&mut fs, root_fd, "big.txt", 0, 10), "data");
+    }
+
+    #[test]
+    fn advise_keeps_file_contents() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
+
+            for filename in ["test.txt", "virtual.txt"] {
+                write_text_file(&mut fs, root_fd, filename, "0123456789", 10_000).unwrap();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::empty(), 0)
+                    .unwrap();
+
+                for advice in [Advice::WillNeed, Advice::DontNeed, Advice::Sequential] {
+                    fs.advise(fd, 0, 100_000, advice).unwrap();
+                    fs.advise(fd, 50_000, FileSize::MAX, advice).unwrap();
+
+                    let mut buf = [0u8; 10];
+                    fs.pread(fd, &mut buf, Whence::SET, 99_990).unwrap();
+                    assert_eq!(&buf, b"0123456789");
+                }
+
+                fs.close(fd).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn files_with_different_chunk_sizes() {
+        let mut fs = test_fs_custom_chunk_size(ChunkSize::CHUNK4K);
+        let root_fd = fs.root_fd();
+
+        let large_fd = fs
+            .open_or_create(
+                root_fd,
+                "large.bin",
+                FdStat::default(),
+                OpenFlags::CREATE,
+                0,
+            )
+            .unwrap();
+        let small_fd = fs
+            .open_or_create(
+                root_fd,
+                "small.log",
+                FdStat::default(),
+                OpenFlags::CREATE,
+                0,
+            )
+            .unwrap();
+
+        fs.set_file_chunk_size(large_fd, ChunkSize::CHUNK64K)
+            .unwrap();
+
+        // the writes of both files interleave in the chunk memory
+        for i in 0..40u8 {
+            fs.write(large_fd, &[i; 10_000]).unwrap();
+            fs.write(small_fd, &[i; 1_000]).unwrap();
+        }
+
+        // the chunk size cannot change once the file is written
+        assert_eq!(
+            fs.set_file_chunk_size(large_fd, ChunkSize::CHUNK16K),
+            Err(Error::IncompatibleChunkSize)
+        );
+
+        let mut buf = vec![0u8; 10_000];
+        for i in 0..40u8 {
+            fs.pread(large_fd, &mut buf, Whence::SET, i as i64 * 10_000)
+                .unwrap();
+            assert!(buf.iter().all(|b| *b == i));
+
+            fs.pread(small_fd, &mut buf[..1_000], Whence::SET, i as i64 * 1_000)
+                .unwrap();
+            assert!(buf[..1_000].iter().all(|b| *b == i));
+        }
+
+        fs.close(large_fd).unwrap();
+        fs.close(small_fd).unwrap();
+    }
+
+    #[test]
+    fn file_chunk_size_smaller_than_storage_chunk_size_fails() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        assert_eq!(
+            fs.set_file_chunk_size(fd, ChunkSize::CHUNK4K),
+            Err(Error::IncompatibleChunkSize)
+        );
+
+        fs.set_file_chunk_size(fd, ChunkSize::CHUNK32K).unwrap();
+        assert_eq!(
+            fs.metadata(fd).unwrap().chunk_size,
+            Some(ChunkSize::CHUNK32K)
+        );
+    }
+
+    #[test]
+    fn chunk_range_of_sparse_file() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "sparse.dat",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0,
+                )
+                .unwrap();
+
+            assert_eq!(fs.chunk_range(fd).unwrap(), None);
+
+            let chunk_size = match fs.effective_chunk_type(fd).unwrap() {
+                ChunkType::V1 => FILE_CHUNK_SIZE_V1,
+                ChunkType::V2 => fs.storage.chunk_size(),
+            } as FileSize;
+
+            fs.write(fd, b"start").unwrap();
+            fs.seek(fd, (chunk_size * 20 + 7) as i64, Whence::SET)
+                .unwrap();
+            fs.write(fd, b"end").unwrap();
+
+            assert_eq!(fs.chunk_range(fd).unwrap(), Some((0, 20)));
+
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_transaction_removes_created_files() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "existing.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+
+            let result: Result<(), Error> = fs.transaction(|fs| {
+                let root_fd = fs.root_fd();
+                fs.create_dir(root_fd, "dir", FdStat::default(), 0)?;
+
+                for i in 0..5 {
+                    let fd =
+                        fs.create_file(root_fd, &format!("dir/file{i}.txt"), FdStat::default(), 0)?;
+                    fs.write(fd, b"content")?;
+                }
+
+                let fd = fs.create_file(root_fd, "a/b/c.txt", FdStat::default(), 0)?;
+                fs.close(fd)?;
+
+                // fails, the file already exists
+                fs.create_file(root_fd, "existing.txt", FdStat::default(), 0)?;
+
+                Ok(())
+            });
+
+            assert_eq!(result, Err(Error::FileAlreadyExists));
+            assert_eq!(list_files(&mut fs, ""), vec!["existing.txt"]);
+
+            // a successful transaction keeps its files
+            fs.transaction(|fs| {
+                let root_fd = fs.root_fd();
+                let fd = fs.create_file(root_fd, "dir/kept.txt", FdStat::default(), 0)?;
+                fs.close(fd)
+            })
+            .unwrap();
+
+            assert_eq!(list_files(&mut fs, "dir"), vec!["kept.txt"]);
+        }
+    }
+
+    #[test]
+    fn create_flag_on_existing_entries() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "file.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.write(fd, b"content").unwrap();
+            fs.close(fd).unwrap();
+            fs.mkdir_exclusive(root_fd, "dir", FdStat::default(), 0)
+                .unwrap();
+
+            // CREATE on an existing file opens the file
+            let fd = fs
+                .open_or_create(root_fd, "file.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().file_type, FileType::RegularFile);
+            assert_eq!(fs.metadata(fd).unwrap().size, 7);
+            fs.close(fd).unwrap();
+
+            // CREATE | DIRECTORY on an existing file is an error
+            assert_eq!(
+                fs.open_or_create(
+                    root_fd,
+                    "file.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE | OpenFlags::DIRECTORY,
+                    0
+                ),
+                Err(Error::InvalidFileType)
+            );
+
+            // CREATE on an existing folder opens the folder, with or without DIRECTORY
+            for flags in [OpenFlags::CREATE, OpenFlags::CREATE | OpenFlags::DIRECTORY] {
+                let fd = fs
+                    .open_or_create(root_fd, "dir", FdStat::default(), flags, 0)
+                    .unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().file_type, FileType::Directory);
+                fs.close(fd).unwrap();
+            }
+
+            // nothing new was created
+            assert_eq!(list_files(&mut fs, ""), vec!["file.txt", "dir"]);
+        }
+    }
+
+    #[test]
+    fn dump_small_tree() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            fs.create_dirs(root_fd, &["a/b", "empty"], 0).unwrap();
+
+            let files = [
+                ("a/b/deep.txt", "deep content".repeat(1000)),
+                ("a/one.txt", "one".to_string()),
+                ("empty.txt", String::new()),
+                ("top.txt", "top level".to_string()),
+            ];
+
+            for (path, content) in files.iter() {
+                let fd = fs.create_file(root_fd, path, FdStat::default(), 0).unwrap();
+                fs.write(fd, content.as_bytes()).unwrap();
+                fs.close(fd).unwrap();
+            }
+
+            let dump = fs.dump(1_000_000).unwrap();
+            let expected: Vec<(String, Vec<u8>)> = files
+                .iter()
+                .map(|(path, content)| (path.to_string(), content.as_bytes().to_vec()))
+                .collect();
+
+            assert_eq!(dump, expected);
+
+            // the limit is checked against the total size of all files
+            assert_eq!(fs.dump(12_011), Err(Error::FileTooLarge));
+            assert_eq!(fs.dump(12_012).unwrap().len(), 4);
+        }
+    }
+
+    #[test]
+    fn ancestors_of_nested_file() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "a/b/c/file.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+
+            let mut expected = Vec::new();
+            for (path, name) in [("a", "a"), ("a/b", "b"), ("a/b/c", "c")] {
+                let fd = fs
+                    .open_or_create(root_fd, path, FdStat::default(), OpenFlags::DIRECTORY, 0)
+                    .unwrap();
+                expected.push((fs.metadata(fd).unwrap().node, name.to_string()));
+                fs.close(fd).unwrap();
+            }
+
+            assert_eq!(fs.ancestors(root_fd, "a/b/c/file.txt").unwrap(), expected);
+            assert_eq!(fs.ancestors(root_fd, "a/b/c").unwrap(), expected[..2]);
+            assert_eq!(fs.ancestors(root_fd, "a").unwrap(), vec![]);
+
+            // relative to a subfolder
+            let a_fd = fs
+                .open_or_create(root_fd, "a", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            assert_eq!(fs.ancestors(a_fd, "b/c/file.txt").unwrap(), expected[1..]);
+
+            assert_eq!(
+                fs.ancestors(root_fd, "a/b/missing/file.txt"),
+                Err(Error::NotFound)
+            );
+            assert_eq!(
+                fs.ancestors(root_fd, "a/b/c/missing.txt"),
+                Err(Error::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn open_with_metadata_matches_metadata() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            let (fd, metadata) = fs
+                .open_with_metadata(root_fd, "file.txt", FdStat::default(), OpenFlags::CREATE, 5)
+                .unwrap();
+            assert_eq!(metadata, fs.metadata(fd).unwrap());
+            assert_eq!(metadata.size, 0);
+            assert_eq!(metadata.times.created, 5);
+
+            fs.write(fd, b"some content").unwrap();
+            fs.close(fd).unwrap();
+
+            let (fd, metadata) = fs
+                .open_with_metadata(
+                    root_fd,
+                    "file.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            assert_eq!(metadata, fs.metadata(fd).unwrap());
+            assert_eq!(metadata.size, 12);
+            fs.close(fd).unwrap();
+
+            assert_eq!(
+                fs.open_with_metadata(
+                    root_fd,
+                    "missing.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0
+                ),
+                Err(Error::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn open_or_create_creates_missing_parent_folders() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            // without CREATE nothing is created
+            assert_eq!(
+                fs.open_or_create(
+                    root_fd,
+                    "missing/dir/file.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0
+                ),
+                Err(Error::NotFound)
+            );
+            assert_eq!(list_files(&mut fs, ""), Vec::<String>::new());
+
+            // CREATE adds the intermediate folders
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "missing/dir/file.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0,
+                )
+                .unwrap();
+            fs.close(fd).unwrap();
+
+            assert_eq!(list_files(&mut fs, ""), vec!["missing"]);
+            assert_eq!(list_files(&mut fs, "missing"), vec!["dir"]);
+            assert_eq!(list_files(&mut fs, "missing/dir"), vec!["file.txt"]);
+
+            // the same for a folder leaf
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "other/nested",
+                    FdStat::default(),
+                    OpenFlags::CREATE | OpenFlags::DIRECTORY,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().file_type, FileType::Directory);
+            fs.close(fd).unwrap();
+            assert_eq!(list_files(&mut fs, "other"), vec!["nested"]);
+
+            // an existing file cannot be a parent folder
+            assert_eq!(
+                fs.open_or_create(
+                    root_fd,
+                    "missing/dir/file.txt/child.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0
+                ),
+                Err(Error::NotFound)
+            );
+            assert_eq!(
+                fs.open_or_create(
+                    root_fd,
+                    "missing/dir/file.txt/child.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0
+                ),
+                Err(Error::InvalidFileType)
+            );
+        }
+    }
+
+    #[test]
+    fn path_of_nested_fd() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            fs.create_dirs(root_fd, &["x/y", "a/b2"], 0).unwrap();
+
+            let fd = fs
+                .create_file(root_fd, "a/b/c.txt", FdStat::default(), 0)
+                .unwrap();
+            assert_eq!(fs.path_of_fd(fd).unwrap(), "/a/b/c.txt");
+
+            let dir_fd = fs
+                .open_or_create(root_fd, "a/b", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            assert_eq!(fs.path_of_fd(dir_fd).unwrap(), "/a/b");
+            assert_eq!(fs.path_of_fd(root_fd).unwrap(), "/");
+
+            let other_fd = fs
+                .create_file(dir_fd, "d.txt", FdStat::default(), 0)
+                .unwrap();
+            assert_eq!(fs.path_of_fd(other_fd).unwrap(), "/a/b/d.txt");
+
+            // the path follows a rename
+            fs.close(other_fd).unwrap();
+            let other_fd = fs.rename(dir_fd, "d.txt", root_fd, "x/y/e.txt").unwrap();
+            assert_eq!(fs.path_of_fd(other_fd).unwrap(), "/x/y/e.txt");
+
+            fs.close(other_fd).unwrap();
+            fs.close(dir_fd).unwrap();
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn total_nodes_tracks_live_nodes() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            assert_eq!(fs.total_nodes(), 1);
+
+            fs.create_dirs(root_fd, &["dir"], 0).unwrap();
+            for i in 0..10 {
+                let fd = fs
+                    .create_file(root_fd, &format!("dir/file{i}.txt"), FdStat::default(), 0)
+                    .unwrap();
+                fs.write(fd, &[1u8; 200_000]).unwrap();
+                fs.close(fd).unwrap();
+            }
+            assert_eq!(fs.total_nodes(), 12);
+
+            for i in 0..4 {
+                fs.remove_file(root_fd, &format!("dir/file{i}.txt"))
+                    .unwrap();
+            }
+            assert_eq!(fs.total_nodes(), 8);
+
+            // a file unlinked in steps is still counted until its chunks are released
+            assert_eq!(
+                fs.remove_file_chunked(root_fd, "dir/file4.txt", 1),
+                Ok(RemoveProgress::Pending)
+            );
+            assert_eq!(list_files(&mut fs, "dir").len(), 5);
+            assert_eq!(fs.total_nodes(), 8);
+
+            while fs.remove_file_chunked(root_fd, "dir/file4.txt", 1) == Ok(RemoveProgress::Pending)
+            {
+            }
+            assert_eq!(fs.total_nodes(), 7);
+        }
+    }
+
+    #[test]
+    fn clear_caches_keeps_contents() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+
+            let fd = fs
+                .create_file(root_fd, "a/b/file.bin", FdStat::default(), 0)
+                .unwrap();
+            fs.write(fd, &data).unwrap();
+
+            let mut buf = vec![0u8; data.len()];
+            fs.pread(fd, &mut buf, Whence::SET, 0).unwrap();
+            assert_eq!(buf, data);
+
+            fs.clear_caches();
+
+            let mut buf = vec![0u8; data.len()];
+            fs.pread(fd, &mut buf, Whence::SET, 0).unwrap();
+            assert_eq!(buf, data);
+            assert_eq!(fs.metadata(fd).unwrap().size, data.len() as FileSize);
+
+            // writes after clearing the caches update the existing chunks
+            fs.seek(fd, 50_000, Whence::SET).unwrap();
+            fs.write(fd, b"updated").unwrap();
+            fs.close(fd).unwrap();
+            fs.clear_caches();
+
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "a/b/file.bin",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            let mut buf = vec![0u8; data.len()];
+            fs.pread(fd, &mut buf, Whence::SET, 0).unwrap();
+            assert_eq!(&buf[50_000..50_007], b"updated");
+            assert_eq!(&buf[..50_000], &data[..50_000]);
+            assert_eq!(&buf[50_007..], &data[50_007..]);
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_last_chunks_returns_file_tail() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "log.txt", FdStat::default(), 0)
+                .unwrap();
+
+            let chunk_size = match fs.effective_chunk_type(fd).unwrap() {
+                ChunkType::V1 => FILE_CHUNK_SIZE_V1,
+                ChunkType::V2 => fs.storage.chunk_size(),
+            };
+
+            let data: Vec<u8> = (0..chunk_size * 5 + 123).map(|i| (i % 253) as u8).collect();
+            fs.write(fd, &data).unwrap();
+
+            for n in [1, 2, 5] {
+                let tail = fs.read_last_chunks(fd, n).unwrap();
+                assert!(tail == data[data.len() - n * chunk_size..]);
+            }
+
+            assert!(fs.read_last_chunks(fd, 6).unwrap() == data);
+            assert!(fs.read_last_chunks(fd, 100).unwrap() == data);
+            assert!(fs.read_last_chunks(fd, 0).unwrap().is_empty());
+
+            // the cursor is kept
+            assert_eq!(fs.tell(fd).unwrap(), data.len() as FileSize);
+
+            // a sparse tail reads as zeroes
+            let mut metadata = fs.metadata(fd).unwrap();
+            metadata.size += chunk_size as FileSize * 3;
+            fs.set_metadata(fd, metadata).unwrap();
+
+            let tail = fs.read_last_chunks(fd, 4).unwrap();
+            assert_eq!(tail.len(), chunk_size * 4);
+            assert!(tail[..chunk_size] == data[data.len() - chunk_size..]);
+            assert!(tail[chunk_size..].iter().all(|b| *b == 0));
+
+            fs.close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn append_only_fd_rejects_backward_seeks() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            let fd = fs
+                .open_append_only(
+                    root_fd,
+                    "audit.log",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0,
+                )
+                .unwrap();
+
+            fs.write(fd, b"first;").unwrap();
+            assert_eq!(fs.seek(fd, 0, Whence::SET), Err(Error::PermissionDenied));
+            assert_eq!(fs.seek(fd, -1, Whence::END), Err(Error::PermissionDenied));
+            assert_eq!(
+                fs.write_vec_with_offset(
+                    fd,
+                    &[SrcBuf {
+                        buf: b"x".as_ptr(),
+                        len: 1,
+                    }],
+                    0
+                ),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(fs.seek(fd, 0, Whence::END), Ok(6));
+
+            // another descriptor moves the file end, the append-only writes follow it
+            let other_fd = fs
+                .open_or_create(
+                    root_fd,
+                    "audit.log",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            fs.seek(other_fd, 0, Whence::END).unwrap();
+            fs.write(other_fd, b"other;").unwrap();
+            fs.close(other_fd).unwrap();
+
+            fs.write(fd, b"second;").unwrap();
+            assert_eq!(fs.tell(fd), Ok(19));
+
+            assert_eq!(fs.read_to_string(fd, None).unwrap(), "first;other;second;");
+        }
+    }
+
+    #[test]
+    fn same_file_detects_hard_links() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            let fd = create_test_file(&mut fs, root_fd, "a.txt");
+            fs.close(fd).unwrap();
+            let fd = create_test_file(&mut fs, root_fd, "b.txt");
+            fs.close(fd).unwrap();
+
+            let link_fd = fs
+                .create_hard_link(root_fd, "a.txt", dir_fd, "link.txt")
+                .unwrap();
+            fs.close(link_fd).unwrap();
+
+            assert_eq!(fs.same_file(root_fd, "a.txt", dir_fd, "link.txt"), Ok(true));
+            assert_eq!(
+                fs.same_file(root_fd, "a.txt", root_fd, "dir/link.txt"),
+                Ok(true)
+            );
+            assert_eq!(fs.same_file(root_fd, "a.txt", root_fd, "b.txt"), Ok(false));
+            assert_eq!(
+                fs.same_file(root_fd, "a.txt", root_fd, "missing.txt"),
+                Err(Error::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn defragment_file_written_in_reverse_order() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
+        let chunk_size = fs.storage.chunk_size() as FileSize;
+
+        let fd = fs
+            .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+
+        let chunks = 20u8;
+        for i in (0..chunks).rev() {
+            fs.seek(fd, (i as FileSize * chunk_size) as i64, Whence::SET)
+                .unwrap();
+            fs.write(fd, &vec![i; chunk_size as usize]).unwrap();
+        }
+
+        let before = fs.file_fragmentation(fd).unwrap();
+        assert!(before > 0.9, "fragmentation {before}");
+
+        fs.defragment_file(fd).unwrap();
+
+        assert_eq!(fs.file_fragmentation(fd).unwrap(), 0.0);
+
+        let mut buf = vec![0u8; chunk_size as usize];
+        for i in 0..chunks {
+            fs.pread(
+                fd,
+                &mut buf,
+                Whence::SET,
+                (i as FileSize * chunk_size) as i64,
+            )
+            .unwrap();
+            assert!(buf.iter().all(|b| *b == i));
+        }
+
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn remove_file_chunked_in_bounded_steps() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            write_text_file(&mut fs, root_fd, "small.txt", "abc", 1).unwrap();
+
+            let fd = fs
+                .open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            write_text_fd(&mut fs, fd, "0123456789", 30_000).unwrap();
+            let node = fs.metadata(fd).unwrap().node;
+            fs.close(fd).unwrap();
+
+            let mut calls = 0;
+            loop {
+                calls += 1;
+                let progress = fs.remove_file_chunked(root_fd, "big.txt", 2).unwrap();
+
+                // the file is hidden from the first call on
+                assert_eq!(
+                    fs.open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::empty(), 0),
+                    Err(Error::NotFound)
+                );
+                let names: Vec<_> = fs
+                    .read_dir_with_open_state(root_fd)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(name, ..)| name)
+                    .collect();
+                assert_eq!(names, vec!["small.txt".to_string()]);
+
+                if progress == RemoveProgress::Done {
+                    break;
+                }
+            }
+
+            assert!(calls > 2);
+            assert_eq!(fs.storage.get_metadata(node), Err(Error::NotFound));
+            assert_eq!(
+                fs.remove_file_chunked(root_fd, "big.txt", 5),
+                Err(Error::NotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn remove_file_chunked_rejects_zero_chunks_per_call() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            let fd = fs
+                .open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
+            write_text_fd(&mut fs, fd, "0123456789", 1000).unwrap();
+            fs.close(fd).unwrap();
+
+            assert_eq!(
+                fs.remove_file_chunked(root_fd, "big.txt", 0),
+                Err(Error::InvalidArgument)
+            );
+
+            // the file is not unlinked
+            let fd = fs
+                .open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().size, 10_000);
+            fs.close(fd).unwrap();
+            assert_eq!(
+                fs.remove_file_chunked(root_fd, "big.txt", 100),
+                Ok(RemoveProgress::Done)
+            );
+        }
+    }
+
+    #[test]
+    fn remove_file_chunked_resumes_after_upgrade() {
+        let memory = new_vector_memory();
+
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(root_fd, "big.txt", FdStat::default(), OpenFlags::CREATE, 0)
+            .unwrap();
+        write_text_fd(&mut fs, fd, "0123456789", 30_000).unwrap();
+        let node = fs.metadata(fd).unwrap().node;
+        fs.close(fd).unwrap();
+
+        assert_eq!(
+            fs.remove_file_chunked(root_fd, "big.txt", 2),
+            Ok(RemoveProgress::Pending)
+        );
+        drop(fs);
+
+        // the unlinked file is still known after the upgrade
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        let root_fd = fs.root_fd();
+
+        while fs.remove_file_chunked(root_fd, "big.txt", 2).unwrap() == RemoveProgress::Pending {}
+
+        assert_eq!(fs.storage.get_metadata(node), Err(Error::NotFound));
+        assert!(fs.storage.pending_removals().is_empty());
+    }
+
+    #[test]
+    fn format_leaves_only_root() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let first_node = fs.next_node_id();
+
+            write_text_file(&mut fs, root_fd, "a.txt", "abc", 1000).unwrap();
+            write_text_file(&mut fs, root_fd, "dir/sub/b.txt", "def", 1000).unwrap();
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "dir/c.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    0,
+                )
+                .unwrap();
+
+            fs.format().unwrap();
+
+            assert!(fs.read_dir(root_fd, DirOrder::Name).unwrap().is_empty());
+            assert_eq!(fs.dir_entry_count(root_fd).unwrap(), 0);
+            assert_eq!(fs.next_node_id(), first_node);
+            assert_eq!(fs.storage.iter_nodes().count(), 1);
+
+            assert_eq!(fs.write(fd, b"x"), Err(Error::NotFound));
+            assert_eq!(fs.open_metadata(root_fd, "a.txt"), Err(Error::NotFound));
+
+            // the file system is usable again
+            write_text_file(&mut fs, root_fd, "dir/a.txt", "xyz", 10).unwrap();
+            assert_eq!(
+                read_text_file(&mut fs, root_fd, "dir/a.txt", 0, 100),
+                "xyz".repeat(10)
+            );
+            assert_eq!(fs.next_node_id(), first_node + 2);
+        }
+    }
+
+    #[test]
+    fn format_reuses_chunk_memory() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
+
+        write_text_file(&mut fs, root_fd, "a.txt", "0123456789", 100_000).unwrap();
+        let pages = memory.size();
+
+        fs.format().unwrap();
+
+        write_text_file(&mut fs, root_fd, "b.txt", "0123456789", 100_000).unwrap();
+        assert_eq!(memory.size(), pages);
+
+        // a mounted memory is not removed
+        fs.mount_memory_file("virtual.txt", Box::new(new_vector_memory()))
+            .unwrap();
+        assert_eq!(fs.format(), Err(Error::CannotRemoveMountedMemoryFile));
+        assert!(fs.open_metadata(root_fd, "b.txt").is_ok());
+
+        fs.unmount_memory_file("virtual.txt").unwrap();
+        fs.format().unwrap();
+        assert!(fs.read_dir(root_fd, DirOrder::Name).unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_sync_persists_over_reopen() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
+
+        let fd = fs
+            .create_file(root_fd, "plain.txt", FdStat::default(), 0)
+            .unwrap();
+        let sync_fd = fs
+            .create_file(root_fd, "synced.txt", FdStat::default(), 0)
+            .unwrap();
+
+        for chunk in [&b"small"[..], &[7u8; 5000][..], b"tail"] {
+            assert_eq!(
+                fs.write_sync(sync_fd, chunk).unwrap(),
+                fs.write(fd, chunk).unwrap()
+            );
+        }
+
+        // reopen the file system on the same memory without closing the file
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(
+                root_fd,
+                "synced.txt",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        let mut buf = vec![0u8; 5009];
+        assert_eq!(fs.read(fd, &mut buf).unwrap(), 5009);
+        assert_eq!(&buf[..5], b"small");
+        assert!(buf[5..5005].iter().all(|b| *b == 7));
+        assert_eq!(&buf[5005..], b"tail");
+    }
+
+    #[test]
+    fn read_only_mode_rejects_modifications() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+
+            write_text_file(&mut fs, root_fd, "dir/a.txt", "abc", 10).unwrap();
+            let dir_fd = fs
+                .open_or_create(root_fd, "dir", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            let fd = fs
+                .open_or_create(dir_fd, "a.txt", FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+
+            fs.set_read_only(true);
+            assert!(fs.is_read_only());
+
+            assert_eq!(fs.write(fd, b"x"), Err(Error::PermissionDenied));
+            assert_eq!(fs.write_u32_le(fd, 0, 5), Err(Error::PermissionDenied));
+            assert_eq!(fs.trim_front(fd, 1), Err(Error::PermissionDenied));
+            assert_eq!(
+                fs.create_file(dir_fd, "b.txt", FdStat::default(), 0),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.open_or_create(dir_fd, "b.txt", FdStat::default(), OpenFlags::CREATE, 0),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.open_or_create(dir_fd, "a.txt", FdStat::default(), OpenFlags::TRUNCATE, 0),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.create_dir(dir_fd, "sub", FdStat::default(), 0),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.mkdir_exclusive(dir_fd, "sub", FdStat::default(), 0),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.remove_file(dir_fd, "a.txt"),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(fs.remove_dir(root_fd, "dir"), Err(Error::PermissionDenied));
+            assert_eq!(
+                fs.rename(dir_fd, "a.txt", dir_fd, "c.txt"),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(
+                fs.create_hard_link(dir_fd, "a.txt", dir_fd, "c.txt"),
+                Err(Error::PermissionDenied)
+            );
+            let meta = fs.metadata(fd).unwrap();
+            assert_eq!(fs.set_metadata(fd, meta), Err(Error::PermissionDenied));
+            assert_eq!(
+                fs.set_xattr(fd, "user.x", b"1"),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(fs.set_modified_time(fd, 10), Err(Error::PermissionDenied));
+            assert_eq!(
+                fs.mount_memory_file("virtual.txt", Box::new(new_vector_memory())),
+                Err(Error::PermissionDenied)
+            );
+            assert_eq!(fs.format(), Err(Error::PermissionDenied));
+
+            // reading and listing still works
+            assert_eq!(
+                read_text_file(&mut fs, dir_fd, "a.txt", 0, 100),
+                "abc".repeat(10)
+            );
+            assert_eq!(list_files(&mut fs, "dir"), vec!["a.txt"]);
+            assert_eq!(fs.metadata(fd).unwrap().size, 30);
+
+            fs.set_read_only(false);
+
+            fs.write(fd, b"x").unwrap();
+            fs.create_dir(dir_fd, "sub", FdStat::default(), 0).unwrap();
+            fs.rename(dir_fd, "a.txt", dir_fd, "c.txt").unwrap();
+            assert_eq!(list_files(&mut fs, "dir"), vec!["sub", "c.txt"]);
+        }
+    }
+
+    #[test]
+    fn write_budget_splits_large_write() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "a.txt", FdStat::default(), 0)
+                .unwrap();
+
+            fs.set_write_budget_per_call(Some(1000));
+
+            let data = vec![7u8; 2500];
+            let mut written = 0;
+            let mut calls = 0;
+
+            while written < data.len() {
+                calls += 1;
+
+                let size = fs.write(fd, &data[written..]).unwrap() as usize;
+                assert_eq!(size, 1000.min(data.len() - written));
+                written += size;
+
+                if written < data.len() {
+                    assert_eq!(fs.write(fd, &data[written..]), Err(Error::WouldBlock));
+                }
+
+                // next message
+                fs.reset_write_budget();
+            }
+
+            assert_eq!(calls, 3);
+            assert_eq!(fs.metadata(fd).unwrap().size, 2500);
+
+            // the vectored writes share the budget
+            let size = fs.write_vectored(fd, &[&[1u8; 600], &[2u8; 600]]).unwrap();
+            assert_eq!(size, 1000);
+            assert_eq!(fs.write_vectored(fd, &[&[3u8; 10]]), Err(Error::WouldBlock));
+
+            fs.set_write_budget_per_call(None);
+            fs.write(fd, &data).unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().size, 6000);
+        }
+    }
+
+    #[test]
+    fn read_only_storage_rejects_writes() {
+        use crate::runtime::structure_helpers::create_dir_entry;
+        use crate::storage::{transient::TransientStorage, Storage};
+
+        let mut storage = TransientStorage::new();
+        let root_node = storage.root_node();
+        let node =
+            create_dir_entry(root_node, b"a.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage.write(node, 0, b"abc").unwrap();
+        storage.set_writable(false);
+
+        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
+        let root_fd = fs.root_fd();
+        let fd = fs
+            .open_or_create(root_fd, "a.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+
+        assert_eq!(fs.write(fd, b"x"), Err(Error::ReadOnlyFileSystem));
+        assert_eq!(
+            fs.create_file(root_fd, "b.txt", FdStat::default(), 0),
+            Err(Error::ReadOnlyFileSystem)
+        );
+        assert_eq!(
+            fs.create_dir(root_fd, "dir", FdStat::default(), 0),
+            Err(Error::ReadOnlyFileSystem)
+        );
+        fs.close(fd).unwrap();
+        assert_eq!(
+            fs.remove_file(root_fd, "a.txt"),
+            Err(Error::ReadOnlyFileSystem)
+        );
 
-        let child_fd = self.fd_table.open(FdEntry::File(child));
-        self.put_dir(parent, dir);
-        Ok(child_fd)
+        assert_eq!(read_text_file(&mut fs, root_fd, "a.txt", 0, 100), "abc");
+        assert_eq!(list_files(&mut fs, ""), vec!["a.txt"]);
     }
 
-    // Delete a file by name `path` in the given file folder.
-    pub fn remove_file(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
-        let dir = self.get_dir(parent)?;
-        dir.remove_file(
-            path,
-            self.fd_table.node_refcount(),
-            &mut self.names_cache,
-            self.storage.as_mut(),
-        )
-    }
+    #[test]
+    fn max_dir_entries_limit() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root_fd = fs.root_fd();
 
-    // Create a new directory named `path` in the given `parent` folder.
-    pub fn create_dir(
-        &mut self,
-        parent: Fd,
-        path: &str,
-        stat: FdStat,
-        ctime: u64,
-    ) -> Result<Fd, Error> {
-        let dir = self.get_dir(parent)?;
-        let child = dir.create_dir(
-            path,
-            stat,
-            &mut self.names_cache,
-            self.storage.as_mut(),
-            ctime,
-        )?;
-        let child_fd = self.fd_table.open(FdEntry::Dir(child));
-        self.put_dir(parent, dir);
-        Ok(child_fd)
-    }
+            fs.storage.set_max_dir_entries(Some(5));
 
-    // Delete a directory by name `path` in the given file folder.
-    pub fn remove_dir(&mut self, parent: Fd, path: &str) -> Result<(), Error> {
-        let dir = self.get_dir(parent)?;
-        dir.remove_dir(
-            path,
-            self.fd_table.node_refcount(),
-            &mut self.names_cache,
-            self.storage.as_mut(),
-        )
-    }
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-    // Create a hard link to an existing file.
-    pub fn create_hard_link(
-        &mut self,
-        old_fd: Fd,
-        old_path: &str,
-        new_fd: Fd,
-        new_path: &str,
-    ) -> Result<Fd, Error> {
-        let src_dir = self.get_dir(old_fd)?;
-        let dst_dir = self.get_dir(new_fd)?;
+            for i in 0..5 {
+                let fd = fs
+                    .create_file(dir_fd, &format!("file{i}.txt"), FdStat::default(), 0)
+                    .unwrap();
+                fs.close(fd).unwrap();
+            }
 
-        create_hard_link(
-            dst_dir.node,
-            new_path,
-            src_dir.node,
-            old_path,
-            false,
-            &mut self.names_cache,
-            self.storage.as_mut(),
-        )?;
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 5);
 
-        let node = find_node(
-            dst_dir.node,
-            new_path,
-            &mut self.names_cache,
-            self.storage.as_ref(),
-        )?;
+            assert_eq!(
+                fs.create_file(dir_fd, "file5.txt", FdStat::default(), 0),
+                Err(Error::TooManyFiles)
+            );
+            assert_eq!(
+                fs.create_dir(dir_fd, "subdir", FdStat::default(), 0),
+                Err(Error::TooManyFiles)
+            );
 
-        self.open(node, FdStat::default(), OpenFlags::empty())
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 5);
+
+            // removing an entry frees up space for another one
+            fs.remove_file(dir_fd, "file0.txt").unwrap();
+            fs.create_file(dir_fd, "file5.txt", FdStat::default(), 0)
+                .unwrap();
+
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 5);
+            assert_eq!(fs.dir_entry_count(root_fd).unwrap(), 1);
+        }
     }
 
-    // Rename a file.
-    pub fn rename(
-        &mut self,
-        old_fd: Fd,
-        old_path: &str,
-        new_fd: Fd,
-        new_path: &str,
-    ) -> Result<Fd, Error> {
-        let src_dir = self.get_dir(old_fd)?;
-        let dst_dir = self.get_dir(new_fd)?;
+    #[test]
+    fn pread_does_not_move_cursor() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
 
-        // create a new link
-        create_hard_link(
-            dst_dir.node,
-            new_path,
-            src_dir.node,
-            old_path,
-            true,
-            &mut self.names_cache,
-            self.storage.as_mut(),
-        )?;
+            for filename in ["test.txt", "virtual.txt"] {
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-        // now unlink the older version
-        let (node, _metadata) = rm_dir_entry(
-            src_dir.node,
-            old_path,
-            None,
-            self.fd_table.node_refcount(),
-            &mut self.names_cache,
-            self.storage.as_mut(),
-        )?;
+                fs.write(fd, b"0123456789abcdefghij").unwrap();
+                fs.seek(fd, 5, Whence::SET).unwrap();
 
-        self.open(node, FdStat::default(), OpenFlags::empty())
-    }
+                // read the last 4 bytes
+                let mut buf = [0u8; 4];
+                let read = fs.pread(fd, &mut buf, Whence::END, -4).unwrap();
+                assert_eq!(read, 4);
+                assert_eq!(&buf, b"ghij");
 
-    #[cfg(test)]
-    pub(crate) fn get_test_storage(&mut self) -> &mut dyn Storage {
-        self.storage.as_mut()
-    }
+                // read relative to the current cursor
+                let read = fs.pread(fd, &mut buf, Whence::CUR, 2).unwrap();
+                assert_eq!(read, 4);
+                assert_eq!(&buf, b"789a");
 
-    #[cfg(test)]
-    pub(crate) fn get_test_file(&self, fd: Fd) -> File {
-        self.get_file(fd).unwrap()
+                let read = fs.pread(fd, &mut buf, Whence::SET, 0).unwrap();
+                assert_eq!(read, 4);
+                assert_eq!(&buf, b"0123");
+
+                assert_eq!(fs.tell(fd).unwrap(), 5);
+
+                assert_eq!(
+                    fs.pread(fd, &mut buf, Whence::END, -100),
+                    Err(Error::InvalidOffset)
+                );
+
+                fs.close(fd).unwrap();
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn xattrs_roundtrip() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root_fd = fs.root_fd();
 
-    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
-    use ic_stable_structures::{Memory, VectorMemory};
+            let fd = fs
+                .create_file(root_fd, "file.txt", FdStat::default(), 0)
+                .unwrap();
 
-    use crate::test_utils::write_text_at_offset;
-    use crate::{
-        error::Error,
-        fs::{DstBuf, FdFlags, SrcBuf},
-        runtime::{
-            structure_helpers::find_node,
-            types::{FdStat, OpenFlags},
-        },
-        storage::{
-            stable::StableStorage,
-            types::{FileSize, FileType},
-        },
-        test_utils::{
-            new_vector_memory, read_text_file, test_fs, test_fs_setups, test_fs_transient,
-            write_text_fd, write_text_file,
-        },
-    };
+            fs.set_xattr(fd, "content-type", b"text/plain").unwrap();
+            fs.set_xattr(fd, "etag", b"123").unwrap();
+            fs.set_xattr(fd, "etag", b"456").unwrap();
 
-    use super::{Fd, FileSystem};
+            assert_eq!(fs.get_xattr(fd, "content-type").unwrap(), b"text/plain");
+            assert_eq!(fs.get_xattr(fd, "etag").unwrap(), b"456");
+            assert_eq!(fs.get_xattr(fd, "missing"), Err(Error::NotFound));
 
-    pub fn list_files(fs: &mut FileSystem, path: &str) -> Vec<String> {
-        use std::str::FromStr;
+            assert_eq!(
+                fs.list_xattrs(fd).unwrap(),
+                vec!["content-type".to_string(), "etag".to_string()]
+            );
 
-        let mut res = vec![];
+            // attributes of other nodes are not affected
+            assert!(fs.list_xattrs(root_fd).unwrap().is_empty());
 
-        let dir = fs.root_fd();
+            fs.remove_xattr(fd, "content-type").unwrap();
+            assert_eq!(fs.list_xattrs(fd).unwrap(), vec!["etag".to_string()]);
+            assert_eq!(fs.remove_xattr(fd, "content-type"), Err(Error::NotFound));
 
-        let fd = fs
-            .open_or_create(dir, path, FdStat::default(), OpenFlags::DIRECTORY, 0)
-            .unwrap();
+            // the total size per node is bounded
+            let big = vec![0u8; MAX_XATTR_SIZE_PER_NODE];
+            assert_eq!(fs.set_xattr(fd, "big", &big), Err(Error::XattrTooLarge));
 
-        let meta = fs.metadata(fd).unwrap();
+            // attributes are removed together with the file
+            fs.close(fd).unwrap();
+            fs.remove_file(root_fd, "file.txt").unwrap();
 
-        let mut entry_index = meta.first_dir_entry;
+            let fd = fs
+                .create_file(root_fd, "file.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.set_xattr(fd, "a", b"1").unwrap();
+            let node = fs.metadata(fd).unwrap().node;
+            fs.close(fd).unwrap();
+            fs.remove_file(root_fd, "file.txt").unwrap();
 
-        while let Some(index) = entry_index {
-            let entry = fs.get_direntry(fd, index).unwrap();
+            assert!(fs.storage.list_xattrs(node).unwrap().is_empty());
+        }
+    }
 
-            let filename_str: &str =
-                std::str::from_utf8(&entry.name.bytes[0..(entry.name.length as usize)]).unwrap();
+    #[test]
+    fn persist_and_unmount_keeps_data() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root_fd = fs.root_fd();
 
-            let st = String::from_str(filename_str).unwrap();
+            fs.mount_memory_file("test.txt", Box::new(new_vector_memory()))
+                .unwrap();
 
-            res.push(st);
+            write_text_file(&mut fs, root_fd, "test.txt", "Hello, world!", 1000).unwrap();
 
-            entry_index = entry.next_entry;
-        }
+            let memory = fs.persist_and_unmount("test.txt").unwrap();
+            assert!(memory.size() > 0);
 
-        res
+            let node = fs.open_metadata(root_fd, "test.txt").unwrap().node;
+            assert!(!fs.storage.is_mounted(node));
+
+            // the data is available in the regular file
+            let content = read_text_file(&mut fs, root_fd, "test.txt", 0, 13000);
+            assert_eq!(content, "Hello, world!".repeat(1000));
+
+            assert_eq!(
+                fs.persist_and_unmount("test.txt").err(),
+                Some(Error::MemoryFileIsNotMounted)
+            );
+        }
     }
 
     #[test]
-    fn get_root_info() {
-        let fs = test_fs();
+    fn freopen_keeps_fd() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
 
-        let fd = fs.root_fd();
-        let path = fs.root_path();
+            for filename in ["test.txt", "virtual.txt"] {
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-        assert!(fd == 3);
-        assert!(path == "/");
+                fs.write(fd, b"Hello, world!").unwrap();
+
+                // switching to the append mode moves the cursor to the end of file
+                fs.seek(fd, 0, Whence::SET).unwrap();
+                let append = FdStat {
+                    flags: FdFlags::APPEND,
+                    ..FdStat::default()
+                };
+                fs.freopen(fd, OpenFlags::empty(), append).unwrap();
+                assert_eq!(fs.tell(fd).unwrap(), 13);
+                assert_eq!(fs.get_stat(fd).unwrap().1.flags, FdFlags::APPEND);
+
+                // truncation empties the file, but the descriptor remains valid
+                fs.freopen(fd, OpenFlags::TRUNCATE, FdStat::default())
+                    .unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().size, 0);
+                assert_eq!(fs.tell(fd).unwrap(), 0);
+
+                fs.write(fd, b"abc").unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().size, 3);
+
+                assert_eq!(
+                    fs.freopen(fd, OpenFlags::DIRECTORY, FdStat::default()),
+                    Err(Error::InvalidFileType)
+                );
+
+                fs.close(fd).unwrap();
+
+                assert_eq!(
+                    fs.freopen(fd, OpenFlags::empty(), FdStat::default()),
+                    Err(Error::NotFound)
+                );
+            }
+        }
     }
 
     #[test]
-    fn create_file() {
-        let mut fs = test_fs();
+    fn adaptive_chunking_by_first_write() {
+        let mut fs = test_fs_custom_chunk_size(ChunkSize::CHUNK64K);
+        fs.storage.set_adaptive_chunking(true);
 
-        let file = fs
-            .create_file(fs.root_fd(), "test.txt", Default::default(), 0)
+        let root_fd = fs.root_fd();
+
+        let small_fd = fs
+            .create_file(root_fd, "small.txt", FdStat::default(), 0)
             .unwrap();
+        assert_eq!(fs.metadata(small_fd).unwrap().chunk_type, None);
 
-        assert!(file > fs.root_fd());
+        let large_fd = fs
+            .create_file(root_fd, "large.txt", FdStat::default(), 0)
+            .unwrap();
+
+        fs.write(small_fd, b"tiny").unwrap();
+
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs.write(large_fd, &large).unwrap();
+
+        // later writes do not change the chosen type
+        fs.write(small_fd, &large).unwrap();
+
+        assert_eq!(
+            fs.metadata(small_fd).unwrap().chunk_type,
+            Some(ChunkType::V1)
+        );
+        assert_eq!(
+            fs.metadata(large_fd).unwrap().chunk_type,
+            Some(ChunkType::V2)
+        );
+        assert_eq!(fs.storage.chunk_size(), 65536);
+
+        let mut buf = vec![0u8; large.len()];
+        fs.seek(large_fd, 0, Whence::SET).unwrap();
+        fs.read(large_fd, &mut buf).unwrap();
+        assert_eq!(buf, large);
+
+        let mut buf = vec![0u8; large.len() + 4];
+        fs.seek(small_fd, 0, Whence::SET).unwrap();
+        fs.read(small_fd, &mut buf).unwrap();
+        assert_eq!(&buf[..4], b"tiny");
+        assert_eq!(buf[4..], large);
     }
 
     #[test]
-    fn create_dir() {
-        let mut fs = test_fs();
+    fn next_node_id_increments_and_persists() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
 
-        let dir = fs
-            .create_dir(fs.root_fd(), "test", FdStat::default(), 0)
-            .unwrap();
+        let first = fs.next_node_id();
 
         let fd = fs
-            .create_file(dir, "file.txt", FdStat::default(), 0)
+            .create_file(root_fd, "a.txt", FdStat::default(), 0)
             .unwrap();
-        fs.write(fd, "Hello, world!".as_bytes()).unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().node, first);
+        assert_eq!(fs.next_node_id(), first + 1);
 
-        let dir = fs
-            .open_or_create(
-                fs.root_fd(),
-                "test",
-                FdStat::default(),
-                OpenFlags::empty(),
-                0,
-            )
+        fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+        assert_eq!(fs.next_node_id(), first + 2);
+
+        // the reserved IDs come first
+        fs.reserve_nodes(10).unwrap();
+        assert_eq!(fs.next_node_id(), first + 2);
+
+        let fd = fs
+            .create_file(root_fd, "b.txt", FdStat::default(), 0)
             .unwrap();
+        assert_eq!(fs.metadata(fd).unwrap().node, first + 2);
+        assert_eq!(fs.next_node_id(), first + 3);
+
+        // the IDs left from the reservation are skipped after an upgrade
+        let fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        assert_eq!(fs.next_node_id(), first + 12);
+    }
+
+    #[test]
+    fn estimate_write_chunks_before_writing() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
+
+                let node = fs.metadata(fd).unwrap().node;
+                let is_mounted = fs.storage.is_mounted(node);
+
+                let estimate = fs.estimate_write_chunks(fd, 0, 100).unwrap();
+                assert_eq!(estimate, if is_mounted { 0 } else { 1 });
+
+                fs.write(fd, &[1u8; 100]).unwrap();
 
+                // the first chunk exists now
+                assert_eq!(fs.estimate_write_chunks(fd, 10, 50).unwrap(), 0);
+                assert_eq!(fs.estimate_write_chunks(fd, 0, 0).unwrap(), 0);
+
+                // the estimate does not change the file
+                assert_eq!(fs.metadata(fd).unwrap().size, 100);
+
+                fs.close(fd).unwrap();
+            }
+        }
+
+        let mut fs = test_fs();
         let fd = fs
-            .open_or_create(dir, "file.txt", FdStat::default(), OpenFlags::empty(), 0)
+            .create_file(fs.root_fd(), "big.txt", FdStat::default(), 0)
             .unwrap();
+        let chunk_size = fs.storage.chunk_size() as FileSize;
+        fs.write(fd, &[1u8; 100]).unwrap();
+
+        assert_eq!(
+            fs.estimate_write_chunks(fd, chunk_size - 10, 2 * chunk_size)
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn create_filled_files() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let chunk_size = fs.storage.chunk_size() as FileSize;
+            let size = 3 * chunk_size + 17;
+
+            let zero_fd = fs.create_filled(root_fd, "zero.bin", size, 0, 0).unwrap();
+            assert_eq!(fs.metadata(zero_fd).unwrap().size, size);
 
-        let mut buf = [0; 13];
-        fs.read(fd, &mut buf).unwrap();
-        assert_eq!(&buf, "Hello, world!".as_bytes());
+            // no chunks are allocated for a zero fill
+            let empty_fd = fs
+                .create_file(root_fd, "empty.bin", FdStat::default(), 0)
+                .unwrap();
+            let all_chunks = fs.estimate_write_chunks(empty_fd, 0, size).unwrap();
+            assert!(all_chunks > 0);
+            assert_eq!(
+                fs.estimate_write_chunks(zero_fd, 0, size).unwrap(),
+                all_chunks
+            );
+
+            let mut buf = vec![1u8; size as usize];
+            fs.read(zero_fd, &mut buf).unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+
+            let fd = fs.create_filled(root_fd, "ab.bin", size, 0xAB, 0).unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().size, size);
+            assert_eq!(fs.estimate_write_chunks(fd, 0, size).unwrap(), 0);
+
+            let mut buf = vec![0u8; size as usize + 10];
+            let read = fs.read(fd, &mut buf).unwrap();
+            assert_eq!(read, size);
+            assert!(buf[..size as usize].iter().all(|b| *b == 0xAB));
+
+            let res = fs.create_filled(root_fd, "ab.bin", size, 0xAB, 0);
+            assert!(res.is_err());
+
+            // the size is checked before the file is created
+            let entries = fs.dir_entry_count(root_fd).unwrap();
+            assert_eq!(
+                fs.create_filled(root_fd, "huge.bin", MAX_FILE_SIZE + 1, 0, 0),
+                Err(Error::FileTooLarge)
+            );
+            assert_eq!(fs.dir_entry_count(root_fd).unwrap(), entries);
+        }
     }
 
     #[test]
-    fn create_file_creates_a_few_files() {
-        let mut fs = test_fs();
+    fn fd_of_another_file_system_is_rejected() {
+        let mut fs1 = test_fs();
 
-        let dir = fs.root_fd();
+        // the instances created in between do not make the descriptors collide
+        for _ in 0..200 {
+            let mut fs = test_fs_transient();
+            let fd = fs
+                .create_file(fs.root_fd(), "test.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+        }
+        let mut fs2 = test_fs();
 
-        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
-            .unwrap();
-        fs.create_file(dir, "test2.txt", FdStat::default(), 0)
+        let fd1 = fs1
+            .create_file(fs1.root_fd(), "test.txt", FdStat::default(), 0)
             .unwrap();
-        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
+        let fd2 = fs2
+            .create_file(fs2.root_fd(), "test.txt", FdStat::default(), 0)
             .unwrap();
 
-        let meta = fs.metadata(fs.root_fd()).unwrap();
-
-        let entry_index = meta.first_dir_entry.unwrap();
+        fs1.write(fd1, b"fs1").unwrap();
 
-        let entry1 = fs.get_direntry(fs.root_fd(), entry_index).unwrap();
-        let entry2 = fs.get_direntry(fs.root_fd(), entry_index + 1).unwrap();
-        let entry3 = fs.get_direntry(fs.root_fd(), entry_index + 2).unwrap();
+        // the descriptor numbers stay small
+        assert_ne!(fd1, fd2);
+        assert!(fd1 < 1000 && fd2 < 1000);
 
-        assert_eq!(entry1.prev_entry, None);
-        assert_eq!(entry1.next_entry, Some(entry_index + 1));
+        assert_eq!(fs2.write(fd1, b"fs2"), Err(Error::BadFileDescriptor));
+        assert_eq!(fs2.tell(fd1), Err(Error::BadFileDescriptor));
+        assert_eq!(fs2.close(fd1), Err(Error::BadFileDescriptor));
+        assert_eq!(fs2.renumber(fd2, fd1), Err(Error::BadFileDescriptor));
+        assert!(matches!(fs2.get_stat(fd1), Err(Error::BadFileDescriptor)));
 
-        assert_eq!(entry2.prev_entry, Some(entry_index));
-        assert_eq!(entry2.next_entry, Some(entry_index + 2));
+        // the file of the second file system is untouched
+        assert_eq!(fs2.metadata(fd2).unwrap().size, 0);
+        assert_eq!(fs1.metadata(fd1).unwrap().size, 3);
 
-        assert_eq!(entry3.prev_entry, Some(entry_index + 1));
-        assert_eq!(entry3.next_entry, None);
+        // the numbers not issued to another instance still work for renumbering
+        fs2.renumber(fd2, 100).unwrap();
+        fs2.write(100, b"fs2").unwrap();
+        assert_eq!(fs2.metadata(100).unwrap().size, 3);
     }
 
     #[test]
-    fn close_file_fd_reused() {
-        let mut fs = test_fs();
+    fn read_dir_with_open_state_marks_open_files() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let dir = fs.root_fd();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-        fs.create_file(dir, "test1.txt", FdStat::default(), 0)
-            .unwrap();
-        let fd2 = fs
-            .create_file(dir, "test2.txt", FdStat::default(), 0)
-            .unwrap();
-        fs.create_file(dir, "test3.txt", FdStat::default(), 0)
-            .unwrap();
+            for name in ["dir/a.txt", "dir/b.txt"] {
+                let fd = create_file_with_size(name, 10, &mut fs);
+                fs.close(fd).unwrap();
+            }
+            let sub_fd = fs.create_dir(dir_fd, "sub", FdStat::default(), 0).unwrap();
+            fs.close(sub_fd).unwrap();
 
-        fs.close(fd2).unwrap();
+            let fd = fs
+                .open_or_create(dir_fd, "b.txt", FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
 
-        let fd4 = fs
-            .create_file(dir, "test4.txt", FdStat::default(), 0)
-            .unwrap();
+            let state: Vec<_> = fs
+                .read_dir_with_open_state(dir_fd)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _node, open, mounted)| (name, open, mounted))
+                .collect();
+
+            assert_eq!(
+                state,
+                vec![
+                    ("a.txt".to_string(), false, false),
+                    ("b.txt".to_string(), true, false),
+                    ("sub".to_string(), false, false),
+                ]
+            );
 
-        assert_eq!(fd2, fd4);
+            fs.close(fd).unwrap();
+
+            let state = fs.read_dir_with_open_state(dir_fd).unwrap();
+            assert!(state.iter().all(|(_, _, open, _)| !open));
+        }
     }
 
     #[test]
-    fn fd_renumber() {
-        let mut fs = test_fs();
+    fn read_dir_with_metadata_lists_sizes() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let dir = fs.root_fd();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-        let fd1 = fs
-            .create_file(dir, "test1.txt", FdStat::default(), 0)
-            .unwrap();
-        let fd2 = fs
-            .create_file(dir, "test2.txt", FdStat::default(), 0)
-            .unwrap();
+            create_file_with_size("dir/b.txt", 100, &mut fs);
+            create_file_with_size("dir/a.txt", 5000, &mut fs);
 
-        let entry1 = fs.get_node(fd1);
+            let sub_fd = fs.create_dir(dir_fd, "sub", FdStat::default(), 0).unwrap();
+            fs.create_dir(sub_fd, "x", FdStat::default(), 0).unwrap();
 
-        fs.renumber(fd1, fd2).unwrap();
+            create_file_with_size("dir/c.txt", 0, &mut fs);
 
-        assert!(fs.get_node(fd1).is_err());
-        assert_eq!(fs.get_node(fd2), entry1);
+            let listing = fs.read_dir_with_metadata(dir_fd).unwrap();
+
+            let mut found: Vec<(String, FileType, FileSize)> = listing
+                .iter()
+                .map(|(name, meta)| (name.clone(), meta.file_type, meta.size))
+                .collect();
+            found.sort_by(|a, b| a.0.cmp(&b.0));
+
+            assert_eq!(
+                found,
+                vec![
+                    ("a.txt".to_string(), FileType::RegularFile, 5000),
+                    ("b.txt".to_string(), FileType::RegularFile, 100),
+                    ("c.txt".to_string(), FileType::RegularFile, 0),
+                    ("sub".to_string(), FileType::Directory, 1),
+                ]
+            );
+
+            // the metadata is fetched in the node order
+            let nodes: Vec<_> = listing.iter().map(|(_, meta)| meta.node).collect();
+            let mut sorted = nodes.clone();
+            sorted.sort();
+            assert_eq!(nodes, sorted);
+        }
     }
 
     #[test]
-    fn seek_and_write() {
-        let mut fs = test_fs();
+    fn list_page_resumes_after_upgrade() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
 
-        let dir = fs.root_fd();
+        let mut dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-            .unwrap();
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            let name = format!("file{i}.txt");
+            let fd = fs.create_file(dir_fd, &name, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+            expected.push(name);
+        }
 
-        fs.seek(fd, 24, super::Whence::SET).unwrap();
+        let mut names = Vec::new();
+        let mut token = None;
+        let mut pages = 0;
+
+        loop {
+            let (page, next) = fs.list_page(dir_fd, token, 100).unwrap();
+            assert!(page.len() <= 100);
+            names.extend(page.into_iter().map(|(_, name)| name));
+            pages += 1;
+
+            token = match next {
+                Some(next) => Some(next),
+                None => break,
+            };
+
+            // imitate canister upgrade between the pages
+            if pages == 5 {
+                fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+                let root_fd = fs.root_fd();
+                dir_fd = fs
+                    .open_or_create(root_fd, "dir", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                    .unwrap();
+            }
+        }
 
-        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(pages, 10);
+        assert_eq!(names, expected);
 
-        let meta = fs.metadata(fd).unwrap();
+        // the token stays valid when its entry is removed
 
-        assert_eq!(meta.size, 29);
+        let (page, token) = fs.list_page(dir_fd, None, 3).unwrap();
+        fs.remove_file(dir_fd, &page[2].1).unwrap();
 
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
-        assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
-        );
+        let (page, _) = fs.list_page(dir_fd, token, 2).unwrap();
+        let page: Vec<String> = page.into_iter().map(|(_, name)| name).collect();
+        assert_eq!(page, vec!["file3.txt", "file4.txt"]);
 
-        fs.close(fd).unwrap();
+        // an empty page would end the listing early
+        assert_eq!(fs.list_page(dir_fd, None, 0), Err(Error::InvalidArgument));
+        assert_eq!(fs.list_page(dir_fd, token, 0), Err(Error::InvalidArgument));
+    }
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-            .unwrap();
+    #[test]
+    fn rename_keeps_created_time() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
-        assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
-        );
+            let dir_fd = fs
+                .create_dir(root_fd, "dir", FdStat::default(), 50)
+                .unwrap();
+            let fd = fs
+                .create_file(dir_fd, "a.txt", FdStat::default(), 100)
+                .unwrap();
+            fs.write(fd, b"abc").unwrap();
+            fs.close(fd).unwrap();
 
-        fs.close(fd).unwrap();
+            let fd = fs.rename(dir_fd, "a.txt", root_fd, "new/b.txt").unwrap();
+            assert_eq!(fs.created_time(fd).unwrap(), 100);
+            fs.close(fd).unwrap();
+
+            let fd = fs.rename(root_fd, "dir", root_fd, "renamed").unwrap();
+            assert_eq!(fs.created_time(fd).unwrap(), 50);
+            fs.close(fd).unwrap();
+        }
     }
 
     #[test]
-    fn read_and_write_vec() {
-        let mut fs = test_fs();
+    fn list_files_and_dirs_of_mixed_folder() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let dir = fs.root_fd();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            for name in ["a.txt", "sub1", "b.txt", "sub2", "c.txt"] {
+                let fd = if name.starts_with("sub") {
+                    fs.create_dir(dir_fd, name, FdStat::default(), 0)
+                } else {
+                    fs.create_file(dir_fd, name, FdStat::default(), 0)
+                }
+                .unwrap();
+                fs.close(fd).unwrap();
+            }
+            fs.create_file(dir_fd, "sub1/nested.txt", FdStat::default(), 0)
+                .unwrap();
 
-        let write_content1 = "This is a sample file content.";
-        let write_content2 = "1234567890";
+            assert_eq!(
+                fs.list_files(dir_fd).unwrap(),
+                vec!["a.txt", "b.txt", "c.txt"]
+            );
+            assert_eq!(fs.list_dirs(dir_fd).unwrap(), vec!["sub1", "sub2"]);
+            assert_eq!(fs.list_dirs(root_fd).unwrap(), vec!["dir"]);
+            assert!(fs.list_files(root_fd).unwrap().is_empty());
+        }
+    }
 
-        let write_content = [
-            SrcBuf {
-                buf: write_content1.as_ptr(),
-                len: write_content1.len(),
-            },
-            SrcBuf {
-                buf: write_content2.as_ptr(),
-                len: write_content2.len(),
-            },
-        ];
+    #[test]
+    fn create_dirs_shares_intermediate_folders() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-            .unwrap();
+            fs.create_dirs(root_fd, &["a/b", "a/c", "a/b/d", "e"], 10)
+                .unwrap();
 
-        fs.write_vec(fd, write_content.as_ref()).unwrap();
+            assert_eq!(fs.list_dirs(root_fd).unwrap(), vec!["a", "e"]);
+            assert_eq!(list_files(&mut fs, "a"), vec!["b", "c"]);
+            assert_eq!(list_files(&mut fs, "a/b"), vec!["d"]);
 
-        let meta = fs.metadata(fd).unwrap();
-        assert_eq!(meta.size, 40);
+            let fd = fs
+                .open_or_create(root_fd, "a/b/d", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().times.created, 10);
+            fs.close(fd).unwrap();
 
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+            // existing folders are kept, the first failing path stops the call
+            let fd = fs
+                .create_file(root_fd, "a/file.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
 
-        let mut read_content1 = String::from("......................");
-        let mut read_content2 = String::from("......................");
+            assert_eq!(
+                fs.create_dirs(root_fd, &["a/b", "x", "a/file.txt/y", "z"], 0),
+                Err(Error::InvalidFileType)
+            );
+            assert_eq!(
+                fs.create_dirs(root_fd, &["a/file.txt"], 0),
+                Err(Error::InvalidFileType)
+            );
+            assert_eq!(fs.list_dirs(root_fd).unwrap(), vec!["a", "e", "x"]);
+            assert_eq!(list_files(&mut fs, "a/b"), vec!["d"]);
+        }
+    }
 
-        let read_content = [
-            DstBuf {
-                buf: read_content1.as_mut_ptr(),
-                len: read_content1.len(),
-            },
-            DstBuf {
-                buf: read_content2.as_mut_ptr(),
-                len: read_content2.len(),
-            },
-        ];
+    #[test]
+    fn listings_match_across_storages() {
+        let mut listings = Vec::new();
 
-        fs.read_vec(fd, &read_content).unwrap();
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let root_fd = fs.root_fd();
 
-        assert_eq!("This is a sample file ", read_content1);
-        assert_eq!("content.1234567890....", read_content2);
+            fs.create_dirs(root_fd, &["dir/sub"], 0).unwrap();
+            let dir_fd = fs
+                .open_or_create(root_fd, "dir", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            for name in ["b.txt", "a.txt"] {
+                let fd = fs.create_file(dir_fd, name, FdStat::default(), 0).unwrap();
+                fs.close(fd).unwrap();
+            }
 
-        fs.close(fd).unwrap();
+            let by_name: Vec<String> = fs
+                .read_dir(dir_fd, DirOrder::Name)
+                .unwrap()
+                .iter()
+                .map(|entry| {
+                    String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize])
+                        .into_owned()
+                })
+                .collect();
+            let iterated: Vec<String> = fs
+                .dir_iter(dir_fd)
+                .unwrap()
+                .map(|entry| entry.unwrap().1)
+                .collect();
+            let (page, _) = fs.list_page(dir_fd, None, 10).unwrap();
+            let paged: Vec<String> = page.into_iter().map(|(_, name)| name).collect();
+
+            listings.push((by_name, iterated, paged, fs.list_dirs(dir_fd).unwrap()));
+        }
+
+        assert_eq!(listings[0], listings[1]);
+
+        let (by_name, iterated, _, _) = &listings[0];
+        assert_eq!(by_name, &vec!["a.txt", "b.txt", "sub"]);
+        assert_eq!(iterated, &vec!["sub", "b.txt", "a.txt"]);
     }
 
     #[test]
-    fn read_and_write_vec_with_offset() {
+    fn dir_iter_stops_at_first_match() {
         let mut fs = test_fs();
+        let root_fd = fs.root_fd();
 
-        let dir = fs.root_fd();
-
-        let write_content1 = "This is a sample file content.";
-        let write_content2 = "1234567890";
+        let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+        for i in 0..200 {
+            fs.create_file(dir_fd, &format!("file{i}.txt"), FdStat::default(), 0)
+                .unwrap();
+        }
 
-        let write_content = [
-            SrcBuf {
-                buf: write_content1.as_ptr(),
-                len: write_content1.len(),
-            },
-            SrcBuf {
-                buf: write_content2.as_ptr(),
-                len: write_content2.len(),
-            },
-        ];
+        #[cfg(feature = "profiling")]
+        let before = fs.operation_counters();
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+        let position = fs
+            .dir_iter(dir_fd)
+            .unwrap()
+            .position(|entry| entry.unwrap().1 == "file10.txt")
             .unwrap();
+        assert_eq!(position, 10);
 
-        fs.write_vec_with_offset(fd, write_content.as_ref(), 2)
-            .unwrap();
+        // only the entries up to the match were read
+        #[cfg(feature = "profiling")]
+        assert_eq!(
+            fs.operation_counters().btree_gets - before.btree_gets,
+            position as u64 + 1
+        );
 
-        let meta = fs.metadata(fd).unwrap();
-        assert_eq!(meta.size, 42);
+        let names: Vec<String> = fs
+            .dir_iter(dir_fd)
+            .unwrap()
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(names.len(), 200);
+        assert_eq!(names[199], "file199.txt");
+    }
 
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
+    #[test]
+    fn truncate_all_keeps_structure() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let mut read_content1 = String::from("......................");
-        let mut read_content2 = String::from("......................");
+            let top_fd = fs.create_dir(root_fd, "top", FdStat::default(), 0).unwrap();
+            let a_fd = fs.create_dir(top_fd, "a", FdStat::default(), 0).unwrap();
+            fs.create_dir(a_fd, "b", FdStat::default(), 0).unwrap();
 
-        let read_content = [
-            DstBuf {
-                buf: read_content1.as_mut_ptr(),
-                len: read_content1.len(),
-            },
-            DstBuf {
-                buf: read_content2.as_mut_ptr(),
-                len: read_content2.len(),
-            },
-        ];
+            create_file_with_size("top/f1.txt", 100, &mut fs);
+            create_file_with_size("top/a/f2.txt", 50_000, &mut fs);
+            create_file_with_size("top/a/b/f3.txt", 0, &mut fs);
+            create_file_with_size("top/a/b/f4.txt", 7, &mut fs);
+            create_file_with_size("outside.txt", 10, &mut fs);
+
+            let structure = fs.glob(top_fd, "**").unwrap();
+            assert_eq!(structure.len(), 6);
+
+            assert_eq!(fs.truncate_all(top_fd, false).unwrap(), 1);
+            assert_eq!(fs.recursive_size(top_fd).unwrap(), 50_007);
+
+            assert_eq!(fs.truncate_all(top_fd, true).unwrap(), 4);
+            assert_eq!(fs.recursive_size(top_fd).unwrap(), 0);
+
+            assert_eq!(fs.glob(top_fd, "**").unwrap(), structure);
+
+            for path in [
+                "top/f1.txt",
+                "top/a/f2.txt",
+                "top/a/b/f3.txt",
+                "top/a/b/f4.txt",
+            ] {
+                let meta = fs.open_metadata(root_fd, path).unwrap();
+                assert_eq!(meta.size, 0);
+            }
+
+            // the files can be written again
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "top/a/f2.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
+            fs.write(fd, b"new").unwrap();
+            fs.seek(fd, 0, Whence::SET).unwrap();
+            let mut buf = [0u8; 10];
+            assert_eq!(fs.read(fd, &mut buf).unwrap(), 3);
+            assert_eq!(&buf[..3], b"new");
+
+            assert_eq!(fs.open_metadata(root_fd, "outside.txt").unwrap().size, 10);
+        }
+    }
+
+    #[test]
+    fn diff_reports_changed_file() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        fs.read_vec_with_offset(fd, &read_content, 1).unwrap();
+            for dir in ["a", "b"] {
+                write_text_file(&mut fs, root_fd, &format!("{dir}/x.txt"), "xyz", 100).unwrap();
+                write_text_file(&mut fs, root_fd, &format!("{dir}/sub/z.txt"), "z", 10).unwrap();
+            }
+            write_text_file(&mut fs, root_fd, "a/sub/y.txt", "abc", 1000).unwrap();
+            write_text_file(&mut fs, root_fd, "b/sub/y.txt", "abd", 1000).unwrap();
 
-        assert_eq!("\0This is a sample file", read_content1);
-        assert_eq!(" content.1234567890...", read_content2);
+            let a_fd = fs
+                .open_or_create(root_fd, "a", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
+            let b_fd = fs
+                .open_or_create(root_fd, "b", FdStat::default(), OpenFlags::DIRECTORY, 0)
+                .unwrap();
 
-        fs.close(fd).unwrap();
+            assert_eq!(
+                fs.diff(a_fd, b_fd).unwrap(),
+                vec![DiffEntry::Changed("sub/y.txt".to_string())]
+            );
+            assert!(fs.diff(a_fd, a_fd).unwrap().is_empty());
+
+            write_text_file(&mut fs, root_fd, "a/only_a.txt", "a", 1).unwrap();
+            write_text_file(&mut fs, root_fd, "b/sub/only_b.txt", "b", 1).unwrap();
+
+            assert_eq!(
+                fs.diff(a_fd, b_fd).unwrap(),
+                vec![
+                    DiffEntry::Removed("only_a.txt".to_string()),
+                    DiffEntry::Added("sub/only_b.txt".to_string()),
+                    DiffEntry::Changed("sub/y.txt".to_string()),
+                ]
+            );
+        }
     }
 
     #[test]
-    fn seek_and_write_transient() {
-        let mut fs = test_fs_transient();
-
-        let dir = fs.root_fd();
+    fn directory_manifest_is_stable_after_reopening() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-            .unwrap();
+        let files = [
+            ("top/b.txt", "bbb"),
+            ("top/a.txt", "a"),
+            ("top/sub/c.txt", ""),
+            ("top/sub/deep/d.txt", "ddddd"),
+        ];
 
-        fs.seek(fd, 24, super::Whence::SET).unwrap();
+        for (path, content) in files {
+            write_text_file(&mut fs, root_fd, path, content, 100).unwrap();
+        }
+        create_file_with_size("outside.txt", 10, &mut fs);
 
-        fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+        let top_fd = fs
+            .open_or_create(root_fd, "top", FdStat::default(), OpenFlags::DIRECTORY, 0)
+            .unwrap();
+        let manifest = fs.directory_manifest(top_fd).unwrap();
 
-        let meta = fs.metadata(fd).unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|(path, _, _)| path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt", "b.txt", "sub/c.txt", "sub/deep/d.txt"]);
 
-        assert_eq!(meta.size, 29);
+        for (path, hash, size) in manifest.iter() {
+            let (_, content) = files
+                .iter()
+                .find(|(p, _)| p.strip_prefix("top/") == Some(path.as_str()))
+                .unwrap();
 
-        fs.seek(fd, 0, crate::fs::Whence::SET).unwrap();
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
-        assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
-        );
+            assert_eq!(*size, content.len() as FileSize * 100);
 
-        fs.close(fd).unwrap();
+            let fd = fs
+                .open_or_create(top_fd, path, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            assert_eq!(*hash, fs.hash_file(fd, HashAlgo::Sha256).unwrap());
+            fs.close(fd).unwrap();
+        }
 
-        let fd = fs
-            .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+        // imitate canister upgrade
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        let root_fd = fs.root_fd();
+        let top_fd = fs
+            .open_or_create(root_fd, "top", FdStat::default(), OpenFlags::DIRECTORY, 0)
             .unwrap();
 
-        let mut buf = [42u8; 29];
-        let rr = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(rr, 29);
-        assert_eq!(
-            buf,
-            [
-                0u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
-                4, 5
-            ]
-        );
-
-        fs.close(fd).unwrap();
+        assert_eq!(fs.directory_manifest(top_fd).unwrap(), manifest);
     }
 
     #[test]
-    fn create_and_remove_file() {
-        for mut fs in test_fs_setups("virtual_memory.txt") {
-            let dir = fs.root_fd();
+    fn write_vec_atomic_beyond_maximum_size_fails() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-            let fd = fs
-                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-                .unwrap();
+                let content1 = "0123456789";
+                let content2 = "abcdefghij";
+
+                let src = [
+                    SrcBuf {
+                        buf: content1.as_ptr(),
+                        len: content1.len(),
+                    },
+                    SrcBuf {
+                        buf: content2.as_ptr(),
+                        len: content2.len(),
+                    },
+                ];
+
+                // only the first buffer would still fit
+                fs.seek(fd, (MAX_FILE_SIZE - 15) as i64, Whence::SET)
+                    .unwrap();
 
-            fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
-            fs.close(fd).unwrap();
+                let res = fs.write_vec_atomic(fd, src.as_ref());
+                assert_eq!(res, Err(Error::FileTooLarge));
 
-            fs.remove_file(dir, "test.txt").unwrap();
+                // nothing was written
+                assert_eq!(fs.metadata(fd).unwrap().size, 0);
+                assert_eq!(fs.tell(fd).unwrap(), MAX_FILE_SIZE - 15);
 
-            let err = fs
-                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::empty(), 0)
-                .unwrap_err();
-            assert_eq!(err, Error::NotFound);
+                fs.seek(fd, 0, Whence::SET).unwrap();
+                assert_eq!(fs.write_vec_atomic(fd, src.as_ref()), Ok(20));
+                assert_eq!(fs.metadata(fd).unwrap().size, 20);
+
+                let content = read_text_file(&mut fs, root_fd, filename, 0, 100);
+                assert_eq!(content, "0123456789abcdefghij");
+
+                fs.close(fd).unwrap();
+            }
         }
     }
 
     #[test]
-    fn cannot_remove_opened_file() {
-        for mut fs in test_fs_setups("virtual_memory.txt") {
-            let dir = fs.root_fd();
-
+    fn write_past_last_file_chunk_fails() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
             let fd = fs
-                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
                 .unwrap();
 
-            fs.write(fd, &[1, 2, 3, 4, 5]).unwrap();
+            // past the last chunk index for any chunk size, only `write_vec_atomic` checks `MAX_FILE_SIZE` upfront
+            let offset = MAX_FILE_CHUNK_COUNT * MAX_FILE_CHUNK_SIZE_V2 as FileSize;
+            fs.seek(fd, offset as i64, Whence::SET).unwrap();
 
-            let err = fs.remove_file(dir, "test.txt").unwrap_err();
-            assert_eq!(err, Error::CannotRemoveOpenedNode);
+            assert_eq!(fs.write(fd, b"a"), Err(Error::FileChunkLimitExceeded));
+            assert_eq!(fs.metadata(fd).unwrap().size, 0);
+            assert_eq!(fs.tell(fd).unwrap(), offset);
 
             fs.close(fd).unwrap();
-            fs.remove_file(dir, "test.txt").unwrap();
         }
     }
 
     #[test]
-    fn cannot_remove_directory_as_file() {
-        for mut fs in test_fs_setups("virtual_memory.txt") {
-            let dir = fs.root_fd();
+    fn write_vec_atomic_beyond_write_budget_fails() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(root_fd, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
 
-            let fd = fs.create_dir(dir, "test", FdStat::default(), 0).unwrap();
-            fs.close(fd).unwrap();
+            let content1 = "0123456789";
+            let content2 = "abcdefghij";
 
-            let err = fs.remove_file(dir, "test").unwrap_err();
-            assert_eq!(err, Error::ExpectedToRemoveFile);
+            let src = [
+                SrcBuf {
+                    buf: content1.as_ptr(),
+                    len: content1.len(),
+                },
+                SrcBuf {
+                    buf: content2.as_ptr(),
+                    len: content2.len(),
+                },
+            ];
 
-            fs.remove_dir(dir, "test").unwrap();
-        }
-    }
+            // only the first buffer would fit into the budget
+            fs.set_write_budget_per_call(Some(15));
 
-    #[test]
-    fn cannot_remove_file_as_directory() {
-        for mut fs in test_fs_setups("virtual_memory.txt") {
-            let dir = fs.root_fd();
+            assert_eq!(
+                fs.write_vec_atomic(fd, src.as_ref()),
+                Err(Error::WouldBlock)
+            );
 
-            let fd = fs
-                .open_or_create(dir, "test.txt", FdStat::default(), OpenFlags::CREATE, 0)
-                .unwrap();
-            fs.close(fd).unwrap();
+            // nothing was written and the budget is not spent
+            assert_eq!(fs.metadata(fd).unwrap().size, 0);
+            assert_eq!(fs.tell(fd).unwrap(), 0);
+            assert_eq!(fs.write(fd, content1.as_bytes()), Ok(10));
 
-            let err = fs.remove_dir(dir, "test.txt").unwrap_err();
-            assert_eq!(err, Error::ExpectedToRemoveDirectory);
+            fs.seek(fd, 0, Whence::SET).unwrap();
 
-            fs.remove_file(dir, "test.txt").unwrap();
+            fs.set_write_budget_per_call(Some(20));
+            assert_eq!(fs.write_vec_atomic(fd, src.as_ref()), Ok(20));
+
+            fs.set_write_budget_per_call(None);
+
+            let content = read_text_file(&mut fs, root_fd, "test.txt", 0, 100);
+            assert_eq!(content, "0123456789abcdefghij");
+
+            fs.close(fd).unwrap();
         }
     }
 
     #[test]
-    fn renumber_when_the_alternative_file_exists() {
-        for mut fs in test_fs_setups("virtual_memory.txt") {
-            let dir = fs.root_fd();
-
-            let fd1 = fs
-                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 0)
-                .unwrap();
-
-            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+    fn read_dir_reverse_is_reverse_of_forward() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-            let pos1 = fs.tell(fd1).unwrap();
+            for i in 0..10 {
+                let fd = fs
+                    .create_file(root_fd, &format!("file{i}.txt"), FdStat::default(), 0)
+                    .unwrap();
+                fs.close(fd).unwrap();
+            }
+            fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+
+            // removing from the middle keeps both links consistent
+            fs.remove_file(root_fd, "file4.txt").unwrap();
+
+            let forward: Vec<(u64, String)> = fs
+                .read_dir(root_fd, DirOrder::Insertion)
+                .unwrap()
+                .into_iter()
+                .map(|entry| {
+                    let name = std::str::from_utf8(&entry.name.bytes[..entry.name.length as usize])
+                        .unwrap()
+                        .to_string();
+                    (entry.node, name)
+                })
+                .collect();
+
+            let mut reverse = fs.read_dir_reverse(root_fd).unwrap();
+            assert_eq!(reverse[0].1, "dir");
+
+            reverse.reverse();
+            assert_eq!(reverse, forward);
+            assert_eq!(forward.len(), 10);
+        }
+    }
 
-            let fd2 = fs
-                .open_or_create(dir, "file2.txt", FdStat::default(), OpenFlags::CREATE, 0)
-                .unwrap();
+    #[test]
+    fn renaming_folder_into_itself_fails() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-            let pos2 = fs.tell(fd2).unwrap();
+            let a_fd = fs.create_dir(root_fd, "a", FdStat::default(), 0).unwrap();
+            fs.create_dir(a_fd, "b", FdStat::default(), 0).unwrap();
 
-            assert!(pos1 == 5);
-            assert!(pos2 == 0);
+            let res = fs.rename(root_fd, "a", root_fd, "a/b/a2");
+            assert_eq!(res.unwrap_err(), Error::CyclicLink);
 
-            fs.renumber(fd1, fd2).unwrap();
+            let res = fs.rename(root_fd, "a", a_fd, "a3");
+            assert_eq!(res.unwrap_err(), Error::CyclicLink);
 
-            let pos2_renumbered = fs.tell(fd2).unwrap();
+            let res = fs.rename(root_fd, "a", root_fd, "a/c/d/a4");
+            assert_eq!(res.unwrap_err(), Error::CyclicLink);
 
-            assert!(pos1 == pos2_renumbered);
+            // nothing was changed
+            assert_eq!(list_files(&mut fs, "a"), vec!["b".to_string()]);
+            assert!(fs.detect_cycles().unwrap().is_empty());
 
-            let res = fs.tell(fd1);
+            // moving to a sibling is allowed
+            fs.create_dir(root_fd, "x", FdStat::default(), 0).unwrap();
+            fs.rename(root_fd, "a", root_fd, "x/a").unwrap();
+            assert!(fs.detect_cycles().unwrap().is_empty());
 
-            assert!(res.is_err());
+            // renaming within the same folder skips the subtree check
+            fs.rename(root_fd, "x", root_fd, "y").unwrap();
+            assert_eq!(list_files(&mut fs, "y/a"), vec!["b".to_string()]);
+            assert!(fs.detect_cycles().unwrap().is_empty());
         }
     }
 
     #[test]
-    fn renumber_when_the_alternative_file_doesnt_exist() {
-        for mut fs in test_fs_setups("file1.txt") {
-            let dir = fs.root_fd();
+    fn detect_cycles_flags_illegal_folder_link() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
 
-            let fd1 = fs
-                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 0)
-                .unwrap();
+        let a_fd = fs.create_dir(root_fd, "a", FdStat::default(), 0).unwrap();
+        let b_fd = fs.create_dir(a_fd, "b", FdStat::default(), 0).unwrap();
+        fs.create_dir(root_fd, "c", FdStat::default(), 0).unwrap();
 
-            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+        assert!(fs.detect_cycles().unwrap().is_empty());
 
-            let pos1 = fs.tell(fd1).unwrap();
+        let a_node = fs.metadata(a_fd).unwrap().node;
+        let b_node = fs.metadata(b_fd).unwrap().node;
 
-            assert!(pos1 == 5);
+        // link "a" into its own child folder bypassing the checks
+        crate::runtime::structure_helpers::add_dir_entry(
+            b_node,
+            a_node,
+            b"loop",
+            fs.storage.as_mut(),
+        )
+        .unwrap();
 
-            let fd2 = 100;
+        assert_eq!(fs.detect_cycles().unwrap(), vec![a_node]);
+    }
 
-            fs.renumber(fd1, fd2).unwrap();
+    #[test]
+    fn read_exact_records() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-            let pos2_renumbered = fs.tell(fd2).unwrap();
+                fs.write(fd, b"record01record02rec").unwrap();
+                fs.seek(fd, 0, Whence::SET).unwrap();
 
-            assert!(pos1 == pos2_renumbered);
+                let mut record = [0u8; 8];
+                fs.read_exact(fd, &mut record).unwrap();
+                assert_eq!(&record, b"record01");
+                fs.read_exact(fd, &mut record).unwrap();
+                assert_eq!(&record, b"record02");
 
-            let res = fs.tell(fd1);
+                // only a partial record is left
+                assert_eq!(fs.read_exact(fd, &mut record), Err(Error::UnexpectedEof));
+                assert_eq!(fs.tell(fd).unwrap(), 16);
 
-            assert!(res.is_err());
+                fs.seek(fd, 0, Whence::END).unwrap();
+                assert_eq!(fs.read_exact(fd, &mut record), Err(Error::UnexpectedEof));
+
+                // regular read still reports the end of file as zero bytes
+                assert_eq!(fs.read(fd, &mut record), Ok(0));
+
+                fs.close(fd).unwrap();
+            }
         }
     }
 
     #[test]
-    fn set_modified_set_accessed_time() {
+    fn require_full_reads_rejects_short_pages() {
         for mut fs in test_fs_setups("") {
-            let dir = fs.root_fd();
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(root_fd, "db.dat", FdStat::default(), OpenFlags::CREATE, 0)
+                .unwrap();
 
-            let fd1 = fs
-                .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 111)
+            fs.write(fd, &[1u8; 100]).unwrap();
+            fs.write(fd, &[2u8; 40]).unwrap();
+            fs.seek(fd, 0, Whence::SET).unwrap();
+
+            fs.set_require_full_reads(fd, FullReads::Strict).unwrap();
+
+            let mut page = [0u8; 100];
+            assert_eq!(fs.read(fd, &mut page), Ok(100));
+            assert_eq!(page, [1u8; 100]);
+
+            // the trailing page is short
+            assert_eq!(fs.read(fd, &mut page), Err(Error::UnexpectedEof));
+            assert_eq!(fs.tell(fd).unwrap(), 100);
+            assert_eq!(
+                fs.pread(fd, &mut page, Whence::SET, 100),
+                Err(Error::UnexpectedEof)
+            );
+
+            // the final page may be shorter, reading past it fails
+            fs.set_require_full_reads(fd, FullReads::ExceptLastPage)
                 .unwrap();
+            assert_eq!(fs.read(fd, &mut page), Ok(40));
+            assert_eq!(fs.read(fd, &mut page), Err(Error::UnexpectedEof));
 
-            fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+            fs.set_require_full_reads(fd, FullReads::Off).unwrap();
+            assert_eq!(fs.read(fd, &mut page), Ok(0));
+        }
+    }
 
-            fs.set_accessed_time(fd1, 333).unwrap();
-            fs.set_modified_time(fd1, 222).unwrap();
+    #[test]
+    fn fetch_add_u64_counter() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-            let metadata = fs.metadata(fd1).unwrap();
+                assert_eq!(fs.fetch_add_u64(fd, 8, 1), Err(Error::UnexpectedEof));
 
-            let times = metadata.times;
+                fs.write(fd, b"header..").unwrap();
+                fs.write_u64_le(fd, 8, 0).unwrap();
 
-            assert_eq!(times.accessed, 333);
-            assert_eq!(times.modified, 222);
-            assert_eq!(times.created, 111);
+                for i in 0..5 {
+                    assert_eq!(fs.fetch_add_u64(fd, 8, 3), Ok(i * 3));
+                }
 
-            assert_eq!(metadata.size, 5);
-            assert_eq!(metadata.file_type, FileType::RegularFile);
+                assert_eq!(fs.read_u64_le(fd, 8), Ok(15));
+                assert_eq!(fs.tell(fd).unwrap(), 8);
+                assert_eq!(fs.metadata(fd).unwrap().size, 16);
+
+                fs.close(fd).unwrap();
+            }
         }
     }
 
     #[test]
-    fn set_stat_get_stat() {
-        let mut fs = test_fs();
-        let dir = fs.root_fd();
+    fn growing_mounted_file_reads_zeroes() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            // the memory was used before and keeps some data
+            let memory = new_vector_memory();
+            memory.grow(1);
+            memory.write(0, &[0xFF; 2000]);
+
+            fs.mount_memory_file("mounted.txt", Box::new(memory.clone()))
+                .unwrap();
 
-        let fd1 = fs
-            .open_or_create(dir, "file1.txt", FdStat::default(), OpenFlags::CREATE, 111)
-            .unwrap();
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "mounted.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
+                .unwrap();
 
-        fs.write(fd1, &[1, 2, 3, 4, 5]).unwrap();
+            fs.write(fd, b"start").unwrap();
 
-        let (file_type, mut stat) = fs.get_stat(fd1).unwrap();
+            // write past the end of file, the gap must not expose old data
+            fs.seek(fd, 1000, Whence::SET).unwrap();
+            fs.write(fd, b"end").unwrap();
 
-        assert_eq!(file_type, FileType::RegularFile);
+            // grow beyond the current memory size
+            let far = 3 * 65536;
+            fs.seek(fd, far as i64, Whence::SET).unwrap();
+            fs.write(fd, b"far").unwrap();
 
-        assert_eq!(stat.flags, FdFlags::empty());
+            let mut buf = vec![1u8; far + 3];
+            fs.seek(fd, 0, Whence::SET).unwrap();
+            fs.read(fd, &mut buf).unwrap();
 
-        stat.flags = FdFlags::APPEND;
+            assert_eq!(&buf[..5], b"start");
+            assert!(buf[5..1000].iter().all(|b| *b == 0));
+            assert_eq!(&buf[1000..1003], b"end");
+            assert!(buf[1003..far].iter().all(|b| *b == 0));
+            assert_eq!(&buf[far..], b"far");
+        }
+    }
 
-        fs.set_stat(fd1, stat).unwrap();
+    #[test]
+    fn open_flags_matrix() {
+        #[derive(Clone, Copy, Debug)]
+        enum Target {
+            File,
+            Dir,
+            Missing,
+        }
 
-        let (_, stat2) = fs.get_stat(fd1).unwrap();
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        assert_eq!(stat2.flags, FdFlags::APPEND);
+            for bits in 0..16u16 {
+                let flags = OpenFlags::from_bits_truncate(bits);
+                let create = flags.contains(OpenFlags::CREATE);
+                let directory = flags.contains(OpenFlags::DIRECTORY);
+                let exclusive = flags.contains(OpenFlags::EXCLUSIVE);
+                let truncate = flags.contains(OpenFlags::TRUNCATE);
+
+                for target in [Target::File, Target::Dir, Target::Missing] {
+                    let path = format!("{target:?}_{bits}");
+
+                    match target {
+                        Target::File => {
+                            let fd = fs
+                                .create_file(root_fd, &path, FdStat::default(), 0)
+                                .unwrap();
+                            fs.write(fd, b"abc").unwrap();
+                            fs.close(fd).unwrap();
+                        }
+                        Target::Dir => {
+                            let fd = fs.create_dir(root_fd, &path, FdStat::default(), 0).unwrap();
+                            fs.close(fd).unwrap();
+                        }
+                        Target::Missing => {}
+                    }
+
+                    let expected = if directory && truncate {
+                        Err(Error::InvalidOpenFlags)
+                    } else {
+                        match target {
+                            Target::File if exclusive => Err(Error::FileAlreadyExists),
+                            Target::File if directory => Err(Error::InvalidFileType),
+                            Target::File => Ok(FileType::RegularFile),
+                            Target::Dir if exclusive => Err(Error::FileAlreadyExists),
+                            Target::Dir if truncate => Err(Error::InvalidFileType),
+                            Target::Dir => Ok(FileType::Directory),
+                            Target::Missing if !create => Err(Error::NotFound),
+                            Target::Missing if directory => Ok(FileType::Directory),
+                            Target::Missing => Ok(FileType::RegularFile),
+                        }
+                    };
+
+                    let res = fs.open_or_create(
+                        root_fd,
+                        &path,
+                        FdStat::default(),
+                        OpenFlags::from_bits_truncate(bits),
+                        0,
+                    );
+
+                    let actual = res.map(|fd| {
+                        let (file_type, _) = fs.get_stat(fd).unwrap();
+                        let size = fs.metadata(fd).unwrap().size;
+
+                        if let Target::File = target {
+                            let expected_size = if truncate { 0 } else { 3 };
+                            assert_eq!(size, expected_size, "{path} {bits}");
+                        }
+
+                        fs.close(fd).unwrap();
+                        file_type
+                    });
+
+                    assert_eq!(actual, expected, "{path} flags {bits}");
+                }
+            }
+        }
     }
 
-    fn create_test_file_with_content(
-        fs: &mut FileSystem,
-        parent: Fd,
-        file_name: &str,
-        content: Vec<String>,
-    ) -> Fd {
-        let file_fd = fs
-            .create_file(parent, file_name, FdStat::default(), 0)
-            .unwrap();
+    #[test]
+    fn recursive_size_counts_hard_links_once() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let mut src = String::from("");
+            let top_fd = fs.create_dir(root_fd, "top", FdStat::default(), 0).unwrap();
+            let a_fd = fs.create_dir(top_fd, "a", FdStat::default(), 0).unwrap();
+            let b_fd = fs.create_dir(top_fd, "b", FdStat::default(), 0).unwrap();
+            fs.create_dir(b_fd, "c", FdStat::default(), 0).unwrap();
 
-        for str in content.iter() {
-            src.push_str(str.as_str());
+            create_file_with_size("top/a/f1.txt", 1000, &mut fs);
+            create_file_with_size("top/b/c/f2.txt", 250, &mut fs);
+            create_file_with_size("top/f3.txt", 7, &mut fs);
+            create_file_with_size("outside.txt", 100_000, &mut fs);
+
+            assert_eq!(fs.recursive_size(top_fd).unwrap(), 1257);
+
+            // the same file appears in two folders
+            fs.create_hard_link(a_fd, "f1.txt", b_fd, "c/f1_link.txt")
+                .unwrap();
+            fs.create_hard_link(root_fd, "outside.txt", a_fd, "inside.txt")
+                .unwrap();
+
+            assert_eq!(fs.recursive_size(top_fd).unwrap(), 101_257);
+            assert_eq!(fs.recursive_size(b_fd).unwrap(), 1250);
+            assert_eq!(fs.recursive_size(root_fd).unwrap(), 101_257);
         }
+    }
 
-        let bytes_written = fs.write(file_fd, src.as_bytes()).unwrap();
+    #[test]
+    fn effective_chunk_type_without_metadata() {
+        for (mut fs, stored) in [
+            (test_fs(), ChunkType::V2),
+            (test_fs_v1(), ChunkType::V1),
+            (test_fs_transient(), ChunkType::V1),
+        ] {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "test.txt", FdStat::default(), 0)
+                .unwrap();
+
+            fs.write(fd, &[7u8; 5000]).unwrap();
 
-        assert!(bytes_written > 0);
+            // forget the chunk type, it is detected from the stored chunks
+            let mut meta = fs.metadata(fd).unwrap();
+            meta.chunk_type = None;
+            fs.set_metadata(fd, meta).unwrap();
 
-        file_fd as Fd
+            assert_eq!(fs.effective_chunk_type(fd).unwrap(), stored);
+            assert_eq!(fs.metadata(fd).unwrap().chunk_type, None);
+        }
     }
 
-    fn create_test_file(fs: &mut FileSystem, parent_fd: Fd, file_name: &str) -> Fd {
-        create_test_file_with_content(
-            fs,
-            parent_fd,
-            file_name,
-            vec![
-                String::from("This is a sample text."),
-                String::from("1234567890"),
-            ],
-        )
+    #[test]
+    fn fix_chunk_type_restores_reads() {
+        for (mut fs, stored) in [
+            (test_fs(), ChunkType::V2),
+            (test_fs_v1(), ChunkType::V1),
+            (test_fs_transient(), ChunkType::V1),
+        ] {
+            let root_fd = fs.root_fd();
+            let fd = fs
+                .create_file(root_fd, "test.txt", FdStat::default(), 0)
+                .unwrap();
+
+            let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+            fs.write(fd, &content).unwrap();
+
+            // break the chunk type
+            let wrong = match stored {
+                ChunkType::V1 => ChunkType::V2,
+                ChunkType::V2 => ChunkType::V1,
+            };
+            let mut meta = fs.metadata(fd).unwrap();
+            meta.chunk_type = Some(wrong);
+            fs.set_metadata(fd, meta).unwrap();
+
+            assert_eq!(fs.fix_chunk_type(fd).unwrap(), stored);
+            assert_eq!(fs.metadata(fd).unwrap().chunk_type, Some(stored));
+
+            let mut buf = vec![0u8; content.len()];
+            fs.seek(fd, 0, Whence::SET).unwrap();
+            fs.read(fd, &mut buf).unwrap();
+            assert_eq!(buf, content);
+
+            // folders have no chunks
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            assert_eq!(fs.fix_chunk_type(dir_fd), Err(Error::InvalidFileType));
+        }
     }
 
     #[test]
-    fn link_seek_tell() {
-        let mut fs = test_fs();
-        let dir = fs.root_fd();
-
-        let root_fd = 3i32;
+    fn read_to_string_validates_utf8() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-        let file_name1 = String::from("file.txt");
-        let file_name2 = String::from("file_link.txt");
+                fs.write(fd, "grüße".as_bytes()).unwrap();
 
-        let file_fd = create_test_file(&mut fs, root_fd as Fd, &file_name1);
+                assert_eq!(fs.read_to_string(fd, None).unwrap(), "grüße");
+                assert_eq!(fs.read_to_string(fd, Some(2)).unwrap(), "gr");
+                assert_eq!(fs.tell(fd).unwrap(), 7);
 
-        let root_node = fs.storage.as_ref().root_node();
-        let node1 = find_node(
-            root_node,
-            &file_name1,
-            &mut fs.names_cache,
-            fs.storage.as_ref(),
-        )
-        .unwrap();
+                // the limit cuts "ü" in half
+                assert_eq!(
+                    fs.read_to_string(fd, Some(3)),
+                    Err(Error::IllegalByteSequence)
+                );
 
-        // test seek and tell
-        let position = fs.tell(file_fd).unwrap();
+                fs.write(fd, &[0xFF, 0xFE]).unwrap();
+                assert_eq!(fs.read_to_string(fd, None), Err(Error::IllegalByteSequence));
 
-        assert_eq!(position, 32);
+                fs.close(fd).unwrap();
+            }
+        }
+    }
 
-        fs.seek(file_fd, 10, crate::fs::Whence::SET).unwrap();
+    #[test]
+    fn with_file_bytes_parses_header() {
+        #[derive(Debug, PartialEq)]
+        struct Header {
+            magic: [u8; 4],
+            version: u32,
+            count: u64,
+        }
 
-        let position_after_seek = fs.tell(file_fd).unwrap();
+        fn parse(bytes: &[u8]) -> Option<Header> {
+            if bytes.len() < 16 {
+                return None;
+            }
 
-        assert_eq!(position_after_seek, 10);
+            Some(Header {
+                magic: bytes[0..4].try_into().unwrap(),
+                version: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+                count: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            })
+        }
 
-        let mut buf_to_read1 = String::from("...............");
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-        let bytes_read = fs
-            .read(file_fd, unsafe { buf_to_read1.as_bytes_mut() })
-            .unwrap();
+                let mut data = vec![0u8; 100];
+                data.extend_from_slice(b"HDR1");
+                data.extend_from_slice(&7u32.to_le_bytes());
+                data.extend_from_slice(&123456789u64.to_le_bytes());
+                fs.write(fd, &data).unwrap();
+
+                let header = fs.with_file_bytes(fd, 100, 16, parse).unwrap();
+                assert_eq!(
+                    header,
+                    Some(Header {
+                        magic: *b"HDR1",
+                        version: 7,
+                        count: 123456789,
+                    })
+                );
 
-        assert_eq!(bytes_read, 15);
-        assert_eq!(buf_to_read1, "sample text.123");
+                // the cursor is not moved
+                assert_eq!(fs.tell(fd).unwrap(), 116);
 
-        // create link
-        fs.create_hard_link(dir, &file_name1, dir, &file_name2)
-            .unwrap();
+                // the region is cut at the end of file
+                let len = fs
+                    .with_file_bytes(fd, 110, 16, |bytes| bytes.len())
+                    .unwrap();
+                assert_eq!(len, 6);
+                assert_eq!(fs.with_file_bytes(fd, 110, 16, parse).unwrap(), None);
 
-        let node2 = find_node(
-            root_node,
-            &file_name2,
-            &mut fs.names_cache,
-            fs.storage.as_ref(),
-        )
-        .unwrap();
+                // a huge length does not allocate past the end of file
+                let len = fs
+                    .with_file_bytes(fd, 100, FileSize::MAX, |bytes| bytes.len())
+                    .unwrap();
+                assert_eq!(len, 16);
+                let len = fs
+                    .with_file_bytes(fd, 200, FileSize::MAX, |bytes| bytes.len())
+                    .unwrap();
+                assert_eq!(len, 0);
 
-        assert_eq!(node1, node2);
+                fs.close(fd).unwrap();
+            }
+        }
+    }
 
-        let link_file_fd = fs
-            .open_or_create(
-                dir,
-                "file_link.txt",
-                FdStat::default(),
-                OpenFlags::empty(),
-                0,
-            )
-            .unwrap();
+    #[test]
+    fn hard_linked_nodes_are_listed() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        fs.seek(link_file_fd, 10, crate::fs::Whence::SET).unwrap();
+            let fd = fs
+                .create_file(root_fd, "file.txt", FdStat::default(), 0)
+                .unwrap();
+            let node = fs.metadata(fd).unwrap().node;
 
-        let position_link = fs.tell(link_file_fd).unwrap();
+            let other_fd = fs
+                .create_file(root_fd, "other.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-        assert_eq!(position_link, 10);
+            assert!(fs.hard_linked_nodes().unwrap().is_empty());
 
-        let mut buf_to_read1 = String::from("................");
+            fs.create_hard_link(root_fd, "file.txt", root_fd, "link1.txt")
+                .unwrap();
+            fs.create_hard_link(root_fd, "file.txt", root_fd, "dir/link2.txt")
+                .unwrap();
 
-        let bytes_read = fs
-            .read(link_file_fd, unsafe { buf_to_read1.as_bytes_mut() })
-            .unwrap();
+            assert_eq!(fs.hard_linked_nodes().unwrap(), vec![(node, 3)]);
 
-        assert_eq!(bytes_read, 16);
+            fs.create_hard_link(root_fd, "other.txt", root_fd, "dir/other.txt")
+                .unwrap();
+            let other = fs.metadata(other_fd).unwrap().node;
 
-        assert_eq!(buf_to_read1, "sample text.1234");
+            assert_eq!(fs.hard_linked_nodes().unwrap(), vec![(node, 3), (other, 2)]);
+        }
     }
 
     #[test]
-    fn renaming_folder_with_contents() {
-        let mut fs = test_fs();
-        let root_fd = fs.root_fd();
+    fn filename_normalization_nfc() {
+        // "é" as a single code point and as "e" followed by a combining acute accent
+        let nfc = "caf\u{e9}.txt";
+        let nfd = "cafe\u{301}.txt";
 
-        let file_name = String::from("dir1/dir2/file.txt");
-        create_test_file(&mut fs, root_fd as Fd, &file_name);
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        fs.rename(root_fd, "dir1/dir2", root_fd, "dir2").unwrap();
+            // byte-exact names by default
+            let fd = fs.create_file(root_fd, nfd, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+            let res = fs.open_or_create(root_fd, nfc, FdStat::default(), OpenFlags::empty(), 0);
+            assert_eq!(res, Err(Error::NotFound));
 
-        let file_fd = fs
-            .open_or_create(
-                root_fd,
-                "dir2/file.txt",
-                FdStat::default(),
-                OpenFlags::empty(),
-                0,
-            )
-            .unwrap();
+            fs.storage.set_filename_normalization(Normalization::NFC);
 
-        fs.seek(file_fd, 10, crate::fs::Whence::SET).unwrap();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            let fd = fs.create_file(dir_fd, nfd, FdStat::default(), 0).unwrap();
+            fs.write(fd, b"coffee").unwrap();
+            fs.close(fd).unwrap();
 
-        let mut buf_to_read1 = String::from("................");
+            let fd = fs
+                .open_or_create(dir_fd, nfc, FdStat::default(), OpenFlags::empty(), 0)
+                .unwrap();
+            let mut buf = [0u8; 6];
+            fs.read(fd, &mut buf).unwrap();
+            assert_eq!(&buf, b"coffee");
+            fs.close(fd).unwrap();
 
-        let bytes_read = fs
-            .read(file_fd, unsafe { buf_to_read1.as_bytes_mut() })
-            .unwrap();
+            // both forms refer to the same entry
+            let res = fs.create_file(dir_fd, nfc, FdStat::default(), 0);
+            assert_eq!(res.unwrap_err(), Error::FileAlreadyExists);
 
-        assert_eq!(bytes_read, 16);
+            // the name is stored in the composed form
+            let names = fs.read_dir_reverse(dir_fd).unwrap();
+            assert_eq!(names.len(), 1);
+            assert_eq!(names[0].1, nfc);
 
-        assert_eq!(buf_to_read1, "sample text.1234");
+            fs.remove_file(dir_fd, nfc).unwrap();
+            assert!(fs.read_dir_reverse(dir_fd).unwrap().is_empty());
+        }
     }
 
     #[test]
-    fn write_and_read_25_files() {
-        for mut fs in test_fs_setups("auto/my_file_6.txt") {
+    fn trim_trailing_space_in_file_names() {
+        for mut fs in test_fs_setups("") {
             let root_fd = fs.root_fd();
-            const SIZE_OF_FILE: usize = 1_000_000;
 
-            // write files
-            let dir_name = "auto";
-            let file_count: u8 = 25;
+            // byte-exact names by default
+            let fd = fs
+                .create_file(root_fd, "file ", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+            let res = fs.open_or_create(root_fd, "file", FdStat::default(), OpenFlags::empty(), 0);
+            assert_eq!(res, Err(Error::NotFound));
 
-            for i in 0..file_count {
-                let filename = format!("{}/my_file_{}.txt", dir_name, i);
-                let content = format!("{i}");
-                let times = SIZE_OF_FILE / content.len();
+            fs.storage.set_trim_trailing_space(true);
 
-                write_text_file(&mut fs, root_fd, filename.as_str(), content.as_str(), times)
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            let fd = fs
+                .create_file(dir_fd, "file ", FdStat::default(), 0)
+                .unwrap();
+            fs.write(fd, b"abc").unwrap();
+            fs.close(fd).unwrap();
+
+            for name in ["file", "file ", "file. ."] {
+                let fd = fs
+                    .open_or_create(dir_fd, name, FdStat::default(), OpenFlags::empty(), 0)
                     .unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().size, 3);
+                fs.close(fd).unwrap();
             }
 
-            // read files
-            for i in 0..file_count {
-                let filename = format!("{}/my_file_{}.txt", dir_name, i);
-                let expected_content = format!("{i}{i}{i}");
-
-                let text_read = read_text_file(
-                    &mut fs,
-                    root_fd,
-                    filename.as_str(),
-                    0,
-                    expected_content.len(),
-                );
-
-                assert_eq!(expected_content, text_read);
-            }
+            // the name is stored trimmed
+            assert_eq!(fs.list_files(dir_fd).unwrap(), vec!["file"]);
         }
-
-        // This test should not crash with an error
     }
 
     #[test]
-    fn empty_path_support() {
-        let mut fs = test_fs();
+    fn file_name_policies_persist_over_upgrade() {
+        let memory = new_vector_memory();
+        let nfd = "cafe\u{301}.txt";
+
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
         let root_fd = fs.root_fd();
+        fs.storage.set_filename_normalization(Normalization::NFC);
+        fs.storage.set_trim_trailing_space(true);
 
-        write_text_file(&mut fs, root_fd, "f1/f2/text.txt", "content123", 100).unwrap();
+        let fd = fs.create_file(root_fd, nfd, FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+        drop(fs);
 
-        let content = read_text_file(&mut fs, root_fd, "f1/f2/text.txt", 7, 10);
-        assert_eq!(content, "123content");
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
+        let root_fd = fs.root_fd();
 
-        let content = read_text_file(&mut fs, root_fd, "f1//f2/text.txt", 6, 10);
-        assert_eq!(content, "t123conten");
+        assert_eq!(fs.storage.filename_normalization(), Normalization::NFC);
+        assert!(fs.storage.trim_trailing_space());
 
-        let content = read_text_file(&mut fs, root_fd, "/f1//f2/text.txt", 5, 10);
-        assert_eq!(content, "nt123conte");
+        // the decomposed name still finds the stored composed name
+        let fd = fs
+            .open_or_create(
+                root_fd,
+                "cafe\u{301}.txt. ",
+                FdStat::default(),
+                OpenFlags::empty(),
+                0,
+            )
+            .unwrap();
+        fs.close(fd).unwrap();
+    }
 
-        write_text_file(&mut fs, root_fd, "text.txt", "abc", 100).unwrap();
+    #[test]
+    fn sorted_directories_list_by_name() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-        let content = read_text_file(&mut fs, root_fd, "text.txt", 0, 6);
-        assert_eq!(content, "abcabc");
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+            fs.set_sorted_entries(dir_fd, true).unwrap();
 
-        let content = read_text_file(&mut fs, root_fd, "/text.txt", 0, 6);
-        assert_eq!(content, "abcabc");
+            for name in [
+                "m.txt", "c.txt", "x.txt", "a.txt", "q.txt", "b.txt", "z.txt",
+            ] {
+                let fd = fs.create_file(dir_fd, name, FdStat::default(), 0).unwrap();
+                fs.close(fd).unwrap();
+            }
 
-        let content = read_text_file(&mut fs, root_fd, "///////text.txt", 0, 6);
-        assert_eq!(content, "abcabc");
+            fs.remove_file(dir_fd, "q.txt").unwrap();
+            fs.rename(dir_fd, "m.txt", dir_fd, "d.txt").unwrap();
+            let fd = fs
+                .create_file(dir_fd, "n.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
 
-        // This test should not crash with an error
+            let expected = vec![
+                "a.txt", "b.txt", "c.txt", "d.txt", "n.txt", "x.txt", "z.txt",
+            ];
+            assert_eq!(list_files(&mut fs, "dir"), expected);
+
+            // the backward links are kept in order as well
+            let mut reverse: Vec<String> = fs
+                .read_dir_reverse(dir_fd)
+                .unwrap()
+                .into_iter()
+                .map(|(_, name)| name)
+                .collect();
+            reverse.reverse();
+            assert_eq!(reverse, expected);
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 7);
+        }
     }
 
     #[test]
-    fn writing_into_mounted_memory() {
-        let memory: VectorMemory = new_vector_memory();
-
-        let mut fs = test_fs();
-
-        let root_fd = fs.root_fd();
-
-        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
-            .unwrap();
+    fn sorted_entries_can_be_disabled_mid_folder() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-        let content = "ABCDEFG123";
+            fs.set_sorted_entries(dir_fd, true).unwrap();
+            for name in ["b.txt", "a.txt"] {
+                let fd = fs.create_file(dir_fd, name, FdStat::default(), 0).unwrap();
+                fs.close(fd).unwrap();
+            }
 
-        write_text_file(&mut fs, root_fd, "test.txt", content, 1).unwrap();
+            // the appended entry does not take the index of an entry inserted in the name order
+            fs.set_sorted_entries(dir_fd, false).unwrap();
+            let fd = fs
+                .create_file(dir_fd, "c.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
 
-        let mut buf = [0u8; 100];
+            assert_eq!(list_files(&mut fs, "dir"), vec!["a.txt", "b.txt", "c.txt"]);
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 3);
 
-        memory.read(0, &mut buf);
+            fs.set_sorted_entries(dir_fd, true).unwrap();
+            let fd = fs
+                .create_file(dir_fd, "ab.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
 
-        println!("{:?}", buf);
+            assert_eq!(
+                list_files(&mut fs, "dir"),
+                vec!["a.txt", "ab.txt", "b.txt", "c.txt"]
+            );
+        }
     }
 
     #[test]
-    fn reading_mounted_memory_after_upgrade() {
-        let memory_manager = MemoryManager::init(new_vector_memory());
-        let memory = memory_manager.get(MemoryId::new(1));
+    fn sorted_entries_persist_over_upgrade() {
+        let memory = new_vector_memory();
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory.clone()))).unwrap();
+        let root_fd = fs.root_fd();
 
-        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
-        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
-        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
-            .unwrap();
+        fs.set_sorted_entries(root_fd, true).unwrap();
+        fs.create_dir(root_fd, "b", FdStat::default(), 0).unwrap();
 
+        // imitate canister upgrade
+        let mut fs = FileSystem::new(Box::new(StableStorage::new(memory))).unwrap();
         let root_fd = fs.root_fd();
-        let content = "ABCDEFG123";
-        write_text_file(&mut fs, root_fd, "test.txt", content, 2).unwrap();
 
-        // imitate canister upgrade (we keep the memory manager but recreate the file system with the same virtual memories)
-        let storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
-        let mut fs = FileSystem::new(Box::new(storage)).unwrap();
-        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
-            .unwrap();
-        let root_fd = fs.root_fd();
+        fs.create_dir(root_fd, "a", FdStat::default(), 0).unwrap();
+        assert_eq!(list_files(&mut fs, ""), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn dir_clock_updates_parent_modified_time() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
+
+            // without a clock the folder time is left alone
+            let fd = fs
+                .create_file(dir_fd, "a.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+            assert_eq!(fs.metadata(dir_fd).unwrap().times.modified, 0);
 
-        let content = read_text_file(&mut fs, root_fd, "test.txt", 0, 100);
+            let ticks = std::rc::Rc::new(std::cell::Cell::new(100u64));
+            let clock_ticks = ticks.clone();
+            fs.set_dir_clock(Some(Box::new(move || {
+                clock_ticks.set(clock_ticks.get() + 1);
+                clock_ticks.get()
+            })));
 
-        assert_eq!(content, "ABCDEFG123ABCDEFG123");
+            let fd = fs
+                .create_file(dir_fd, "b.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+            let created = fs.metadata(dir_fd).unwrap().times.modified;
+            assert!(created > 100);
+
+            fs.remove_file(dir_fd, "a.txt").unwrap();
+            let removed = fs.metadata(dir_fd).unwrap().times.modified;
+            assert!(removed > created);
+
+            fs.set_dir_clock(None);
+            fs.remove_file(dir_fd, "b.txt").unwrap();
+            assert_eq!(fs.metadata(dir_fd).unwrap().times.modified, removed);
+        }
     }
 
     #[test]
-    fn deleting_mounted_file_fails() {
-        let memory: VectorMemory = new_vector_memory();
+    fn read_ranges_matches_read_vec_with_offset() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            for filename in ["test.txt", "virtual.txt"] {
+                let root_fd = fs.root_fd();
+                let fd = fs
+                    .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
 
-        let mut fs = test_fs();
+                let content: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+                fs.write(fd, &content).unwrap();
 
-        let root_fd = fs.root_fd();
+                let ranges = [(150_000, 5000), (10, 100), (70_000, 40_000), (199_990, 100)];
 
-        fs.mount_memory_file("test.txt", Box::new(memory.clone()))
-            .unwrap();
+                let result = fs.read_ranges(fd, &ranges).unwrap();
+                assert_eq!(result.len(), ranges.len());
 
-        let res = fs.remove_file(root_fd, "test.txt");
+                for ((offset, len), bytes) in ranges.iter().zip(result.iter()) {
+                    let mut expected = vec![0u8; *len as usize];
+                    let dst = [DstBuf {
+                        buf: expected.as_mut_ptr(),
+                        len: expected.len(),
+                    }];
+                    let read = fs.read_vec_with_offset(fd, &dst, *offset).unwrap();
+                    expected.truncate(read as usize);
 
-        assert!(
-            res == Err(Error::CannotRemoveMountedMemoryFile),
-            "Deleting a mounted file should not be allowed!"
-        );
+                    assert_eq!(bytes, &expected);
+                }
 
-        // check the dir entry still exists after deletion
-        let files = list_files(&mut fs, "");
+                assert_eq!(result[3].len(), 10);
+                assert_eq!(fs.tell(fd).unwrap(), 200_000);
 
-        assert_eq!(files[0], "test.txt".to_string());
+                // huge lengths are cut at the end of file
+                let result = fs
+                    .read_ranges(fd, &[(199_000, FileSize::MAX), (300_000, FileSize::MAX)])
+                    .unwrap();
+                assert_eq!(result[0], content[199_000..]);
+                assert!(result[1].is_empty());
+
+                fs.close(fd).unwrap();
+            }
+        }
     }
 
     #[test]
-    fn mounted_memory_store_and_init_roundtrip() {
-        for mut fs in test_fs_setups("") {
-            let memory1: VectorMemory = new_vector_memory();
-            let memory2: VectorMemory = new_vector_memory();
-
-            let file_name = "test.txt";
+    fn close_nosync_before_remove() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
 
-            fs.mount_memory_file(file_name, Box::new(memory1.clone()))
+            let fd = fs
+                .create_file(root_fd, "temp.txt", FdStat::default(), 0)
                 .unwrap();
+            fs.write(fd, b"scratch data").unwrap();
 
-            let content = "ABCDEFG123";
-            let len = content.len();
-            let count = 1000;
+            // an open file cannot be removed
+            assert_eq!(
+                fs.remove_file(root_fd, "temp.txt"),
+                Err(Error::CannotRemoveOpenedNode)
+            );
 
-            memory1.grow(5);
+            fs.close_nosync(fd).unwrap();
+            assert_eq!(fs.close_nosync(fd), Err(Error::NotFound));
+            assert!(fs.tell(fd).is_err());
 
-            // fill up memory with some data
-            for i in 0..count {
-                memory1.write(i as u64 * len as u64, content.as_bytes());
-            }
+            fs.remove_file(root_fd, "temp.txt").unwrap();
+            assert!(!list_files(&mut fs, "").contains(&"temp.txt".to_string()));
 
+            // the regular close still flushes, the written size is visible after reopening
             let fd = fs
                 .open_or_create(
-                    fs.root_fd,
-                    file_name,
+                    root_fd,
+                    "virtual.txt",
                     FdStat::default(),
-                    OpenFlags::empty(),
+                    OpenFlags::CREATE,
                     0,
                 )
                 .unwrap();
-            let mut metadata = fs.metadata(fd).unwrap();
-            metadata.size = len as FileSize * count as FileSize;
-            fs.set_metadata(fd, metadata).unwrap();
+            fs.write(fd, b"kept").unwrap();
             fs.close(fd).unwrap();
 
-            // store memory into a file
-            fs.store_memory_file(file_name).unwrap();
-
-            fs.unmount_memory_file(file_name).unwrap();
-
-            fs.mount_memory_file(file_name, Box::new(memory2.clone()))
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "virtual.txt",
+                    FdStat::default(),
+                    OpenFlags::empty(),
+                    0,
+                )
                 .unwrap();
+            assert_eq!(fs.metadata(fd).unwrap().size, 4);
+            fs.close(fd).unwrap();
+        }
+    }
 
-            // init new memory into a file
-            fs.init_memory_file(file_name).unwrap();
+    #[test]
+    fn is_within_detects_escaping_paths() {
+        let mut fs = test_fs();
+        let root_fd = fs.root_fd();
 
-            let mut buf1 = [0u8; 10];
-            let mut buf2 = [0u8; 10];
+        let sandbox = fs
+            .create_dir(root_fd, "sandbox", FdStat::default(), 0)
+            .unwrap();
 
-            for i in 0..count {
-                memory1.read(i as u64 * len as u64, &mut buf1);
-                memory2.read(i as u64 * len as u64, &mut buf2);
+        assert!(fs.is_within(sandbox, "a.txt").unwrap());
+        assert!(fs.is_within(sandbox, "./dir/../a.txt").unwrap());
+        assert!(fs.is_within(sandbox, "dir/sub/../../b.txt").unwrap());
+        assert!(fs.is_within(sandbox, "").unwrap());
 
-                assert_eq!(buf1, buf2);
-            }
-        }
+        assert!(!fs.is_within(sandbox, "..").unwrap());
+        assert!(!fs.is_within(sandbox, "../sandbox/a.txt").unwrap());
+        assert!(!fs.is_within(sandbox, "dir/../../other.txt").unwrap());
+        assert!(!fs.is_within(sandbox, "./../../etc").unwrap());
+
+        let fd = fs
+            .create_file(sandbox, "file.txt", FdStat::default(), 0)
+            .unwrap();
+        assert!(fs.is_within(fd, "a.txt").is_err());
     }
 
     #[test]
-    fn writing_from_different_file_descriptors() {
-        for mut fs in test_fs_setups("f1/f2/text.txt") {
+    fn little_endian_integers_round_trip() {
+        for mut fs in test_fs_setups("virtual.txt") {
             let root_fd = fs.root_fd();
-
-            let fd1 = fs
+            let fd = fs
                 .open_or_create(
                     root_fd,
-                    "f1/f2/text.txt",
+                    "virtual.txt",
                     FdStat::default(),
                     OpenFlags::CREATE,
-                    40,
+                    0,
                 )
                 .unwrap();
-            let fd2 = fs
-                .open_or_create(
-                    root_fd,
-                    "f1//f2/text.txt",
-                    FdStat::default(),
-                    OpenFlags::CREATE,
-                    44,
+
+            let chunk_size = fs.storage.chunk_size() as FileSize;
+
+            // the last offsets cross a chunk boundary
+            for offset in [0, 3, 17, chunk_size - 1, chunk_size - 3, 3 * chunk_size - 7] {
+                fs.write_u16_le(fd, offset, 0xbeef).unwrap();
+                assert_eq!(fs.read_u16_le(fd, offset).unwrap(), 0xbeef);
+
+                fs.write_u32_le(fd, offset, 0xdead_beef).unwrap();
+                assert_eq!(fs.read_u32_le(fd, offset).unwrap(), 0xdead_beef);
+
+                fs.write_u64_le(fd, offset, 0x0123_4567_89ab_cdef).unwrap();
+                assert_eq!(fs.read_u64_le(fd, offset).unwrap(), 0x0123_4567_89ab_cdef);
+
+                let mut bytes = [0u8; 8];
+                fs.read_vec_with_offset(
+                    fd,
+                    &[DstBuf {
+                        buf: bytes.as_mut_ptr(),
+                        len: 8,
+                    }],
+                    offset,
                 )
                 .unwrap();
+                assert_eq!(bytes, 0x0123_4567_89ab_cdef_u64.to_le_bytes());
+            }
 
-            write_text_fd(&mut fs, fd1, "abc", 1).unwrap();
-            write_text_fd(&mut fs, fd2, "123", 1).unwrap();
-            write_text_fd(&mut fs, fd1, "xyz", 1).unwrap();
+            // the cursor is not moved
+            assert_eq!(fs.tell(fd).unwrap(), 0);
 
-            let content = read_text_file(&mut fs, root_fd, "/f1/f2/text.txt", 0, 9);
+            let size = fs.metadata(fd).unwrap().size;
+            assert_eq!(fs.read_u32_le(fd, size - 2), Err(Error::UnexpectedEof));
 
-            assert_eq!("123xyz", content);
+            fs.close(fd).unwrap();
         }
     }
 
     #[test]
-    fn write_into_empty_filename_fails() {
+    fn bulk_create_scope_stores_folder_on_drop() {
         for mut fs in test_fs_setups("") {
             let root_fd = fs.root_fd();
-            let res = write_text_file(&mut fs, root_fd, "", "content123", 100);
-            assert!(res.is_err());
-        }
-    }
+            let dir_fd = fs
+                .create_dir(root_fd, "bulk", FdStat::default(), 0)
+                .unwrap();
 
-    fn create_file_with_size(filename: &str, size: FileSize, fs: &mut FileSystem) -> Fd {
-        let fd = fs
-            .open_or_create(
-                fs.root_fd,
-                filename,
-                FdStat::default(),
-                OpenFlags::CREATE,
-                12,
-            )
-            .unwrap();
-        let mut meta = fs.metadata(fd).unwrap();
-        meta.size = size;
+            {
+                let mut scope = fs.bulk_create_scope(dir_fd).unwrap();
 
-        fs.set_metadata(fd, meta).unwrap();
+                for i in 0..100 {
+                    let fd = scope
+                        .create_file(dir_fd, &format!("file{i}.txt"), FdStat::default(), 0)
+                        .unwrap();
+                    scope.write(fd, b"data").unwrap();
+                    scope.close(fd).unwrap();
+                }
 
-        fd
+                assert_eq!(scope.dir_entry_count(dir_fd).unwrap(), 100);
+            }
+
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 100);
+
+            let entries = fs.read_dir(dir_fd, DirOrder::Insertion).unwrap();
+            assert_eq!(entries.len(), 100);
+            assert_eq!(entries.first().unwrap().prev_entry, None);
+            assert_eq!(entries.last().unwrap().next_entry, None);
+
+            let files = list_files(&mut fs, "bulk");
+            assert!(files.contains(&"file0.txt".to_string()));
+            assert!(files.contains(&"file99.txt".to_string()));
+        }
     }
 
-    // test sparse files
     #[test]
-    fn set_size_for_an_empty_file() {
-        let filename = "test.txt";
-
-        for mut fs in test_fs_setups(filename) {
+    fn verify_and_repair_dir_size() {
+        for mut fs in test_fs_setups("") {
             let root_fd = fs.root_fd();
-            let fd = create_file_with_size(filename, 15, &mut fs);
+            let dir_fd = fs.create_dir(root_fd, "dir", FdStat::default(), 0).unwrap();
 
-            let content = read_text_file(&mut fs, root_fd, filename, 0, 100);
+            for name in ["a.txt", "b.txt", "c.txt"] {
+                let fd = fs.create_file(dir_fd, name, FdStat::default(), 0).unwrap();
+                fs.close(fd).unwrap();
+            }
+            fs.remove_file(dir_fd, "b.txt").unwrap();
 
-            assert_eq!(content, "\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+            assert!(fs.verify_dir_size(dir_fd).unwrap());
+            assert!(fs.verify_dir_size(root_fd).unwrap());
 
-            write_text_at_offset(&mut fs, fd, "abc", 3, 3).unwrap();
+            // corrupt the stored entry count
+            let mut metadata = fs.metadata(dir_fd).unwrap();
+            metadata.size = 0;
+            fs.storage.put_metadata(metadata.node, metadata);
 
-            let content = read_text_file(&mut fs, root_fd, filename, 1, 100);
+            assert!(!fs.verify_dir_size(dir_fd).unwrap());
 
-            assert_eq!(content, "\0\0abcabcabc\0\0\0");
+            // once repaired, the folder is no longer treated as empty
+            assert_eq!(fs.repair_dir_size(dir_fd).unwrap(), 2);
+            assert!(fs.verify_dir_size(dir_fd).unwrap());
+            assert_eq!(fs.dir_entry_count(dir_fd).unwrap(), 2);
+            assert_eq!(fs.remove_dir(root_fd, "dir"), Err(Error::DirectoryNotEmpty));
         }
     }
 
     #[test]
-    fn create_file_missing_chunk_in_the_middle() {
-        let filename = "test.txt";
-
-        for mut fs in test_fs_setups(filename) {
-            let chunk_size = fs.storage.chunk_size();
-
+    fn create_temp_files_have_unique_names() {
+        for mut fs in test_fs_setups("") {
             let root_fd = fs.root_fd();
-            let fd = create_file_with_size(filename, chunk_size as FileSize * 2 + 500, &mut fs);
 
-            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
-
-            let vec = vec![0; chunk_size * 2 + 500];
-            let expected = String::from_utf8(vec).unwrap();
-
-            // expect all zeroes at first
-            assert_eq!(content, expected);
-
-            // write some text to the first chunk
-            write_text_at_offset(&mut fs, fd, "abc", 33, 3).unwrap();
+            // occupy the name the first temp file would get, the taken file uses up the next node
+            let taken = format!("tmp{}", fs.next_node_id() + 1);
+            let fd = fs
+                .create_file(root_fd, &taken, FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
 
-            // write some text to the 100th position of the third chunk
-            write_text_at_offset(&mut fs, fd, "abc", 33, chunk_size as FileSize * 2 + 100).unwrap();
+            let mut names = std::collections::BTreeSet::new();
 
-            // read what we have now
-            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+            for _ in 0..5 {
+                let (fd, name) = fs.create_temp(root_fd, "tmp", 0).unwrap();
+                assert!(name.starts_with("tmp"));
+                assert_ne!(name, taken);
 
-            let mut expected = vec![0u8; chunk_size * 2 + 500];
+                fs.write(fd, name.as_bytes()).unwrap();
+                fs.close(fd).unwrap();
 
-            let pattern = b"abc".repeat(33);
-            expected[3..3 + 99].copy_from_slice(&pattern[..]);
-            expected[chunk_size * 2 + 100..chunk_size * 2 + 100 + 99].copy_from_slice(&pattern[..]);
+                names.insert(name);
+            }
 
-            let expected = String::from_utf8(expected).unwrap();
+            assert_eq!(names.len(), 5);
 
-            assert_eq!(content, expected);
+            let files = list_files(&mut fs, "");
+            for name in &names {
+                assert!(files.contains(name));
+                assert_eq!(&read_text_file(&mut fs, root_fd, name, 0, 100), name);
+            }
         }
     }
 
     #[test]
-    fn iterate_file_only_middle_chunk_is_present() {
-        let filename = "test.txt";
+    fn set_all_times_at_once() {
+        for mut fs in test_fs_setups("virtual.txt") {
+            let root_fd = fs.root_fd();
 
-        for mut fs in test_fs_setups(filename) {
-            let chunk_size = fs.storage.chunk_size();
+            let fd = fs
+                .open_or_create(
+                    root_fd,
+                    "virtual.txt",
+                    FdStat::default(),
+                    OpenFlags::CREATE,
+                    10,
+                )
+                .unwrap();
+            let dir_fd = fs
+                .create_dir(root_fd, "dir", FdStat::default(), 10)
+                .unwrap();
 
-            let root_fd = fs.root_fd();
-            let fd = create_file_with_size(filename, chunk_size as FileSize * 2 + 500, &mut fs);
+            let times = Times {
+                accessed: 111,
+                modified: 222,
+                created: 333,
+            };
 
-            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+            for fd in [fd, dir_fd] {
+                fs.set_all_times(fd, times).unwrap();
+                assert_eq!(fs.metadata(fd).unwrap().times, times);
+            }
 
-            let vec = vec![0; chunk_size * 2 + 500];
+            fs.close(fd).unwrap();
 
-            let expected = String::from_utf8(vec).unwrap();
+            let meta = fs.open_metadata(root_fd, "virtual.txt").unwrap();
+            assert_eq!(meta.times, times);
+        }
+    }
 
-            assert_eq!(content, expected);
+    #[test]
+    fn ensure_mounted_capacity_grows_memory() {
+        for mut fs in [test_fs(), test_fs_transient()] {
+            let memory = new_vector_memory();
+            fs.mount_memory_file("mount.txt", Box::new(memory.clone()))
+                .unwrap();
 
-            write_text_at_offset(&mut fs, fd, "abc", 33, chunk_size as FileSize + 100).unwrap();
+            assert_eq!(memory.size(), 0);
 
-            let content = read_text_file(&mut fs, root_fd, filename, 0, chunk_size * 10);
+            let bytes = 3 * WASM_PAGE_SIZE_IN_BYTES + 10;
+            fs.ensure_mounted_capacity("mount.txt", bytes).unwrap();
 
-            let mut expected = vec![0u8; chunk_size * 2 + 500];
+            assert_eq!(memory.size(), 4);
+            assert!(memory.size() * WASM_PAGE_SIZE_IN_BYTES >= bytes);
 
-            let pattern = b"abc".repeat(33);
-            expected[chunk_size + 100..chunk_size + 100 + 99].copy_from_slice(&pattern[..]);
+            let meta = fs.open_metadata(fs.root_fd(), "mount.txt").unwrap();
+            assert_eq!(meta.size, 0);
 
-            let expected = String::from_utf8(expected).unwrap();
+            // a smaller capacity does not shrink or grow the memory
+            fs.ensure_mounted_capacity("mount.txt", 100).unwrap();
+            assert_eq!(memory.size(), 4);
 
-            assert_eq!(content, expected);
+            let fd = fs
+                .create_file(fs.root_fd(), "regular.txt", FdStat::default(), 0)
+                .unwrap();
+            fs.close(fd).unwrap();
+            assert_eq!(
+                fs.ensure_mounted_capacity("regular.txt", 100),
+                Err(Error::MemoryFileIsNotMounted)
+            );
         }
     }
 
     #[test]
-    fn filename_cached_on_open_or_create() {
-        let filename = "test.txt";
-
+    fn ensure_mounted_capacity_past_memory_cap_fails() {
         for mut fs in test_fs_setups("") {
-            let root_fd = fs.root_fd();
-            let fd = fs
-                .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 12)
+            let memory = CappedMemory {
+                inner: new_vector_memory(),
+                max_pages: 2,
+            };
+            fs.mount_memory_file("capped.txt", Box::new(memory.clone()))
                 .unwrap();
 
-            fs.close(fd).unwrap();
+            fs.ensure_mounted_capacity("capped.txt", 2 * WASM_PAGE_SIZE_IN_BYTES)
+                .unwrap();
+            assert_eq!(memory.size(), 2);
 
-            assert_eq!(fs.names_cache.get_nodes().len(), 1);
+            assert_eq!(
+                fs.ensure_mounted_capacity("capped.txt", 2 * WASM_PAGE_SIZE_IN_BYTES + 1),
+                Err(Error::OutOfMemory)
+            );
+            assert_eq!(memory.size(), 2);
         }
     }
 
     #[test]
-    fn deleted_file_cannot_be_found() {
-        let filename = "test.txt";
-
+    fn glob_finds_matching_paths() {
         for mut fs in test_fs_setups("") {
             let root_fd = fs.root_fd();
-            let fd = fs
-                .open_or_create(root_fd, filename, FdStat::default(), OpenFlags::CREATE, 12)
+
+            for path in [
+                "a.txt",
+                "b.md",
+                "docs/c.txt",
+                "docs/d.rs",
+                "docs/deep/e.txt",
+                "src/f.txt",
+            ] {
+                let fd = fs
+                    .open_or_create(root_fd, path, FdStat::default(), OpenFlags::CREATE, 0)
+                    .unwrap();
+                fs.close(fd).unwrap();
+            }
+
+            assert_eq!(fs.glob(root_fd, "*.txt").unwrap(), vec!["a.txt"]);
+            assert_eq!(fs.glob(root_fd, "?.md").unwrap(), vec!["b.md"]);
+
+            assert_eq!(
+                fs.glob(root_fd, "**/*.txt").unwrap(),
+                vec!["a.txt", "docs/c.txt", "docs/deep/e.txt", "src/f.txt"]
+            );
+            assert_eq!(
+                fs.glob(root_fd, "docs/**/*.txt").unwrap(),
+                vec!["docs/c.txt", "docs/deep/e.txt"]
+            );
+            assert_eq!(
+                fs.glob(root_fd, "*").unwrap(),
+                vec!["a.txt", "b.md", "docs", "src"]
+            );
+
+            // the paths are relative to the given folder
+            let docs_fd = fs
+                .open_or_create(root_fd, "docs", FdStat::default(), OpenFlags::DIRECTORY, 0)
                 .unwrap();
-            fs.close(fd).unwrap();
+            assert_eq!(fs.glob(docs_fd, "*.txt").unwrap(), vec!["c.txt"]);
+            assert!(fs.glob(docs_fd, "*.md").unwrap().is_empty());
+        }
+    }
 
-            fs.remove_file(root_fd, filename).unwrap();
+    #[test]
+    fn metadata_batch_per_node_results() {
+        for mut fs in test_fs_setups("") {
+            let root_fd = fs.root_fd();
 
-            assert_eq!(fs.names_cache.get_nodes().len(), 0);
+            let mut nodes = Vec::new();
+            for (i, name) in ["c.txt", "a.txt", "b.txt"].iter().enumerate() {
+                let fd = fs.create_file(root_fd, name, FdStat::default(), 0).unwrap();
+                fs.write(fd, &vec![1u8; i * 10]).unwrap();
+                nodes.push(fs.metadata(fd).unwrap().node);
+                fs.close(fd).unwrap();
+            }
 
-            // check we don't increase cache when the file is opened but not created
-            let fd2 =
-                fs.open_or_create(root_fd, filename, FdStat::default(), OpenFlags::empty(), 12);
+            let missing = fs.next_node_id() + 100;
+            let query = [nodes[2], missing, nodes[0], nodes[1], nodes[0]];
 
-            assert_eq!(fd2, Err(Error::NotFound));
-            assert_eq!(fs.names_cache.get_nodes().len(), 0);
+            let result = fs.metadata_batch(&query);
+            assert_eq!(result.len(), query.len());
+
+            for (node, meta) in query.iter().zip(result) {
+                if *node == missing {
+                    assert_eq!(meta, Err(Error::NotFound));
+                } else {
+                    assert_eq!(meta, fs.metadata_from_node(*node));
+                }
+            }
+
+            assert_eq!(fs.metadata_batch(&[nodes[2]])[0].as_ref().unwrap().size, 20);
         }
     }
 
@@ -1,5 +1,6 @@
 pub mod dir;
 pub mod fd;
 pub mod file;
+pub(crate) mod hash;
 pub(crate) mod structure_helpers;
 pub mod types;
@@ -12,7 +12,7 @@ use crate::{
 
 use super::{
     structure_helpers::{create_path, find_node, rm_dir_entry},
-    types::FdStat,
+    types::{DirOrder, FdStat},
 };
 
 #[derive(Clone, Debug)]
@@ -139,12 +139,55 @@ impl Dir {
     ) -> Result<DirEntry, Error> {
         storage.get_direntry(self.node, index)
     }
+
+    // Get all directory entries in the requested order.
+    pub fn entries(&self, order: DirOrder, storage: &dyn Storage) -> Result<Vec<DirEntry>, Error> {
+        let mut entries = Vec::new();
+
+        let mut next_index = storage.get_metadata(self.node)?.first_dir_entry;
+
+        while let Some(index) = next_index {
+            let entry = storage.get_direntry(self.node, index)?;
+            next_index = entry.next_entry;
+            entries.push(entry);
+        }
+
+        match order {
+            DirOrder::Insertion => {}
+            DirOrder::Node => entries.sort_by_key(|entry| entry.node),
+            DirOrder::Name => entries.sort_by(|a, b| {
+                a.name.bytes[..a.name.length as usize].cmp(&b.name.bytes[..b.name.length as usize])
+            }),
+        }
+
+        Ok(entries)
+    }
+
+    // Get all directory entries from the newest to the oldest, walking the list backwards.
+    pub fn entries_reverse(&self, storage: &dyn Storage) -> Result<Vec<DirEntry>, Error> {
+        let mut entries = Vec::new();
+
+        let mut prev_index = storage.get_metadata(self.node)?.last_dir_entry;
+
+        while let Some(index) = prev_index {
+            let entry = storage.get_direntry(self.node, index)?;
+            prev_index = entry.prev_entry;
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{error::Error, fs::OpenFlags, runtime::types::FdStat, test_utils::test_fs};
+    use crate::{
+        error::Error,
+        fs::OpenFlags,
+        runtime::types::{DirOrder, FdStat},
+        test_utils::test_fs,
+    };
 
     #[test]
     fn remove_middle_file() {
@@ -186,6 +229,42 @@ mod tests {
         assert_eq!(entry2.next_entry, None);
     }
 
+    #[test]
+    fn read_dir_by_name_is_independent_of_history() {
+        let mut fs = test_fs();
+        let dir = fs.root_fd();
+
+        for name in ["c.txt", "a.txt", "d.txt", "b.txt"] {
+            let fd = fs.create_file(dir, name, FdStat::default(), 0).unwrap();
+            fs.close(fd).unwrap();
+        }
+
+        fs.remove_file(dir, "a.txt").unwrap();
+        let fd = fs.rename(dir, "c.txt", dir, "a.txt").unwrap();
+        fs.close(fd).unwrap();
+        fs.remove_file(dir, "d.txt").unwrap();
+        let fd = fs.create_file(dir, "d.txt", FdStat::default(), 0).unwrap();
+        fs.close(fd).unwrap();
+
+        let names = |entries: Vec<crate::storage::types::DirEntry>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|e| {
+                    String::from_utf8(e.name.bytes[..e.name.length as usize].to_vec()).unwrap()
+                })
+                .collect()
+        };
+
+        let by_name = names(fs.read_dir(dir, DirOrder::Name).unwrap());
+        assert_eq!(by_name, vec!["a.txt", "b.txt", "d.txt"]);
+
+        let insertion = names(fs.read_dir(dir, DirOrder::Insertion).unwrap());
+        assert_eq!(insertion, vec!["b.txt", "a.txt", "d.txt"]);
+
+        let by_node = fs.read_dir(dir, DirOrder::Node).unwrap();
+        assert!(by_node.windows(2).all(|w| w[0].node < w[1].node));
+    }
+
     #[test]
     fn create_dir_file_creation_time() {
         let mut fs = test_fs();
@@ -1,4 +1,7 @@
-use std::collections::BTreeMap;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
 
 use crate::{
     error::Error,
@@ -10,6 +13,80 @@ const RESERVED_FD_COUNT: Fd = 3;
 
 pub type Fd = u32;
 
+// descriptor numbers above the root folder are shared by all file system instances of a thread,
+// each number is owned by one instance at a time, so a descriptor of another instance can be recognized.
+#[derive(Default)]
+struct FdOwners {
+    // the ID of the next created table.
+    next_instance: u64,
+    // the next never issued descriptor number.
+    next_fd: Fd,
+    // numbers released by the dropped tables, ready to reuse.
+    free: BTreeSet<Fd>,
+    // the owning table of every issued number.
+    owners: BTreeMap<Fd, u64>,
+}
+
+impl FdOwners {
+    // issue the lowest available number to a table.
+    fn issue(&mut self, instance: u64) -> Fd {
+        let fd = match self.free.pop_first() {
+            Some(fd) => fd,
+            None => {
+                self.next_fd = self.next_fd.max(RESERVED_FD_COUNT + 1);
+
+                // skip the numbers claimed by renumbering
+                while self.owners.contains_key(&self.next_fd) {
+                    self.next_fd += 1;
+                }
+
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                fd
+            }
+        };
+
+        self.owners.insert(fd, instance);
+        fd
+    }
+
+    // claim a number chosen by the caller, fails if it belongs to another table.
+    fn claim(&mut self, fd: Fd, instance: u64) -> Result<(), Error> {
+        if fd <= RESERVED_FD_COUNT {
+            return Ok(());
+        }
+
+        match self.owners.get(&fd) {
+            Some(owner) if *owner != instance => Err(Error::BadFileDescriptor),
+            Some(_) => Ok(()),
+            None => {
+                self.free.remove(&fd);
+                self.owners.insert(fd, instance);
+                Ok(())
+            }
+        }
+    }
+
+    // release all the numbers of a dropped table.
+    fn release(&mut self, instance: u64) {
+        let released: Vec<Fd> = self
+            .owners
+            .iter()
+            .filter(|(_, owner)| **owner == instance)
+            .map(|(fd, _)| *fd)
+            .collect();
+
+        for fd in released {
+            self.owners.remove(&fd);
+            self.free.insert(fd);
+        }
+    }
+}
+
+thread_local! {
+    static FD_OWNERS: RefCell<FdOwners> = RefCell::new(FdOwners::default());
+}
+
 pub enum FdEntry {
     File(File),
     Dir(Dir),
@@ -21,21 +98,68 @@ pub struct FdTable {
     table: BTreeMap<Fd, FdEntry>,
     // backward links to see how many file descriptors are currently pointing to any particular node.
     node_refcount: BTreeMap<Node, usize>,
-    // the next generated descriptor's ID (if there is nothing to reuse).
-    next_fd: Fd,
+    // the root folder descriptor was issued.
+    root_issued: bool,
     // freed file descriptors ready to reuse.
     free_fds: Vec<Fd>,
+    // file descriptors opened with the `OpenFlags::CLOSE_ON_BOUNDARY` flag.
+    boundary_fds: BTreeSet<Fd>,
+    // the ID of this table among the tables sharing the descriptor numbers.
+    instance: u64,
+    // maximum number of simultaneously open descriptors, None means no limit.
+    max_open: Option<usize>,
 }
 
 impl FdTable {
     // create a new file descriptor table.
     pub fn new() -> Self {
+        let instance = FD_OWNERS.with(|owners| {
+            let mut owners = owners.borrow_mut();
+            owners.next_instance += 1;
+            owners.next_instance
+        });
+
         Self {
             table: BTreeMap::default(),
             node_refcount: BTreeMap::default(),
-            next_fd: RESERVED_FD_COUNT,
+            root_issued: false,
             free_fds: vec![],
+            boundary_fds: BTreeSet::new(),
+            instance,
+            max_open: None,
+        }
+    }
+
+    // Check that the file descriptor was not issued by another file system instance.
+    // The root folder descriptor and the numbers not issued to anyone are always accepted.
+    pub fn check(&self, fd: Fd) -> Result<(), Error> {
+        let foreign = FD_OWNERS.with(|owners| {
+            owners
+                .borrow()
+                .owners
+                .get(&fd)
+                .is_some_and(|owner| *owner != self.instance)
+        });
+
+        if foreign {
+            return Err(Error::BadFileDescriptor);
+        }
+
+        Ok(())
+    }
+
+    // Limit the number of simultaneously open descriptors, None means no limit.
+    pub fn set_max_open(&mut self, limit: Option<usize>) {
+        self.max_open = limit;
+    }
+
+    // Check that one more descriptor can be opened without exceeding the limit.
+    pub fn check_open_limit(&self) -> Result<(), Error> {
+        if self.max_open.is_some_and(|limit| self.table.len() >= limit) {
+            return Err(Error::TooManyOpenFiles);
         }
+
+        Ok(())
     }
 
     // Get the map of node references.
@@ -60,6 +184,11 @@ impl FdTable {
         prev_entry
     }
 
+    // Get all currently open file descriptors.
+    pub fn fds(&self) -> Vec<Fd> {
+        self.table.keys().copied().collect()
+    }
+
     // Get an FdEntry for a given file descriptor.
     pub fn get(&self, fd: Fd) -> Option<&FdEntry> {
         self.table.get(&fd)
@@ -69,11 +198,12 @@ impl FdTable {
     pub fn open(&mut self, entry: FdEntry) -> Fd {
         let fd = match self.free_fds.pop() {
             Some(fd) => fd,
-            None => {
-                let fd = self.next_fd;
-                self.next_fd += 1;
-                fd
+            // the first descriptor (the root folder) keeps its well-known number
+            None if !self.root_issued => {
+                self.root_issued = true;
+                RESERVED_FD_COUNT
             }
+            None => FD_OWNERS.with(|owners| owners.borrow_mut().issue(self.instance)),
         };
 
         let prev = self.insert(fd, entry);
@@ -84,6 +214,10 @@ impl FdTable {
     // Reassign a file descriptor to a new number, the source descriptor is closed in the process.
     // If the destination descriptor is busy, it is closed in the process.
     pub fn renumber(&mut self, src: Fd, dst: Fd) -> Result<(), Error> {
+        self.check(src)?;
+        FD_OWNERS.with(|owners| owners.borrow_mut().claim(dst, self.instance))?;
+
+        let is_boundary = self.boundary_fds.contains(&src);
         let old_entry = self.close(src).ok_or(Error::NotFound)?;
 
         // quietly close the destination file descriptor
@@ -94,14 +228,32 @@ impl FdTable {
 
         self.insert(dst, old_entry);
 
+        // the boundary tag follows the descriptor
+        if is_boundary {
+            self.boundary_fds.insert(dst);
+        }
+
         Ok(())
     }
 
+    // Tag a file descriptor to be closed on the next boundary.
+    pub fn mark_close_on_boundary(&mut self, fd: Fd) {
+        if self.table.contains_key(&fd) {
+            self.boundary_fds.insert(fd);
+        }
+    }
+
+    // Get the list of file descriptors tagged to be closed on the next boundary.
+    pub fn boundary_fds(&self) -> Vec<Fd> {
+        self.boundary_fds.iter().copied().collect()
+    }
+
     // Close file descriptor.
     pub fn close(&mut self, fd: Fd) -> Option<FdEntry> {
         let entry = self.table.remove(&fd);
 
         if let Some(entry) = entry {
+            self.boundary_fds.remove(&fd);
             self.free_fds.push(fd);
             self.dec_node_refcount(&entry);
 
@@ -135,3 +287,9 @@ impl FdTable {
         }
     }
 }
+
+impl Drop for FdTable {
+    fn drop(&mut self) {
+        FD_OWNERS.with(|owners| owners.borrow_mut().release(self.instance));
+    }
+}
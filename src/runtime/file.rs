@@ -1,6 +1,6 @@
 use crate::{
     error::Error,
-    runtime::types::{FdFlags, FdStat, Whence},
+    runtime::types::{FdFlags, FdStat, FullReads, Whence},
     storage::{
         types::{FileSize, FileType, Node},
         Storage,
@@ -12,6 +12,9 @@ pub struct File {
     pub node: Node,
     pub cursor: FileSize,
     pub stat: FdStat,
+    pub full_reads: FullReads,
+    // writes only go to the end of the file, and the cursor cannot be moved before the end.
+    pub append_only: bool,
 }
 
 impl File {
@@ -31,7 +34,26 @@ impl File {
         } else {
             0
         };
-        Ok(Self { node, cursor, stat })
+        Ok(Self {
+            node,
+            cursor,
+            stat,
+            full_reads: FullReads::Off,
+            append_only: false,
+        })
+    }
+
+    // Check a read that returned `read_size` of the `len` requested bytes against the full reads mode.
+    pub fn check_full_read(&self, len: FileSize, read_size: FileSize) -> Result<(), Error> {
+        let short = read_size < len;
+
+        match self.full_reads {
+            FullReads::Off => Ok(()),
+            FullReads::ExceptLastPage if short && read_size == 0 => Err(Error::UnexpectedEof),
+            FullReads::ExceptLastPage => Ok(()),
+            FullReads::Strict if short => Err(Error::UnexpectedEof),
+            FullReads::Strict => Ok(()),
+        }
     }
 
     // Seek a position in a file for reading or writing.
@@ -69,6 +91,9 @@ impl File {
                 size - back
             }
         };
+        if self.append_only && position < size {
+            return Err(Error::PermissionDenied);
+        }
         self.cursor = position;
         Ok(self.cursor)
     }
@@ -95,6 +120,9 @@ impl File {
         buf: &[u8],
         storage: &mut dyn Storage,
     ) -> Result<FileSize, Error> {
+        if self.append_only {
+            self.cursor = storage.get_metadata(self.node)?.size;
+        }
         let written_size = self.write_with_offset(self.cursor, buf, storage)?;
         self.cursor += written_size;
         Ok(written_size)
@@ -123,6 +151,9 @@ impl File {
         buf: &[u8],
         storage: &mut dyn Storage,
     ) -> Result<FileSize, Error> {
+        if self.append_only && offset < storage.get_metadata(self.node)?.size {
+            return Err(Error::PermissionDenied);
+        }
         storage.write(self.node, offset, buf)
     }
 
@@ -0,0 +1,41 @@
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::runtime::types::HashAlgo;
+
+// Streaming hasher producing a 32-byte digest for any of the supported algorithms.
+pub(crate) enum FileHasher {
+    Sha256(Box<Sha256>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl FileHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha256 => FileHasher::Sha256(Box::new(Sha256::new())),
+            HashAlgo::Xxh3 => FileHasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+
+    // Feed the next portion of data into the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+            FileHasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    // Produce the final digest, shorter digests are padded with zeroes.
+    pub fn finalize(self) -> [u8; 32] {
+        let mut res = [0u8; 32];
+
+        match self {
+            FileHasher::Sha256(hasher) => res.copy_from_slice(&hasher.finalize()),
+            FileHasher::Xxh3(hasher) => {
+                res[0..16].copy_from_slice(&hasher.digest128().to_le_bytes());
+            }
+        }
+
+        res
+    }
+}
@@ -7,13 +7,21 @@ use crate::{
     storage::{
         types::{
             ChunkHandle, DirEntry, DirEntryIndex, FileChunkIndex, FileName, FileSize, FileType,
-            Metadata, Node, Times,
+            Metadata, Node, Times, MAX_FILE_CHUNK_COUNT, MAX_FILE_ENTRY_INDEX,
         },
         Storage,
     },
 };
 
-use std::collections::BTreeMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use unicode_normalization::UnicodeNormalization;
+
+use super::types::Normalization;
+
 #[derive(Debug)]
 struct EntryFindResult {
     node: Node,
@@ -89,6 +97,43 @@ pub fn find_node(
     }
 }
 
+// Find the folders on the way to the entry `path`, from the first path component down to the parent of the leaf.
+// Returns the node and stored name of each folder, the leaf itself must exist but is not included.
+pub fn find_ancestors(
+    parent_dir_node: Node,
+    path: &str,
+    storage: &dyn Storage,
+) -> Result<Vec<(Node, String)>, Error> {
+    let mut ancestors = Vec::new();
+    let mut cur_node = parent_dir_node;
+    // the last visited entry, it becomes an ancestor once there is a path component after it
+    let mut last: Option<(Node, String)> = None;
+
+    for part in path.split('/') {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+
+        if part == ".." {
+            return Err(Error::InvalidFileName);
+        }
+
+        if let Some(ancestor) = last.take() {
+            ancestors.push(ancestor);
+        }
+
+        let entry_index = find_entry_index(cur_node, part.as_bytes(), storage)?;
+        let entry = storage.get_direntry(cur_node, entry_index)?;
+        cur_node = entry.node;
+
+        // the stored name, it may differ from the path component after name normalization
+        let name = String::from_utf8_lossy(&entry.name.bytes[..entry.name.length as usize]);
+        last = Some((cur_node, name.into_owned()));
+    }
+
+    Ok(ancestors)
+}
+
 // Create a hard link to an existing node
 pub fn create_hard_link(
     parent_dir_node: Node,
@@ -112,6 +157,19 @@ pub fn create_hard_link(
     let mut metadata = storage.get_metadata(node)?;
     let ctime = metadata.times.created;
 
+    // a folder cannot be linked into itself or into one of its subfolders,
+    // renaming it within the same folder cannot create a cycle and does not need the subtree walk.
+    let same_folder = parent_dir_node == src_dir_node
+        && path_name_count(new_path) == 1
+        && path_name_count(src_path) == 1;
+
+    if metadata.file_type == FileType::Directory
+        && !same_folder
+        && path_leads_into(node, parent_dir_node, new_path, storage)?
+    {
+        return Err(Error::CyclicLink);
+    }
+
     //
     let (dir_node, leaf_name) = create_path(parent_dir_node, new_path, None, ctime, storage)?;
 
@@ -128,6 +186,164 @@ pub fn create_hard_link(
     Ok(())
 }
 
+// Count the names in a path, the empty and "." parts are skipped.
+fn path_name_count(path: &str) -> usize {
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .count()
+}
+
+// Check if the folder `dir` is the target folder or one of the existing folders on the `path` from it.
+fn path_leads_into(
+    dir: Node,
+    parent_dir_node: Node,
+    path: &str,
+    storage: &dyn Storage,
+) -> Result<bool, Error> {
+    if is_in_subtree(dir, parent_dir_node, storage)? {
+        return Ok(true);
+    }
+
+    let mut cur_node = parent_dir_node;
+
+    for part in path.split('/') {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+
+        let index = match find_entry_index(cur_node, part.as_bytes(), storage) {
+            Ok(index) => index,
+            Err(Error::NotFound) => break,
+            Err(err) => return Err(err),
+        };
+
+        cur_node = storage.get_direntry(cur_node, index)?.node;
+
+        if cur_node == dir {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Check if `node` is the folder `dir` itself or is located anywhere in its subtree.
+fn is_in_subtree(dir: Node, node: Node, storage: &dyn Storage) -> Result<bool, Error> {
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![dir];
+
+    while let Some(cur_dir) = stack.pop() {
+        if cur_dir == node {
+            return Ok(true);
+        }
+
+        if !visited.insert(cur_dir) {
+            continue;
+        }
+
+        let mut next_index = storage.get_metadata(cur_dir)?.first_dir_entry;
+
+        while let Some(index) = next_index {
+            let entry = storage.get_direntry(cur_dir, index)?;
+            next_index = entry.next_entry;
+
+            if storage.get_metadata(entry.node)?.file_type == FileType::Directory {
+                stack.push(entry.node);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+// Visit every entry of the folder tree below `dir`, each folder is descended only once even if it is linked several times.
+pub fn walk_tree(
+    dir: Node,
+    storage: &dyn Storage,
+    mut visit: impl FnMut(&DirEntry, &Metadata),
+) -> Result<(), Error> {
+    let mut visited = BTreeSet::from([dir]);
+    let mut stack = vec![dir];
+
+    while let Some(cur_dir) = stack.pop() {
+        let mut next_index = storage.get_metadata(cur_dir)?.first_dir_entry;
+
+        while let Some(index) = next_index {
+            let entry = storage.get_direntry(cur_dir, index)?;
+            next_index = entry.next_entry;
+
+            let metadata = storage.get_metadata(entry.node)?;
+
+            if metadata.file_type == FileType::Directory && visited.insert(entry.node) {
+                stack.push(entry.node);
+            }
+
+            visit(&entry, &metadata);
+        }
+    }
+
+    Ok(())
+}
+
+// Match a single name against a pattern part, `*` matches any sequence of characters and `?` any single character.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+fn glob_parts_match(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        // `**` spans any number of folders, including none
+        Some(&"**") => {
+            glob_parts_match(&pattern[1..], path)
+                || (!path.is_empty() && glob_parts_match(pattern, &path[1..]))
+        }
+        Some(part) => {
+            !path.is_empty()
+                && wildcard_match(
+                    &part.chars().collect::<Vec<_>>(),
+                    &path[0].chars().collect::<Vec<_>>(),
+                )
+                && glob_parts_match(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+// Check if a relative path matches a glob pattern with `*`, `?` and `**` (any number of folders).
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|p| !p.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+
+    glob_parts_match(&pattern, &path)
+}
+
+// Walk the folder tree from `root` and return the folders reachable through more than one parent link.
+pub fn find_multi_linked_dirs(root: Node, storage: &dyn Storage) -> Result<Vec<Node>, Error> {
+    // the root folder has no parent entry, any link to it is an extra one
+    let mut links = BTreeMap::from([(root, 1usize)]);
+
+    walk_tree(root, storage, |entry, metadata| {
+        if metadata.file_type == FileType::Directory {
+            *links.entry(entry.node).or_default() += 1;
+        }
+    })?;
+
+    Ok(links
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(node, _)| node)
+        .collect())
+}
+
 pub fn create_dir_entry(
     parent_dir_node: Node,
     entry_name: &[u8],
@@ -141,7 +357,8 @@ pub fn create_dir_entry(
 
     let node = storage.new_node();
 
-    let chunk_type = if entry_type == FileType::RegularFile {
+    // in adaptive mode the chunk type is decided on the first write
+    let chunk_type = if entry_type == FileType::RegularFile && !storage.adaptive_chunking() {
         Some(storage.chunk_type())
     } else {
         None
@@ -162,10 +379,17 @@ pub fn create_dir_entry(
             first_dir_entry: None,
             last_dir_entry: None,
             chunk_type,
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         },
     );
 
-    add_dir_entry(parent_dir_node, node, entry_name, storage)?;
+    // do not leave orphaned nodes if the entry could not be added
+    if let Err(err) = add_dir_entry(parent_dir_node, node, entry_name, storage) {
+        storage.rm_file(node)?;
+        return Err(err);
+    }
 
     Ok(node)
 }
@@ -267,6 +491,9 @@ pub fn find_entry_index(
     path_element: &[u8],
     storage: &dyn Storage,
 ) -> Result<DirEntryIndex, Error> {
+    let path_element = normalize_name(path_element, storage);
+    let path_element = path_element.as_ref();
+
     let mut next_index = storage.get_metadata(dir_entry_node)?.first_dir_entry;
 
     while let Some(index) = next_index {
@@ -284,6 +511,30 @@ pub fn find_entry_index(
     Err(Error::NotFound)
 }
 
+// Apply the file name normalization configured in the storage, names that are not valid UTF-8 are kept as they are.
+fn normalize_name<'a>(name: &'a [u8], storage: &dyn Storage) -> Cow<'a, [u8]> {
+    let name = match (storage.filename_normalization(), std::str::from_utf8(name)) {
+        (Normalization::NFC, Ok(name)) => Cow::Owned(name.nfc().collect::<String>().into_bytes()),
+        _ => Cow::Borrowed(name),
+    };
+
+    if !storage.trim_trailing_space() {
+        return name;
+    }
+
+    // names consisting only of spaces and dots, such as "." and "..", are kept
+    match name.iter().rposition(|b| *b != b' ' && *b != b'.') {
+        Some(last) if last + 1 < name.len() => match name {
+            Cow::Borrowed(name) => Cow::Borrowed(&name[..=last]),
+            Cow::Owned(mut name) => {
+                name.truncate(last + 1);
+                Cow::Owned(name)
+            }
+        },
+        _ => name,
+    }
+}
+
 //  Add new directory entry
 pub fn add_dir_entry(
     parent_dir_node: Node,
@@ -293,10 +544,25 @@ pub fn add_dir_entry(
 ) -> Result<(), Error> {
     let mut metadata = storage.get_metadata(parent_dir_node)?;
 
-    let name = FileName::new(entry_name)?;
+    if let Some(limit) = storage.max_dir_entries() {
+        if metadata.size >= limit {
+            return Err(Error::TooManyFiles);
+        }
+    }
+
+    let name = FileName::new(&normalize_name(entry_name, storage))?;
 
-    // start numbering with 1
-    let new_entry_index: DirEntryIndex = metadata.last_dir_entry.unwrap_or(0) + 1;
+    if metadata.sorted_dir_entries {
+        insert_sorted_dir_entry(parent_dir_node, new_node, name, &mut metadata, storage)?;
+
+        metadata.size += 1;
+        touch_dir_modified(&mut metadata, storage);
+        storage.put_metadata(parent_dir_node, metadata);
+
+        return Ok(());
+    }
+
+    let new_entry_index = new_dir_entry_index(&mut metadata)?;
 
     storage.put_direntry(
         parent_dir_node,
@@ -324,12 +590,106 @@ pub fn add_dir_entry(
         metadata.first_dir_entry = Some(new_entry_index);
     }
     metadata.size += 1;
+    touch_dir_modified(&mut metadata, storage);
 
     storage.put_metadata(parent_dir_node, metadata);
 
     Ok(())
 }
 
+// set the directory modification time from the storage clock, if one is set
+fn touch_dir_modified(metadata: &mut Metadata, storage: &dyn Storage) {
+    if let Some(now) = storage.dir_clock_time() {
+        metadata.times.modified = now;
+    }
+}
+
+// The index following `index`, fails with `Error::DirEntryLimitExceeded` after `MAX_FILE_ENTRY_INDEX`.
+fn next_entry_index(index: DirEntryIndex) -> Result<DirEntryIndex, Error> {
+    if index == MAX_FILE_ENTRY_INDEX {
+        return Err(Error::DirEntryLimitExceeded);
+    }
+
+    Ok(index + 1)
+}
+
+// Take the index for a new entry of the folder, both the appending and the sorted insertion take it here,
+// so the folder can switch between them without handing out an index twice.
+fn new_dir_entry_index(metadata: &mut Metadata) -> Result<DirEntryIndex, Error> {
+    // start numbering with 1
+    let max_index = metadata
+        .max_dir_entry
+        .or(metadata.last_dir_entry)
+        .unwrap_or(0);
+    let index = next_entry_index(max_index)?;
+
+    metadata.max_dir_entry = Some(index);
+
+    Ok(index)
+}
+
+// Insert a new entry in front of the first entry with a greater name, so that the entry list stays sorted by name.
+// The entry indices do not follow the list order then, the new entry gets an index above all existing ones.
+fn insert_sorted_dir_entry(
+    parent_dir_node: Node,
+    new_node: Node,
+    name: FileName,
+    metadata: &mut Metadata,
+    storage: &mut dyn Storage,
+) -> Result<(), Error> {
+    let new_name = &name.bytes[..name.length as usize];
+
+    let mut prev_entry = None;
+    let mut next_entry = None;
+
+    let mut next_index = metadata.first_dir_entry;
+
+    while let Some(index) = next_index {
+        let entry = storage.get_direntry(parent_dir_node, index)?;
+        next_index = entry.next_entry;
+
+        if &entry.name.bytes[..entry.name.length as usize] > new_name {
+            next_entry = Some(index);
+            break;
+        }
+
+        prev_entry = Some(index);
+    }
+
+    let new_entry_index = new_dir_entry_index(metadata)?;
+
+    storage.put_direntry(
+        parent_dir_node,
+        new_entry_index,
+        DirEntry {
+            node: new_node,
+            name,
+            next_entry,
+            prev_entry,
+        },
+    );
+
+    match prev_entry {
+        Some(index) => {
+            let mut entry = storage.get_direntry(parent_dir_node, index)?;
+            entry.next_entry = Some(new_entry_index);
+            storage.put_direntry(parent_dir_node, index, entry);
+        }
+        None => metadata.first_dir_entry = Some(new_entry_index),
+    }
+
+    match next_entry {
+        Some(index) => {
+            let mut entry = storage.get_direntry(parent_dir_node, index)?;
+            entry.prev_entry = Some(new_entry_index);
+            storage.put_direntry(parent_dir_node, index, entry);
+        }
+        None => metadata.last_dir_entry = Some(new_entry_index),
+    }
+
+    Ok(())
+}
+
 /// Remove the directory entry from the current directory by entry name.
 ///
 /// parent_dir_node Parent directory
@@ -415,6 +775,7 @@ pub fn rm_dir_entry(
 
     // dir entry size is reduced by one
     parent_dir_metadata.size -= 1;
+    touch_dir_modified(&mut parent_dir_metadata, storage);
 
     // update parent metadata
     storage.put_metadata(parent_dir_node, parent_dir_metadata);
@@ -439,6 +800,34 @@ pub fn grow_memory(memory: &dyn Memory, max_address: FileSize) {
     }
 }
 
+// Grow the memory like `grow_memory`, fails with `Error::OutOfMemory` if the memory cannot grow that far.
+pub fn try_grow_memory(memory: &dyn Memory, max_address: FileSize) -> Result<(), Error> {
+    let pages_required = max_address.div_ceil(WASM_PAGE_SIZE_IN_BYTES);
+
+    let cur_pages = memory.size();
+
+    if cur_pages < pages_required && memory.grow(pages_required - cur_pages) < 0 {
+        return Err(Error::OutOfMemory);
+    }
+
+    Ok(())
+}
+
+// Fill the memory range between `from` and `to` with zeroes. The pages grown later are zero-initialized,
+// so only the part within the current memory size is written.
+pub fn zero_memory(memory: &dyn Memory, from: FileSize, to: FileSize) {
+    let zeroes = [0u8; 4096];
+
+    let to = to.min(memory.size() * WASM_PAGE_SIZE_IN_BYTES);
+    let mut offset = from;
+
+    while offset < to {
+        let len = (to - offset).min(zeroes.len() as FileSize);
+        memory.write(offset, &zeroes[..len as usize]);
+        offset += len;
+    }
+}
+
 #[inline]
 pub fn read_obj<T: Sized>(memory: &dyn Memory, address: u64, obj: &mut T) {
     let obj_size = std::mem::size_of::<T>();
@@ -459,6 +848,39 @@ pub fn write_obj<T: Sized>(memory: &dyn Memory, address: u64, obj: &T) {
     memory.write(address, obj_bytes);
 }
 
+// Copy file contents starting from `offset` to the beginning of the file, the file size is not changed.
+pub fn copy_to_front(node: Node, offset: FileSize, storage: &mut dyn Storage) -> Result<(), Error> {
+    let size = storage.get_metadata(node)?.size;
+
+    let mut buf = vec![0u8; storage.chunk_size()];
+    let mut written: FileSize = 0;
+
+    while offset + written < size {
+        let to_read = (size - offset - written).min(buf.len() as FileSize) as usize;
+
+        let read_size = storage.read(node, offset + written, &mut buf[..to_read])?;
+
+        if read_size == 0 {
+            break;
+        }
+
+        storage.write(node, written, &buf[..read_size as usize])?;
+
+        written += read_size;
+    }
+
+    Ok(())
+}
+
+// Check that a file range ending at `end` fits into `MAX_FILE_CHUNK_COUNT` chunks of `chunk_size`.
+pub fn check_chunk_limit(end: FileSize, chunk_size: usize) -> Result<(), Error> {
+    if end > MAX_FILE_CHUNK_COUNT * chunk_size as FileSize {
+        return Err(Error::FileChunkLimitExceeded);
+    }
+
+    Ok(())
+}
+
 pub fn offset_to_file_chunk_index(offset: FileSize, chunk_size: usize) -> FileChunkIndex {
     (offset / chunk_size as FileSize) as FileChunkIndex
 }
@@ -470,7 +892,8 @@ pub fn file_chunk_index_to_offset(index: FileChunkIndex, chunk_size: usize) -> F
 pub fn get_chunk_infos(start: FileSize, end: FileSize, chunk_size: usize) -> Vec<ChunkHandle> {
     let mut result = vec![];
     let start_index = offset_to_file_chunk_index(start, chunk_size);
-    let end_index = offset_to_file_chunk_index(end, chunk_size);
+    // the chunk of the last byte, the chunk starting at `end` may be past the last chunk index
+    let end_index = offset_to_file_chunk_index(end.saturating_sub(1), chunk_size);
 
     for index in start_index..=end_index {
         let start_of_chunk = file_chunk_index_to_offset(index, chunk_size);
@@ -497,7 +920,7 @@ mod tests {
     use crate::{
         error::Error,
         filename_cache::FilenameCache,
-        runtime::structure_helpers::{create_path, find_node, get_chunk_infos},
+        runtime::structure_helpers::{create_path, find_node, get_chunk_infos, glob_match},
         storage::{
             stable::StableStorage,
             types::{ChunkHandle, FileChunkIndex, FileSize, FileType, FILE_CHUNK_SIZE_V1},
@@ -539,6 +962,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn glob_match_patterns() {
+        assert!(glob_match("*.txt", "a.txt"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("?.txt", "ab.txt"));
+        assert!(!glob_match("*.txt", "dir/a.txt"));
+        assert!(glob_match("dir/*", "dir/a.txt"));
+        assert!(glob_match("**/*.txt", "a.txt"));
+        assert!(glob_match("**/*.txt", "dir/sub/a.txt"));
+        assert!(glob_match("dir/**/a.*", "dir/a.md"));
+        assert!(glob_match("dir/**", "dir/sub/a.txt"));
+        assert!(!glob_match("dir/**/*.txt", "other/a.txt"));
+        assert!(glob_match("*", "dir"));
+    }
+
     #[test]
     fn get_chunk_infos_full() {
         let chunks = get_chunk_infos(0, 10 * FILE_CHUNK_SIZE_V1 as FileSize, FILE_CHUNK_SIZE_V1);
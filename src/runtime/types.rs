@@ -25,7 +25,7 @@ pub enum Whence {
     END,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChunkSize {
     CHUNK4K = 4096,
     CHUNK8K = 8192,
@@ -50,6 +50,85 @@ pub enum ChunkType {
     V2 = 2,
 }
 
+// The order of the directory entries returned by `FileSystem::read_dir`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DirOrder {
+    /// The order of the internal entry list, it changes after deletions and renames.
+    Insertion,
+    /// Sorted by node ID.
+    Node,
+    /// Sorted by the name bytes, independent of the directory history.
+    /// Use this order wherever reproducible output is required (exports, snapshots, checks).
+    Name,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Normalization {
+    /// File names are stored and compared byte by byte.
+    #[default]
+    None,
+    /// File names are converted to the Unicode canonical composition form (NFC) before storing and comparing.
+    NFC,
+}
+
+// Which reads of a file descriptor must fill the whole buffer, see `FileSystem::set_require_full_reads`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FullReads {
+    /// Reads return fewer bytes than requested when the file ends earlier.
+    #[default]
+    Off,
+    /// A read must fill the buffer unless it returns the final, shorter page of the file.
+    /// Reading at or past the end of the file fails.
+    ExceptLastPage,
+    /// Every read must fill the buffer.
+    Strict,
+}
+
+// Expected access pattern of a file range, see WASI `fd_advise`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    /// No specific access pattern.
+    Normal,
+    /// The range is read sequentially.
+    Sequential,
+    /// The range is read in random order.
+    Random,
+    /// The range is accessed soon, its chunk pointers are loaded into the cache.
+    WillNeed,
+    /// The range is not accessed in the near future, its cached chunk pointers are evicted.
+    DontNeed,
+    /// The range is accessed only once.
+    NoReuse,
+}
+
+// Result of a bounded removal step, see `FileSystem::remove_file_chunked`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemoveProgress {
+    /// The file is completely removed.
+    Done,
+    /// Some chunks are still left, call the removal again to continue.
+    Pending,
+}
+
+// A difference between two folders, see `FileSystem::diff`. The paths are relative to the compared folders.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// The file only exists in the second folder.
+    Added(String),
+    /// The file only exists in the first folder.
+    Removed(String),
+    /// The file exists in both folders, but its size or content differs.
+    Changed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgo {
+    /// SHA-256 digest.
+    Sha256,
+    /// Fast non-cryptographic XXH3 128-bit hash, padded with zeroes to 32 bytes.
+    Xxh3,
+}
+
 bitflags! {
     #[derive(Copy, Clone, Debug, PartialEq)]
     pub struct FdFlags: u16 {
@@ -71,6 +150,8 @@ bitflags! {
         const EXCLUSIVE = 4;
         /// Truncate file to size 0.
         const TRUNCATE = 8;
+        /// Close the descriptor on the next `FileSystem::close_boundary_fds` call.
+        const CLOSE_ON_BOUNDARY = 16;
     }
 }
 
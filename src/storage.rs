@@ -2,9 +2,11 @@ use ic_stable_structures::Memory;
 
 use crate::{
     error::Error,
+    fs::Advice,
     fs::ChunkSize,
     fs::ChunkType,
-    storage::types::{DirEntry, DirEntryIndex, FileSize, Metadata, Node},
+    fs::Normalization,
+    storage::types::{DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node},
 };
 
 mod allocator;
@@ -25,9 +27,27 @@ pub trait Storage {
     // Get version of the file system.
     fn get_version(&self) -> u32;
 
+    // false if the stored header was changed by something else than this storage since it was last written.
+    fn check_integrity_fingerprint(&self) -> bool;
+
+    // false if the underlying memory cannot be written, e.g. a read-only view of another file system.
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    // iterate over the IDs of all stored nodes in ascending order.
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = Node> + '_>;
+    // the number of stored nodes, including the ones no longer linked from any folder.
+    fn node_count(&self) -> u64;
+
     // Generate the next available node ID.
     fn new_node(&mut self) -> Node;
 
+    // take `count` node IDs in advance, so that creating that many nodes does not update the stored node counter each time
+    fn reserve_nodes(&mut self, count: u64);
+    // the node ID the next call to new_node will return.
+    fn next_node(&self) -> Node;
+
     // mark node as mounted.
     fn mount_node(&mut self, node: Node, memory: Box<dyn Memory>) -> Result<(), Error>;
     // mark note as not mounted.
@@ -41,6 +61,13 @@ pub trait Storage {
     fn init_mounted_memory(&mut self, node: Node) -> Result<(), Error>;
     // store mounted memory state back to host file.
     fn store_mounted_memory(&mut self, node: Node) -> Result<(), Error>;
+    // mount the memory to the node once a write extends the file past `threshold`, the file content is moved into it.
+    fn set_spill_mount(
+        &mut self,
+        node: Node,
+        threshold: FileSize,
+        memory: Box<dyn Memory>,
+    ) -> Result<(), Error>;
 
     // Get the metadata associated with the node.
     fn get_metadata(&self, node: Node) -> Result<Metadata, Error>;
@@ -62,12 +89,79 @@ pub trait Storage {
         buf: &mut [u8],
     ) -> Result<FileSize, Error>;
 
+    // read a single chunk of `chunk_size()` bytes, the chunk pointer is looked up directly without iterating
+    fn read_chunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        buf: &mut [u8],
+    ) -> Result<FileSize, Error>;
+
     // Write file at the current file cursor, the cursor position will NOT be updated after reading.
     fn write(&mut self, node: Node, offset: FileSize, buf: &[u8]) -> Result<FileSize, Error>;
 
+    // number of new chunks a write of `len` bytes at `offset` would allocate, nothing is written
+    fn estimate_write_chunks(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Result<usize, Error>;
+
+    // reserve the chunks covering the first `size` bytes of the file as one contiguous run, the file size is not changed.
+    fn preallocate(&mut self, node: Node, size: FileSize) -> Result<(), Error>;
+
+    // drop the cached chunk pointers and metadata to free heap memory, the stored data is not affected.
+    fn clear_caches(&mut self);
+
+    // apply the expected access pattern of a file range to the chunk pointer cache
+    fn advise(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        len: FileSize,
+        advice: Advice,
+    ) -> Result<(), Error>;
+
+    // ratio of the chunks not following the previous chunk of the file in memory, 0.0 for a contiguous file
+    fn fragmentation(&mut self, node: Node) -> Result<f64, Error>;
+
+    // move the file chunks into one contiguous run of memory, the file contents are not changed
+    fn defragment(&mut self, node: Node) -> Result<(), Error>;
+
+    // the lowest and the highest index of the chunks stored for the file, None if no chunks are stored
+    fn chunk_range(
+        &mut self,
+        node: Node,
+    ) -> Result<Option<(FileChunkIndex, FileChunkIndex)>, Error>;
+
+    // remove all nodes leaving only an empty root folder and reset the node counter,
+    // fails with `Error::CannotRemoveMountedMemoryFile` while any memory is mounted.
+    fn format(&mut self) -> Result<(), Error>;
+
+    // remove at most `max_chunks` chunks of the file, returns true once the file has no chunks left
+    fn rm_file_chunks(&mut self, node: Node, max_chunks: usize) -> Result<bool, Error>;
+
+    // files unlinked by a chunked removal whose chunks are not released yet, as (parent folder, path, node).
+    // The list is stored in the header, so a removal interrupted by an upgrade can be resumed.
+    fn pending_removals(&self) -> Vec<(Node, String, Node)>;
+    fn set_pending_removals(&mut self, removals: Vec<(Node, String, Node)>);
+
     // remove all file chunks
     fn rm_file(&mut self, node: Node) -> Result<(), Error>;
 
+    // remove the first `bytes` of the file, the remaining content is shifted to the beginning of the file
+    fn trim_front(&mut self, node: Node, bytes: FileSize) -> Result<(), Error>;
+
+    // get the value of an extended attribute
+    fn get_xattr(&self, node: Node, name: &str) -> Result<Vec<u8>, Error>;
+    // insert or update an extended attribute
+    fn set_xattr(&mut self, node: Node, name: &str, value: &[u8]) -> Result<(), Error>;
+    // list the names of all extended attributes of the node
+    fn list_xattrs(&self, node: Node) -> Result<Vec<String>, Error>;
+    // remove an extended attribute
+    fn remove_xattr(&mut self, node: Node, name: &str) -> Result<(), Error>;
+
     // configure desired chunk size
     fn set_chunk_size(&mut self, chunk_size: ChunkSize) -> Result<(), Error>;
     // the current FS chunk size in bytes
@@ -77,6 +171,54 @@ pub trait Storage {
     fn set_chunk_type(&mut self, chunk_type: ChunkType);
     fn chunk_type(&self) -> ChunkType;
 
+    // set the file chunk type to match the chunks actually stored for the node
+    fn fix_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error>;
+    // the chunk type used to read and write the node, even if it is not set in the metadata
+    fn effective_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error>;
+    // use V2 chunks of the given size for the file, only possible before anything is written into it
+    fn set_file_chunk_size(&mut self, node: Node, chunk_size: ChunkSize) -> Result<(), Error>;
+
+    // pick the chunk type of new files on their first write: small writes use V1, large writes use V2
+    fn set_adaptive_chunking(&mut self, enabled: bool);
+    fn adaptive_chunking(&self) -> bool;
+
+    // keep small appends in memory until a chunk is filled or the node is flushed, disabled by default.
+    // The stored file size does not include the buffered bytes, call flush_all before an upgrade to keep them.
+    fn set_append_buffering(&mut self, enabled: bool) -> Result<(), Error>;
+    fn append_buffering(&self) -> bool;
+
+    // limit the number of entries in a single directory, None means no limit
+    fn set_max_dir_entries(&mut self, limit: Option<FileSize>);
+    fn max_dir_entries(&self) -> Option<FileSize>;
+
+    // normalize file names before storing and looking them up, disabled by default, the setting is stored in the header
+    fn set_filename_normalization(&mut self, normalization: Normalization);
+    fn filename_normalization(&self) -> Normalization;
+
+    // ignore trailing spaces and dots of file names like Windows does, disabled by default, the setting is stored in the header
+    fn set_trim_trailing_space(&mut self, trim: bool);
+    fn trim_trailing_space(&self) -> bool;
+
+    // clock setting the modification time of a directory when its entries are added or removed, disabled by default.
+    // The clock is not stored, it has to be set again after every upgrade.
+    fn set_dir_clock(&mut self, clock: Option<Box<dyn Fn() -> u64>>);
+    // the current time of the directory clock, None if it is not set
+    fn dir_clock_time(&self) -> Option<u64>;
+
+    // keep the metadata of the node in memory, its updates are only stored by flush_deferred_metadata
+    fn defer_metadata(&mut self, node: Node) -> Result<(), Error>;
+    fn flush_deferred_metadata(&mut self);
+
+    // low-level operation counts collected since the storage was created
+    #[cfg(feature = "profiling")]
+    fn operation_counters(&self) -> types::OperationCounters;
+
     // flush changes related to the node
-    fn flush(&mut self, node: Node);
+    fn flush(&mut self, node: Node) -> Result<(), Error>;
+
+    // flush the changes of all nodes, including the buffered file tails
+    fn flush_all(&mut self) -> Result<(), Error>;
+
+    // store the current size and times of all mounted files with the mounted metadata, where mounting picks them up again
+    fn persist_mounted_metadata(&mut self);
 }
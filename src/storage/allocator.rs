@@ -3,7 +3,7 @@ use ic_stable_structures::{memory_manager::VirtualMemory, Memory};
 use crate::{
     error::Error,
     runtime::{
-        structure_helpers::{read_obj, write_obj},
+        structure_helpers::{grow_memory, read_obj, write_obj},
         types::ChunkSize,
     },
 };
@@ -19,6 +19,14 @@ const CHUNK_SIZE_IDX: u64 = 1;
 const AVAILABLE_CHUNKS_LEN_IDX: u64 = 2;
 // index containing the next address to use, when there are no reusable indices available
 const MAX_PTR_IDX: u64 = 3;
+// indices containing the heads of the released spans of 2, 4, 8 and 16 chunks,
+// the rest of each list is linked through the first bytes of the released spans
+const FIRST_SPAN_HEAD_IDX: u64 = 4;
+const MAX_LISTED_SPAN: u64 = 16;
+
+// the number of the most recently released chunks searched for a free run,
+// so that a contiguous allocation does not get slower with the length of the free list
+const CONTIGUOUS_SEARCH_LIMIT: u64 = 256;
 
 pub struct ChunkPtrAllocator<M: Memory> {
     v2_available_chunks: VirtualMemory<M>,
@@ -111,7 +119,6 @@ impl<M: Memory> ChunkPtrAllocator<M> {
         self.write_u64(FIRST_PTR_IDX + index, value);
     }
 
-    #[cfg(test)]
     pub fn available_ptrs(&self) -> Vec<u64> {
         let mut res = Vec::new();
 
@@ -189,6 +196,189 @@ impl<M: Memory> ChunkPtrAllocator<M> {
         self.get_next_max_ptr()
     }
 
+    // The address where the next run above the high-water mark starts.
+    pub fn high_water_mark(&self) -> FileChunkPtr {
+        self.read_u64(MAX_PTR_IDX)
+    }
+
+    // Allocate `count` chunks following each other in memory, the chunks are always taken above the high-water mark.
+    // Returns the pointer to the first chunk of the run.
+    pub fn allocate_run(&mut self, count: u64) -> FileChunkPtr {
+        let ret = self.read_u64(MAX_PTR_IDX);
+
+        self.write_u64(MAX_PTR_IDX, ret + count * self.chunk_size() as u64);
+
+        ret
+    }
+
+    // Allocate `count` chunks following each other in memory, returns the pointer to the first chunk or `None`
+    // if `count` is zero. A run of released chunks is reused if the end of the free list has one (only the last
+    // `CONTIGUOUS_SEARCH_LIMIT` released chunks are searched), otherwise the run is taken above the high-water mark
+    // like in `allocate_run`.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<FileChunkPtr> {
+        match count {
+            0 => return None,
+            1 => return Some(self.allocate()),
+            _ => {}
+        }
+
+        let chunk_size = self.chunk_size() as FileChunkPtr;
+
+        let len = self.get_len();
+        let tail_start = len.saturating_sub(CONTIGUOUS_SEARCH_LIMIT);
+        let tail: Vec<FileChunkPtr> = (tail_start..len).map(|i| self.get_ptr(i)).collect();
+
+        let mut sorted = tail.clone();
+        sorted.sort_unstable();
+
+        let mut run_start = 0;
+
+        for i in 0..sorted.len() {
+            if i > 0 && sorted[i] != sorted[i - 1] + chunk_size {
+                run_start = i;
+            }
+
+            if i + 1 - run_start == count {
+                let run = sorted[run_start]..=sorted[i];
+
+                // only the searched part of the free list is rewritten
+                let rest: Vec<FileChunkPtr> =
+                    tail.into_iter().filter(|ptr| !run.contains(ptr)).collect();
+
+                for (i, ptr) in rest.iter().enumerate() {
+                    self.set_ptr(tail_start + i as u64, *ptr);
+                }
+                self.set_len(tail_start + rest.len() as u64);
+
+                return Some(*run.start());
+            }
+        }
+
+        Some(self.allocate_run(count as u64))
+    }
+
+    // index of the list head keeping the released spans of the given size, single chunks use the common free list
+    fn span_head_idx(span: u64) -> Option<u64> {
+        if span > 1 && span <= MAX_LISTED_SPAN && span.is_power_of_two() {
+            Some(FIRST_SPAN_HEAD_IDX + span.trailing_zeros() as u64 - 1)
+        } else {
+            None
+        }
+    }
+
+    // the list links are stored shifted by one, so that zero marks the end of the list
+    fn read_span_link(chunks: &VirtualMemory<M>, ptr: FileChunkPtr) -> Option<FileChunkPtr> {
+        let mut link = 0u64;
+        read_obj(chunks, ptr, &mut link);
+
+        link.checked_sub(1)
+    }
+
+    fn span_head(&self, head_idx: u64) -> Option<FileChunkPtr> {
+        self.read_u64(head_idx).checked_sub(1)
+    }
+
+    fn set_span_head(&self, head_idx: u64, ptr: Option<FileChunkPtr>) {
+        self.write_u64(head_idx, ptr.map_or(0, |ptr| ptr + 1));
+    }
+
+    // Check if a released span of the given size can be reused.
+    pub fn has_free_span(&self, span: u64) -> bool {
+        Self::span_head_idx(span).is_some_and(|head_idx| self.span_head(head_idx).is_some())
+    }
+
+    // Released spans of the given size, the `chunks` memory holds the links between them.
+    pub fn available_spans(&self, chunks: &VirtualMemory<M>, span: u64) -> Vec<FileChunkPtr> {
+        let mut res = Vec::new();
+
+        let Some(head_idx) = Self::span_head_idx(span) else {
+            return res;
+        };
+
+        let max_ptr = self.read_u64(MAX_PTR_IDX);
+        let mut next = self.span_head(head_idx);
+
+        while let Some(ptr) = next {
+            res.push(ptr);
+
+            // a broken link should not send us round in circles
+            if ptr >= max_ptr || res.len() as u64 > max_ptr / self.chunk_size() as u64 {
+                break;
+            }
+
+            next = Self::read_span_link(chunks, ptr);
+        }
+
+        res
+    }
+
+    // Allocate a chunk spanning `span` allocator chunks, a released span of the same size is reused first,
+    // single chunks are taken from the free list as usual.
+    pub fn allocate_span(&mut self, chunks: &VirtualMemory<M>, span: u64) -> FileChunkPtr {
+        if span <= 1 {
+            return self.allocate();
+        }
+
+        if let Some(head_idx) = Self::span_head_idx(span) {
+            if let Some(ptr) = self.span_head(head_idx) {
+                self.set_span_head(head_idx, Self::read_span_link(chunks, ptr));
+                return ptr;
+            }
+        }
+
+        self.allocate_run(span)
+    }
+
+    // Release a chunk spanning `span` allocator chunks, it is kept on the list for its size and reused whole.
+    // The first bytes of the released span are overwritten with the link to the next span on the list.
+    pub fn free_span(&mut self, chunks: &VirtualMemory<M>, ptr: FileChunkPtr, span: u64) {
+        let chunk_size = self.chunk_size() as FileChunkPtr;
+
+        let Some(head_idx) = Self::span_head_idx(span) else {
+            for i in 0..span.max(1) {
+                self.free(ptr + i * chunk_size);
+            }
+            return;
+        };
+
+        #[cfg(test)]
+        self.check_free_span(chunks, ptr, span);
+
+        let link = self.read_u64(head_idx);
+        grow_memory(chunks, ptr + std::mem::size_of::<u64>() as FileChunkPtr);
+        write_obj(chunks, ptr, &link);
+
+        self.set_span_head(head_idx, Some(ptr));
+    }
+
+    // Release `count` chunks spanning `span` allocator chunks each, following each other in memory.
+    pub fn free_spans(
+        &mut self,
+        chunks: &VirtualMemory<M>,
+        ptr: FileChunkPtr,
+        count: u64,
+        span: u64,
+    ) {
+        let span_size = span.max(1) * self.chunk_size() as FileChunkPtr;
+
+        for i in 0..count {
+            self.free_span(chunks, ptr + i * span_size, span);
+        }
+    }
+
+    // Release all chunks at once, the next allocation starts from the beginning of the chunk memory.
+    pub fn reset(&mut self) {
+        self.set_len(0);
+
+        let mut span = 2;
+        while let Some(head_idx) = Self::span_head_idx(span) {
+            self.set_span_head(head_idx, None);
+            span *= 2;
+        }
+
+        self.write_u64(MAX_PTR_IDX, 0);
+    }
+
     #[cfg(test)]
     fn check_free(&self, ptr: FileChunkPtr) {
         if ptr % self.chunk_size() as u64 != 0 {
@@ -209,6 +399,60 @@ impl<M: Memory> ChunkPtrAllocator<M> {
         }
     }
 
+    #[cfg(test)]
+    fn check_free_span(&self, chunks: &VirtualMemory<M>, ptr: FileChunkPtr, span: u64) {
+        let chunk_size = self.chunk_size() as u64;
+
+        for i in 0..span {
+            self.check_free(ptr + i * chunk_size);
+        }
+
+        if self.available_spans(chunks, span).contains(&ptr) {
+            panic!("Second free of span at address {}", ptr);
+        }
+    }
+
+    // Check the free lists are consistent: every pointer is chunk-aligned, was allocated before,
+    // appears only once and is not used by any of the `live` chunks.
+    pub fn verify(
+        &self,
+        chunks: &VirtualMemory<M>,
+        live: impl Iterator<Item = FileChunkPtr>,
+    ) -> Result<(), Error> {
+        let chunk_size = self.chunk_size() as u64;
+        let max_ptr = self.read_u64(MAX_PTR_IDX);
+
+        let mut free = std::collections::HashSet::new();
+
+        for ptr in self.available_ptrs() {
+            if ptr % chunk_size != 0 || ptr >= max_ptr || !free.insert(ptr) {
+                return Err(Error::CorruptedAllocator);
+            }
+        }
+
+        let mut span = 2;
+        while Self::span_head_idx(span).is_some() {
+            for ptr in self.available_spans(chunks, span) {
+                for i in 0..span {
+                    let part = ptr + i * chunk_size;
+
+                    if ptr % chunk_size != 0 || part >= max_ptr || !free.insert(part) {
+                        return Err(Error::CorruptedAllocator);
+                    }
+                }
+            }
+            span *= 2;
+        }
+
+        for ptr in live {
+            if free.contains(&ptr) {
+                return Err(Error::CorruptedAllocator);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn free(&mut self, ptr: FileChunkPtr) {
         #[cfg(test)]
         self.check_free(ptr);
@@ -416,6 +660,197 @@ mod tests {
         assert_eq!(mem.size(), 2);
     }
 
+    #[test]
+    fn allocate_run_is_contiguous() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let chunks = memory_manager.get(MemoryId::new(2));
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let chunk_size = DEFAULT_FILE_CHUNK_SIZE_V2 as FileChunkPtr;
+
+        assert_eq!(allocator.allocate(), 0);
+        assert_eq!(allocator.allocate(), chunk_size);
+        allocator.free(0);
+
+        // the free list is not used for the run
+        assert_eq!(allocator.allocate_run(10), chunk_size * 2);
+        assert_eq!(allocator.allocate(), 0);
+        assert_eq!(allocator.allocate(), chunk_size * 12);
+
+        assert_eq!(allocator.verify(&chunks, std::iter::empty()), Ok(()));
+    }
+
+    #[test]
+    fn allocate_contiguous_reuses_released_runs() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let chunks = memory_manager.get(MemoryId::new(2));
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let chunk_size = DEFAULT_FILE_CHUNK_SIZE_V2 as FileChunkPtr;
+
+        assert_eq!(allocator.allocate_contiguous(0), None);
+        assert_eq!(allocator.allocate_contiguous(10), Some(0));
+        assert_eq!(allocator.allocate_contiguous(1), Some(chunk_size * 10));
+
+        // release chunks 1, 2, 3, 5, 6 in a mixed order
+        for index in [5, 2, 6, 3, 1] {
+            allocator.free(index * chunk_size);
+        }
+
+        // no free run of 4 chunks, the run is taken above the high-water mark
+        assert_eq!(allocator.allocate_contiguous(4), Some(chunk_size * 11));
+        assert_eq!(allocator.available_ptrs().len(), 5);
+
+        // the run 1..=3 is reused, the other released chunks are kept
+        assert_eq!(allocator.allocate_contiguous(3), Some(chunk_size));
+        let mut free = allocator.available_ptrs();
+        free.sort();
+        assert_eq!(free, vec![chunk_size * 5, chunk_size * 6]);
+
+        assert_eq!(allocator.allocate_contiguous(2), Some(chunk_size * 5));
+        assert!(allocator.available_ptrs().is_empty());
+        assert_eq!(allocator.allocate(), chunk_size * 15);
+
+        assert_eq!(allocator.verify(&chunks, std::iter::empty()), Ok(()));
+    }
+
+    #[test]
+    fn allocate_contiguous_searches_the_recently_released_chunks() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let chunks = memory_manager.get(MemoryId::new(2));
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let chunk_size = DEFAULT_FILE_CHUNK_SIZE_V2 as FileChunkPtr;
+        let limit = CONTIGUOUS_SEARCH_LIMIT;
+
+        allocator.allocate_run(limit * 3);
+
+        // a run released early is hidden by the single chunks released after it
+        allocator.free(0);
+        allocator.free(chunk_size);
+        for i in 0..limit {
+            allocator.free((i * 2 + 4) * chunk_size);
+        }
+
+        assert_eq!(
+            allocator.allocate_contiguous(2),
+            Some(chunk_size * limit * 3)
+        );
+        assert_eq!(allocator.available_ptrs().len() as u64, limit + 2);
+
+        // a run among the recently released chunks is found, the rest of the free list keeps its order
+        let before = allocator.available_ptrs();
+        let last = (limit * 2 + 2) * chunk_size;
+        allocator.free(last + chunk_size);
+        assert_eq!(allocator.allocate_contiguous(2), Some(last));
+        assert_eq!(
+            allocator.available_ptrs(),
+            before
+                .into_iter()
+                .filter(|ptr| *ptr != last)
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(allocator.verify(&chunks, std::iter::empty()), Ok(()));
+    }
+
+    #[test]
+    fn released_spans_are_reused_whole() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let chunks = memory_manager.get(MemoryId::new(2));
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let chunk_size = DEFAULT_FILE_CHUNK_SIZE_V2 as FileChunkPtr;
+
+        assert_eq!(allocator.allocate_span(&chunks, 4), 0);
+        assert_eq!(allocator.allocate_span(&chunks, 4), chunk_size * 4);
+        assert_eq!(allocator.allocate_span(&chunks, 2), chunk_size * 8);
+
+        allocator.free_span(&chunks, 0, 4);
+        allocator.free_span(&chunks, chunk_size * 4, 4);
+        allocator.free_span(&chunks, chunk_size * 8, 2);
+
+        assert!(allocator.available_ptrs().is_empty());
+        assert_eq!(
+            allocator.available_spans(&chunks, 4),
+            vec![chunk_size * 4, 0]
+        );
+        assert_eq!(allocator.verify(&chunks, std::iter::empty()), Ok(()));
+
+        // imitate canister upgrade here
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+
+        // single chunks and spans of other sizes do not take the released spans
+        assert_eq!(allocator.allocate(), chunk_size * 10);
+        assert_eq!(allocator.allocate_span(&chunks, 8), chunk_size * 11);
+
+        assert_eq!(allocator.allocate_span(&chunks, 4), chunk_size * 4);
+        assert_eq!(allocator.allocate_span(&chunks, 4), 0);
+        assert_eq!(allocator.allocate_span(&chunks, 2), chunk_size * 8);
+        assert_eq!(allocator.allocate_span(&chunks, 4), chunk_size * 19);
+
+        // a live span on the free list is detected
+        allocator.free_span(&chunks, 0, 4);
+        assert_eq!(
+            allocator.verify(&chunks, [0].into_iter()),
+            Err(Error::CorruptedAllocator)
+        );
+
+        allocator.reset();
+        assert!(!allocator.has_free_span(4));
+        assert_eq!(allocator.allocate_span(&chunks, 4), 0);
+    }
+
+    #[test]
+    fn verify_detects_free_list_corruption() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let chunks = memory_manager.get(MemoryId::new(2));
+        let mut allocator = ChunkPtrAllocator::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let chunk_size = DEFAULT_FILE_CHUNK_SIZE_V2 as FileChunkPtr;
+
+        for _ in 0..4 {
+            allocator.allocate();
+        }
+
+        allocator.free(chunk_size);
+        allocator.free(chunk_size * 3);
+
+        assert_eq!(
+            allocator.verify(&chunks, [0, chunk_size * 2].into_iter()),
+            Ok(())
+        );
+
+        // a live chunk on the free list
+        assert_eq!(
+            allocator.verify(&chunks, [0, chunk_size * 3].into_iter()),
+            Err(Error::CorruptedAllocator)
+        );
+
+        // a duplicate pointer
+        allocator.push_ptr(chunk_size);
+        assert_eq!(
+            allocator.verify(&chunks, std::iter::empty()),
+            Err(Error::CorruptedAllocator)
+        );
+        allocator.pop_ptr();
+
+        // unaligned pointer
+        allocator.push_ptr(chunk_size + 1);
+        assert_eq!(
+            allocator.verify(&chunks, std::iter::empty()),
+            Err(Error::CorruptedAllocator)
+        );
+        allocator.pop_ptr();
+
+        // pointer beyond the high-water mark
+        allocator.push_ptr(chunk_size * 4);
+        assert_eq!(
+            allocator.verify(&chunks, std::iter::empty()),
+            Err(Error::CorruptedAllocator)
+        );
+    }
+
     #[test]
     fn wrong_custom_chunk_size_fails() {
         let mem = new_vector_memory();
@@ -102,6 +102,9 @@ mod tests {
                 first_dir_entry: Some(42),
                 last_dir_entry: Some(24),
                 chunk_type: Some(storage.chunk_type()),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
             },
         );
         node
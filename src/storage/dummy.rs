@@ -1,7 +1,7 @@
-use crate::{error::Error, fs::ChunkSize, fs::ChunkType};
+use crate::{error::Error, fs::Advice, fs::ChunkSize, fs::ChunkType, fs::Normalization};
 
 use super::{
-    types::{DirEntry, DirEntryIndex, FileSize, Metadata, Node},
+    types::{DirEntry, DirEntryIndex, FileChunkIndex, FileSize, Metadata, Node},
     Storage,
 };
 
@@ -32,6 +32,26 @@ impl Storage for DummyStorage {
         0
     }
 
+    fn check_integrity_fingerprint(&self) -> bool {
+        panic!("Not supported")
+    }
+
+    fn next_node(&self) -> Node {
+        panic!("Not supported")
+    }
+
+    fn reserve_nodes(&mut self, _count: u64) {
+        panic!("Not supported")
+    }
+
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = Node> + '_> {
+        panic!("Not supported")
+    }
+
+    fn node_count(&self) -> u64 {
+        panic!("Not supported")
+    }
+
     fn get_metadata(&self, _node: Node) -> Result<Metadata, Error> {
         panic!("Not supported")
     }
@@ -56,6 +76,65 @@ impl Storage for DummyStorage {
         panic!("Not supported")
     }
 
+    fn read_chunk(
+        &mut self,
+        _node: Node,
+        _index: FileChunkIndex,
+        _buf: &mut [u8],
+    ) -> Result<FileSize, Error> {
+        panic!("Not supported")
+    }
+
+    fn estimate_write_chunks(
+        &mut self,
+        _node: Node,
+        _offset: FileSize,
+        _len: FileSize,
+    ) -> Result<usize, Error> {
+        panic!("Not supported")
+    }
+
+    fn preallocate(&mut self, _node: Node, _size: FileSize) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn clear_caches(&mut self) {
+        panic!("Not supported")
+    }
+
+    fn advise(
+        &mut self,
+        _node: Node,
+        _offset: FileSize,
+        _len: FileSize,
+        _advice: Advice,
+    ) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn fragmentation(&mut self, _node: Node) -> Result<f64, Error> {
+        panic!("Not supported")
+    }
+
+    fn defragment(&mut self, _node: Node) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn chunk_range(
+        &mut self,
+        _node: Node,
+    ) -> Result<Option<(FileChunkIndex, FileChunkIndex)>, Error> {
+        panic!("Not supported")
+    }
+
+    fn rm_file_chunks(&mut self, _node: Node, _max_chunks: usize) -> Result<bool, Error> {
+        panic!("Not supported")
+    }
+
+    fn format(&mut self) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
     fn mount_node(
         &mut self,
         _node: Node,
@@ -87,6 +166,15 @@ impl Storage for DummyStorage {
         panic!("Not supported")
     }
 
+    fn set_spill_mount(
+        &mut self,
+        _node: Node,
+        _threshold: FileSize,
+        _memory: Box<dyn ic_stable_structures::Memory>,
+    ) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
     fn write(&mut self, _node: Node, _offset: FileSize, _buf: &[u8]) -> Result<FileSize, Error> {
         panic!("Not supported")
     }
@@ -95,6 +183,26 @@ impl Storage for DummyStorage {
         panic!("Not supported")
     }
 
+    fn trim_front(&mut self, _node: Node, _bytes: FileSize) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn get_xattr(&self, _node: Node, _name: &str) -> Result<Vec<u8>, Error> {
+        panic!("Not supported")
+    }
+
+    fn set_xattr(&mut self, _node: Node, _name: &str, _value: &[u8]) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn list_xattrs(&self, _node: Node) -> Result<Vec<String>, Error> {
+        panic!("Not supported")
+    }
+
+    fn remove_xattr(&mut self, _node: Node, _name: &str) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
     fn set_chunk_size(&mut self, _chunk_size: ChunkSize) -> Result<(), Error> {
         panic!("Not supported")
     }
@@ -111,7 +219,96 @@ impl Storage for DummyStorage {
         panic!("Not supported")
     }
 
-    fn flush(&mut self, _node: Node) {
+    fn fix_chunk_type(&mut self, _node: Node) -> Result<ChunkType, Error> {
+        panic!("Not supported")
+    }
+
+    fn effective_chunk_type(&mut self, _node: Node) -> Result<ChunkType, Error> {
+        panic!("Not supported")
+    }
+
+    fn set_file_chunk_size(&mut self, _node: Node, _chunk_size: ChunkSize) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn set_adaptive_chunking(&mut self, _enabled: bool) {
+        panic!("Not supported")
+    }
+
+    fn adaptive_chunking(&self) -> bool {
+        panic!("Not supported")
+    }
+
+    fn set_append_buffering(&mut self, _enabled: bool) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn append_buffering(&self) -> bool {
+        panic!("Not supported")
+    }
+
+    fn set_max_dir_entries(&mut self, _limit: Option<FileSize>) {
+        panic!("Not supported")
+    }
+
+    fn max_dir_entries(&self) -> Option<FileSize> {
+        panic!("Not supported")
+    }
+
+    fn set_filename_normalization(&mut self, _normalization: Normalization) {
+        panic!("Not supported")
+    }
+
+    fn filename_normalization(&self) -> Normalization {
+        panic!("Not supported")
+    }
+
+    fn set_trim_trailing_space(&mut self, _trim: bool) {
+        panic!("Not supported")
+    }
+
+    fn trim_trailing_space(&self) -> bool {
+        panic!("Not supported")
+    }
+
+    fn pending_removals(&self) -> Vec<(Node, String, Node)> {
+        panic!("Not supported")
+    }
+
+    fn set_pending_removals(&mut self, _removals: Vec<(Node, String, Node)>) {
+        panic!("Not supported")
+    }
+
+    fn set_dir_clock(&mut self, _clock: Option<Box<dyn Fn() -> u64>>) {
+        panic!("Not supported")
+    }
+
+    fn dir_clock_time(&self) -> Option<u64> {
+        panic!("Not supported")
+    }
+
+    fn defer_metadata(&mut self, _node: Node) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn flush_deferred_metadata(&mut self) {
+        panic!("Not supported")
+    }
+
+    #[cfg(feature = "profiling")]
+    fn operation_counters(&self) -> crate::storage::types::OperationCounters {
+        panic!("Not supported")
+    }
+
+    fn flush(&mut self, _node: Node) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn flush_all(&mut self) -> Result<(), Error> {
+        panic!("Not supported")
+    }
+
+    fn persist_mounted_metadata(&mut self) {
         panic!("Not supported")
     }
 }
@@ -138,6 +335,9 @@ mod tests {
                 first_dir_entry: Some(42),
                 last_dir_entry: Some(24),
                 chunk_type: None,
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
             },
         )
     }
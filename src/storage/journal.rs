@@ -2,14 +2,101 @@ use ic_stable_structures::{memory_manager::VirtualMemory, Memory};
 
 use crate::{
     error::Error,
+    fs::ChunkType,
     runtime::structure_helpers::{read_obj, write_obj},
 };
 
-use super::types::{Metadata, Node};
+use super::types::{FileType, Metadata, Node, Times};
 
 // index containing cached metadata
 const MOUNTED_META_PTR: u64 = 16;
 
+// The stored form of the cached metadata. It keeps the byte layout `Metadata` had when the journal was introduced,
+// so that journals written by earlier versions are read correctly. Only integer fields are used,
+// any stored bytes are a valid value, and the conversion checks the enum values.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct JournalMetadata {
+    first_dir_entry_tag: u32,
+    first_dir_entry: u32,
+    last_dir_entry_tag: u32,
+    last_dir_entry: u32,
+    node: u64,
+    link_count: u64,
+    size: u64,
+    accessed: u64,
+    modified: u64,
+    created: u64,
+    file_type: u8,
+    chunk_type: u8,
+    padding: [u8; 6],
+}
+
+// The cached node together with its metadata.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct JournalEntry {
+    node: u64,
+    meta: JournalMetadata,
+}
+
+const _: () = assert!(std::mem::size_of::<JournalEntry>() == 80);
+
+impl From<&Metadata> for JournalMetadata {
+    fn from(meta: &Metadata) -> Self {
+        Self {
+            first_dir_entry_tag: meta.first_dir_entry.is_some() as u32,
+            first_dir_entry: meta.first_dir_entry.unwrap_or(0),
+            last_dir_entry_tag: meta.last_dir_entry.is_some() as u32,
+            last_dir_entry: meta.last_dir_entry.unwrap_or(0),
+            node: meta.node,
+            link_count: meta.link_count,
+            size: meta.size,
+            accessed: meta.times.accessed,
+            modified: meta.times.modified,
+            created: meta.times.created,
+            file_type: match meta.file_type {
+                FileType::Directory => 0,
+                FileType::RegularFile => 1,
+                FileType::SymbolicLink => 2,
+            },
+            chunk_type: meta.chunk_type.map_or(0, |chunk_type| chunk_type as u8),
+            padding: [0; 6],
+        }
+    }
+}
+
+impl From<&JournalMetadata> for Metadata {
+    // the chunk size and the folder entry fields are not stored, the metadata in the journal belongs to mounted files
+    fn from(meta: &JournalMetadata) -> Self {
+        Self {
+            node: meta.node,
+            file_type: match meta.file_type {
+                0 => FileType::Directory,
+                2 => FileType::SymbolicLink,
+                _ => FileType::RegularFile,
+            },
+            link_count: meta.link_count,
+            size: meta.size,
+            times: Times {
+                accessed: meta.accessed,
+                modified: meta.modified,
+                created: meta.created,
+            },
+            first_dir_entry: (meta.first_dir_entry_tag == 1).then_some(meta.first_dir_entry),
+            last_dir_entry: (meta.last_dir_entry_tag == 1).then_some(meta.last_dir_entry),
+            chunk_type: match meta.chunk_type {
+                1 => Some(ChunkType::V1),
+                2 => Some(ChunkType::V2),
+                _ => None,
+            },
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
+        }
+    }
+}
+
 pub struct CacheJournal<M: Memory> {
     journal: VirtualMemory<M>,
 
@@ -54,16 +141,11 @@ impl<M: Memory> CacheJournal<M> {
             };
 
             // init local cache variables
-            read_obj(
-                &cache_journal.journal,
-                MOUNTED_META_PTR,
-                &mut cache_journal.mounted_node,
-            );
-            read_obj(
-                &cache_journal.journal,
-                MOUNTED_META_PTR + 8,
-                &mut cache_journal.mounted_meta,
-            );
+            let mut entry = JournalEntry::default();
+            read_obj(&cache_journal.journal, MOUNTED_META_PTR, &mut entry);
+
+            cache_journal.mounted_node = entry.node;
+            cache_journal.mounted_meta = Metadata::from(&entry.meta);
 
             cache_journal
         };
@@ -99,7 +181,12 @@ impl<M: Memory> CacheJournal<M> {
         self.mounted_node = *node;
         self.mounted_meta = (*meta).clone();
 
-        write_obj(&self.journal, MOUNTED_META_PTR, &(*node, (*meta).clone()));
+        let entry = JournalEntry {
+            node: *node,
+            meta: JournalMetadata::from(meta),
+        };
+
+        write_obj(&self.journal, MOUNTED_META_PTR, &entry);
     }
 }
 
@@ -134,6 +221,9 @@ mod tests {
             first_dir_entry: None,
             last_dir_entry: Some(876),
             chunk_type: None,
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
 
         let mut node2 = 0;
@@ -152,6 +242,70 @@ mod tests {
         assert_eq!(meta, meta2);
     }
 
+    #[test]
+    fn journal_in_the_original_layout_is_decoded() {
+        let mem = new_vector_memory();
+        let memory_manager = MemoryManager::init(mem);
+        let _journal = CacheJournal::new(memory_manager.get(MemoryId::new(1))).unwrap();
+
+        // the entry as written by the versions that copied `Metadata` directly into the journal
+        let mut entry = [0u8; 80];
+        entry[0..8].copy_from_slice(&123u64.to_le_bytes());
+        let meta = &mut entry[8..];
+        meta[0..4].copy_from_slice(&0u32.to_le_bytes());
+        meta[8..12].copy_from_slice(&1u32.to_le_bytes());
+        meta[12..16].copy_from_slice(&876u32.to_le_bytes());
+        meta[16..24].copy_from_slice(&123u64.to_le_bytes());
+        meta[24..32].copy_from_slice(&1u64.to_le_bytes());
+        meta[32..40].copy_from_slice(&1234u64.to_le_bytes());
+        meta[40..48].copy_from_slice(&48u64.to_le_bytes());
+        meta[48..56].copy_from_slice(&388u64.to_le_bytes());
+        meta[56..64].copy_from_slice(&34u64.to_le_bytes());
+        meta[64] = 1;
+        meta[65] = 2;
+        memory_manager
+            .get(MemoryId::new(1))
+            .write(MOUNTED_META_PTR, &entry);
+
+        let journal = CacheJournal::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        let mut restored = Metadata::default();
+        journal.read_mounted_meta(&mut restored);
+
+        assert_eq!(journal.read_mounted_meta_node(), Some(123));
+        assert_eq!(
+            restored,
+            Metadata {
+                node: 123,
+                file_type: crate::storage::types::FileType::RegularFile,
+                link_count: 1,
+                size: 1234,
+                times: crate::storage::types::Times {
+                    accessed: 48,
+                    modified: 388,
+                    created: 34,
+                },
+                first_dir_entry: None,
+                last_dir_entry: Some(876),
+                chunk_type: Some(ChunkType::V2),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
+            }
+        );
+
+        // bytes that are not a valid enum value are read as the defaults
+        entry[8 + 64] = 7;
+        entry[8 + 65] = 9;
+        memory_manager
+            .get(MemoryId::new(1))
+            .write(MOUNTED_META_PTR, &entry);
+
+        let journal = CacheJournal::new(memory_manager.get(MemoryId::new(1))).unwrap();
+        journal.read_mounted_meta(&mut restored);
+        assert_eq!(restored.file_type, FileType::RegularFile);
+        assert_eq!(restored.chunk_type, None);
+    }
+
     #[test]
     fn fsj1_marker_is_written() {
         let mem = new_vector_memory();
@@ -227,6 +381,9 @@ mod tests {
             first_dir_entry: None,
             last_dir_entry: Some(876),
             chunk_type: None,
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
 
         journal.write_mounted_meta(&123, &meta);
@@ -80,6 +80,9 @@ mod tests {
             first_dir_entry: None,
             last_dir_entry: None,
             chunk_type: Some(ChunkType::V2),
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
 
         cache.update(node, &metadata);
@@ -111,6 +114,9 @@ mod tests {
             first_dir_entry: None,
             last_dir_entry: None,
             chunk_type: Some(ChunkType::V2),
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
 
         cache.update(node, &metadata);
@@ -150,6 +156,9 @@ mod tests {
                 first_dir_entry: None,
                 last_dir_entry: None,
                 chunk_type: Some(ChunkType::V2),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
             };
 
             cache.update(node, &metadata);
@@ -177,6 +186,9 @@ mod tests {
             first_dir_entry: None,
             last_dir_entry: None,
             chunk_type: Some(ChunkType::V2),
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
 
         cache.update(extra_node, &extra_metadata);
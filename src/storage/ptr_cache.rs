@@ -21,13 +21,20 @@ pub enum CachedChunkPtr {
 #[derive(Debug)]
 pub(crate) struct PtrCache {
     pointers: HashMap<(Node, FileChunkIndex), CachedChunkPtr>,
+    // number of range lookups in the chunk pointer map
+    #[cfg(test)]
+    pub range_lookups: usize,
 }
 
 impl PtrCache {
     pub fn new() -> PtrCache {
         let pointers: HashMap<(Node, FileChunkIndex), CachedChunkPtr> =
             HashMap::with_capacity(CACHE_CAPACITY);
-        PtrCache { pointers }
+        PtrCache {
+            pointers,
+            #[cfg(test)]
+            range_lookups: 0,
+        }
     }
 
     // add new cache pointer
@@ -53,6 +60,11 @@ impl PtrCache {
 
         let range = (node, from_index)..(node, to_index);
 
+        #[cfg(test)]
+        {
+            self.range_lookups += 1;
+        }
+
         let items = v2_chunk_ptr.range(range);
 
         let mut new_cache = Vec::with_capacity(to_index as usize - from_index as usize);
@@ -88,6 +100,23 @@ impl PtrCache {
         self.add(new_cache);
     }
 
+    // remove the cached pointers of the node chunks in the index range
+    pub fn remove_range(
+        &mut self,
+        node: Node,
+        from_index: FileChunkIndex,
+        to_index: FileChunkIndex,
+    ) {
+        if (to_index - from_index) as usize > self.pointers.len() {
+            self.pointers
+                .retain(|(n, index), _| *n != node || *index < from_index || *index >= to_index);
+        } else {
+            for index in from_index..to_index {
+                self.pointers.remove(&(node, index));
+            }
+        }
+    }
+
     // clear cache completely
     pub fn clear(&mut self) {
         self.pointers.clear();
@@ -132,6 +161,30 @@ mod tests {
         assert_eq!(cache.get((5 as Node, 8 as FileChunkIndex)), None);
     }
 
+    #[test]
+    fn remove_range_keeps_other_entries() {
+        let mut cache = PtrCache::new();
+        for node in [5, 6] {
+            for index in 0..10 {
+                cache.add(vec![(
+                    (node as Node, index as FileChunkIndex),
+                    CachedChunkPtr::ChunkExists(index as FileChunkPtr * 4096),
+                )]);
+            }
+        }
+
+        cache.remove_range(5, 2, 8);
+        assert_eq!(cache.pointers.len(), 14);
+
+        // a range larger than the cache takes the other path
+        cache.remove_range(6, 0, 1000);
+        assert_eq!(cache.pointers.len(), 4);
+
+        for index in [0, 1, 8, 9] {
+            assert!(cache.get((5, index)).is_some());
+        }
+    }
+
     #[test]
     fn test_clear() {
         let mut cache = PtrCache::new();
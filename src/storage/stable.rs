@@ -11,9 +11,14 @@ use crate::storage::ptr_cache::CachedChunkPtr;
 use crate::{
     error::Error,
     runtime::{
-        structure_helpers::{get_chunk_infos, grow_memory},
+        structure_helpers::{
+            check_chunk_limit, copy_to_front, get_chunk_infos, grow_memory, try_grow_memory,
+            zero_memory,
+        },
+        types::Advice,
         types::ChunkSize,
         types::ChunkType,
+        types::Normalization,
     },
 };
 
@@ -25,7 +30,8 @@ use super::{
     ptr_cache::PtrCache,
     types::{
         DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileChunkPtr, FileSize, FileType,
-        Header, Metadata, Node, Times, FILE_CHUNK_SIZE_V1, MAX_FILE_CHUNK_SIZE_V2,
+        Header, Metadata, Node, OperationCounters, Times, XattrName, FILE_CHUNK_SIZE_V1,
+        MAX_FILE_CHUNK_SIZE_V2, MAX_XATTR_SIZE_PER_NODE,
     },
     Storage,
 };
@@ -35,10 +41,13 @@ const FS_VERSION: u32 = 1;
 
 const DEFAULT_FIRST_MEMORY_INDEX: u8 = 229;
 
+// the stable cell bytes in front of the serialized header: the magic, the layout version and the value length.
+const HEADER_CELL_PREFIX_LEN: u64 = 8;
+
 // the maximum index accepted as the end range
 const MAX_MEMORY_INDEX: u8 = 254;
 
-// the number of memory indices used by the file system (currently 8 plus some reserved ids)
+// the number of memory indices used by the file system (currently 10)
 const MEMORY_INDEX_COUNT: u8 = 10;
 
 const ZEROES: [u8; MAX_FILE_CHUNK_SIZE_V2] = [0u8; MAX_FILE_CHUNK_SIZE_V2];
@@ -59,10 +68,17 @@ enum StorageMemoryIdx {
 
     // caching helper
     CacheJournal = 8,
+
+    // extended attributes
+    Xattrs = 9,
 }
 
+type XattrMap<M> = BTreeMap<(Node, XattrName), Vec<u8>, VirtualMemory<M>>;
+
 struct StorageMemories<M: Memory> {
     header_memory: VirtualMemory<M>,
+    // a second handle of the header memory to read its raw bytes.
+    header_view_memory: VirtualMemory<M>,
     metadata_memory: VirtualMemory<M>,
     direntry_memory: VirtualMemory<M>,
     filechunk_memory: VirtualMemory<M>,
@@ -74,12 +90,65 @@ struct StorageMemories<M: Memory> {
     v2_allocator_memory: VirtualMemory<M>,
 
     cache_journal: VirtualMemory<M>,
+
+    xattr_memory: VirtualMemory<M>,
+}
+
+// Frees `count` newly allocated chunks when dropped, unless the chunks are committed.
+// This keeps the allocator consistent if writing the chunk fails half way.
+struct NewChunkGuard<'a, M: Memory> {
+    allocator: &'a mut ChunkPtrAllocator<M>,
+    chunks: &'a VirtualMemory<M>,
+    ptr: Option<FileChunkPtr>,
+    count: u64,
+    span: u64,
+}
+
+impl<'a, M: Memory> NewChunkGuard<'a, M> {
+    fn new(
+        allocator: &'a mut ChunkPtrAllocator<M>,
+        chunks: &'a VirtualMemory<M>,
+        ptr: FileChunkPtr,
+        count: u64,
+        span: u64,
+    ) -> Self {
+        Self {
+            allocator,
+            chunks,
+            ptr: Some(ptr),
+            count,
+            span,
+        }
+    }
+
+    fn commit(mut self) {
+        self.ptr = None;
+    }
+}
+
+impl<M: Memory> Drop for NewChunkGuard<'_, M> {
+    fn drop(&mut self) {
+        if let Some(ptr) = self.ptr.take() {
+            self.allocator
+                .free_spans(self.chunks, ptr, self.count, self.span);
+        }
+    }
+}
+
+// The unwritten tail of a file, it never crosses a chunk boundary.
+struct AppendBuffer {
+    node: Node,
+    offset: FileSize,
+    data: Vec<u8>,
 }
 
 #[repr(C)]
 pub struct StableStorage<M: Memory> {
     // some static-sized filesystem data, contains version number and the next node id.
     header: Cell<Header, VirtualMemory<M>>,
+    // the header memory as seen by the file system, to detect writes from outside, see `check_integrity_fingerprint`.
+    header_memory: VirtualMemory<M>,
+    header_fingerprint: u64,
     // data about one file or a folder such as creation time, file size, associated chunk type, etc.
     metadata: BTreeMap<Node, Metadata, VirtualMemory<M>>,
     // information about the directory structure.
@@ -108,13 +177,29 @@ pub struct StableStorage<M: Memory> {
     // extra cache for storing information between upgrades.
     cache_journal: CacheJournal<M>,
 
+    // extended attributes of the nodes,
+    // the map is only created when the first attribute is stored to avoid allocating memory for it otherwise.
+    xattrs: Option<XattrMap<M>>,
+    xattr_memory: Option<VirtualMemory<M>>,
+
     // It is not used, but is needed to keep memories alive.
     _memory_manager: Option<MemoryManager<M>>,
     // active mounts.
     active_mounts: HashMap<Node, Box<dyn Memory>>,
+    // memories to mount once the file grows past the threshold.
+    spill_mounts: HashMap<Node, (FileSize, Box<dyn Memory>)>,
 
     // chunk type to use when creating new files.
     chunk_type: ChunkType,
+    // choose the chunk type of a new file on its first write.
+    adaptive_chunking: bool,
+    // collect small appends in memory and write them once a chunk is filled.
+    append_buffering: bool,
+
+    // maximum number of entries in a single directory.
+    max_dir_entries: Option<FileSize>,
+    // clock for the directory modification times.
+    dir_clock: Option<Box<dyn Fn() -> u64>>,
 
     // chunk pointer cache. This cache reduces chunk search overhead when reading a file,
     // or writing a file over existing data. (the new files still need insert new pointers into the treemap, hence it is rather slow)
@@ -122,6 +207,26 @@ pub struct StableStorage<M: Memory> {
 
     // only use it with non-mounted files. This reduces metadata search overhead, when the same file is .
     meta_cache: MetadataCache,
+
+    // metadata of a node that is only stored on flush, used to avoid rewriting a folder for each new entry.
+    deferred_metadata: Option<Metadata>,
+
+    // appended data of a file not yet written into its chunks, the file size already includes it.
+    append_buffer: Option<AppendBuffer>,
+
+    // node IDs taken from the header counter in advance, `new_node` hands them out without writing the header.
+    reserved_nodes: Range<Node>,
+
+    // number of metadata writes into the metadata map
+    #[cfg(test)]
+    pub(crate) metadata_writes: usize,
+    // number of header writes
+    #[cfg(test)]
+    pub(crate) header_writes: usize,
+
+    // low-level operation counts
+    #[cfg(feature = "profiling")]
+    counters: std::cell::Cell<OperationCounters>,
 }
 
 impl<M: Memory> StableStorage<M> {
@@ -186,8 +291,17 @@ impl<M: Memory> StableStorage<M> {
             memory_indices.start + StorageMemoryIdx::CacheJournal as u8,
         ));
 
+        let xattr_memory = memory_manager.get(MemoryId::new(
+            memory_indices.start + StorageMemoryIdx::Xattrs as u8,
+        ));
+
+        let header_view_memory = memory_manager.get(MemoryId::new(
+            memory_indices.start + StorageMemoryIdx::Header as u8,
+        ));
+
         let memories = StorageMemories {
             header_memory,
+            header_view_memory,
             metadata_memory,
             direntry_memory,
             filechunk_memory,
@@ -196,6 +310,7 @@ impl<M: Memory> StableStorage<M> {
             v2_chunks_memory,
             v2_allocator_memory,
             cache_journal,
+            xattr_memory,
         };
 
         Self::new_with_custom_memories(memories)
@@ -205,13 +320,25 @@ impl<M: Memory> StableStorage<M> {
         let default_header_value = Header {
             version: FS_VERSION,
             next_node: ROOT_NODE + 1,
+            filename_normalization: Normalization::None,
+            trim_trailing_space: false,
+            pending_removals: Vec::new(),
         };
 
         let v2_allocator = ChunkPtrAllocator::new(memories.v2_allocator_memory).unwrap();
         let cache_journal = CacheJournal::new(memories.cache_journal).unwrap();
 
+        // only load the extended attributes if they were ever stored
+        let (xattrs, xattr_memory) = if memories.xattr_memory.size() > 0 {
+            (Some(BTreeMap::init(memories.xattr_memory)), None)
+        } else {
+            (None, Some(memories.xattr_memory))
+        };
+
         let mut result = Self {
             header: Cell::init(memories.header_memory, default_header_value).unwrap(),
+            header_memory: memories.header_view_memory,
+            header_fingerprint: 0,
             metadata: BTreeMap::init(memories.metadata_memory),
             direntry: BTreeMap::init(memories.direntry_memory),
             filechunk: BTreeMap::init(memories.filechunk_memory),
@@ -223,18 +350,42 @@ impl<M: Memory> StableStorage<M> {
 
             cache_journal,
 
+            xattrs,
+            xattr_memory,
+
             // transient runtime data
             _memory_manager: None,
             active_mounts: HashMap::new(),
+            spill_mounts: HashMap::new(),
             // default chunk type is V2
             chunk_type: ChunkType::V2,
+            adaptive_chunking: false,
+            append_buffering: false,
+            max_dir_entries: None,
+            dir_clock: None,
             ptr_cache: PtrCache::new(),
 
             meta_cache: MetadataCache::new(),
+
+            deferred_metadata: None,
+
+            append_buffer: None,
+
+            reserved_nodes: 0..0,
+
+            #[cfg(test)]
+            metadata_writes: 0,
+            #[cfg(test)]
+            header_writes: 0,
+
+            #[cfg(feature = "profiling")]
+            counters: std::cell::Cell::new(OperationCounters::default()),
         };
 
         let version = result.header.get().version;
 
+        result.header_fingerprint = result.compute_header_fingerprint();
+
         if version != FS_VERSION {
             panic!("Unsupported file system version");
         }
@@ -251,6 +402,9 @@ impl<M: Memory> StableStorage<M> {
                     first_dir_entry: None,
                     last_dir_entry: None,
                     chunk_type: None,
+                    chunk_size: None,
+                    max_dir_entry: None,
+                    sorted_dir_entries: false,
                 };
                 result.put_metadata(ROOT_NODE, metadata);
             }
@@ -262,18 +416,129 @@ impl<M: Memory> StableStorage<M> {
         result
     }
 
+    // Get a copy of the file system header.
+    pub fn header(&self) -> Header {
+        self.header.get().clone()
+    }
+
+    // Store the header and remember the fingerprint of the header memory it results in.
+    fn set_header(&mut self, header: Header) {
+        self.header.set(header).unwrap();
+        self.header_fingerprint = self.compute_header_fingerprint();
+
+        #[cfg(test)]
+        {
+            self.header_writes += 1;
+        }
+    }
+
+    // Hash the size of the header memory and its stored bytes, the cell prefix and the whole serialized header.
+    fn compute_header_fingerprint(&self) -> u64 {
+        let size = self.header_memory.size();
+
+        let mut buf = Vec::new();
+        if size > 0 {
+            let mut prefix = [0u8; HEADER_CELL_PREFIX_LEN as usize];
+            self.header_memory.read(0, &mut prefix);
+            let value_len = u32::from_le_bytes(prefix[4..8].try_into().unwrap()) as u64;
+
+            // an overwritten length must not make the read go past the end of memory
+            let len = (HEADER_CELL_PREFIX_LEN + value_len).min(size * WASM_PAGE_SIZE_IN_BYTES);
+
+            buf.resize(len as usize, 0);
+            self.header_memory.read(0, &mut buf);
+        }
+        buf.extend_from_slice(&size.to_le_bytes());
+
+        xxhash_rust::xxh3::xxh3_64(&buf)
+    }
+
+    // Move the node ID counter forward to reserve the IDs below `node`, e.g. when importing nodes with preserved IDs.
+    // The counter can only grow, setting a smaller value would allow the existing IDs to be reused.
+    pub fn set_next_node(&mut self, node: Node) -> Result<(), Error> {
+        let mut header = self.header.get().clone();
+
+        if node < header.next_node {
+            return Err(Error::InvalidNodeId);
+        }
+
+        // the IDs below `node` may be imported, so the reserved ones cannot be handed out anymore
+        self.reserved_nodes = 0..0;
+
+        header.next_node = node;
+        self.set_header(header);
+
+        Ok(())
+    }
+
+    // Create the metadata of a node with an explicit ID, used to restore a snapshot with its hard links preserved.
+    // The node counter is moved past the restored ID, so that it is never handed out by `new_node` again.
+    pub fn create_node_with_id(&mut self, node: Node, metadata: Metadata) -> Result<(), Error> {
+        if metadata.node != node {
+            return Err(Error::InvalidNodeId);
+        }
+
+        if self.metadata.contains_key(&node) || self.mounted_meta.contains_key(&node) {
+            return Err(Error::FileAlreadyExists);
+        }
+
+        if node >= self.header.get().next_node {
+            self.set_next_node(node + 1)?;
+        } else if self.reserved_nodes.contains(&node) {
+            self.reserved_nodes = 0..0;
+        }
+
+        self.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    // List the V2 chunks of a node as `(index, pointer)` pairs, useful to inspect how a file is laid out in memory.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn debug_chunk_map(&self, node: Node) -> Vec<(FileChunkIndex, FileChunkPtr)> {
+        self.v2_chunk_ptr
+            .range((node, 0)..(node + 1, 0))
+            .map(|((_, index), ptr)| (index, ptr))
+            .collect()
+    }
+
+    // Check the integrity of the V2 chunk allocator: its free list must not contain duplicates,
+    // unaligned or never allocated pointers, nor pointers still used by a file.
+    pub fn verify_allocator(&self) -> Result<(), Error> {
+        self.v2_allocator.verify(
+            &self.v2_chunks,
+            self.v2_chunk_ptr.iter().map(|(_, ptr)| ptr),
+        )
+    }
+
     // write into mounted memory
-    fn write_mounted(&self, memory: &dyn Memory, offset: FileSize, buf: &[u8]) -> FileSize {
+    fn write_mounted(
+        &self,
+        memory: &dyn Memory,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<FileSize, Error> {
         let length_to_write = buf.len() as FileSize;
 
         // grow memory if needed
         let max_address = offset as FileSize + length_to_write;
 
-        grow_memory(memory, max_address);
+        try_grow_memory(memory, max_address)?;
 
         memory.write(offset, buf);
 
-        length_to_write
+        Ok(length_to_write)
+    }
+
+    // Update the low-level operation counts, this is a no-op without the `profiling` feature.
+    #[inline]
+    fn count(&self, _update: impl FnOnce(&mut OperationCounters)) {
+        #[cfg(feature = "profiling")]
+        {
+            let mut counters = self.counters.get();
+            _update(&mut counters);
+            self.counters.set(counters);
+        }
     }
 
     // Insert of update a selected file chunk with the data provided in a buffer.
@@ -287,6 +552,12 @@ impl<M: Memory> StableStorage<M> {
         let mut entry = self.filechunk.get(&(node, index)).unwrap_or_default();
         entry.bytes[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
         self.filechunk.insert((node, index), entry);
+
+        self.count(|c| {
+            c.btree_gets += 1;
+            c.btree_inserts += 1;
+            c.chunk_writes += 1;
+        });
     }
 
     fn write_chunks_v2(
@@ -294,11 +565,12 @@ impl<M: Memory> StableStorage<M> {
         node: Node,
         offset: FileSize,
         buf: &[u8],
+        chunk_size: usize,
     ) -> Result<FileSize, Error> {
         let mut remainder = buf.len() as FileSize;
         let last_address = offset + remainder;
 
-        let chunk_size = self.chunk_size();
+        let span = (chunk_size / self.chunk_size()) as u64;
 
         let start_index = (offset / chunk_size as FileSize) as FileChunkIndex;
 
@@ -310,13 +582,33 @@ impl<M: Memory> StableStorage<M> {
             node,
             offset,
             last_address,
-            self.chunk_size() as FileSize,
+            chunk_size as FileSize,
             &mut self.ptr_cache,
             &mut self.v2_chunk_ptr,
         );
 
         let write_iter: Vec<_> = write_iter.collect();
 
+        // a write covering several new chunks places them next to each other, the pointer to the next unused chunk
+        // of the run and the number of unused chunks are kept
+        let new_chunks = write_iter
+            .iter()
+            .filter(|((_, index), ptr)| {
+                // the iterator may end with the chunk starting at the end of the write
+                (*index as FileSize * (chunk_size as FileSize)) < last_address
+                    && !matches!(ptr, CachedChunkPtr::ChunkExists(_))
+            })
+            .count() as u64;
+
+        // spans released before are reused one by one rather than growing the memory for a new run
+        let mut run = if new_chunks > 1 && (span <= 1 || !self.v2_allocator.has_free_span(span)) {
+            self.v2_allocator
+                .allocate_contiguous((new_chunks * span) as usize)
+                .map(|ptr| (ptr, new_chunks))
+        } else {
+            None
+        };
+
         for ((nd, index), chunk_ptr) in write_iter {
             assert!(nd == node);
 
@@ -331,11 +623,34 @@ impl<M: Memory> StableStorage<M> {
             let write_buf =
                 &buf[size_written as usize..(size_written as usize + to_write as usize)];
 
-            let chunk_ptr = if let CachedChunkPtr::ChunkExists(ptr) = chunk_ptr {
-                ptr
+            if let CachedChunkPtr::ChunkExists(ptr) = chunk_ptr {
+                // growing here should not be required as the grow is called during
+                // grow_memory(&self.v2_chunks, chunk_ptr + offset + buf.len() as FileSize);
+                self.v2_chunks.write(ptr + chunk_offset, write_buf);
             } else {
                 // insert new chunk
-                let ptr = self.v2_allocator.allocate();
+                let (ptr, guard_count) = match run.as_mut() {
+                    Some((next_ptr, left)) => {
+                        let ptr = *next_ptr;
+                        // the rest of the run is released as well if this chunk fails
+                        let guard_count = *left;
+
+                        *next_ptr += chunk_size as FileSize;
+                        *left -= 1;
+
+                        (ptr, guard_count)
+                    }
+                    None => (self.v2_allocator.allocate_span(&self.v2_chunks, span), 1),
+                };
+
+                // the chunk is returned to the allocator, if any of the writes below fails
+                let new_chunk = NewChunkGuard::new(
+                    &mut self.v2_allocator,
+                    &self.v2_chunks,
+                    ptr,
+                    guard_count,
+                    span,
+                );
 
                 grow_memory(&self.v2_chunks, ptr + chunk_size as FileSize);
 
@@ -344,31 +659,43 @@ impl<M: Memory> StableStorage<M> {
                 // fill before written content
                 self.v2_chunks.write(ptr, &ZEROES[0..chunk_offset as usize]);
 
+                self.v2_chunks.write(ptr + chunk_offset, write_buf);
+
                 // fill after written content
                 self.v2_chunks.write(
                     ptr + chunk_offset + to_write as FileSize,
                     &ZEROES[0..(chunk_size - chunk_offset as usize - to_write as usize)],
                 );
 
-                // register new chunk pointer
+                new_chunk.commit();
+
+                // register new chunk pointer only once the chunk is fully written
                 self.v2_chunk_ptr.insert((node, index), ptr);
+                self.count(|c| {
+                    c.allocations += 1;
+                    c.btree_inserts += 1;
+                });
 
                 //
                 self.ptr_cache
                     .add(vec![((node, index), CachedChunkPtr::ChunkExists(ptr))]);
+            }
 
-                ptr
-            };
-
-            // growing here should not be required as the grow is called during
-            // grow_memory(&self.v2_chunks, chunk_ptr + offset + buf.len() as FileSize);
-            self.v2_chunks.write(chunk_ptr + chunk_offset, write_buf);
+            self.count(|c| c.chunk_writes += 1);
 
             chunk_offset = 0;
             size_written += to_write;
             remainder -= to_write;
         }
 
+        // release the chunks of the run that were not needed
+        if let Some((ptr, left)) = run {
+            if left > 0 {
+                self.v2_allocator
+                    .free_spans(&self.v2_chunks, ptr, left, span);
+            }
+        }
+
         Ok(size_written)
     }
 
@@ -420,6 +747,8 @@ impl<M: Memory> StableStorage<M> {
                             [chunk_offset as usize..chunk_offset as usize + to_read as usize],
                     );
 
+                    self.count(|c| c.chunk_reads += 1);
+
                     // consume token
                     cur_fetched = None;
                 } else {
@@ -445,6 +774,7 @@ impl<M: Memory> StableStorage<M> {
         offset: FileSize,
         file_size: FileSize,
         buf: &mut [u8],
+        chunk_size: usize,
     ) -> Result<FileSize, Error> {
         // early exit if nothing left to read
         if offset >= file_size {
@@ -454,8 +784,6 @@ impl<M: Memory> StableStorage<M> {
         // compute remainder to read
         let mut remainder = file_size - offset;
 
-        let chunk_size = self.chunk_size();
-
         let start_index = (offset / chunk_size as FileSize) as FileChunkIndex;
 
         let mut chunk_offset = offset - start_index as FileSize * chunk_size as FileSize;
@@ -465,6 +793,8 @@ impl<M: Memory> StableStorage<M> {
 
         let mut size_read: FileSize = 0;
 
+        let mut chunks_read = 0;
+
         let read_iter = ChunkV2Iterator::new(
             node,
             offset,
@@ -492,6 +822,7 @@ impl<M: Memory> StableStorage<M> {
 
             if let CachedChunkPtr::ChunkExists(cptr) = cached_chunk {
                 self.v2_chunks.read(cptr + chunk_offset, read_buf);
+                chunks_read += 1;
             } else {
                 // fill read buffer with 0
                 read_buf.iter_mut().for_each(|m| *m = 0)
@@ -502,6 +833,8 @@ impl<M: Memory> StableStorage<M> {
             remainder -= to_read;
         }
 
+        self.count(|c| c.chunk_reads += chunks_read);
+
         Ok(size_read)
     }
 
@@ -512,163 +845,113 @@ impl<M: Memory> StableStorage<M> {
             let mut meta: Metadata = Metadata::default();
             self.cache_journal.read_mounted_meta(&mut meta);
             self.mounted_meta.insert(node, meta);
+            self.count(|c| c.btree_inserts += 1);
         }
     }
 
-    fn use_v2(&mut self, metadata: &Metadata, node: u64) -> bool {
-        // decide if we use v2 chunks for reading/writing
-        let use_v2 = match metadata.chunk_type {
-            Some(ChunkType::V2) => true,
-            Some(ChunkType::V1) => false,
-
-            // try to figure out, which chunk type to use
-            None => {
-                if metadata.size > 0 {
-                    // try to find any v2 node, othersize use v1
-                    let ptr = self.v2_chunk_ptr.range((node, 0)..(node + 1, 0)).next();
-
-                    ptr.is_some()
-                } else {
-                    self.chunk_type() == ChunkType::V2
-                }
-            }
-        };
-        use_v2
-    }
-}
-
-impl<M: Memory> Storage for StableStorage<M> {
-    // Get the root node ID of the storage.
-    fn root_node(&self) -> Node {
-        ROOT_NODE
-    }
-
-    // Generate the next available node ID.
-    fn new_node(&mut self) -> Node {
-        let mut header = self.header.get().clone();
-
-        let result = header.next_node;
-
-        header.next_node += 1;
-
-        self.header.set(header).unwrap();
-
-        result
-    }
-
-    fn get_version(&self) -> u32 {
-        let header = self.header.get();
-        header.version
-    }
-
-    // Get the metadata associated with the node.
-    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
-        if self.is_mounted(node) {
-            if self.cache_journal.read_mounted_meta_node() == Some(node) {
-                let mut meta = Metadata::default();
-                self.cache_journal.read_mounted_meta(&mut meta);
-
-                return Ok(meta);
-            }
+    // remove file chunks beyond the new file size, the tail of the last chunk is filled with zeroes
+    fn rm_chunks_beyond(&mut self, node: Node, size: FileSize) -> Result<(), Error> {
+        let metadata = self.get_metadata(node)?;
 
-            self.mounted_meta.get(&node).ok_or(Error::NotFound)
+        let chunk_size = if self.use_v2(&metadata, node) {
+            self.file_chunk_size(&metadata)
         } else {
-            let meta = self.meta_cache.get(node);
-
-            if let Some(meta) = meta {
-                return Ok(meta);
-            }
-
-            let meta = self.metadata.get(&node).ok_or(Error::NotFound);
+            FILE_CHUNK_SIZE_V1
+        } as FileSize;
 
-            if let Ok(ref meta) = meta {
-                self.meta_cache.update(node, meta);
-            }
+        let tail_end = size.next_multiple_of(chunk_size).min(metadata.size);
 
-            meta
+        if tail_end > size {
+            self.write(node, size, &ZEROES[0..(tail_end - size) as usize])?;
         }
-    }
 
-    // Update the metadata associated with the node.
-    fn put_metadata(&mut self, node: Node, metadata: Metadata) {
-        assert_eq!(node, metadata.node, "Node does not match medatada.node!");
+        // delete v1 chunks
+        let first_index = size.div_ceil(FILE_CHUNK_SIZE_V1 as FileSize) as FileChunkIndex;
+        let range = (node, first_index)..(node + 1, 0);
+        let chunks: Vec<_> = self.filechunk.range(range).map(|(k, _v)| k).collect();
 
-        if self.is_mounted(node) {
-            // flush changes if the last node was different
-            if self.cache_journal.read_mounted_meta_node() != Some(node) {
-                self.flush_mounted_meta();
-            }
+        for key in chunks.into_iter() {
+            self.filechunk.remove(&key);
+        }
 
-            self.cache_journal.write_mounted_meta(&node, &metadata)
-        } else {
-            self.meta_cache.update(node, &metadata);
-            self.metadata.insert(node, metadata);
+        // delete v2 chunks
+        let first_index =
+            size.div_ceil(self.file_chunk_size(&metadata) as FileSize) as FileChunkIndex;
+        let range = (node, first_index)..(node + 1, 0);
+        let chunks: Vec<_> = self.v2_chunk_ptr.range(range).collect();
+
+        let span = self.file_chunk_span(&metadata);
+        for (key, ptr) in chunks.into_iter() {
+            self.v2_chunk_ptr.remove(&key);
+            self.v2_allocator.free_span(&self.v2_chunks, ptr, span);
         }
-    }
 
-    // Retrieve the DirEntry instance given the Node and DirEntryIndex.
-    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
-        self.direntry.get(&(node, index)).ok_or(Error::NotFound)
-    }
+        self.ptr_cache.clear();
 
-    // Update or insert the DirEntry instance given the Node and DirEntryIndex.
-    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
-        self.direntry.insert((node, index), entry);
+        Ok(())
     }
 
-    // Remove the DirEntry instance given the Node and DirEntryIndex.
-    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
-        self.direntry.remove(&(node, index));
+    fn xattrs_mut(&mut self) -> &mut XattrMap<M> {
+        self.xattrs
+            .get_or_insert_with(|| BTreeMap::init(self.xattr_memory.take().unwrap()))
     }
 
-    // Fill the buffer contents with data of a chosen data range.
-    fn read(&mut self, node: Node, offset: FileSize, buf: &mut [u8]) -> Result<FileSize, Error> {
-        let metadata = self.get_metadata(node)?;
-
-        let file_size = metadata.size;
-
-        if offset >= file_size {
-            return Ok(0);
-        }
+    fn xattr_keys(&self, node: Node) -> Vec<(Node, XattrName)> {
+        let Some(xattrs) = self.xattrs.as_ref() else {
+            return Vec::new();
+        };
 
-        let size_read = if let Some(memory) = self.active_mounts.get(&node) {
-            let remainder = file_size - offset;
-            let to_read = remainder.min(buf.len() as FileSize);
+        let range = (node, XattrName(String::new()))..(node + 1, XattrName(String::new()));
 
-            // grow memory also for reading
-            grow_memory(memory.as_ref(), offset + to_read);
+        xattrs.range(range).map(|(k, _v)| k).collect()
+    }
 
-            memory.read(offset, &mut buf[..to_read as usize]);
-            to_read
-        } else {
-            let use_v2 = self.use_v2(&metadata, node);
+    // Write the data into the file chunks or the mounted memory, bypassing the append buffer.
+    fn write_direct(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<FileSize, Error> {
+        let mut metadata = self.get_metadata(node)?;
 
-            if use_v2 {
-                self.read_chunks_v2(node, offset, file_size, buf)?
+        // the chunk type of a new file is decided by the size of its first write
+        if self.adaptive_chunking
+            && metadata.file_type == FileType::RegularFile
+            && metadata.chunk_type.is_none()
+            && metadata.size == 0
+            && !self.is_mounted(node)
+        {
+            let chunk_type = if buf.len() >= self.chunk_size() {
+                ChunkType::V2
             } else {
-                self.read_chunks_v1(node, offset, file_size, buf)?
-            }
-        };
-
-        Ok(size_read)
-    }
+                ChunkType::V1
+            };
 
-    // Write file at the current file cursor, the cursor position will NOT be updated after writing.
-    fn write(&mut self, node: Node, offset: FileSize, buf: &[u8]) -> Result<FileSize, Error> {
-        let mut metadata = self.get_metadata(node)?;
+            metadata.chunk_type = Some(chunk_type);
+            self.put_metadata(node, metadata.clone());
+        }
 
         let written_size = if let Some(memory) = self.get_mounted_memory(node) {
-            self.write_mounted(memory, offset, buf);
+            // the memory might keep stale data past the end of file, clear the gap the write exposes
+            if offset > metadata.size {
+                zero_memory(memory, metadata.size, offset);
+            }
 
-            buf.len() as FileSize
+            self.write_mounted(memory, offset, buf)?
         } else {
             let end = offset + buf.len() as FileSize;
 
             let use_v2 = self.use_v2(&metadata, node);
 
             if use_v2 {
-                self.write_chunks_v2(node, offset, buf)?
+                let chunk_size = self.file_chunk_size(&metadata);
+                check_chunk_limit(end, chunk_size)?;
+
+                self.write_chunks_v2(node, offset, buf, chunk_size)?
             } else {
+                check_chunk_limit(end, FILE_CHUNK_SIZE_V1)?;
+
                 let chunk_infos = get_chunk_infos(offset, end, FILE_CHUNK_SIZE_V1);
 
                 let mut written = 0usize;
@@ -697,17 +980,788 @@ impl<M: Memory> Storage for StableStorage<M> {
         Ok(written_size)
     }
 
-    //
-    fn rm_file(&mut self, node: Node) -> Result<(), Error> {
-        if self.is_mounted(node) {
-            return Err(Error::CannotRemoveMountedMemoryFile);
+    // Append a small record to the buffered file tail, returns None if the write is not a buffered append.
+    fn buffer_append(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        buf: &[u8],
+    ) -> Result<Option<FileSize>, Error> {
+        let metadata = self.get_metadata(node)?;
+
+        // the first write of a file decides its chunk type, so it is never buffered
+        if metadata.file_type != FileType::RegularFile
+            || self
+                .deferred_metadata
+                .as_ref()
+                .is_some_and(|meta| meta.node == node)
+            || metadata.size == 0
+            || offset != metadata.size
+            || self.is_mounted(node)
+        {
+            return Ok(None);
         }
 
-        // delete v1 chunks
-        let range = (node, 0)..(node + 1, 0);
-        let mut chunks: Vec<(Node, FileChunkIndex)> = Vec::new();
-        for (k, _v) in self.filechunk.range(range) {
-            chunks.push(k);
+        let chunk_size = if self.use_v2(&metadata, node) {
+            self.file_chunk_size(&metadata) as FileSize
+        } else {
+            FILE_CHUNK_SIZE_V1 as FileSize
+        };
+
+        if buf.len() as FileSize >= chunk_size {
+            return Ok(None);
+        }
+
+        check_chunk_limit(offset + buf.len() as FileSize, chunk_size as usize)?;
+
+        let continues_tail = self.append_buffer.as_ref().is_some_and(|tail| {
+            tail.node == node && tail.offset + tail.data.len() as FileSize == offset
+        });
+
+        if !continues_tail {
+            // only one file tail is kept at a time
+            self.flush_all_append_buffers()?;
+
+            self.append_buffer = Some(AppendBuffer {
+                node,
+                offset,
+                data: Vec::with_capacity(chunk_size as usize),
+            });
+        }
+
+        let end = offset + buf.len() as FileSize;
+
+        let tail = self.append_buffer.as_mut().unwrap();
+        tail.data.extend_from_slice(buf);
+
+        // write the tail once it reaches the chunk boundary, the rest stays buffered
+        let chunk_end = (tail.offset / chunk_size + 1) * chunk_size;
+
+        if end >= chunk_end {
+            let tail = self.append_buffer.take().unwrap();
+            let split = (chunk_end - tail.offset) as usize;
+
+            self.write_direct(node, tail.offset, &tail.data[..split])?;
+
+            if split < tail.data.len() {
+                self.append_buffer = Some(AppendBuffer {
+                    node,
+                    offset: chunk_end,
+                    data: tail.data[split..].to_vec(),
+                });
+            }
+        }
+
+        // the stored size is only advanced by writing the tail, the size seen by get_metadata includes the buffered bytes
+        Ok(Some(buf.len() as FileSize))
+    }
+
+    // Get the metadata of a node that is not mounted as it is stored, without the buffered tail.
+    fn get_stored_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        if let Some(meta) = self.deferred_metadata.as_ref() {
+            if meta.node == node {
+                return Ok(meta.clone());
+            }
+        }
+
+        let meta = self.meta_cache.get(node);
+
+        if let Some(meta) = meta {
+            return Ok(meta);
+        }
+
+        let meta = self.metadata.get(&node).ok_or(Error::NotFound);
+        self.count(|c| c.btree_gets += 1);
+
+        if let Ok(ref meta) = meta {
+            self.meta_cache.update(node, meta);
+        }
+
+        meta
+    }
+
+    // Write the buffered tail of the node into its chunks.
+    fn flush_append_buffer(&mut self, node: Node) -> Result<(), Error> {
+        if self
+            .append_buffer
+            .as_ref()
+            .is_some_and(|tail| tail.node == node)
+        {
+            self.flush_all_append_buffers()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_all_append_buffers(&mut self) -> Result<(), Error> {
+        if let Some(tail) = self.append_buffer.take() {
+            self.write_direct(tail.node, tail.offset, &tail.data)?;
+        }
+
+        Ok(())
+    }
+
+    // Move the file into its spill memory if the file end passes the spill threshold.
+    fn spill_if_needed(&mut self, node: Node, end: FileSize) -> Result<(), Error> {
+        if self
+            .spill_mounts
+            .get(&node)
+            .is_none_or(|(threshold, _)| end <= *threshold)
+        {
+            return Ok(());
+        }
+
+        let (_, memory) = self.spill_mounts.remove(&node).unwrap();
+
+        self.mount_node(node, memory)?;
+        self.init_mounted_memory(node)
+    }
+
+    // Size of the V2 chunks of the file.
+    fn file_chunk_size(&self, metadata: &Metadata) -> usize {
+        metadata
+            .chunk_size
+            .map_or(self.chunk_size(), |chunk_size| chunk_size as usize)
+    }
+
+    // Number of allocator chunks taken by one V2 chunk of the file.
+    fn file_chunk_span(&self, metadata: &Metadata) -> u64 {
+        (self.file_chunk_size(metadata) / self.chunk_size()) as u64
+    }
+
+    fn use_v2(&mut self, metadata: &Metadata, node: u64) -> bool {
+        // decide if we use v2 chunks for reading/writing
+        let use_v2 = match metadata.chunk_type {
+            Some(ChunkType::V2) => true,
+            Some(ChunkType::V1) => false,
+
+            // try to figure out, which chunk type to use
+            None => {
+                if metadata.size > 0 {
+                    // try to find any v2 node, othersize use v1
+                    let ptr = self.v2_chunk_ptr.range((node, 0)..(node + 1, 0)).next();
+
+                    ptr.is_some()
+                } else {
+                    self.chunk_type() == ChunkType::V2
+                }
+            }
+        };
+        use_v2
+    }
+}
+
+impl<M: Memory> Storage for StableStorage<M> {
+    // Get the root node ID of the storage.
+    fn root_node(&self) -> Node {
+        ROOT_NODE
+    }
+
+    // Generate the next available node ID.
+    fn new_node(&mut self) -> Node {
+        if let Some(node) = self.reserved_nodes.next() {
+            return node;
+        }
+
+        let mut header = self.header.get().clone();
+
+        let result = header.next_node;
+
+        header.next_node += 1;
+
+        self.set_header(header);
+
+        result
+    }
+
+    fn reserve_nodes(&mut self, count: u64) {
+        // the IDs left from a previous reservation are skipped
+        let mut header = self.header.get().clone();
+
+        let start = header.next_node;
+        header.next_node += count;
+
+        self.set_header(header);
+        self.reserved_nodes = start..start + count;
+    }
+
+    fn get_version(&self) -> u32 {
+        let header = self.header.get();
+        header.version
+    }
+
+    fn check_integrity_fingerprint(&self) -> bool {
+        self.compute_header_fingerprint() == self.header_fingerprint
+    }
+
+    fn next_node(&self) -> Node {
+        // the reserved IDs are handed out before the stored counter
+        if !self.reserved_nodes.is_empty() {
+            return self.reserved_nodes.start;
+        }
+
+        self.header.get().next_node
+    }
+
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = Node> + '_> {
+        Box::new(self.metadata.iter().map(|(node, _)| node))
+    }
+
+    fn node_count(&self) -> u64 {
+        self.metadata.len()
+    }
+
+    // Get the metadata associated with the node.
+    fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
+        if self.is_mounted(node) {
+            if self.cache_journal.read_mounted_meta_node() == Some(node) {
+                let mut meta = Metadata::default();
+                self.cache_journal.read_mounted_meta(&mut meta);
+
+                return Ok(meta);
+            }
+
+            self.count(|c| c.btree_gets += 1);
+            self.mounted_meta.get(&node).ok_or(Error::NotFound)
+        } else {
+            let mut meta = self.get_stored_metadata(node)?;
+
+            // the stored size ends where the buffered tail starts, the tail is added until it is written,
+            // unless the file was extended past the tail
+            if let Some(tail) = self.append_buffer.as_ref() {
+                if tail.node == node {
+                    meta.size = meta.size.max(tail.offset + tail.data.len() as FileSize);
+                }
+            }
+
+            Ok(meta)
+        }
+    }
+
+    // Update the metadata associated with the node.
+    fn put_metadata(&mut self, node: Node, mut metadata: Metadata) {
+        assert_eq!(node, metadata.node, "Node does not match medatada.node!");
+
+        if self.is_mounted(node) {
+            // flush changes if the last node was different
+            if self.cache_journal.read_mounted_meta_node() != Some(node) {
+                self.flush_mounted_meta();
+            }
+
+            self.cache_journal.write_mounted_meta(&node, &metadata)
+        } else {
+            if let Some(meta) = self.deferred_metadata.as_mut() {
+                if meta.node == node {
+                    *meta = metadata;
+                    return;
+                }
+            }
+
+            #[cfg(test)]
+            {
+                self.metadata_writes += 1;
+            }
+
+            if let Some(tail) = self.append_buffer.as_mut().filter(|tail| tail.node == node) {
+                let end = tail.offset + tail.data.len() as FileSize;
+
+                // a truncated file drops the buffered data past its new end
+                if metadata.size < end {
+                    tail.data
+                        .truncate(metadata.size.saturating_sub(tail.offset) as usize);
+                }
+
+                if metadata.size > end {
+                    // the file grows past the tail, the tail stays buffered and is written inside the new size.
+                    // The callers extending a file flush it first, so the stored size does not cover unwritten bytes
+                } else if tail.data.is_empty() {
+                    self.append_buffer = None;
+                } else {
+                    // the buffered bytes are not stored yet, so the stored size must not cover them
+                    metadata.size = tail.offset;
+                }
+            }
+
+            self.meta_cache.update(node, &metadata);
+            self.metadata.insert(node, metadata);
+            self.count(|c| c.btree_inserts += 1);
+        }
+    }
+
+    // Retrieve the DirEntry instance given the Node and DirEntryIndex.
+    fn get_direntry(&self, node: Node, index: DirEntryIndex) -> Result<DirEntry, Error> {
+        self.count(|c| c.btree_gets += 1);
+        self.direntry.get(&(node, index)).ok_or(Error::NotFound)
+    }
+
+    // Update or insert the DirEntry instance given the Node and DirEntryIndex.
+    fn put_direntry(&mut self, node: Node, index: DirEntryIndex, entry: DirEntry) {
+        self.count(|c| c.btree_inserts += 1);
+        self.direntry.insert((node, index), entry);
+    }
+
+    // Remove the DirEntry instance given the Node and DirEntryIndex.
+    fn rm_direntry(&mut self, node: Node, index: DirEntryIndex) {
+        self.direntry.remove(&(node, index));
+    }
+
+    // Fill the buffer contents with data of a chosen data range.
+    fn read(&mut self, node: Node, offset: FileSize, buf: &mut [u8]) -> Result<FileSize, Error> {
+        self.flush_append_buffer(node)?;
+
+        let metadata = self.get_metadata(node)?;
+
+        let file_size = metadata.size;
+
+        if offset >= file_size {
+            return Ok(0);
+        }
+
+        let size_read = if let Some(memory) = self.active_mounts.get(&node) {
+            let remainder = file_size - offset;
+            let to_read = remainder.min(buf.len() as FileSize);
+
+            // grow memory also for reading, the size may point past what the memory can hold
+            try_grow_memory(memory.as_ref(), offset + to_read)?;
+
+            memory.read(offset, &mut buf[..to_read as usize]);
+            to_read
+        } else {
+            let use_v2 = self.use_v2(&metadata, node);
+
+            if use_v2 {
+                let chunk_size = self.file_chunk_size(&metadata);
+                self.read_chunks_v2(node, offset, file_size, buf, chunk_size)?
+            } else {
+                self.read_chunks_v1(node, offset, file_size, buf)?
+            }
+        };
+
+        Ok(size_read)
+    }
+
+    // Read a single chunk directly from the V2 chunk memory, V1 chunks, mounted files and files with their own
+    // chunk size use the regular read.
+    fn read_chunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        buf: &mut [u8],
+    ) -> Result<FileSize, Error> {
+        self.flush_append_buffer(node)?;
+
+        let metadata = self.get_metadata(node)?;
+
+        let chunk_size = self.chunk_size() as FileSize;
+        let offset = index as FileSize * chunk_size;
+
+        if offset >= metadata.size {
+            return Ok(0);
+        }
+
+        let to_read = (metadata.size - offset)
+            .min(chunk_size)
+            .min(buf.len() as FileSize);
+
+        let read_buf = &mut buf[..to_read as usize];
+
+        if self.is_mounted(node) || !self.use_v2(&metadata, node) || metadata.chunk_size.is_some() {
+            return self.read(node, offset, read_buf);
+        }
+
+        let cached_ptr = match self.ptr_cache.get((node, index)) {
+            Some(ptr) => ptr,
+            None => {
+                self.count(|c| c.btree_gets += 1);
+                let ptr = match self.v2_chunk_ptr.get(&(node, index)) {
+                    Some(ptr) => CachedChunkPtr::ChunkExists(ptr),
+                    None => CachedChunkPtr::ChunkMissing,
+                };
+
+                self.ptr_cache.add(vec![((node, index), ptr)]);
+
+                ptr
+            }
+        };
+
+        if let CachedChunkPtr::ChunkExists(ptr) = cached_ptr {
+            self.v2_chunks.read(ptr, read_buf);
+            self.count(|c| c.chunk_reads += 1);
+        } else {
+            read_buf.iter_mut().for_each(|m| *m = 0)
+        }
+
+        Ok(to_read)
+    }
+
+    // Write file at the current file cursor, the cursor position will NOT be updated after writing.
+    fn write(&mut self, node: Node, offset: FileSize, buf: &[u8]) -> Result<FileSize, Error> {
+        self.spill_if_needed(node, offset + buf.len() as FileSize)?;
+
+        if self.append_buffering {
+            if let Some(written) = self.buffer_append(node, offset, buf)? {
+                return Ok(written);
+            }
+        }
+
+        self.flush_append_buffer(node)?;
+
+        self.write_direct(node, offset, buf)
+    }
+
+    fn estimate_write_chunks(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Result<usize, Error> {
+        self.flush_append_buffer(node)?;
+
+        let metadata = self.get_metadata(node)?;
+
+        if len == 0 || self.is_mounted(node) {
+            return Ok(0);
+        }
+
+        // mirror the chunk type decision of the write
+        let use_v2 = if self.adaptive_chunking
+            && metadata.file_type == FileType::RegularFile
+            && metadata.chunk_type.is_none()
+            && metadata.size == 0
+        {
+            len >= self.chunk_size() as FileSize
+        } else {
+            self.use_v2(&metadata, node)
+        };
+
+        let chunk_size = if use_v2 {
+            self.file_chunk_size(&metadata) as FileSize
+        } else {
+            FILE_CHUNK_SIZE_V1 as FileSize
+        };
+
+        let first = (offset / chunk_size) as FileChunkIndex;
+        let last_excluded = ((offset + len - 1) / chunk_size + 1) as FileChunkIndex;
+        let range = (node, first)..(node, last_excluded);
+
+        let existing = if use_v2 {
+            self.v2_chunk_ptr.range(range).count()
+        } else {
+            self.filechunk.range(range).count()
+        };
+
+        Ok((last_excluded - first) as usize - existing)
+    }
+
+    fn preallocate(&mut self, node: Node, size: FileSize) -> Result<(), Error> {
+        self.flush_append_buffer(node)?;
+
+        let mut metadata = self.get_metadata(node)?;
+
+        if self.is_mounted(node) || !self.use_v2(&metadata, node) {
+            return Ok(());
+        }
+
+        let chunk_size = self.file_chunk_size(&metadata) as FileSize;
+        let span = self.file_chunk_span(&metadata);
+
+        check_chunk_limit(size, chunk_size as usize)?;
+
+        // the reserved chunks are V2, make sure the first write does not pick another chunk type
+        if metadata.chunk_type.is_none() {
+            metadata.chunk_type = Some(ChunkType::V2);
+            self.put_metadata(node, metadata);
+        }
+
+        let chunk_count = size.div_ceil(chunk_size);
+
+        if chunk_count == 0 {
+            return Ok(());
+        }
+
+        // within the chunk limit, the index of the last chunk fits into FileChunkIndex
+        let last_index = (chunk_count - 1) as FileChunkIndex;
+
+        let existing: Vec<FileChunkIndex> = self
+            .v2_chunk_ptr
+            .range((node, 0)..=(node, last_index))
+            .map(|((_, index), _)| index)
+            .collect();
+
+        let missing_count = chunk_count - existing.len() as u64;
+
+        if missing_count == 0 {
+            return Ok(());
+        }
+
+        // the chunk memory is grown before the run is taken, so a failed grow leaves the allocator untouched
+        try_grow_memory(
+            &self.v2_chunks,
+            self.v2_allocator.high_water_mark() + missing_count * chunk_size,
+        )?;
+
+        let missing: Vec<FileChunkIndex> = (0..=last_index)
+            .filter(|index| existing.binary_search(index).is_err())
+            .collect();
+
+        let first_ptr = self.v2_allocator.allocate_run(missing.len() as u64 * span);
+
+        // the memory above the high-water mark may still hold the chunks of a formatted file system, clear it
+        let zeroes = vec![0u8; chunk_size as usize];
+
+        for (i, index) in missing.iter().enumerate() {
+            let ptr = first_ptr + i as FileSize * chunk_size;
+            self.v2_chunks.write(ptr, &zeroes);
+            self.v2_chunk_ptr.insert((node, *index), ptr);
+        }
+
+        self.ptr_cache.clear();
+
+        self.count(|c| {
+            c.allocations += 1;
+            c.btree_inserts += missing.len() as u64;
+            c.chunk_writes += missing.len() as u64;
+        });
+
+        Ok(())
+    }
+
+    fn clear_caches(&mut self) {
+        self.ptr_cache.clear();
+        self.meta_cache.clear();
+    }
+
+    fn advise(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        len: FileSize,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        let metadata = self.get_metadata(node)?;
+
+        // only the V2 chunk pointers are cached
+        if len == 0 || self.is_mounted(node) || !self.use_v2(&metadata, node) {
+            return Ok(());
+        }
+
+        let chunk_size = self.file_chunk_size(&metadata) as FileSize;
+        let first = (offset / chunk_size) as FileChunkIndex;
+        let last_excluded = offset
+            .saturating_add(len)
+            .div_ceil(chunk_size)
+            .min(FileChunkIndex::MAX as FileSize) as FileChunkIndex;
+
+        match advice {
+            Advice::WillNeed => {
+                self.ptr_cache
+                    .add_range(node, first, last_excluded, &self.v2_chunk_ptr)
+            }
+            Advice::DontNeed => self.ptr_cache.remove_range(node, first, last_excluded),
+            Advice::Normal | Advice::Sequential | Advice::Random | Advice::NoReuse => {}
+        }
+
+        Ok(())
+    }
+
+    fn fragmentation(&mut self, node: Node) -> Result<f64, Error> {
+        self.flush_append_buffer(node)?;
+        let metadata = self.get_metadata(node)?;
+
+        if self.is_mounted(node) {
+            return Ok(0.0);
+        }
+
+        let chunk_size = self.file_chunk_size(&metadata) as FileChunkPtr;
+
+        let ptrs: Vec<FileChunkPtr> = self
+            .v2_chunk_ptr
+            .range((node, 0)..(node + 1, 0))
+            .map(|(_, ptr)| ptr)
+            .collect();
+
+        if ptrs.is_empty() {
+            return Ok(0.0);
+        }
+
+        let breaks = ptrs
+            .windows(2)
+            .filter(|w| w[1] != w[0] + chunk_size)
+            .count();
+
+        Ok(breaks as f64 / ptrs.len() as f64)
+    }
+
+    fn chunk_range(
+        &mut self,
+        node: Node,
+    ) -> Result<Option<(FileChunkIndex, FileChunkIndex)>, Error> {
+        self.flush_append_buffer(node)?;
+        self.get_metadata(node)?;
+
+        if self.is_mounted(node) {
+            return Ok(None);
+        }
+
+        let range = (node, 0)..(node + 1, 0);
+
+        // a file keeps its chunks either as V1 or as V2 chunks
+        let mut v2_chunks = self
+            .v2_chunk_ptr
+            .range(range.clone())
+            .map(|((_, index), _)| index);
+        if let Some(first) = v2_chunks.next() {
+            let last = v2_chunks.next_back().unwrap_or(first);
+            return Ok(Some((first, last)));
+        }
+
+        let mut v1_chunks = self.filechunk.range(range).map(|((_, index), _)| index);
+        Ok(v1_chunks
+            .next()
+            .map(|first| (first, v1_chunks.next_back().unwrap_or(first))))
+    }
+
+    fn defragment(&mut self, node: Node) -> Result<(), Error> {
+        if self.fragmentation(node)? == 0.0 {
+            return Ok(());
+        }
+
+        let metadata = self.get_metadata(node)?;
+        let chunk_size = self.file_chunk_size(&metadata) as FileSize;
+        let span = self.file_chunk_span(&metadata);
+
+        let chunks: Vec<_> = self.v2_chunk_ptr.range((node, 0)..(node + 1, 0)).collect();
+        let count = chunks.len() as u64;
+
+        // the chunks are copied into a fresh run, the old chunks are released afterwards
+        let first_ptr = self.v2_allocator.allocate_run(count * span);
+        grow_memory(&self.v2_chunks, first_ptr + count * chunk_size);
+
+        let mut buf = vec![0u8; chunk_size as usize];
+
+        for (i, ((_, index), ptr)) in chunks.into_iter().enumerate() {
+            let new_ptr = first_ptr + i as FileSize * chunk_size;
+
+            self.v2_chunks.read(ptr, &mut buf);
+            self.v2_chunks.write(new_ptr, &buf);
+
+            self.v2_chunk_ptr.insert((node, index), new_ptr);
+            self.v2_allocator.free_span(&self.v2_chunks, ptr, span);
+        }
+
+        self.ptr_cache.clear();
+
+        self.count(|c| {
+            c.allocations += 1;
+            c.chunk_reads += count;
+            c.chunk_writes += count;
+            c.btree_inserts += count;
+        });
+
+        Ok(())
+    }
+
+    fn format(&mut self) -> Result<(), Error> {
+        if !self.active_mounts.is_empty() {
+            return Err(Error::CannotRemoveMountedMemoryFile);
+        }
+
+        let mut root = self.get_metadata(ROOT_NODE)?;
+        root.size = 0;
+        root.first_dir_entry = None;
+        root.last_dir_entry = None;
+        root.max_dir_entry = None;
+
+        self.deferred_metadata = None;
+        self.append_buffer = None;
+        self.spill_mounts.clear();
+        self.reserved_nodes = 0..0;
+
+        self.metadata.clear_new();
+        self.direntry.clear_new();
+        self.filechunk.clear_new();
+        self.mounted_meta.clear_new();
+        self.v2_chunk_ptr.clear_new();
+
+        if let Some(xattrs) = self.xattrs.as_mut() {
+            xattrs.clear_new();
+        }
+
+        // all chunks are released, the chunk memory is reused from the start
+        self.v2_allocator.reset();
+
+        self.ptr_cache.clear();
+        self.meta_cache.clear();
+        self.cache_journal.reset_mounted_meta();
+
+        let mut header = self.header.get().clone();
+        header.next_node = ROOT_NODE + 1;
+        header.pending_removals.clear();
+        self.set_header(header);
+
+        self.put_metadata(ROOT_NODE, root);
+
+        Ok(())
+    }
+
+    fn rm_file_chunks(&mut self, node: Node, max_chunks: usize) -> Result<bool, Error> {
+        if self.is_mounted(node) {
+            return Err(Error::CannotRemoveMountedMemoryFile);
+        }
+
+        self.flush_append_buffer(node)?;
+
+        let span = self.file_chunk_span(&self.get_metadata(node)?);
+
+        let range = (node, 0)..(node + 1, 0);
+
+        // delete v1 chunks
+        let chunks: Vec<(Node, FileChunkIndex)> = self
+            .filechunk
+            .range(range.clone())
+            .map(|(k, _v)| k)
+            .take(max_chunks)
+            .collect();
+
+        let mut budget = max_chunks - chunks.len();
+
+        for key in chunks.into_iter() {
+            self.filechunk.remove(&key);
+        }
+
+        // delete v2 chunks
+        let chunks: Vec<((Node, FileChunkIndex), FileChunkPtr)> = self
+            .v2_chunk_ptr
+            .range(range.clone())
+            .take(budget)
+            .collect();
+
+        budget -= chunks.len();
+
+        for (key, ptr) in chunks.into_iter() {
+            self.v2_chunk_ptr.remove(&key);
+            self.v2_allocator.free_span(&self.v2_chunks, ptr, span);
+        }
+
+        self.ptr_cache.clear();
+
+        // the budget is only left over if both ranges were exhausted
+        Ok(budget > 0
+            || (self.filechunk.range(range.clone()).next().is_none()
+                && self.v2_chunk_ptr.range(range).next().is_none()))
+    }
+
+    //
+    fn rm_file(&mut self, node: Node) -> Result<(), Error> {
+        if self.is_mounted(node) {
+            return Err(Error::CannotRemoveMountedMemoryFile);
+        }
+
+        // delete v1 chunks
+        let range = (node, 0)..(node + 1, 0);
+        let mut chunks: Vec<(Node, FileChunkIndex)> = Vec::new();
+        for (k, _v) in self.filechunk.range(range) {
+            chunks.push(k);
         }
 
         for (nd, idx) in chunks.into_iter() {
@@ -715,182 +1769,1353 @@ impl<M: Memory> Storage for StableStorage<M> {
             self.filechunk.remove(&(node, idx));
         }
 
-        // delete v2 chunks
-        let range = (node, 0)..(node + 1, 0);
-        let mut chunks: Vec<(Node, FileChunkIndex)> = Vec::new();
-        for (k, _v) in self.v2_chunk_ptr.range(range) {
-            chunks.push(k);
+        // delete v2 chunks
+        let span = self
+            .get_metadata(node)
+            .map_or(1, |metadata| self.file_chunk_span(&metadata));
+
+        let range = (node, 0)..(node + 1, 0);
+        let mut chunks: Vec<(Node, FileChunkIndex)> = Vec::new();
+        for (k, _v) in self.v2_chunk_ptr.range(range) {
+            chunks.push(k);
+        }
+
+        for (nd, idx) in chunks.into_iter() {
+            assert!(nd == node);
+            let removed = self.v2_chunk_ptr.remove(&(node, idx));
+
+            if let Some(removed) = removed {
+                self.v2_allocator.free_span(&self.v2_chunks, removed, span);
+            }
+        }
+
+        // clear cache
+        self.ptr_cache.clear();
+
+        // remove extended attributes
+        let keys = self.xattr_keys(node);
+        if let Some(xattrs) = self.xattrs.as_mut() {
+            for key in keys.into_iter() {
+                xattrs.remove(&key);
+            }
+        }
+
+        self.spill_mounts.remove(&node);
+
+        // the buffered data of the removed node is dropped
+        if self
+            .append_buffer
+            .as_ref()
+            .is_some_and(|tail| tail.node == node)
+        {
+            self.append_buffer = None;
+        }
+
+        // the removed node must not be stored back on flush
+        if self
+            .deferred_metadata
+            .as_ref()
+            .is_some_and(|meta| meta.node == node)
+        {
+            self.deferred_metadata = None;
+        }
+
+        // remove metadata
+        self.mounted_meta.remove(&node);
+        self.metadata.remove(&node);
+
+        let mounted_meta_node = self.cache_journal.read_mounted_meta_node();
+        if mounted_meta_node == Some(node) {
+            // reset cached mounted metadata
+            self.cache_journal.reset_mounted_meta()
+        }
+
+        self.meta_cache.clear();
+
+        Ok(())
+    }
+
+    fn trim_front(&mut self, node: Node, bytes: FileSize) -> Result<(), Error> {
+        self.flush_append_buffer(node)?;
+
+        let mut metadata = self.get_metadata(node)?;
+
+        let bytes = bytes.min(metadata.size);
+
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let new_size = metadata.size - bytes;
+        let chunk_size = self.file_chunk_size(&metadata) as FileSize;
+        let span = self.file_chunk_span(&metadata);
+
+        if !self.is_mounted(node)
+            && self.use_v2(&metadata, node)
+            && bytes.is_multiple_of(chunk_size)
+        {
+            // chunk-aligned trim, only shift chunk pointers without copying the data
+            let shift = (bytes / chunk_size) as FileChunkIndex;
+
+            let range = (node, 0)..(node + 1, 0);
+            let chunks: Vec<_> = self.v2_chunk_ptr.range(range).collect();
+
+            for ((nd, idx), ptr) in chunks.into_iter() {
+                assert!(nd == node);
+                self.v2_chunk_ptr.remove(&(node, idx));
+
+                if idx < shift {
+                    self.v2_allocator.free_span(&self.v2_chunks, ptr, span);
+                } else {
+                    self.v2_chunk_ptr.insert((node, idx - shift), ptr);
+                }
+            }
+
+            self.ptr_cache.clear();
+        } else {
+            copy_to_front(node, bytes, self)?;
+
+            if let Some(memory) = self.get_mounted_memory(node) {
+                // clear the leftover data of the mounted memory
+                let mut offset = new_size;
+
+                while offset < metadata.size {
+                    let len = (metadata.size - offset).min(ZEROES.len() as FileSize);
+                    memory.write(offset, &ZEROES[..len as usize]);
+                    offset += len;
+                }
+            } else {
+                self.rm_chunks_beyond(node, new_size)?;
+            }
+
+            metadata = self.get_metadata(node)?;
+        }
+
+        metadata.size = new_size;
+        self.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    fn mount_node(&mut self, node: Node, memory: Box<dyn Memory>) -> Result<(), Error> {
+        if self.is_mounted(node) {
+            return Err(Error::MemoryFileIsMountedAlready);
+        }
+
+        self.flush_append_buffer(node)?;
+
+        // do extra meta preparation
+        // get the file metadata (we are not mounted at this point)
+        let mut file_meta = self.get_metadata(node)?;
+
+        // activate mount
+        self.active_mounts.insert(node, memory);
+
+        if let Ok(_old_mounted_meta) = self.get_metadata(node) {
+            // do nothing, we already have the metadata
+        } else {
+            // take a copy of the file meta, set the size to 0 by default
+            file_meta.size = 0;
+
+            // update mounted metadata
+            self.put_metadata(node, file_meta);
+        };
+
+        Ok(())
+    }
+
+    fn unmount_node(&mut self, node: Node) -> Result<Box<dyn Memory>, Error> {
+        let memory = self.active_mounts.remove(&node);
+
+        memory.ok_or(Error::MemoryFileIsNotMounted)
+    }
+
+    fn is_mounted(&self, node: Node) -> bool {
+        self.active_mounts.contains_key(&node)
+    }
+
+    fn get_mounted_memory(&self, node: Node) -> Option<&dyn Memory> {
+        let res: Option<&Box<dyn Memory>> = self.active_mounts.get(&node);
+
+        res.map(|b| b.as_ref())
+    }
+
+    fn init_mounted_memory(&mut self, node: Node) -> Result<(), Error> {
+        // temporary disable mount to activate access to the original file
+        let memory = self.unmount_node(node)?;
+
+        let meta = self.get_metadata(node)?;
+        let file_size = meta.size;
+
+        // grow memory if needed
+        grow_memory(memory.as_ref(), file_size);
+
+        let mut remainder = file_size;
+
+        let mut buf = [0u8; WASM_PAGE_SIZE_IN_BYTES as usize];
+
+        let mut offset = 0;
+
+        while remainder > 0 {
+            let to_read = remainder.min(buf.len() as FileSize);
+
+            self.read(node, offset, &mut buf[..to_read as usize])?;
+
+            memory.write(offset, &buf[..to_read as usize]);
+
+            offset += to_read;
+            remainder -= to_read;
+        }
+
+        self.mount_node(node, memory)?;
+
+        self.put_metadata(node, meta);
+
+        Ok(())
+    }
+
+    fn store_mounted_memory(&mut self, node: Node) -> Result<(), Error> {
+        // get current size of the mounted memory
+        let meta = self.get_metadata(node)?;
+        let file_size = meta.size;
+
+        // temporary disable mount to activate access to the original file
+        let memory = self.unmount_node(node)?;
+
+        // grow memory if needed
+        grow_memory(memory.as_ref(), file_size);
+
+        let mut remainder = file_size;
+
+        let mut buf = [0u8; WASM_PAGE_SIZE_IN_BYTES as usize];
+
+        let mut offset = 0;
+
+        while remainder > 0 {
+            let to_read = remainder.min(buf.len() as FileSize);
+
+            // grow memory also for reading
+            grow_memory(memory.as_ref(), offset + to_read);
+
+            memory.read(offset, &mut buf[..to_read as usize]);
+
+            // restore the mount if the data could not be stored
+            if let Err(err) = self.write(node, offset, &buf[..to_read as usize]) {
+                self.mount_node(node, memory)?;
+                return Err(err);
+            }
+
+            offset += to_read;
+            remainder -= to_read;
+        }
+
+        self.put_metadata(node, meta);
+
+        self.mount_node(node, memory)?;
+
+        Ok(())
+    }
+
+    fn set_spill_mount(
+        &mut self,
+        node: Node,
+        threshold: FileSize,
+        memory: Box<dyn Memory>,
+    ) -> Result<(), Error> {
+        let size = self.get_metadata(node)?.size;
+
+        if self.is_mounted(node) {
+            return Err(Error::MemoryFileIsMountedAlready);
+        }
+
+        self.spill_mounts.insert(node, (threshold, memory));
+
+        // a file already past the threshold is moved right away
+        self.spill_if_needed(node, size)
+    }
+
+    fn get_xattr(&self, node: Node, name: &str) -> Result<Vec<u8>, Error> {
+        let key = (node, XattrName::new(name)?);
+
+        self.xattrs
+            .as_ref()
+            .and_then(|xattrs| xattrs.get(&key))
+            .ok_or(Error::NotFound)
+    }
+
+    fn set_xattr(&mut self, node: Node, name: &str, value: &[u8]) -> Result<(), Error> {
+        let key = (node, XattrName::new(name)?);
+
+        // compute the total attribute size of the node without the attribute being replaced
+        let mut total_size = name.len() + value.len();
+
+        if let Some(xattrs) = self.xattrs.as_ref() {
+            for other in self.xattr_keys(node) {
+                if other != key {
+                    total_size += other.1 .0.len() + xattrs.get(&other).unwrap().len();
+                }
+            }
+        }
+
+        if total_size > MAX_XATTR_SIZE_PER_NODE {
+            return Err(Error::XattrTooLarge);
+        }
+
+        self.xattrs_mut().insert(key, value.to_vec());
+
+        Ok(())
+    }
+
+    fn list_xattrs(&self, node: Node) -> Result<Vec<String>, Error> {
+        Ok(self
+            .xattr_keys(node)
+            .into_iter()
+            .map(|(_node, name)| name.0)
+            .collect())
+    }
+
+    fn remove_xattr(&mut self, node: Node, name: &str) -> Result<(), Error> {
+        let key = (node, XattrName::new(name)?);
+
+        self.xattrs
+            .as_mut()
+            .and_then(|xattrs| xattrs.remove(&key))
+            .map(|_| ())
+            .ok_or(Error::NotFound)
+    }
+
+    fn set_chunk_size(&mut self, chunk_size: ChunkSize) -> Result<(), Error> {
+        self.v2_allocator.set_chunk_size(chunk_size as usize)
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.v2_allocator.chunk_size()
+    }
+
+    fn set_chunk_type(&mut self, chunk_type: ChunkType) {
+        self.chunk_type = chunk_type;
+    }
+
+    fn chunk_type(&self) -> ChunkType {
+        self.chunk_type
+    }
+
+    fn fix_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error> {
+        self.flush_append_buffer(node)?;
+
+        let mut metadata = self.get_metadata(node)?;
+
+        let range = (node, 0)..(node + 1, 0);
+
+        let chunk_type = if self.v2_chunk_ptr.range(range.clone()).next().is_some() {
+            ChunkType::V2
+        } else if self.filechunk.range(range).next().is_some() {
+            ChunkType::V1
+        } else {
+            // nothing is stored yet, use the configured type
+            self.chunk_type()
+        };
+
+        metadata.chunk_type = Some(chunk_type);
+        self.put_metadata(node, metadata);
+
+        Ok(chunk_type)
+    }
+
+    fn effective_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error> {
+        let metadata = self.get_metadata(node)?;
+
+        if self.use_v2(&metadata, node) {
+            Ok(ChunkType::V2)
+        } else {
+            Ok(ChunkType::V1)
+        }
+    }
+
+    fn set_file_chunk_size(&mut self, node: Node, chunk_size: ChunkSize) -> Result<(), Error> {
+        let mut metadata = self.get_metadata(node)?;
+
+        if metadata.file_type != FileType::RegularFile || self.is_mounted(node) {
+            return Err(Error::InvalidFileType);
+        }
+
+        // the chunks of a file must all have the same size
+        let range = (node, 0)..(node + 1, 0);
+        if metadata.size > 0
+            || self.v2_chunk_ptr.range(range.clone()).next().is_some()
+            || self.filechunk.range(range).next().is_some()
+        {
+            return Err(Error::IncompatibleChunkSize);
+        }
+
+        // a file chunk consists of whole allocator chunks
+        if (chunk_size as usize) < self.chunk_size() {
+            return Err(Error::IncompatibleChunkSize);
+        }
+
+        metadata.chunk_type = Some(ChunkType::V2);
+        metadata.chunk_size = if chunk_size as usize == self.chunk_size() {
+            None
+        } else {
+            Some(chunk_size)
+        };
+
+        self.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
+    fn set_adaptive_chunking(&mut self, enabled: bool) {
+        self.adaptive_chunking = enabled;
+    }
+
+    fn adaptive_chunking(&self) -> bool {
+        self.adaptive_chunking
+    }
+
+    fn set_append_buffering(&mut self, enabled: bool) -> Result<(), Error> {
+        if !enabled {
+            self.flush_all_append_buffers()?;
+        }
+
+        self.append_buffering = enabled;
+
+        Ok(())
+    }
+
+    fn append_buffering(&self) -> bool {
+        self.append_buffering
+    }
+
+    fn set_max_dir_entries(&mut self, limit: Option<FileSize>) {
+        self.max_dir_entries = limit;
+    }
+
+    fn max_dir_entries(&self) -> Option<FileSize> {
+        self.max_dir_entries
+    }
+
+    fn set_filename_normalization(&mut self, normalization: Normalization) {
+        let mut header = self.header.get().clone();
+
+        if header.filename_normalization != normalization {
+            header.filename_normalization = normalization;
+            self.set_header(header);
+        }
+    }
+
+    fn filename_normalization(&self) -> Normalization {
+        self.header.get().filename_normalization
+    }
+
+    fn set_trim_trailing_space(&mut self, trim: bool) {
+        let mut header = self.header.get().clone();
+
+        if header.trim_trailing_space != trim {
+            header.trim_trailing_space = trim;
+            self.set_header(header);
+        }
+    }
+
+    fn trim_trailing_space(&self) -> bool {
+        self.header.get().trim_trailing_space
+    }
+
+    fn pending_removals(&self) -> Vec<(Node, String, Node)> {
+        self.header.get().pending_removals.clone()
+    }
+
+    fn set_pending_removals(&mut self, removals: Vec<(Node, String, Node)>) {
+        let mut header = self.header.get().clone();
+        header.pending_removals = removals;
+        self.set_header(header);
+    }
+
+    fn set_dir_clock(&mut self, clock: Option<Box<dyn Fn() -> u64>>) {
+        self.dir_clock = clock;
+    }
+
+    fn dir_clock_time(&self) -> Option<u64> {
+        self.dir_clock.as_ref().map(|clock| clock())
+    }
+
+    fn defer_metadata(&mut self, node: Node) -> Result<(), Error> {
+        if self.is_mounted(node) {
+            return Err(Error::InvalidFileType);
+        }
+
+        // only one node can be deferred at a time
+        self.flush_deferred_metadata();
+        self.flush_append_buffer(node)?;
+
+        self.deferred_metadata = Some(self.get_metadata(node)?);
+
+        Ok(())
+    }
+
+    fn flush_deferred_metadata(&mut self) {
+        if let Some(meta) = self.deferred_metadata.take() {
+            self.put_metadata(meta.node, meta);
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    fn operation_counters(&self) -> OperationCounters {
+        self.counters.get()
+    }
+
+    fn flush(&mut self, node: Node) -> Result<(), Error> {
+        self.flush_append_buffer(node)?;
+        self.flush_mounted_meta();
+
+        Ok(())
+    }
+
+    fn flush_all(&mut self) -> Result<(), Error> {
+        self.flush_all_append_buffers()?;
+        self.flush_deferred_metadata();
+        self.flush_mounted_meta();
+
+        Ok(())
+    }
+
+    fn persist_mounted_metadata(&mut self) {
+        // only the metadata of the last updated mounted file is kept in the cache journal,
+        // the metadata of the other mounted files is already stored
+        self.flush_mounted_meta();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    use crate::{
+        filename_cache::FilenameCache,
+        runtime::structure_helpers::{add_dir_entry, create_dir_entry, find_node},
+        storage::types::FileName,
+    };
+
+    use super::*;
+
+    #[test]
+    fn header_is_persisted() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new(memory.clone());
+
+        assert_eq!(storage.header().version, FS_VERSION);
+        assert_eq!(storage.header().next_node, ROOT_NODE + 1);
+
+        let node = storage.new_node();
+        assert_eq!(storage.header().next_node, node + 1);
+
+        let storage = StableStorage::new(memory);
+        assert_eq!(storage.header().next_node, node + 1);
+    }
+
+    #[test]
+    fn integrity_fingerprint_detects_header_overwrite() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+
+        let mut storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        assert!(storage.check_integrity_fingerprint());
+
+        // the storage own header updates keep the fingerprint valid
+        storage.new_node();
+        assert!(storage.check_integrity_fingerprint());
+
+        // another structure writes into the header memory
+        let header_memory = memory_manager.get(MemoryId::new(200 + StorageMemoryIdx::Header as u8));
+        header_memory.write(8, &[0xFF; 4]);
+        assert!(!storage.check_integrity_fingerprint());
+    }
+
+    #[test]
+    fn integrity_fingerprint_covers_the_whole_header() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+
+        let mut storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        storage.new_node();
+
+        let header_memory = memory_manager.get(MemoryId::new(200 + StorageMemoryIdx::Header as u8));
+
+        let mut prefix = [0u8; HEADER_CELL_PREFIX_LEN as usize];
+        header_memory.read(0, &mut prefix);
+        let value_len = u32::from_le_bytes(prefix[4..8].try_into().unwrap()) as u64;
+        assert!(value_len > 64);
+
+        // overwrite the last byte of the serialized header
+        let last = HEADER_CELL_PREFIX_LEN + value_len - 1;
+        let mut byte = [0u8; 1];
+        header_memory.read(last, &mut byte);
+        header_memory.write(last, &[!byte[0]]);
+        assert!(!storage.check_integrity_fingerprint());
+    }
+
+    #[test]
+    fn persist_mounted_metadata_moves_journaled_metadata() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mount_memory = memory_manager.get(MemoryId::new(1));
+
+        let mut storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let node =
+            create_dir_entry(ROOT_NODE, b"a.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage
+            .mount_node(node, Box::new(mount_memory.clone()))
+            .unwrap();
+        storage.write(node, 0, &[5u8; 100]).unwrap();
+
+        // the last update is only in the cache journal
+        assert_eq!(storage.cache_journal.read_mounted_meta_node(), Some(node));
+        assert_ne!(
+            storage.mounted_meta.get(&node).map(|meta| meta.size),
+            Some(100)
+        );
+
+        storage.persist_mounted_metadata();
+        assert_eq!(storage.mounted_meta.get(&node).unwrap().size, 100);
+    }
+
+    #[test]
+    fn original_journal_layout_survives_upgrade() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mount_memory = memory_manager.get(MemoryId::new(1));
+
+        let mut storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        let node =
+            create_dir_entry(ROOT_NODE, b"a.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage
+            .mount_node(node, Box::new(mount_memory.clone()))
+            .unwrap();
+        storage.write(node, 0, &[5u8; 100]).unwrap();
+        storage.unmount_node(node).unwrap();
+
+        // the mounted file metadata as the original library wrote it into the cache journal:
+        // the node followed by a direct copy of `Metadata`, the metadata is not in the mounted metadata map
+        storage.mounted_meta.remove(&node);
+
+        let mut entry = [0u8; 80];
+        entry[0..8].copy_from_slice(&node.to_le_bytes());
+        let meta = &mut entry[8..];
+        meta[16..24].copy_from_slice(&node.to_le_bytes());
+        meta[24..32].copy_from_slice(&1u64.to_le_bytes());
+        meta[32..40].copy_from_slice(&12345u64.to_le_bytes());
+        meta[64] = 1;
+        meta[65] = 2;
+        memory_manager
+            .get(MemoryId::new(200 + StorageMemoryIdx::CacheJournal as u8))
+            .write(16, &entry);
+
+        // imitate canister upgrade
+        let mut storage = StableStorage::new_with_memory_manager(&memory_manager, 200..210);
+        assert_eq!(storage.get_version(), FS_VERSION);
+        assert_eq!(storage.cache_journal.read_mounted_meta_node(), Some(node));
+
+        storage
+            .mount_node(node, Box::new(mount_memory.clone()))
+            .unwrap();
+        assert_eq!(storage.get_metadata(node).unwrap().size, 12345);
+
+        storage.flush_all().unwrap();
+        assert_eq!(storage.mounted_meta.get(&node).unwrap().size, 12345);
+    }
+
+    #[test]
+    fn reserved_nodes_skip_header_writes() {
+        let mut header_writes = Vec::new();
+
+        for reserve in [false, true] {
+            let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+            let root = storage.root_node();
+
+            if reserve {
+                storage.reserve_nodes(100);
+            }
+            storage.header_writes = 0;
+
+            for i in 0..100 {
+                let name = format!("file{i}.txt");
+                let next = storage.next_node();
+                let node = create_dir_entry(
+                    root,
+                    name.as_bytes(),
+                    FileType::RegularFile,
+                    &mut storage,
+                    0,
+                )
+                .unwrap();
+                assert_eq!(node, next);
+            }
+
+            header_writes.push(storage.header_writes);
+
+            // the next node after the reservation continues the counter
+            let node = storage.new_node();
+            assert_eq!(node, ROOT_NODE + 101);
+        }
+
+        assert_eq!(header_writes, vec![100, 0]);
+    }
+
+    #[test]
+    fn deferred_folder_metadata_is_written_once() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+
+        storage.defer_metadata(root).unwrap();
+        storage.metadata_writes = 0;
+
+        for i in 0..1000 {
+            let name = format!("file{i}.txt");
+            create_dir_entry(
+                root,
+                name.as_bytes(),
+                FileType::RegularFile,
+                &mut storage,
+                0,
+            )
+            .unwrap();
+        }
+
+        // only the new file nodes are written
+        assert_eq!(storage.metadata_writes, 1000);
+        assert_eq!(storage.get_metadata(root).unwrap().size, 1000);
+        assert_eq!(storage.metadata.get(&root).unwrap().size, 0);
+
+        storage.flush_deferred_metadata();
+
+        assert_eq!(storage.metadata_writes, 1001);
+        assert_eq!(storage.metadata.get(&root).unwrap().size, 1000);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn single_chunk_write_operation_counts() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+
+        let node =
+            create_dir_entry(root, b"test.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        let before = storage.operation_counters();
+        storage.write(node, 0, &[1u8; 100]).unwrap();
+        let after = storage.operation_counters();
+
+        // one chunk pointer and the new file size
+        assert_eq!(after.btree_inserts - before.btree_inserts, 2);
+        assert_eq!(after.allocations - before.allocations, 1);
+        assert_eq!(after.chunk_writes - before.chunk_writes, 1);
+
+        let mut buf = [0u8; 100];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(
+            storage.operation_counters().chunk_reads - after.chunk_reads,
+            1
+        );
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn buffered_appends_write_each_chunk_once() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+
+        let node =
+            create_dir_entry(root, b"log.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        storage.write(node, 0, b"#").unwrap();
+        storage.set_append_buffering(true).unwrap();
+
+        let before = storage.operation_counters();
+
+        let mut expected = b"#".to_vec();
+        for i in 0..1000 {
+            let record = format!("record {i:>8} of the log file........\n");
+            let size = storage.get_metadata(node).unwrap().size;
+            storage.write(node, size, record.as_bytes()).unwrap();
+            expected.extend_from_slice(record.as_bytes());
+        }
+
+        storage.flush(node).unwrap();
+
+        let after = storage.operation_counters();
+
+        // one write per filled chunk, instead of one write per record
+        let chunk_size = storage.chunk_size();
+        assert_eq!(
+            after.chunk_writes - before.chunk_writes,
+            expected.len().div_ceil(chunk_size) as u64
+        );
+
+        let mut buf = vec![0u8; expected.len()];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn buffered_appends_over_upgrade() {
+        let memory = DefaultMemoryImpl::default();
+
+        let mut storage = StableStorage::new(memory.clone());
+        storage.set_append_buffering(true).unwrap();
+        let root = storage.root_node();
+        let node =
+            create_dir_entry(root, b"log.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        storage.write(node, 0, b"first").unwrap();
+        storage.write(node, 5, b"-second").unwrap();
+        assert_eq!(storage.get_metadata(node).unwrap().size, 12);
+
+        // without a flush the buffered record is lost, but the stored size does not cover it
+        let restored = StableStorage::new(memory.clone());
+        assert_eq!(restored.get_metadata(node).unwrap().size, 5);
+
+        storage.write(node, 12, b"-third").unwrap();
+        storage.flush_all().unwrap();
+
+        let mut restored = StableStorage::new(memory);
+        assert_eq!(restored.get_metadata(node).unwrap().size, 18);
+
+        let mut buf = vec![0u8; 18];
+        restored.read(node, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"first-second-third");
+    }
+
+    #[test]
+    fn buffered_appends_keep_file_contents() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        storage.set_append_buffering(true).unwrap();
+        let root = storage.root_node();
+
+        let node =
+            create_dir_entry(root, b"log.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        let mut expected = Vec::new();
+        for i in 0..1000 {
+            let record = format!("{i}:{}\n", "x".repeat(i % 50));
+            storage
+                .write(node, expected.len() as FileSize, record.as_bytes())
+                .unwrap();
+            expected.extend_from_slice(record.as_bytes());
+
+            assert_eq!(
+                storage.get_metadata(node).unwrap().size,
+                expected.len() as FileSize
+            );
+
+            // reading sees the buffered tail
+            if i % 97 == 0 {
+                let mut buf = vec![0u8; expected.len()];
+                storage.read(node, 0, &mut buf).unwrap();
+                assert_eq!(buf, expected);
+            }
+        }
+
+        // truncating drops the buffered data past the new end
+        storage
+            .write(node, expected.len() as FileSize, b"tail")
+            .unwrap();
+        let mut metadata = storage.get_metadata(node).unwrap();
+        metadata.size = expected.len() as FileSize;
+        storage.put_metadata(node, metadata);
+
+        storage.flush(node).unwrap();
+
+        assert_eq!(
+            storage.get_metadata(node).unwrap().size,
+            expected.len() as FileSize
+        );
+
+        let mut buf = vec![0u8; expected.len() + 4];
+        let read = storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..read as usize], &expected[..]);
+    }
+
+    #[test]
+    fn extending_a_file_keeps_the_buffered_tail() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        storage.set_append_buffering(true).unwrap();
+        let root = storage.root_node();
+
+        let node =
+            create_dir_entry(root, b"log.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        storage.write(node, 0, b"first-").unwrap();
+        storage.write(node, 6, b"second").unwrap();
+        assert!(storage.append_buffer.is_some());
+
+        // the new size is stored without writing the tail
+        let mut metadata = storage.get_metadata(node).unwrap();
+        metadata.size = 20;
+        storage.put_metadata(node, metadata);
+        assert!(storage.append_buffer.is_some());
+        assert_eq!(storage.get_metadata(node).unwrap().size, 20);
+
+        let mut buf = [1u8; 20];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..12], b"first-second");
+        assert_eq!(&buf[12..], &[0u8; 8]);
+        assert_eq!(storage.get_metadata(node).unwrap().size, 20);
+
+        storage.set_append_buffering(false).unwrap();
+        assert!(storage.append_buffer.is_none());
+    }
+
+    const FAULT_MARKER: &[u8] = b"inject write fault";
+
+    // Memory failing on writes starting with FAULT_MARKER, while armed.
+    #[derive(Clone, Default)]
+    struct FaultyMemory {
+        inner: DefaultMemoryImpl,
+        armed: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl Memory for FaultyMemory {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn grow(&self, pages: u64) -> i64 {
+            self.inner.grow(pages)
         }
 
-        for (nd, idx) in chunks.into_iter() {
-            assert!(nd == node);
-            let removed = self.v2_chunk_ptr.remove(&(node, idx));
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            self.inner.read(offset, dst)
+        }
 
-            if let Some(removed) = removed {
-                self.v2_allocator.free(removed);
+        fn write(&self, offset: u64, src: &[u8]) {
+            if self.armed.get() && src.starts_with(FAULT_MARKER) {
+                panic!("injected write fault");
             }
+
+            self.inner.write(offset, src)
         }
+    }
 
-        // clear cache
-        self.ptr_cache.clear();
+    #[test]
+    fn failed_chunk_write_registers_no_pointer() {
+        let memory = FaultyMemory::default();
+        let mut storage = StableStorage::new(memory.clone());
+        let root = storage.root_node();
 
-        // remove metadata
-        self.mounted_meta.remove(&node);
-        self.metadata.remove(&node);
+        let node =
+            create_dir_entry(root, b"test.txt", FileType::RegularFile, &mut storage, 0).unwrap();
 
-        let mounted_meta_node = self.cache_journal.read_mounted_meta_node();
-        if mounted_meta_node == Some(node) {
-            // reset cached mounted metadata
-            self.cache_journal.reset_mounted_meta()
-        }
+        let mut data = FAULT_MARKER.to_vec();
+        data.resize(100, 7);
 
-        self.meta_cache.clear();
+        memory.armed.set(true);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            storage.write(node, 10, &data).unwrap();
+        }));
+        memory.armed.set(false);
 
-        Ok(())
+        assert!(result.is_err());
+
+        // no dangling pointer, the chunk is returned to the allocator
+        assert_eq!(
+            storage.v2_chunk_ptr.range((node, 0)..(node + 1, 0)).count(),
+            0
+        );
+        assert_eq!(storage.v2_allocator.available_ptrs().len(), 1);
+        assert_eq!(storage.get_metadata(node).unwrap().size, 0);
+
+        // the next write reuses the chunk
+        storage.write(node, 10, &data).unwrap();
+        assert_eq!(storage.v2_allocator.available_ptrs().len(), 0);
+
+        let mut buf = [1u8; 110];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..10], &[0u8; 10]);
+        assert_eq!(&buf[10..], &data[..]);
     }
 
-    fn mount_node(&mut self, node: Node, memory: Box<dyn Memory>) -> Result<(), Error> {
-        if self.is_mounted(node) {
-            return Err(Error::MemoryFileIsMountedAlready);
+    #[test]
+    fn large_write_places_new_chunks_contiguously() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+        let chunk_size = storage.chunk_size();
+
+        // interleaved single chunk writes leave two files fragmented
+        let a = create_dir_entry(root, b"a.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        let b = create_dir_entry(root, b"b.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        for i in 0..4 {
+            let offset = (i * chunk_size) as FileSize;
+            storage.write(a, offset, &vec![1u8; chunk_size]).unwrap();
+            storage.write(b, offset, &vec![2u8; chunk_size]).unwrap();
+        }
+        storage.rm_file(a).unwrap();
+
+        // the released chunks of `a` are not adjacent, the run is taken from fresh memory
+        let node =
+            create_dir_entry(root, b"file.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        let data: Vec<u8> = (0..chunk_size * 3 + 100).map(|i| i as u8).collect();
+        storage.write(node, 10, &data).unwrap();
+
+        let map = storage.debug_chunk_map(node);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[0].1, (8 * chunk_size) as FileChunkPtr);
+        for window in map.windows(2) {
+            assert_eq!(window[1].1, window[0].1 + chunk_size as FileChunkPtr);
         }
 
-        // do extra meta preparation
-        // get the file metadata (we are not mounted at this point)
-        let mut file_meta = self.get_metadata(node)?;
-
-        // activate mount
-        self.active_mounts.insert(node, memory);
+        let mut buf = vec![1u8; data.len() + 10];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..10], &[0u8; 10]);
+        assert_eq!(&buf[10..], &data[..]);
 
-        if let Ok(_old_mounted_meta) = self.get_metadata(node) {
-            // do nothing, we already have the metadata
-        } else {
-            // take a copy of the file meta, set the size to 0 by default
-            file_meta.size = 0;
+        // a released run is reused
+        storage.rm_file(node).unwrap();
+        let node =
+            create_dir_entry(root, b"next.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage.write(node, 0, &vec![3u8; chunk_size * 2]).unwrap();
 
-            // update mounted metadata
-            self.put_metadata(node, file_meta);
-        };
+        let map = storage.debug_chunk_map(node);
+        assert_eq!(map[0].1, (8 * chunk_size) as FileChunkPtr);
+        assert_eq!(map[1].1, (9 * chunk_size) as FileChunkPtr);
 
-        Ok(())
+        assert_eq!(storage.verify_allocator(), Ok(()));
     }
 
-    fn unmount_node(&mut self, node: Node) -> Result<Box<dyn Memory>, Error> {
-        let memory = self.active_mounts.remove(&node);
+    #[test]
+    fn debug_chunk_map_of_sparse_file() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
 
-        memory.ok_or(Error::MemoryFileIsNotMounted)
-    }
+        let node =
+            create_dir_entry(root, b"sparse.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        let other =
+            create_dir_entry(root, b"other.txt", FileType::RegularFile, &mut storage, 0).unwrap();
 
-    fn is_mounted(&self, node: Node) -> bool {
-        self.active_mounts.contains_key(&node)
-    }
+        let chunk_size = storage.chunk_size() as FileSize;
 
-    fn get_mounted_memory(&self, node: Node) -> Option<&dyn Memory> {
-        let res: Option<&Box<dyn Memory>> = self.active_mounts.get(&node);
+        storage.write(node, 2 * chunk_size + 5, b"a").unwrap();
+        storage.write(other, 0, b"b").unwrap();
+        storage.write(node, 7 * chunk_size, b"c").unwrap();
 
-        res.map(|b| b.as_ref())
+        let map = storage.debug_chunk_map(node);
+        let indices: Vec<FileChunkIndex> = map.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![2, 7]);
+
+        for (index, ptr) in map {
+            assert_eq!(storage.v2_chunk_ptr.get(&(node, index)), Some(ptr));
+        }
+
+        assert_eq!(storage.debug_chunk_map(other).len(), 1);
     }
 
-    fn init_mounted_memory(&mut self, node: Node) -> Result<(), Error> {
-        // temporary disable mount to activate access to the original file
-        let memory = self.unmount_node(node)?;
+    #[test]
+    fn verify_allocator_detects_live_chunk_on_free_list() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+        let chunk_size = storage.chunk_size();
+
+        let mut nodes = Vec::new();
+        for name in [b"a.txt", b"b.txt", b"c.txt"] {
+            let node =
+                create_dir_entry(root, name, FileType::RegularFile, &mut storage, 0).unwrap();
+            storage.write(node, 0, &vec![7u8; chunk_size * 3]).unwrap();
+            nodes.push(node);
+        }
 
-        let meta = self.get_metadata(node)?;
-        let file_size = meta.size;
+        storage.rm_file(nodes[1]).unwrap();
+        assert_eq!(storage.verify_allocator(), Ok(()));
 
-        // grow memory if needed
-        grow_memory(memory.as_ref(), file_size);
+        // a freed chunk is reused by the next write
+        storage
+            .write(nodes[2], 3 * chunk_size as FileSize, &[1u8; 10])
+            .unwrap();
+        assert_eq!(storage.verify_allocator(), Ok(()));
 
-        let mut remainder = file_size;
+        // inject a free pointer into the live chunks of a file
+        let free_ptr = storage.v2_allocator.available_ptrs()[0];
+        storage.v2_chunk_ptr.insert((nodes[0], 100), free_ptr);
 
-        let mut buf = [0u8; WASM_PAGE_SIZE_IN_BYTES as usize];
+        assert_eq!(storage.verify_allocator(), Err(Error::CorruptedAllocator));
+    }
 
-        let mut offset = 0;
+    #[test]
+    fn preallocated_chunks_are_contiguous_and_zeroed() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+        let chunk_size = storage.chunk_size();
 
-        while remainder > 0 {
-            let to_read = remainder.min(buf.len() as FileSize);
+        // reuse some chunks to make sure the preallocation does not take them
+        let other =
+            create_dir_entry(root, b"other.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage.write(other, 0, &vec![9u8; chunk_size * 2]).unwrap();
+        storage.rm_file(other).unwrap();
 
-            self.read(node, offset, &mut buf[..to_read as usize])?;
+        let node =
+            create_dir_entry(root, b"file.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage.write(node, 0, b"head").unwrap();
 
-            memory.write(offset, &buf[..to_read as usize]);
+        storage
+            .preallocate(node, 5 * chunk_size as FileSize - 1)
+            .unwrap();
 
-            offset += to_read;
-            remainder -= to_read;
+        assert_eq!(storage.get_metadata(node).unwrap().size, 4);
+
+        let map = storage.debug_chunk_map(node);
+        assert_eq!(map.len(), 5);
+        for window in map[1..].windows(2) {
+            assert_eq!(window[1].1, window[0].1 + chunk_size as FileChunkPtr);
         }
 
-        self.mount_node(node, memory)?;
+        // the gap exposed by a write is filled with zeroes
+        storage
+            .write(node, 3 * chunk_size as FileSize, b"tail")
+            .unwrap();
+        let mut buf = vec![1u8; chunk_size * 3 + 4];
+        storage.read(node, 0, &mut buf).unwrap();
 
-        self.put_metadata(node, meta);
+        assert_eq!(&buf[..4], b"head");
+        assert!(buf[4..chunk_size * 3].iter().all(|b| *b == 0));
+        assert_eq!(&buf[chunk_size * 3..], b"tail");
 
-        Ok(())
+        assert_eq!(storage.debug_chunk_map(node), map);
+        assert_eq!(storage.verify_allocator(), Ok(()));
     }
 
-    fn store_mounted_memory(&mut self, node: Node) -> Result<(), Error> {
-        // get current size of the mounted memory
-        let meta = self.get_metadata(node)?;
-        let file_size = meta.size;
-
-        // temporary disable mount to activate access to the original file
-        let memory = self.unmount_node(node)?;
+    #[test]
+    fn preallocated_chunks_are_zeroed_after_format() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+        let chunk_size = storage.chunk_size();
 
-        // grow memory if needed
-        grow_memory(memory.as_ref(), file_size);
+        let old =
+            create_dir_entry(root, b"old.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage
+            .write(old, 0, &vec![0xABu8; chunk_size * 2])
+            .unwrap();
 
-        let mut remainder = file_size;
+        storage.format().unwrap();
 
-        let mut buf = [0u8; WASM_PAGE_SIZE_IN_BYTES as usize];
+        let node =
+            create_dir_entry(root, b"new.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        storage
+            .preallocate(node, 2 * chunk_size as FileSize)
+            .unwrap();
+        storage.write(node, 100, b"x").unwrap();
 
-        let mut offset = 0;
+        // the contents of the removed file are not visible
+        let mut buf = vec![1u8; 101];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert!(buf[..100].iter().all(|b| *b == 0));
+        assert_eq!(buf[100], b'x');
+    }
 
-        while remainder > 0 {
-            let to_read = remainder.min(buf.len() as FileSize);
+    #[test]
+    fn dont_need_advice_evicts_cached_pointers() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
+        let chunk_size = storage.chunk_size();
 
-            // grow memory also for reading
-            grow_memory(memory.as_ref(), offset + to_read);
+        let node =
+            create_dir_entry(root, b"scan.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+        let other =
+            create_dir_entry(root, b"hot.txt", FileType::RegularFile, &mut storage, 0).unwrap();
 
-            memory.read(offset, &mut buf[..to_read as usize]);
+        let content: Vec<u8> = (0..chunk_size * 4).map(|i| (i % 253) as u8).collect();
+        storage.write(node, 0, &content).unwrap();
+        storage.write(other, 0, &content).unwrap();
 
-            self.write(node, offset, &buf[..to_read as usize])?;
+        // populate the cache by reading both files
+        let mut buf = vec![0u8; content.len()];
+        storage.read(node, 0, &mut buf).unwrap();
+        storage.read(other, 0, &mut buf).unwrap();
 
-            offset += to_read;
-            remainder -= to_read;
+        for index in 0..4 {
+            assert!(storage.ptr_cache.get((node, index)).is_some());
+            assert!(storage.ptr_cache.get((other, index)).is_some());
         }
 
-        self.put_metadata(node, meta);
+        storage
+            .advise(node, 0, content.len() as FileSize, Advice::DontNeed)
+            .unwrap();
 
-        self.mount_node(node, memory)?;
+        for index in 0..4 {
+            assert_eq!(storage.ptr_cache.get((node, index)), None);
+            // the other file keeps its cached pointers
+            assert!(storage.ptr_cache.get((other, index)).is_some());
+        }
 
-        Ok(())
-    }
+        // the data is still read correctly from the storage
+        let mut buf = vec![0u8; content.len()];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(buf, content);
 
-    fn set_chunk_size(&mut self, chunk_size: ChunkSize) -> Result<(), Error> {
-        self.v2_allocator.set_chunk_size(chunk_size as usize)
+        // the pointers can be loaded back in advance
+        storage.ptr_cache.clear();
+        storage
+            .advise(node, chunk_size as FileSize, 1, Advice::WillNeed)
+            .unwrap();
+        assert_eq!(
+            storage.ptr_cache.get((node, 1)),
+            storage
+                .v2_chunk_ptr
+                .get(&(node, 1))
+                .map(CachedChunkPtr::ChunkExists)
+        );
     }
 
-    fn chunk_size(&self) -> usize {
-        self.v2_allocator.chunk_size()
-    }
+    #[test]
+    fn large_file_chunks_are_released_whole() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        storage.set_chunk_size(ChunkSize::CHUNK4K).unwrap();
+        let root = storage.root_node();
 
-    fn set_chunk_type(&mut self, chunk_type: ChunkType) {
-        self.chunk_type = chunk_type;
+        let large =
+            create_dir_entry(root, b"large.bin", FileType::RegularFile, &mut storage, 0).unwrap();
+        let small =
+            create_dir_entry(root, b"small.bin", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        storage
+            .set_file_chunk_size(large, ChunkSize::CHUNK64K)
+            .unwrap();
+
+        storage.write(small, 0, &[1u8; 5000]).unwrap();
+        storage.write(large, 0, &[2u8; 100_000]).unwrap();
+        storage.write(small, 5000, &[1u8; 5000]).unwrap();
+
+        // two 64K chunks, each is one run of 16 allocator chunks
+        let map = storage.debug_chunk_map(large);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[1].1 - map[0].1, 65536);
+        assert_eq!(storage.fragmentation(large).unwrap(), 0.0);
+
+        storage.trim_front(large, 65536).unwrap();
+        assert_eq!(storage.verify_allocator(), Ok(()));
+        assert_eq!(
+            storage
+                .v2_allocator
+                .available_spans(&storage.v2_chunks, 16)
+                .len(),
+            1
+        );
+
+        storage.rm_file(large).unwrap();
+        assert_eq!(storage.verify_allocator(), Ok(()));
+        assert_eq!(
+            storage
+                .v2_allocator
+                .available_spans(&storage.v2_chunks, 16)
+                .len(),
+            2
+        );
+        assert!(storage.v2_allocator.available_ptrs().is_empty());
+
+        let mut buf = [0u8; 10_000];
+        storage.read(small, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 1));
     }
 
-    fn chunk_type(&self) -> ChunkType {
-        self.chunk_type
+    #[test]
+    fn large_file_chunks_are_reused() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        storage.set_chunk_size(ChunkSize::CHUNK4K).unwrap();
+        let root = storage.root_node();
+
+        let mut pages = Vec::new();
+
+        for i in 0..10 {
+            let name = format!("large{i}.bin");
+            let node = create_dir_entry(
+                root,
+                name.as_bytes(),
+                FileType::RegularFile,
+                &mut storage,
+                0,
+            )
+            .unwrap();
+            storage
+                .set_file_chunk_size(node, ChunkSize::CHUNK64K)
+                .unwrap();
+
+            storage.write(node, 0, &[2u8; 200_000]).unwrap();
+            storage.write(node, 300_000, &[3u8; 1000]).unwrap();
+
+            let mut buf = [0u8; 1000];
+            storage.read(node, 250_000, &mut buf).unwrap();
+            assert!(buf.iter().all(|b| *b == 0));
+
+            storage.rm_file(node).unwrap();
+            assert_eq!(storage.verify_allocator(), Ok(()));
+
+            pages.push(storage.v2_chunks.size());
+        }
+
+        // the released 64K chunks are reused, the chunk memory stops growing after the first file
+        assert!(pages.iter().all(|size| *size == pages[0]));
     }
 
-    fn flush(&mut self, _node: Node) {
-        self.flush_mounted_meta();
+    #[test]
+    fn set_next_node_only_moves_forward() {
+        let memory = DefaultMemoryImpl::default();
+        let mut storage = StableStorage::new(memory.clone());
+
+        let node = storage.new_node();
+
+        storage.set_next_node(1000).unwrap();
+        assert!(storage.new_node() >= 1000);
+
+        assert_eq!(storage.set_next_node(node), Err(Error::InvalidNodeId));
+        assert_eq!(storage.set_next_node(500), Err(Error::InvalidNodeId));
+
+        // the counter is persisted
+        let mut storage = StableStorage::new(memory);
+        assert!(storage.new_node() > 1000);
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn nodes_created_with_id_can_be_linked_twice() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let root = storage.root_node();
 
-    use ic_stable_structures::DefaultMemoryImpl;
+        let node = 500;
+        let metadata = Metadata {
+            node,
+            file_type: FileType::RegularFile,
+            link_count: 2,
+            size: 0,
+            times: Times::default(),
+            first_dir_entry: None,
+            last_dir_entry: None,
+            chunk_type: Some(ChunkType::V2),
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
+        };
+
+        storage.create_node_with_id(node, metadata.clone()).unwrap();
+        assert_eq!(
+            storage.create_node_with_id(node, metadata.clone()),
+            Err(Error::FileAlreadyExists)
+        );
+        assert_eq!(
+            storage.create_node_with_id(node + 1, metadata),
+            Err(Error::InvalidNodeId)
+        );
 
-    use crate::storage::types::FileName;
+        // the counter stays ahead of the restored node
+        assert!(storage.new_node() > node);
 
-    use super::*;
+        add_dir_entry(root, node, b"a.txt", &mut storage).unwrap();
+        add_dir_entry(root, node, b"b.txt", &mut storage).unwrap();
+
+        storage.write(node, 0, b"shared").unwrap();
+
+        let mut names_cache = FilenameCache::new();
+        let a = find_node(root, "a.txt", &mut names_cache, &storage).unwrap();
+        let b = find_node(root, "b.txt", &mut names_cache, &storage).unwrap();
+        assert_eq!(a, node);
+        assert_eq!(b, node);
+
+        let mut buf = [0u8; 6];
+        storage.read(b, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"shared");
+    }
 
     #[test]
     fn read_and_write_filechunk() {
@@ -907,6 +3132,9 @@ mod tests {
                 first_dir_entry: Some(42),
                 last_dir_entry: Some(24),
                 chunk_type: Some(storage.chunk_type()),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
             },
         );
         let metadata = storage.get_metadata(node).unwrap();
@@ -922,6 +3150,151 @@ mod tests {
         assert_eq!(buf, [42; 10]);
     }
 
+    #[test]
+    fn aligned_trim_front_reuses_chunks() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let node = storage.new_node();
+        storage.put_metadata(
+            node,
+            Metadata {
+                node,
+                file_type: FileType::RegularFile,
+                link_count: 1,
+                size: 0,
+                times: Times::default(),
+                first_dir_entry: None,
+                last_dir_entry: None,
+                chunk_type: Some(ChunkType::V2),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
+            },
+        );
+
+        let chunk_size = storage.chunk_size();
+        let content: Vec<u8> = (0..chunk_size * 3 + 100).map(|i| (i % 251) as u8).collect();
+        storage.write(node, 0, &content).unwrap();
+
+        let ptr = storage.v2_chunk_ptr.get(&(node, 2)).unwrap();
+
+        storage
+            .trim_front(node, 2 * chunk_size as FileSize)
+            .unwrap();
+
+        // the chunk was moved without copying
+        assert_eq!(storage.v2_chunk_ptr.get(&(node, 0)), Some(ptr));
+        assert_eq!(storage.v2_chunk_ptr.get(&(node, 2)), None);
+
+        let meta = storage.get_metadata(node).unwrap();
+        assert_eq!(meta.size, (chunk_size + 100) as FileSize);
+
+        let mut buf = vec![0u8; chunk_size + 100];
+        storage.read(node, 0, &mut buf).unwrap();
+        assert_eq!(buf, content[2 * chunk_size..]);
+    }
+
+    #[test]
+    fn estimate_write_chunks_matches_allocations() {
+        for chunk_type in [ChunkType::V1, ChunkType::V2] {
+            let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+            let node = storage.new_node();
+            storage.put_metadata(
+                node,
+                Metadata {
+                    node,
+                    file_type: FileType::RegularFile,
+                    link_count: 1,
+                    size: 0,
+                    times: Times::default(),
+                    first_dir_entry: None,
+                    last_dir_entry: None,
+                    chunk_type: Some(chunk_type),
+                    chunk_size: None,
+                    max_dir_entry: None,
+                    sorted_dir_entries: false,
+                },
+            );
+
+            let chunk_size = match chunk_type {
+                ChunkType::V1 => FILE_CHUNK_SIZE_V1,
+                ChunkType::V2 => storage.chunk_size(),
+            } as FileSize;
+
+            let writes = [
+                (0, 10),
+                (5, 100),
+                (chunk_size - 1, 2),
+                (3 * chunk_size, chunk_size),
+                (chunk_size / 2, 4 * chunk_size),
+                (10 * chunk_size + 7, 0),
+            ];
+
+            for (offset, len) in writes {
+                let before = storage.filechunk.len() + storage.v2_chunk_ptr.len();
+
+                let estimate = storage.estimate_write_chunks(node, offset, len).unwrap();
+
+                storage
+                    .write(node, offset, &vec![7u8; len as usize])
+                    .unwrap();
+
+                let after = storage.filechunk.len() + storage.v2_chunk_ptr.len();
+
+                assert_eq!(estimate as u64, after - before);
+            }
+        }
+    }
+
+    #[test]
+    fn sorted_range_reads_reuse_cached_pointers() {
+        let mut storage = StableStorage::new(DefaultMemoryImpl::default());
+        let node = storage.new_node();
+        storage.put_metadata(
+            node,
+            Metadata {
+                node,
+                file_type: FileType::RegularFile,
+                link_count: 1,
+                size: 0,
+                times: Times::default(),
+                first_dir_entry: None,
+                last_dir_entry: None,
+                chunk_type: Some(ChunkType::V2),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
+            },
+        );
+
+        let chunk_size = storage.chunk_size() as FileSize;
+        let content = vec![7u8; 90 * chunk_size as usize];
+        storage.write(node, 0, &content).unwrap();
+
+        let offsets = [80 * chunk_size, 40 * chunk_size, 10];
+        let mut buf = [0u8; 100];
+
+        // descending order, every read misses the cache
+        storage.ptr_cache.clear();
+        storage.ptr_cache.range_lookups = 0;
+        for offset in offsets {
+            storage.read(node, offset, &mut buf).unwrap();
+        }
+        let unsorted_lookups = storage.ptr_cache.range_lookups;
+
+        // ascending order, the first lookup preloads the pointers for the following reads
+        storage.ptr_cache.clear();
+        storage.ptr_cache.range_lookups = 0;
+        let mut sorted = offsets;
+        sorted.sort();
+        for offset in sorted {
+            storage.read(node, offset, &mut buf).unwrap();
+        }
+        let sorted_lookups = storage.ptr_cache.range_lookups;
+
+        assert_eq!(sorted_lookups, 1);
+        assert!(sorted_lookups < unsorted_lookups);
+    }
+
     #[test]
     fn read_and_write_direntry() {
         let mut storage = StableStorage::new(DefaultMemoryImpl::default());
@@ -5,8 +5,11 @@ use ic_stable_structures::Memory;
 
 use crate::{
     error::Error,
-    fs::{ChunkSize, ChunkType},
-    runtime::structure_helpers::{get_chunk_infos, grow_memory},
+    fs::{Advice, ChunkSize, ChunkType, Normalization},
+    runtime::structure_helpers::{
+        check_chunk_limit, copy_to_front, get_chunk_infos, grow_memory, try_grow_memory,
+        zero_memory,
+    },
     storage::{
         types::{
             DirEntry, DirEntryIndex, FileChunk, FileChunkIndex, FileSize, FileType, Metadata, Node,
@@ -16,7 +19,9 @@ use crate::{
     },
 };
 
-use super::types::{Header, FILE_CHUNK_SIZE_V1};
+use super::types::{
+    Header, XattrName, FILE_CHUNK_SIZE_V1, MAX_FILE_CHUNK_COUNT, MAX_XATTR_SIZE_PER_NODE,
+};
 
 // The root node ID.
 const ROOT_NODE: Node = 0;
@@ -37,6 +42,16 @@ pub struct TransientStorage {
     mounted_meta: BTreeMap<Node, Metadata>,
     // Active mounts.
     active_mounts: HashMap<Node, Box<dyn Memory>>,
+    // Memories to mount once the file grows past the threshold.
+    spill_mounts: HashMap<Node, (FileSize, Box<dyn Memory>)>,
+    // Maximum number of entries in a single directory.
+    max_dir_entries: Option<FileSize>,
+    // Clock for the directory modification times.
+    dir_clock: Option<Box<dyn Fn() -> u64>>,
+    // Extended attributes of the nodes.
+    xattrs: BTreeMap<(Node, XattrName), Vec<u8>>,
+    // Report the storage as not writable.
+    writable: bool,
 }
 
 impl TransientStorage {
@@ -51,11 +66,17 @@ impl TransientStorage {
             first_dir_entry: None,
             last_dir_entry: None,
             chunk_type: None,
+            chunk_size: None,
+            max_dir_entry: None,
+            sorted_dir_entries: false,
         };
         let mut result = Self {
             header: Header {
                 version: 1,
                 next_node: ROOT_NODE + 1,
+                filename_normalization: Normalization::None,
+                trim_trailing_space: false,
+                pending_removals: Vec::new(),
             },
             metadata: Default::default(),
             direntry: Default::default(),
@@ -63,11 +84,37 @@ impl TransientStorage {
 
             mounted_meta: Default::default(),
             active_mounts: Default::default(),
+            spill_mounts: Default::default(),
+            max_dir_entries: None,
+            dir_clock: None,
+            xattrs: Default::default(),
+            writable: true,
         };
         result.put_metadata(ROOT_NODE, metadata);
         result
     }
 
+    // Move the file into its spill memory if the file end passes the spill threshold.
+    fn spill_if_needed(&mut self, node: Node, end: FileSize) -> Result<(), Error> {
+        if self
+            .spill_mounts
+            .get(&node)
+            .is_none_or(|(threshold, _)| end <= *threshold)
+        {
+            return Ok(());
+        }
+
+        let (_, memory) = self.spill_mounts.remove(&node).unwrap();
+
+        self.mount_node(node, memory)?;
+        self.init_mounted_memory(node)
+    }
+
+    // Make the storage report itself as read-only, to check how a file system behaves on a read-only backend.
+    pub fn set_writable(&mut self, writable: bool) {
+        self.writable = writable;
+    }
+
     // Insert of update a selected file chunk with the data provided in buffer.
     fn write_filechunk(&mut self, node: Node, index: FileChunkIndex, offset: FileSize, buf: &[u8]) {
         if let Some(memory) = self.get_mounted_memory(node) {
@@ -99,6 +146,15 @@ impl Storage for TransientStorage {
         FS_TRANSIENT_VERSION
     }
 
+    fn check_integrity_fingerprint(&self) -> bool {
+        // the heap data is not shared with anything else
+        true
+    }
+
+    fn is_writable(&self) -> bool {
+        self.writable
+    }
+
     // Generate the next available node ID.
     fn new_node(&mut self) -> Node {
         let result = self.header.next_node;
@@ -106,6 +162,22 @@ impl Storage for TransientStorage {
         result
     }
 
+    fn next_node(&self) -> Node {
+        self.header.next_node
+    }
+
+    fn reserve_nodes(&mut self, _count: u64) {
+        // Noop, the node counter is on the heap
+    }
+
+    fn iter_nodes(&self) -> Box<dyn Iterator<Item = Node> + '_> {
+        Box::new(self.metadata.keys().copied())
+    }
+
+    fn node_count(&self) -> u64 {
+        self.metadata.len() as u64
+    }
+
     // Get the metadata associated with the node.
     fn get_metadata(&self, node: Node) -> Result<Metadata, Error> {
         let meta = if self.is_mounted(node) {
@@ -154,16 +226,17 @@ impl Storage for TransientStorage {
             let remainder = file_size - offset;
             let to_read = remainder.min(buf.len() as FileSize);
 
-            // grow memory also for reading
-            grow_memory(memory.as_ref(), offset + to_read);
+            // grow memory also for reading, the size may point past what the memory can hold
+            try_grow_memory(memory.as_ref(), offset + to_read)?;
 
             memory.read(offset, &mut buf[..to_read as usize]);
             to_read
         } else {
             let start_index = (offset / FILE_CHUNK_SIZE_V1 as FileSize) as FileChunkIndex;
 
+            // the index past the last chunk may not fit into `FileChunkIndex`
             let end_index = ((offset + buf.len() as FileSize) / FILE_CHUNK_SIZE_V1 as FileSize + 1)
-                as FileChunkIndex;
+                .min(MAX_FILE_CHUNK_COUNT);
 
             let mut chunk_offset =
                 offset - start_index as FileSize * FILE_CHUNK_SIZE_V1 as FileSize;
@@ -176,7 +249,7 @@ impl Storage for TransientStorage {
             let mut iter = self.filechunk.range(range);
             let mut cur_fetched = None;
 
-            for cur_index in start_index..end_index {
+            for cur_index in start_index as u64..end_index {
                 let chunk_space = FILE_CHUNK_SIZE_V1 as FileSize - chunk_offset;
 
                 let to_read = remainder
@@ -195,7 +268,7 @@ impl Storage for TransientStorage {
                 let read_buf = &mut buf[size_read as usize..size_read as usize + to_read as usize];
 
                 if let Some(((nd, idx), value)) = cur_fetched {
-                    if *idx == cur_index {
+                    if *idx as u64 == cur_index {
                         assert!(*nd == node);
 
                         read_buf.copy_from_slice(
@@ -225,7 +298,147 @@ impl Storage for TransientStorage {
         Ok(size_read)
     }
 
+    // Transient storage has no direct chunk access, read the chunk range instead
+    fn read_chunk(
+        &mut self,
+        node: Node,
+        index: FileChunkIndex,
+        buf: &mut [u8],
+    ) -> Result<FileSize, Error> {
+        let chunk_size = FILE_CHUNK_SIZE_V1.min(buf.len());
+        let offset = index as FileSize * FILE_CHUNK_SIZE_V1 as FileSize;
+
+        self.read(node, offset, &mut buf[..chunk_size])
+    }
+
+    fn estimate_write_chunks(
+        &mut self,
+        node: Node,
+        offset: FileSize,
+        len: FileSize,
+    ) -> Result<usize, Error> {
+        self.get_metadata(node)?;
+
+        if len == 0 || self.is_mounted(node) {
+            return Ok(0);
+        }
+
+        let chunk_size = FILE_CHUNK_SIZE_V1 as FileSize;
+        let first = (offset / chunk_size) as FileChunkIndex;
+        let last_excluded = ((offset + len - 1) / chunk_size + 1) as FileChunkIndex;
+
+        let existing = self
+            .filechunk
+            .range((node, first)..(node, last_excluded))
+            .count();
+
+        Ok((last_excluded - first) as usize - existing)
+    }
+
+    fn preallocate(&mut self, node: Node, _size: FileSize) -> Result<(), Error> {
+        // the chunks are allocated on the heap, there is nothing to reserve
+        self.get_metadata(node)?;
+
+        Ok(())
+    }
+
+    fn clear_caches(&mut self) {
+        // Noop, all data is on the heap
+    }
+
+    fn advise(
+        &mut self,
+        node: Node,
+        _offset: FileSize,
+        _len: FileSize,
+        _advice: Advice,
+    ) -> Result<(), Error> {
+        // there are no caches to manage
+        self.get_metadata(node)?;
+
+        Ok(())
+    }
+
+    fn fragmentation(&mut self, node: Node) -> Result<f64, Error> {
+        // the chunks are kept on the heap, there is no memory layout to measure
+        self.get_metadata(node)?;
+
+        Ok(0.0)
+    }
+
+    fn defragment(&mut self, node: Node) -> Result<(), Error> {
+        self.get_metadata(node)?;
+
+        Ok(())
+    }
+
+    fn chunk_range(
+        &mut self,
+        node: Node,
+    ) -> Result<Option<(FileChunkIndex, FileChunkIndex)>, Error> {
+        self.get_metadata(node)?;
+
+        if self.is_mounted(node) {
+            return Ok(None);
+        }
+
+        let mut chunks = self
+            .filechunk
+            .range((node, 0)..(node + 1, 0))
+            .map(|((_, index), _)| *index);
+
+        Ok(chunks
+            .next()
+            .map(|first| (first, chunks.next_back().unwrap_or(first))))
+    }
+
+    fn format(&mut self) -> Result<(), Error> {
+        if !self.active_mounts.is_empty() {
+            return Err(Error::CannotRemoveMountedMemoryFile);
+        }
+
+        let mut root = self.get_metadata(ROOT_NODE)?;
+        root.size = 0;
+        root.first_dir_entry = None;
+        root.last_dir_entry = None;
+        root.max_dir_entry = None;
+
+        self.metadata.clear();
+        self.direntry.clear();
+        self.filechunk.clear();
+        self.spill_mounts.clear();
+        self.mounted_meta.clear();
+        self.xattrs.clear();
+
+        self.header.next_node = ROOT_NODE + 1;
+        self.header.pending_removals.clear();
+        self.put_metadata(ROOT_NODE, root);
+
+        Ok(())
+    }
+
     //
+    fn rm_file_chunks(&mut self, node: Node, max_chunks: usize) -> Result<bool, Error> {
+        if self.is_mounted(node) {
+            return Err(Error::CannotRemoveMountedMemoryFile);
+        }
+
+        let range = (node, 0)..(node + 1, 0);
+
+        let chunks: Vec<(Node, FileChunkIndex)> = self
+            .filechunk
+            .range(range.clone())
+            .map(|(k, _v)| *k)
+            .take(max_chunks)
+            .collect();
+
+        for key in chunks.into_iter() {
+            self.filechunk.remove(&key);
+        }
+
+        Ok(self.filechunk.range(range).next().is_none())
+    }
+
     fn rm_file(&mut self, node: Node) -> Result<(), Error> {
         if self.is_mounted(node) {
             return Err(Error::CannotRemoveMountedMemoryFile);
@@ -244,6 +457,16 @@ impl Storage for TransientStorage {
             self.filechunk.remove(&(node, idx));
         }
 
+        self.spill_mounts.remove(&node);
+
+        // remove extended attributes
+        let range = (node, XattrName(String::new()))..(node + 1, XattrName(String::new()));
+        let names: Vec<_> = self.xattrs.range(range).map(|(k, _v)| k.clone()).collect();
+
+        for key in names.into_iter() {
+            self.xattrs.remove(&key);
+        }
+
         // remove metadata
         self.mounted_meta.remove(&node);
         self.metadata.remove(&node);
@@ -251,6 +474,48 @@ impl Storage for TransientStorage {
         Ok(())
     }
 
+    fn trim_front(&mut self, node: Node, bytes: FileSize) -> Result<(), Error> {
+        let mut metadata = self.get_metadata(node)?;
+
+        let bytes = bytes.min(metadata.size);
+
+        if bytes == 0 {
+            return Ok(());
+        }
+
+        let new_size = metadata.size - bytes;
+
+        copy_to_front(node, bytes, self)?;
+
+        if let Some(memory) = self.get_mounted_memory(node) {
+            // clear the leftover data of the mounted memory
+            memory.write(new_size, &vec![0u8; bytes as usize]);
+        } else {
+            // zero the tail of the last chunk and remove the chunks beyond the new size
+            let tail_end = new_size
+                .next_multiple_of(FILE_CHUNK_SIZE_V1 as FileSize)
+                .min(metadata.size);
+
+            if tail_end > new_size {
+                self.write(node, new_size, &vec![0u8; (tail_end - new_size) as usize])?;
+            }
+
+            let first_index = new_size.div_ceil(FILE_CHUNK_SIZE_V1 as FileSize) as FileChunkIndex;
+            let range = (node, first_index)..(node + 1, 0);
+            let chunks: Vec<_> = self.filechunk.range(range).map(|(k, _v)| *k).collect();
+
+            for key in chunks.into_iter() {
+                self.filechunk.remove(&key);
+            }
+        }
+
+        metadata = self.get_metadata(node)?;
+        metadata.size = new_size;
+        self.put_metadata(node, metadata);
+
+        Ok(())
+    }
+
     fn mount_node(&mut self, node: Node, memory: Box<dyn Memory>) -> Result<(), Error> {
         if self.is_mounted(node) {
             return Err(Error::MemoryFileIsMountedAlready);
@@ -347,7 +612,11 @@ impl Storage for TransientStorage {
 
             memory.read(offset, &mut buf[..to_read as usize]);
 
-            self.write(node, offset, &buf[..to_read as usize])?;
+            // restore the mount if the data could not be stored
+            if let Err(err) = self.write(node, offset, &buf[..to_read as usize]) {
+                self.mount_node(node, memory)?;
+                return Err(err);
+            }
 
             offset += to_read;
             remainder -= to_read;
@@ -361,7 +630,22 @@ impl Storage for TransientStorage {
     }
 
     fn write(&mut self, node: Node, offset: FileSize, buf: &[u8]) -> Result<FileSize, Error> {
+        self.spill_if_needed(node, offset + buf.len() as FileSize)?;
+
         let mut metadata = self.get_metadata(node)?;
+
+        if !self.is_mounted(node) {
+            check_chunk_limit(offset + buf.len() as FileSize, FILE_CHUNK_SIZE_V1)?;
+        }
+
+        // the memory might keep stale data past the end of file, clear the gap the write exposes
+        if let Some(memory) = self.get_mounted_memory(node) {
+            try_grow_memory(memory, offset + buf.len() as FileSize)?;
+
+            if offset > metadata.size {
+                zero_memory(memory, metadata.size, offset);
+            }
+        }
         let end = offset + buf.len() as FileSize;
         let chunk_infos = get_chunk_infos(offset, end, FILE_CHUNK_SIZE_V1);
         let mut written_size = 0;
@@ -383,6 +667,67 @@ impl Storage for TransientStorage {
         Ok(written_size as FileSize)
     }
 
+    fn set_spill_mount(
+        &mut self,
+        node: Node,
+        threshold: FileSize,
+        memory: Box<dyn Memory>,
+    ) -> Result<(), Error> {
+        let size = self.get_metadata(node)?.size;
+
+        if self.is_mounted(node) {
+            return Err(Error::MemoryFileIsMountedAlready);
+        }
+
+        self.spill_mounts.insert(node, (threshold, memory));
+
+        // a file already past the threshold is moved right away
+        self.spill_if_needed(node, size)
+    }
+
+    fn get_xattr(&self, node: Node, name: &str) -> Result<Vec<u8>, Error> {
+        let key = (node, XattrName::new(name)?);
+
+        self.xattrs.get(&key).cloned().ok_or(Error::NotFound)
+    }
+
+    fn set_xattr(&mut self, node: Node, name: &str, value: &[u8]) -> Result<(), Error> {
+        let key = (node, XattrName::new(name)?);
+
+        // compute the total attribute size of the node without the attribute being replaced
+        let range = (node, XattrName(String::new()))..(node + 1, XattrName(String::new()));
+        let total_size: usize = self
+            .xattrs
+            .range(range)
+            .filter(|(k, _v)| **k != key)
+            .map(|(k, v)| k.1 .0.len() + v.len())
+            .sum();
+
+        if total_size + name.len() + value.len() > MAX_XATTR_SIZE_PER_NODE {
+            return Err(Error::XattrTooLarge);
+        }
+
+        self.xattrs.insert(key, value.to_vec());
+
+        Ok(())
+    }
+
+    fn list_xattrs(&self, node: Node) -> Result<Vec<String>, Error> {
+        let range = (node, XattrName(String::new()))..(node + 1, XattrName(String::new()));
+
+        Ok(self
+            .xattrs
+            .range(range)
+            .map(|(k, _v)| k.1 .0.clone())
+            .collect())
+    }
+
+    fn remove_xattr(&mut self, node: Node, name: &str) -> Result<(), Error> {
+        let key = (node, XattrName::new(name)?);
+
+        self.xattrs.remove(&key).map(|_| ()).ok_or(Error::NotFound)
+    }
+
     fn set_chunk_size(&mut self, _chunk_size: ChunkSize) -> Result<(), Error> {
         // Noop
         Ok(())
@@ -400,13 +745,120 @@ impl Storage for TransientStorage {
         ChunkType::V1
     }
 
-    fn flush(&mut self, _node: Node) {
+    fn fix_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error> {
+        // only V1 chunks are stored
+        let mut metadata = self.get_metadata(node)?;
+        metadata.chunk_type = Some(ChunkType::V1);
+        self.put_metadata(node, metadata);
+
+        Ok(ChunkType::V1)
+    }
+
+    fn effective_chunk_type(&mut self, node: Node) -> Result<ChunkType, Error> {
+        // only V1 chunks are stored
+        self.get_metadata(node)?;
+        Ok(ChunkType::V1)
+    }
+
+    fn set_file_chunk_size(&mut self, node: Node, _chunk_size: ChunkSize) -> Result<(), Error> {
+        // only V1 chunks are stored, the chunk size is fixed
+        self.get_metadata(node)?;
+        Ok(())
+    }
+
+    fn set_adaptive_chunking(&mut self, _enabled: bool) {
+        // Noop
+    }
+
+    fn adaptive_chunking(&self) -> bool {
+        false
+    }
+
+    fn set_append_buffering(&mut self, _enabled: bool) -> Result<(), Error> {
+        // Noop
+        Ok(())
+    }
+
+    fn append_buffering(&self) -> bool {
+        false
+    }
+
+    fn set_max_dir_entries(&mut self, limit: Option<FileSize>) {
+        self.max_dir_entries = limit;
+    }
+
+    fn max_dir_entries(&self) -> Option<FileSize> {
+        self.max_dir_entries
+    }
+
+    fn set_filename_normalization(&mut self, normalization: Normalization) {
+        self.header.filename_normalization = normalization;
+    }
+
+    fn filename_normalization(&self) -> Normalization {
+        self.header.filename_normalization
+    }
+
+    fn set_trim_trailing_space(&mut self, trim: bool) {
+        self.header.trim_trailing_space = trim;
+    }
+
+    fn trim_trailing_space(&self) -> bool {
+        self.header.trim_trailing_space
+    }
+
+    fn pending_removals(&self) -> Vec<(Node, String, Node)> {
+        self.header.pending_removals.clone()
+    }
+
+    fn set_pending_removals(&mut self, removals: Vec<(Node, String, Node)>) {
+        self.header.pending_removals = removals;
+    }
+
+    fn set_dir_clock(&mut self, clock: Option<Box<dyn Fn() -> u64>>) {
+        self.dir_clock = clock;
+    }
+
+    fn dir_clock_time(&self) -> Option<u64> {
+        self.dir_clock.as_ref().map(|clock| clock())
+    }
+
+    fn defer_metadata(&mut self, node: Node) -> Result<(), Error> {
+        // the metadata is already kept in memory
+        self.get_metadata(node)?;
+        Ok(())
+    }
+
+    fn flush_deferred_metadata(&mut self) {
+        // Noop
+    }
+
+    #[cfg(feature = "profiling")]
+    fn operation_counters(&self) -> super::types::OperationCounters {
+        // there are no low-level operations to count
+        super::types::OperationCounters::default()
+    }
+
+    fn flush(&mut self, _node: Node) -> Result<(), Error> {
+        // Noop
+        Ok(())
+    }
+
+    fn flush_all(&mut self) -> Result<(), Error> {
+        // Noop
+        Ok(())
+    }
+
+    fn persist_mounted_metadata(&mut self) {
         // Noop
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::runtime::structure_helpers::create_dir_entry;
+    use crate::storage::types::MAX_FILE_ENTRY_INDEX;
+
     use super::*;
 
     #[test]
@@ -424,6 +876,9 @@ mod tests {
                 first_dir_entry: None,
                 last_dir_entry: None,
                 chunk_type: Some(storage.chunk_type()),
+                chunk_size: None,
+                max_dir_entry: None,
+                sorted_dir_entries: false,
             },
         );
         storage.write(node, 0, &[42; 10]).unwrap();
@@ -431,4 +886,66 @@ mod tests {
         storage.read(node, 0, &mut buf).unwrap();
         assert_eq!(buf, [42; 10]);
     }
+
+    #[test]
+    fn write_past_last_chunk_fails() {
+        let mut storage = TransientStorage::default();
+        let root = storage.root_node();
+        let node =
+            create_dir_entry(root, b"file.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        let limit = MAX_FILE_CHUNK_COUNT * FILE_CHUNK_SIZE_V1 as FileSize;
+
+        // the last chunk can be written
+        storage.write(node, limit - 10, &[1u8; 10]).unwrap();
+        assert_eq!(storage.get_metadata(node).unwrap().size, limit);
+
+        assert_eq!(
+            storage.write(node, limit - 5, &[2u8; 10]),
+            Err(Error::FileChunkLimitExceeded)
+        );
+        assert_eq!(
+            storage.write(node, limit, &[2u8; 1]),
+            Err(Error::FileChunkLimitExceeded)
+        );
+
+        // nothing was written, the first chunk is not overwritten by a wrapped index
+        assert_eq!(storage.get_metadata(node).unwrap().size, limit);
+        assert!(!storage.filechunk.contains_key(&(node, 0)));
+
+        let mut buf = [0u8; 10];
+        storage.read(node, limit - 10, &mut buf).unwrap();
+        assert_eq!(buf, [1u8; 10]);
+    }
+
+    #[test]
+    fn dir_entry_past_last_index_fails() {
+        let mut storage = TransientStorage::default();
+        let root = storage.root_node();
+        let dir = create_dir_entry(root, b"dir", FileType::Directory, &mut storage, 0).unwrap();
+        create_dir_entry(dir, b"a.txt", FileType::RegularFile, &mut storage, 0).unwrap();
+
+        // move the entry to the last index
+        let mut metadata = storage.get_metadata(dir).unwrap();
+        let index = metadata.last_dir_entry.unwrap();
+        let entry = storage.get_direntry(dir, index).unwrap();
+        storage.rm_direntry(dir, index);
+        storage.put_direntry(dir, MAX_FILE_ENTRY_INDEX, entry);
+        metadata.first_dir_entry = Some(MAX_FILE_ENTRY_INDEX);
+        metadata.last_dir_entry = Some(MAX_FILE_ENTRY_INDEX);
+        metadata.max_dir_entry = Some(MAX_FILE_ENTRY_INDEX);
+        storage.put_metadata(dir, metadata);
+
+        let nodes = storage.node_count();
+        let dir_size = storage.get_metadata(dir).unwrap().size;
+
+        assert_eq!(
+            create_dir_entry(dir, b"b.txt", FileType::RegularFile, &mut storage, 0),
+            Err(Error::DirEntryLimitExceeded)
+        );
+
+        // no orphaned node is left behind
+        assert_eq!(storage.node_count(), nodes);
+        assert_eq!(storage.get_metadata(dir).unwrap().size, dir_size);
+    }
 }
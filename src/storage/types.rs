@@ -1,4 +1,7 @@
-use crate::{error::Error, fs::ChunkType};
+use crate::{
+    error::Error,
+    fs::{ChunkSize, ChunkType, Normalization},
+};
 use ic_stable_structures::storable::Bound;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +12,11 @@ pub const MAX_FILE_CHUNK_SIZE_V2: usize = 65536;
 
 pub const MAX_FILE_NAME: usize = 255;
 
+pub const MAX_XATTR_NAME: usize = 255;
+
+// The maximum total size of all extended attribute names and values of a single node.
+pub const MAX_XATTR_SIZE_PER_NODE: usize = 65536;
+
 // The unique identifier of a node, which can be a file or a directory.
 // Also known as inode in WASI and other file systems.
 pub type Node = u64;
@@ -19,9 +27,27 @@ pub type FileSize = u64;
 // An index of a file chunk.
 pub type FileChunkIndex = u32;
 
+// The number of chunks a file can have, a write past the last chunk fails with `Error::FileChunkLimitExceeded`.
+// With the smallest chunk size the chunks end at `MAX_FILE_SIZE`, larger chunks allow writing further.
+pub const MAX_FILE_CHUNK_COUNT: u64 = FileChunkIndex::MAX as u64 + 1;
+
+// The largest file size addressable with the smallest chunk size.
+pub const MAX_FILE_SIZE: FileSize =
+    FILE_CHUNK_SIZE_V1 as FileSize * (FileChunkIndex::MAX as FileSize + 1);
+
 // The address in memory where the V2 chunk is stored.
 pub type FileChunkPtr = u64;
 
+// Low-level operation counts of a storage, they are only collected with the `profiling` feature.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OperationCounters {
+    pub chunk_reads: u64,
+    pub chunk_writes: u64,
+    pub btree_gets: u64,
+    pub btree_inserts: u64,
+    pub allocations: u64,
+}
+
 // A handle used for writing files in chunks
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct ChunkHandle {
@@ -65,6 +91,15 @@ impl ic_stable_structures::Storable for FileChunk {
 pub struct Header {
     pub version: u32,
     pub next_node: Node,
+    // the file name policies are stored with the header to stay the same after an upgrade,
+    // the headers written before they existed decode with the defaults.
+    #[serde(default)]
+    pub filename_normalization: Normalization,
+    #[serde(default)]
+    pub trim_trailing_space: bool,
+    // files unlinked by a chunked removal whose chunks are not released yet, as (parent folder, path, node).
+    #[serde(default)]
+    pub pending_removals: Vec<(Node, String, Node)>,
 }
 
 impl ic_stable_structures::Storable for Header {
@@ -92,6 +127,15 @@ pub struct Metadata {
     pub first_dir_entry: Option<DirEntryIndex>,
     pub last_dir_entry: Option<DirEntryIndex>,
     pub chunk_type: Option<ChunkType>,
+    // size of the V2 chunks of the file, if it differs from the storage chunk size.
+    pub chunk_size: Option<ChunkSize>,
+    // the highest entry index given out in the folder, the indices of the removed entries are not reused.
+    // Not set for the folders created by older versions, there the last entry has the highest index.
+    #[serde(default)]
+    pub max_dir_entry: Option<DirEntryIndex>,
+    // the folder inserts the new entries in the name order rather than appending them.
+    #[serde(default)]
+    pub sorted_dir_entries: bool,
 }
 
 // Contains metadata that is stored together with the file chunks (for faster access and updates of the file size)
@@ -213,9 +257,45 @@ impl FileName {
     }
 }
 
+// The name of an extended attribute.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct XattrName(pub String);
+
+impl XattrName {
+    pub fn new(name: &str) -> Result<Self, Error> {
+        if name.is_empty() {
+            return Err(Error::InvalidFileName);
+        }
+
+        if name.len() > MAX_XATTR_NAME {
+            return Err(Error::NameTooLong);
+        }
+
+        Ok(Self(name.to_string()))
+    }
+}
+
+impl ic_stable_structures::Storable for XattrName {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_XATTR_NAME as u32,
+        is_fixed_size: false,
+    };
+}
+
 // An index of a directory entry.
 pub type DirEntryIndex = u32;
 
+// The largest directory entry index, adding an entry after it fails with `Error::DirEntryLimitExceeded`.
+pub const MAX_FILE_ENTRY_INDEX: DirEntryIndex = DirEntryIndex::MAX;
+
 // A directory contains a list of directory entries.
 // Each entry describes a name of a file or a directory.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -359,4 +439,29 @@ mod tests {
         assert_eq!(meta_new.last_dir_entry, meta_old.last_dir_entry);
         assert_eq!(meta_new.chunk_type, None);
     }
+
+    #[test]
+    fn load_metadata_without_chunk_size() {
+        use ic_stable_structures::Storable;
+
+        let meta_prev = MetadataNew {
+            node: 23,
+            file_type: FileType::RegularFile,
+            link_count: 1,
+            size: 123,
+            times: Times::default(),
+            first_dir_entry: None,
+            last_dir_entry: None,
+            chunk_type: Some(ChunkType::V2),
+        };
+
+        let mut buf = vec![];
+        ciborium::ser::into_writer(&meta_prev, &mut buf).unwrap();
+
+        let meta = super::Metadata::from_bytes(std::borrow::Cow::Owned(buf));
+
+        assert_eq!(meta.size, meta_prev.size);
+        assert_eq!(meta.chunk_type, Some(ChunkType::V2));
+        assert_eq!(meta.chunk_size, None);
+    }
 }
@@ -230,8 +230,8 @@ mod test_env {
 
             let dir = fs.root_fd();
 
-            let fd = fs
-                .open_or_create(
+            let (_fd, meta) = fs
+                .open_with_metadata(
                     dir,
                     filename.as_str(),
                     FdStat::default(),
@@ -240,8 +240,6 @@ mod test_env {
                 )
                 .unwrap();
 
-            let meta = fs.metadata(fd).unwrap();
-
             let size = meta.size;
 
             size as usize
@@ -493,11 +491,17 @@ mod test_env {
 
                 let root_fd = (*fs).root_fd();
 
-                let fd = (*fs)
-                    .open_or_create(root_fd, &filename, FdStat::default(), OpenFlags::CREATE, 42)
+                let (fd, metadata) = (*fs)
+                    .open_with_metadata(
+                        root_fd,
+                        &filename,
+                        FdStat::default(),
+                        OpenFlags::CREATE,
+                        42,
+                    )
                     .unwrap();
 
-                let size = (*fs).metadata(fd).unwrap().size as usize;
+                let size = metadata.size as usize;
 
                 (*fs).seek(fd, 0, Whence::SET).unwrap();
 
@@ -329,6 +329,46 @@ fn read_bytes(filename: String, offset: i64, size: usize) -> (u64, usize) {
     (etime - stime, res.len())
 }
 
+// read the file chunk by chunk with `read_chunk_into`, to compare with `read_bytes`,
+// the `chunk_size` should be the chunk size of the file system.
+#[ic_cdk::update]
+fn read_chunks(filename: String, chunk_size: usize, size: usize) -> (u64, usize) {
+
+    let stime = ic_cdk::api::instruction_counter();    
+
+    let mut buf = vec![0u8; chunk_size];
+    let mut total = 0;
+
+    FS.with(|fs| {
+
+        let mut fs = fs.borrow_mut();
+
+        let dir = fs.root_fd();
+
+        let fd = fs
+            .open_or_create(dir, filename.as_str(), FdStat::default(), OpenFlags::empty(), 0)
+            .unwrap();
+
+        let mut index = 0;
+        while total < size {
+            let len = fs.read_chunk_into(fd, index, &mut buf).unwrap() as usize;
+
+            if len == 0 {
+                break;
+            }
+
+            total += len;
+            index += 1;
+        }
+        
+        let _ = fs.close(fd);
+        
+    });
+
+    let etime = ic_cdk::api::instruction_counter();    
+    (etime - stime, total)
+}
+
 
 // delete file
 #[ic_cdk::query]